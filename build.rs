@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // We only serve the admin API, clients bring their own stubs
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/admin.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Addresses with a long history, like ones spammed with dust, would make every status hash
+//! go through all of it, and keep all of it in memory. Past
+//! [crate::config::ResourceLimits::max_hot_history] mined transactions, the oldest ones are
+//! moved to an archive in our database, only loaded when the whole history is asked for.
+//! Status hashes hash an address' history in order, so all they need from the archived part
+//! is the hash engine's state after it, which we keep with the address. That only works
+//! while archived transactions come first, so one mined before some of them, like one a
+//! rescan finds, brings the archive back into memory, to be archived again in order.
+
+use bitcoin::hashes::{
+    hex::{FromHex, ToHex},
+    sha256, HashEngine,
+};
+
+use super::{stored::StoredArchive, CachedTransaction};
+
+/// The archived part of an address history
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchivedHistory {
+    /// How many transactions are archived. They come before the ones we keep in memory, so
+    /// this is also where those start in the address' history
+    pub count: usize,
+    /// The height of the newest archived transaction
+    pub last_height: u32,
+    /// How many bytes of status preimage the archived transactions make
+    length: usize,
+    /// The status hash engine's midstate after the last whole block of that preimage
+    midstate: [u8; 32],
+    /// What's left of the preimage after that block
+    tail: Vec<u8>,
+}
+
+impl ArchivedHistory {
+    /// Adds these transactions to the archive, oldest first
+    pub fn extend(&mut self, transactions: &[CachedTransaction]) {
+        let mut engine = self.status_engine();
+        let mut unhashed = self.tail.clone();
+        for transaction in transactions {
+            let entry = format!("{}:{}:", transaction.hash, transaction.height);
+            engine.input(entry.as_bytes());
+            unhashed.extend_from_slice(entry.as_bytes());
+            self.last_height = self.last_height.max(transaction.height);
+        }
+        self.count += transactions.len();
+        self.length = engine.n_bytes_hashed();
+        self.midstate = engine.midstate().into_inner();
+        self.tail = unhashed[unhashed.len() - self.length % 64..].to_vec();
+    }
+    /// A status hash engine that already hashed the archived transactions
+    pub fn status_engine(&self) -> sha256::HashEngine {
+        if self.length == 0 {
+            return sha256::HashEngine::default();
+        }
+        let mut engine = sha256::HashEngine::from_midstate(
+            sha256::Midstate::from_inner(self.midstate),
+            self.length - self.tail.len(),
+        );
+        engine.input(&self.tail);
+        engine
+    }
+}
+
+impl From<&ArchivedHistory> for StoredArchive {
+    fn from(archived: &ArchivedHistory) -> Self {
+        StoredArchive {
+            count: archived.count,
+            last_height: archived.last_height,
+            length: archived.length,
+            midstate: archived.midstate.to_hex(),
+            tail: archived.tail.to_hex(),
+        }
+    }
+}
+
+impl TryFrom<StoredArchive> for ArchivedHistory {
+    type Error = crate::error::Error;
+    fn try_from(stored: StoredArchive) -> Result<Self, Self::Error> {
+        let midstate = Vec::from_hex(&stored.midstate)?
+            .try_into()
+            .map_err(|_| crate::error::Error::DbParseError)?;
+        Ok(ArchivedHistory {
+            count: stored.count,
+            last_height: stored.last_height,
+            length: stored.length,
+            midstate,
+            tail: Vec::from_hex(&stored.tail)?,
+        })
+    }
+}
+
+/// How older versions wrote it, `count;last_height;length;midstate;tail`
+impl TryFrom<&str> for ArchivedHistory {
+    type Error = crate::error::Error;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let fields = value.split(';').collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(crate::error::Error::DbParseError);
+        }
+        let midstate = Vec::from_hex(fields[3])?
+            .try_into()
+            .map_err(|_| crate::error::Error::DbParseError)?;
+        Ok(ArchivedHistory {
+            count: fields[0].parse()?,
+            last_height: fields[1].parse()?,
+            length: fields[2].parse()?,
+            midstate,
+            tail: Vec::from_hex(fields[4])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        hashes::{sha256, Hash, HashEngine},
+        Txid,
+    };
+
+    use super::ArchivedHistory;
+    use crate::address_cache::{stored::StoredArchive, CachedTransaction};
+
+    #[test]
+    fn test_status_engine() {
+        let transactions = (1..=10u8)
+            .map(|n| CachedTransaction {
+                height: n as u32 * 1000,
+                hash: Txid::from_slice(&[n; 32]).unwrap(),
+                position: 1,
+            })
+            .collect::<Vec<_>>();
+        let preimage = transactions
+            .iter()
+            .map(|tx| format!("{}:{}:", tx.hash, tx.height))
+            .collect::<String>();
+        let mut archived = ArchivedHistory::default();
+        archived.extend(&transactions[..3]);
+        // Reloading it must not change where it was
+        let mut archived = ArchivedHistory::try_from(StoredArchive::from(&archived)).unwrap();
+        archived.extend(&transactions[3..7]);
+        assert_eq!(archived.count, 7);
+        assert_eq!(archived.last_height, 7000);
+
+        let mut engine = archived.status_engine();
+        for tx in transactions[7..].iter() {
+            engine.input(format!("{}:{}:", tx.hash, tx.height).as_bytes());
+        }
+        assert_eq!(
+            sha256::Hash::from_engine(engine),
+            sha256::Hash::hash(preimage.as_bytes())
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Signed statements that a range of a descriptor's addresses was never used, up to our tip.
+//! Operators hand these to accountants or auditors, who check the signature against our
+//! server identity. We can only vouch for addresses we watch, so the ones we don't are
+//! listed apart, and keep the range from being attested as unused.
+//!
+//! The signature is over `sha256(tag || tip_commitment || descriptor length || descriptor ||
+//! from || to || unused || used count || used indexes || unwatched count || unwatched
+//! indexes)`, with numbers as u32 LE and `unused` as one byte. Both lists are prefixed by
+//! their length, so an index can't be moved from one to the other under the same signature.
+//! `tip_commitment` is the one `server.identity` signs.
+
+use std::str::FromStr;
+
+use bitcoin::{
+    hashes::{sha256, Hash, HashEngine},
+    secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1},
+    BlockHash,
+};
+use serde::Serialize;
+
+use crate::electrum::identity::ServerIdentity;
+
+/// Domain separation for unused attestations
+const UNUSED_ATTESTATION_TAG: &[u8] = b"utreexo-electrum-server/unused-attestation";
+
+#[derive(Debug, Serialize)]
+pub struct UnusedAttestation {
+    pub descriptor: String,
+    /// The first derivation index covered
+    pub from: u32,
+    /// The first derivation index past the range
+    pub to: u32,
+    /// Our tip, up to which we vouch for these addresses
+    pub height: u32,
+    pub block_hash: BlockHash,
+    /// Commits to our tip and accumulator, see [ServerIdentity::tip_commitment]
+    pub tip_commitment: sha256::Hash,
+    /// Indexes whose address has a transaction
+    pub used: Vec<u32>,
+    /// Indexes whose address we don't watch, so we can't tell whether it was used
+    pub unwatched: Vec<u32>,
+    /// Whether every address in the range is watched, and has no transactions
+    pub unused: bool,
+    /// What we've signed
+    pub commitment: sha256::Hash,
+    pub pubkey: String,
+    pub signature: String,
+}
+
+impl UnusedAttestation {
+    /// Builds an attestation and signs it with our identity
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        descriptor: String,
+        from: u32,
+        to: u32,
+        height: u32,
+        block_hash: BlockHash,
+        tip_commitment: sha256::Hash,
+        used: Vec<u32>,
+        unwatched: Vec<u32>,
+        identity: &ServerIdentity,
+    ) -> UnusedAttestation {
+        let mut attestation = UnusedAttestation {
+            descriptor,
+            from,
+            to,
+            height,
+            block_hash,
+            tip_commitment,
+            unused: used.is_empty() && unwatched.is_empty(),
+            used,
+            unwatched,
+            commitment: sha256::Hash::all_zeros(),
+            pubkey: identity.public_key().to_string(),
+            signature: String::new(),
+        };
+        attestation.commitment = attestation.compute_commitment();
+        attestation.signature = identity.sign(&attestation.commitment).to_string();
+        attestation
+    }
+    /// What we sign, from everything else in the attestation
+    fn compute_commitment(&self) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        engine.input(UNUSED_ATTESTATION_TAG);
+        engine.input(&self.tip_commitment[..]);
+        engine.input(&(self.descriptor.len() as u32).to_le_bytes());
+        engine.input(self.descriptor.as_bytes());
+        engine.input(&self.from.to_le_bytes());
+        engine.input(&self.to.to_le_bytes());
+        engine.input(&[self.unused as u8]);
+        for list in [&self.used, &self.unwatched] {
+            engine.input(&(list.len() as u32).to_le_bytes());
+            for index in list {
+                engine.input(&index.to_le_bytes());
+            }
+        }
+        sha256::Hash::from_engine(engine)
+    }
+    /// Whether the attestation is signed by `pubkey`, and says what was signed. Whoever
+    /// checks it still has to pin our key, and check our tip is on their chain.
+    pub fn verify(&self) -> bool {
+        let (pubkey, signature) = match (
+            PublicKey::from_str(&self.pubkey),
+            Signature::from_str(&self.signature),
+        ) {
+            (Ok(pubkey), Ok(signature)) => (pubkey, signature),
+            _ => return false,
+        };
+        let message =
+            Message::from_slice(&self.commitment[..]).expect("sha256 hashes are always 32 bytes");
+        self.commitment == self.compute_commitment()
+            && Secp256k1::verification_only()
+                .verify_ecdsa(&message, &signature, &pubkey)
+                .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{hashes::Hash, BlockHash};
+
+    use super::UnusedAttestation;
+    use crate::electrum::identity::ServerIdentity;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let dir = "/tmp/utreexo_attestation";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let identity = ServerIdentity::load_or_create(dir).unwrap();
+        let attest = |used: Vec<u32>, unwatched: Vec<u32>| {
+            UnusedAttestation::new(
+                "wpkh(xpub/0/*)".into(),
+                0,
+                10,
+                100,
+                BlockHash::all_zeros(),
+                Hash::all_zeros(),
+                used,
+                unwatched,
+                &identity,
+            )
+        };
+        let unused = attest(vec![], vec![]);
+        assert!(unused.unused);
+        assert!(unused.verify());
+        // Nothing can be changed under the same signature
+        let widened = UnusedAttestation {
+            to: 20,
+            ..attest(vec![], vec![])
+        };
+        assert!(!widened.verify());
+        let mut recommitted = attest(vec![], vec![]);
+        recommitted.to = 20;
+        recommitted.commitment = recommitted.compute_commitment();
+        assert!(!recommitted.verify());
+        let mut moved = attest(vec![3], vec![4]);
+        assert!(!moved.unused && moved.verify());
+        moved.used = vec![3, 4];
+        moved.unwatched = vec![];
+        assert_ne!(moved.compute_commitment(), moved.commitment);
+        assert!(!moved.verify());
+
+        // Nor signed by anyone else
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let other = ServerIdentity::load_or_create(dir).unwrap();
+        let mut forged = attest(vec![], vec![]);
+        forged.pubkey = other.public_key().to_string();
+        assert!(!forged.verify());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
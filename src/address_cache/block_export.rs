@@ -0,0 +1,99 @@
+//! A stream of compact, per-block records with everything that changed in our wallet. This
+//! lets external indexers and analytics pipelines follow our wallet without parsing the
+//! whole chain again. Records are written as one JSON object per line. Blocks we skipped,
+//! while our disk was almost full, are marked by a [SkippedBlocks] line instead.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+};
+
+use bitcoin::{hashes::sha256, BlockHash, Network, OutPoint, Script, Txid};
+use serde::Serialize;
+
+use super::script_type::{get_address, AddressFormat};
+
+/// Wallet-relevant changes in a single block
+#[derive(Debug, Serialize)]
+pub struct BlockRecord {
+    pub height: u32,
+    pub block_hash: BlockHash,
+    /// Transactions in this block that touch our wallet
+    pub transactions: Vec<Txid>,
+    /// Wallet UTXOs created in this block
+    pub created: Vec<OutPoint>,
+    /// Wallet UTXOs spent in this block
+    pub spent: Vec<OutPoint>,
+    /// The addresses of the UTXOs created and spent, in the format our operator chose.
+    /// Scripts without an address in that format are left out.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<String>,
+    /// Our accumulator leaf count after this block
+    pub leaves: u64,
+    /// Our accumulator roots after this block
+    pub roots: Vec<sha256::Hash>,
+}
+
+impl BlockRecord {
+    /// Whether this block changed anything in our wallet
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.spent.is_empty()
+    }
+}
+
+/// Written instead of the records of blocks we skipped
+#[derive(Debug, Serialize)]
+pub struct SkippedBlocks {
+    pub skipped_from: u32,
+    pub skipped_to: u32,
+}
+
+/// Appends [BlockRecord]s to a file
+pub struct BlockExporter {
+    file: File,
+    network: Network,
+    format: AddressFormat,
+}
+
+impl BlockExporter {
+    pub fn new(
+        path: String,
+        network: Network,
+        format: AddressFormat,
+    ) -> Result<BlockExporter, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(BlockExporter {
+            file,
+            network,
+            format,
+        })
+    }
+    /// The addresses of `scripts` for a [BlockRecord], each once
+    pub fn addresses(&self, scripts: &[Script]) -> Vec<String> {
+        let mut addresses = scripts
+            .iter()
+            .filter_map(|script| get_address(script, self.network, self.format))
+            .collect::<Vec<_>>();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+    pub fn write(&mut self, record: &BlockRecord) -> Result<(), crate::error::Error> {
+        self.write_line(record)
+    }
+    /// Marks the blocks from `from` to `to` as having no records, because we skipped them,
+    /// so readers know the stream isn't complete there
+    pub fn write_gap(&mut self, from: u32, to: u32) -> Result<(), crate::error::Error> {
+        self.write_line(&SkippedBlocks {
+            skipped_from: from,
+            skipped_to: to,
+        })
+    }
+    fn write_line(&mut self, line: &impl Serialize) -> Result<(), crate::error::Error> {
+        let mut line = serde_json::to_vec(line)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+//! A log of every block we've processed, kept for the last [BLOCK_LOG_DEPTH] blocks. It lets
+//! operators find out, after the fact, when something appeared in our wallet or when a block
+//! got replaced by a reorg.
+
+use bitcoin::BlockHash;
+use serde::{Deserialize, Serialize};
+
+/// How many blocks back we keep log entries for
+pub const BLOCK_LOG_DEPTH: u32 = 50_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockLogEntry {
+    pub height: u32,
+    pub block_hash: BlockHash,
+    /// How many transactions in this block touched our wallet
+    pub transactions: usize,
+    /// Our accumulator leaf count after this block
+    pub leaves: u64,
+    /// If we've processed another block at this height before, its hash
+    pub replaced: Option<BlockHash>,
+}
@@ -0,0 +1,59 @@
+//! A portable copy of our chain state: our accumulator at our current height, and the
+//! snapshots we keep for the blocks before it, each with its block's header if we have it.
+//! Loading one into a fresh wallet lets a new machine start from where an existing instance
+//! is, instead of syncing from genesis, and serve those headers right away.
+
+use bitcoin::{
+    consensus::{deserialize, encode::serialize_hex},
+    hashes::{hex::FromHex, sha256},
+    BlockHeader,
+};
+use rustreexo::accumulator::stump::Stump;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the dump format changes in an incompatible way
+pub const CHAINSTATE_DUMP_VERSION: u32 = 1;
+
+/// Our accumulator after processing the block at `height`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccumulatorState {
+    pub height: u32,
+    pub leaves: u64,
+    pub roots: Vec<sha256::Hash>,
+    /// The header of the block at `height`, hex encoded. Older dumps don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+}
+
+impl AccumulatorState {
+    pub fn new(height: u32, acc: &Stump, header: Option<&BlockHeader>) -> AccumulatorState {
+        AccumulatorState {
+            height,
+            leaves: acc.leafs,
+            roots: acc.roots.clone(),
+            header: header.map(serialize_hex),
+        }
+    }
+    /// The header of this block, if the dump has it
+    pub fn header(&self) -> Result<Option<BlockHeader>, crate::error::Error> {
+        match &self.header {
+            Some(header) => Ok(Some(deserialize(&Vec::from_hex(header)?)?)),
+            None => Ok(None),
+        }
+    }
+    pub fn to_stump(&self) -> Stump {
+        Stump {
+            leafs: self.leaves,
+            roots: self.roots.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChainStateDump {
+    pub version: u32,
+    /// The state we'll continue syncing from
+    pub tip: AccumulatorState,
+    /// Older states, used for rolling back
+    pub checkpoints: Vec<AccumulatorState>,
+}
@@ -0,0 +1,133 @@
+//! The last [EVENT_LOG_SIZE] things that happened to our wallet, numbered in order. A client
+//! that remembers the number of the last event it saw can ask for everything after it, and
+//! update its state from that alone, instead of fetching every history again. The log only
+//! lives in memory, so each one has an id, and clients must start over when it changes.
+
+use std::collections::VecDeque;
+
+use bitcoin::{hashes::sha256, BlockHash, OutPoint, Txid};
+use serde::Serialize;
+
+/// How many events we keep
+pub const EVENT_LOG_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LogEvent {
+    /// We've learned of a transaction touching these addresses. It's unconfirmed if
+    /// `height` is 0
+    TxAdded {
+        txid: Txid,
+        height: u32,
+        script_hashes: Vec<sha256::Hash>,
+    },
+    /// An unconfirmed transaction we knew of got mined
+    TxConfirmed {
+        txid: Txid,
+        height: u32,
+        script_hashes: Vec<sha256::Hash>,
+    },
+    /// One of our outputs got spent
+    UtxoSpent {
+        outpoint: OutPoint,
+        spending_txid: Txid,
+        height: u32,
+        script_hash: sha256::Hash,
+    },
+    /// The block we had at `height` was replaced by another one
+    Reorg {
+        height: u32,
+        block_hash: BlockHash,
+        replaced: BlockHash,
+    },
+}
+
+impl LogEvent {
+    /// The addresses this event is about. Reorgs are about everyone
+    pub fn script_hashes(&self) -> &[sha256::Hash] {
+        match self {
+            LogEvent::TxAdded { script_hashes, .. }
+            | LogEvent::TxConfirmed { script_hashes, .. } => script_hashes,
+            LogEvent::UtxoSpent { script_hash, .. } => std::slice::from_ref(script_hash),
+            LogEvent::Reorg { .. } => &[],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoggedEvent {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub event: LogEvent,
+}
+
+#[derive(Debug)]
+pub struct EventLog {
+    /// Tells this log apart from the ones before a restart
+    id: u64,
+    events: VecDeque<LoggedEvent>,
+    /// The number of the last event we've logged, 0 if none
+    last: u64,
+}
+
+impl EventLog {
+    pub fn new(id: u64) -> EventLog {
+        EventLog {
+            id,
+            events: VecDeque::new(),
+            last: 0,
+        }
+    }
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+    pub fn push(&mut self, event: LogEvent) {
+        self.last += 1;
+        if self.events.len() == EVENT_LOG_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back(LoggedEvent {
+            seq: self.last,
+            event,
+        });
+    }
+    /// Every event after the one numbered `seq`, or `None` if we no longer have all of them
+    pub fn since(&self, seq: u64) -> Option<impl Iterator<Item = &LoggedEvent>> {
+        let first = self.events.front().map_or(self.last + 1, |event| event.seq);
+        if seq > self.last || seq + 1 < first {
+            return None;
+        }
+        Some(self.events.iter().skip((seq + 1 - first) as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::BlockHash;
+
+    use super::{EventLog, LogEvent, EVENT_LOG_SIZE};
+
+    #[test]
+    fn test_since() {
+        let mut log = EventLog::new(1);
+        assert_eq!(log.since(0).unwrap().count(), 0);
+        let reorg = LogEvent::Reorg {
+            height: 1,
+            block_hash: BlockHash::default(),
+            replaced: BlockHash::default(),
+        };
+        for _ in 0..EVENT_LOG_SIZE + 10 {
+            log.push(reorg.clone());
+        }
+        let last = log.last();
+        assert!(log.since(0).is_none());
+        assert!(log.since(last + 1).is_none());
+        assert_eq!(log.since(last).unwrap().count(), 0);
+        let seqs = log.since(last - 2).unwrap().map(|event| event.seq);
+        assert_eq!(seqs.collect::<Vec<_>>(), vec![last - 1, last]);
+        assert_eq!(log.since(10).unwrap().count(), EVENT_LOG_SIZE);
+    }
+}
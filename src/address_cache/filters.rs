@@ -0,0 +1,149 @@
+//! The pieces of [super::AddressCache::block_process] that look for what we care about in a
+//! block. Each [BlockFilter] looks at one transaction at a time and reports what it found as
+//! [FilterEvent]s, which the cache then applies. A new kind of watch is a new filter, block
+//! processing itself doesn't change.
+
+use std::collections::HashMap;
+
+use bitcoin::{OutPoint, Script, Transaction, TxOut};
+
+use super::op_return::{self, OpReturnMatch};
+use bitcoin::hashes::hex::ToHex;
+
+/// What filters may know about our wallet
+pub trait WalletView {
+    /// Whether this script is one of our addresses
+    fn is_wallet_script(&self, script: &Script) -> bool;
+    /// Returns the output this outpoint points to, if it's one of ours
+    fn get_wallet_output(&self, outpoint: &OutPoint) -> Option<TxOut>;
+    /// Whether a client asked us to watch this outpoint
+    fn is_watched_outpoint(&self, outpoint: &OutPoint) -> bool;
+    /// Whether clients asked us to watch any outpoint at all
+    fn has_watched_outpoints(&self) -> bool;
+    /// The prefixes of the OP_RETURN payloads we keep
+    fn op_return_prefixes(&self) -> &[Vec<u8>];
+}
+
+/// Something a filter found in a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterEvent {
+    /// The transaction pays one of our addresses
+    Received { vout: u32, output: TxOut },
+    /// The transaction spends one of our outputs
+    Spent { outpoint: OutPoint, prevout: TxOut },
+    /// The transaction creates an outpoint we watch
+    OutpointCreated(OutPoint),
+    /// The transaction spends an outpoint we watch
+    OutpointSpent(OutPoint),
+    /// The transaction has an OP_RETURN output matching one of our watch rules
+    OpReturn(OpReturnMatch),
+    /// The transaction pays us through a silent payment. `tweak` is what must be added to our
+    /// spend key to spend it
+    SilentPayment {
+        vout: u32,
+        output: TxOut,
+        tweak: [u8; 32],
+    },
+}
+
+pub trait BlockFilter: Send + Sync {
+    /// Looks at a transaction. `prevouts` has the outputs spent in its block, if we have them
+    fn scan(
+        &self,
+        wallet: &dyn WalletView,
+        transaction: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Vec<FilterEvent>;
+}
+
+/// Finds transactions paying to, or spending from, our addresses
+pub struct ScriptFilter;
+
+impl BlockFilter for ScriptFilter {
+    fn scan(
+        &self,
+        wallet: &dyn WalletView,
+        transaction: &Transaction,
+        _prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Vec<FilterEvent> {
+        let spent = transaction.input.iter().filter_map(|input| {
+            Some(FilterEvent::Spent {
+                outpoint: input.previous_output,
+                prevout: wallet.get_wallet_output(&input.previous_output)?,
+            })
+        });
+        let received = transaction
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| wallet.is_wallet_script(&output.script_pubkey))
+            .map(|(vout, output)| FilterEvent::Received {
+                vout: vout as u32,
+                output: output.clone(),
+            });
+        spent.chain(received).collect()
+    }
+}
+
+/// Finds transactions creating or spending the outpoints our clients watch
+pub struct OutpointFilter;
+
+impl BlockFilter for OutpointFilter {
+    fn scan(
+        &self,
+        wallet: &dyn WalletView,
+        transaction: &Transaction,
+        _prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Vec<FilterEvent> {
+        // Most servers watch no outpoint, and then we don't even need the txid
+        if !wallet.has_watched_outpoints() {
+            return vec![];
+        }
+        let txid = transaction.txid();
+        let created = (0..transaction.output.len() as u32)
+            .map(|vout| OutPoint { txid, vout })
+            .filter(|outpoint| wallet.is_watched_outpoint(outpoint))
+            .map(FilterEvent::OutpointCreated);
+        let spent = transaction
+            .input
+            .iter()
+            .filter(|input| wallet.is_watched_outpoint(&input.previous_output))
+            .map(|input| FilterEvent::OutpointSpent(input.previous_output));
+        created.chain(spent).collect()
+    }
+}
+
+/// Finds OP_RETURN outputs whose payload starts with one of our prefixes
+pub struct OpReturnFilter;
+
+impl BlockFilter for OpReturnFilter {
+    fn scan(
+        &self,
+        wallet: &dyn WalletView,
+        transaction: &Transaction,
+        _prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Vec<FilterEvent> {
+        let prefixes = wallet.op_return_prefixes();
+        if prefixes.is_empty() {
+            return vec![];
+        }
+        transaction
+            .output
+            .iter()
+            .enumerate()
+            .filter_map(|(vout, output)| {
+                let payload = op_return::get_payload(&output.script_pubkey)?;
+                prefixes
+                    .iter()
+                    .any(|prefix| payload.starts_with(prefix))
+                    .then(|| {
+                        FilterEvent::OpReturn(OpReturnMatch {
+                            txid: transaction.txid(),
+                            vout: vout as u32,
+                            payload: payload.to_hex(),
+                        })
+                    })
+            })
+            .collect()
+    }
+}
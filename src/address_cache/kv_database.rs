@@ -1,50 +1,398 @@
-use super::{AddressCacheDatabase, CachedAddress};
-use bitcoin::hashes::hex::ToHex;
-use kv::{Bucket, Config, Store};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+};
 
-pub struct KvDatabase(Store, Bucket<'static, String, String>);
+use super::{
+    stored::{
+        StoredAddress, StoredBody, StoredJournalEntry, StoredMeta, StoredTx, StoredTxLocation,
+    },
+    tx_index::TxLocation,
+    AddressCacheDatabase, CachedAddress, CachedTransaction, JournalEntry, TransactionBody,
+};
+use bitcoin::{
+    hashes::{hex::FromHex, sha256},
+    Txid,
+};
+use kv::{Batch, Bucket, Config, Store};
+use log::info;
+
+/// How many addresses a thread parses at a time, while loading our wallet
+const LOAD_CHUNK_SIZE: usize = 10_000;
+/// Wallets with at least this many addresses get progress logs while loading
+const LOAD_PROGRESS_THRESHOLD: usize = 100_000;
+
+/// Where our [StoredMeta] is, in the `meta` bucket
+const META_KEY: &str = "wallet";
+/// Where the height of the last block we filtered is, in the `meta` bucket
+const HEIGHT_KEY: &str = "cache_height";
+
+/// Keys older versions kept each piece of metadata under. They started in the `addresses`
+/// bucket, where the only thing telling them apart from a script hash was a string
+/// comparison, then moved to the `meta` bucket.
+#[derive(Debug, Clone, Copy)]
+enum LegacyMetaKey {
+    Height,
+    Descriptor,
+}
+
+impl LegacyMetaKey {
+    const ALL: [LegacyMetaKey; 2] = [LegacyMetaKey::Height, LegacyMetaKey::Descriptor];
+    fn key(&self) -> String {
+        match self {
+            LegacyMetaKey::Height => "height",
+            LegacyMetaKey::Descriptor => "desc",
+        }
+        .to_string()
+    }
+}
+
+/// Clones share the same store. The last field is how many threads parse our addresses
+/// while loading them.
+#[derive(Clone)]
+pub struct KvDatabase(
+    Store,
+    Bucket<'static, String, String>,
+    Bucket<'static, String, String>,
+    usize,
+);
 impl KvDatabase {
-    pub fn new(datadir: String) -> Result<KvDatabase, kv::Error> {
+    pub fn new(datadir: String, cache_capacity: u64) -> Result<KvDatabase, crate::error::Error> {
         // Configure the database
-        let cfg = Config::new(datadir);
+        let cfg = Config::new(datadir).cache_capacity(cache_capacity);
 
         // Open the key/value store
         let store = Store::new(cfg)?;
-        let bucket = store.bucket::<String, String>(Some("addresses"))?;
-        Ok(KvDatabase(store, bucket))
+        let addresses = store.bucket::<String, String>(Some("addresses"))?;
+        let meta = store.bucket::<String, String>(Some("meta"))?;
+        let database = KvDatabase(store, addresses, meta, 1);
+        database.migrate_meta_keys()?;
+        Ok(database)
+    }
+    /// Sets how many threads parse our addresses while loading them
+    pub fn set_load_workers(&mut self, workers: usize) {
+        self.3 = workers.max(1);
+    }
+    /// Moves the metadata older versions wrote under their own keys to where we keep it now
+    fn migrate_meta_keys(&self) -> Result<(), crate::error::Error> {
+        let mut meta = self.load_meta()?;
+        let mut height = None;
+        let mut migrated = vec![];
+        // The `meta` bucket goes last, since what's there is newer
+        for bucket in [&self.1, &self.2] {
+            for key in LegacyMetaKey::ALL {
+                if let Some(value) = bucket.get(&key.key())? {
+                    match key {
+                        LegacyMetaKey::Height => height = Some(value.parse()?),
+                        LegacyMetaKey::Descriptor => meta.descriptor = Some(value),
+                    }
+                    migrated.push((bucket, key));
+                }
+            }
+        }
+        if migrated.is_empty() {
+            return Ok(());
+        }
+        self.save_meta(&meta)?;
+        if let Some(height) = height {
+            self.set_cache_height(height)?;
+        }
+        for (bucket, key) in migrated {
+            bucket.remove(&key.key())?;
+            bucket.flush()?;
+        }
+        Ok(())
+    }
+    fn load_meta(&self) -> Result<StoredMeta, crate::error::Error> {
+        match self.2.get(&META_KEY.to_string())? {
+            Some(meta) => Ok(serde_json::from_str(&meta)?),
+            None => Ok(StoredMeta::default()),
+        }
+    }
+    fn save_meta(&self, meta: &StoredMeta) -> Result<(), crate::error::Error> {
+        self.2
+            .set(&META_KEY.to_string(), &serde_json::to_string(meta)?)?;
+        self.2.flush()?;
+        Ok(())
+    }
+    /// Returns the key and value we store an address under
+    fn serialize_address(address: &CachedAddress) -> (String, String) {
+        let value = serde_json::to_string(&StoredAddress::from(address))
+            .expect("Addresses are always serializable");
+        (address.script_hash.to_string(), value)
+    }
+    /// Rewrites every address, and anything else older versions wrote in their own formats,
+    /// as records, and drops transaction bodies that no address refers to anymore. Returns
+    /// how many transactions were dropped. Only for when nothing else uses our database, a
+    /// running server uses [KvDatabase::find_orphaned_bodies] instead.
+    pub fn compact(&self) -> Result<usize, crate::error::Error> {
+        let addresses = self.load::<crate::error::Error>()?;
+        for address in addresses.iter() {
+            self.save(address);
+        }
+        self.migrate_legacy_records()?;
+        let orphaned = self.find_orphaned_bodies()?;
+        self.drop_tx_bodies(&orphaned)?;
+        Ok(orphaned.len())
+    }
+    /// Rewrites the transaction bodies, broadcast journal entries and transaction locations
+    /// older versions wrote in their own formats as records
+    fn migrate_legacy_records(&self) -> Result<(), crate::error::Error> {
+        let bodies = self.0.bucket::<String, String>(Some("transactions"))?;
+        let mut legacy = vec![];
+        for item in bodies.iter() {
+            let item = item?;
+            let value = item.value::<String>()?;
+            if !value.starts_with('{') {
+                legacy.push((Txid::from_hex(&item.key::<String>()?)?, value));
+            }
+        }
+        for (txid, value) in legacy {
+            self.save_tx_body(&txid, &TransactionBody::try_from(value)?)?;
+        }
+
+        let journal = self.0.bucket::<String, String>(Some("broadcast_journal"))?;
+        let mut legacy = vec![];
+        for item in journal.iter() {
+            let value = item?.value::<String>()?;
+            if !value.starts_with('{') {
+                legacy.push(JournalEntry::try_from(value)?);
+            }
+        }
+        for entry in legacy {
+            self.journal_save(&entry)?;
+        }
+
+        let index = self.0.bucket::<String, String>(Some("tx_index"))?;
+        let mut legacy = vec![];
+        for item in index.iter() {
+            let item = item?;
+            if !item.value::<String>()?.starts_with('{') {
+                legacy.push(Txid::from_hex(&item.key::<String>()?)?);
+            }
+        }
+        let mut locations = vec![];
+        for txid in legacy {
+            if let Some(location) = self.tx_index_load(&txid)? {
+                locations.push((txid, location));
+            }
+        }
+        self.tx_index_save(&locations)
+    }
+    /// Writes archived transactions from `first` on, each under `script_hash:position`, and
+    /// where each one is under `script_hash:txid` in `archive_index`
+    fn write_archive_entries(
+        &self,
+        script_hash: &sha256::Hash,
+        first: usize,
+        transactions: &[CachedTransaction],
+    ) -> Result<(), crate::error::Error> {
+        let mut entries = Batch::<String, String>::new();
+        let mut positions = Batch::<String, String>::new();
+        for (position, transaction) in (first..).zip(transactions) {
+            entries.set(
+                &format!("{script_hash}:{position}"),
+                &serde_json::to_string(&StoredTx::from(transaction))?,
+            )?;
+            positions.set(
+                &format!("{script_hash}:{}", transaction.hash),
+                &position.to_string(),
+            )?;
+        }
+        // Entries go first, so a position we find always has one
+        let bucket = self.0.bucket::<String, String>(Some("archive"))?;
+        bucket.batch(entries)?;
+        bucket.flush()?;
+        let index = self.0.bucket::<String, String>(Some("archive_index"))?;
+        index.batch(positions)?;
+        index.flush()?;
+        Ok(())
+    }
+    /// Older versions kept an address' whole archive in one value under its script hash, so
+    /// every archival rewrote all of it. This moves it to one entry per transaction.
+    fn migrate_legacy_archive(
+        &self,
+        script_hash: &sha256::Hash,
+    ) -> Result<(), crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("archive"))?;
+        let value = match bucket.get(&script_hash.to_string())? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        let archive = if value.starts_with('[') {
+            serde_json::from_str::<Vec<StoredTx>>(&value)?
+                .into_iter()
+                .map(CachedTransaction::from)
+                .collect::<Vec<_>>()
+        } else {
+            // Even older ones joined `txid;height;position` entries with `:`
+            value
+                .split(':')
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| CachedTransaction::try_from(entry.to_string()))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        self.write_archive_entries(script_hash, 0, &archive)?;
+        bucket.remove(&script_hash.to_string())?;
+        bucket.flush()?;
+        Ok(())
+    }
+    /// Finds the transaction bodies no address refers to. This only reads, so it can run
+    /// while we serve, but bodies saved meanwhile may be found too. Addresses older
+    /// versions wrote are left for [AddressCacheDatabase::load] to migrate, since saving
+    /// them from here could undo what we served meanwhile.
+    pub fn find_orphaned_bodies(&self) -> Result<Vec<Txid>, crate::error::Error> {
+        let addresses = self.load_addresses(false)?;
+        let mut referenced = addresses
+            .iter()
+            .flat_map(|address| address.transactions.iter())
+            .map(|transaction| transaction.hash)
+            .collect::<HashSet<_>>();
+        for address in addresses
+            .iter()
+            .filter(|address| address.archived.count > 0)
+        {
+            let archived = self.archive_load(&address.script_hash, address.archived.count)?;
+            referenced.extend(archived.iter().map(|transaction| transaction.hash));
+        }
+
+        let bucket = self.0.bucket::<String, String>(Some("transactions"))?;
+        let mut orphaned = vec![];
+        for item in bucket.iter() {
+            let txid = Txid::from_hex(&item?.key::<String>()?)?;
+            if !referenced.contains(&txid) {
+                orphaned.push(txid);
+            }
+        }
+        Ok(orphaned)
+    }
+    pub fn drop_tx_bodies(&self, txids: &[Txid]) -> Result<(), crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("transactions"))?;
+        for txid in txids {
+            bucket.remove(&txid.to_string())?;
+        }
+        bucket.flush()?;
+        Ok(())
+    }
+    /// Reads our addresses in chunks, handed to [KvDatabase::set_load_workers] threads to
+    /// parse, so we only hold the raw values of a few chunks at a time. Returns them in the
+    /// order they are stored. If `migrate`, the ones older versions wrote are migrated too.
+    fn load_addresses(&self, migrate: bool) -> Result<Vec<CachedAddress>, crate::error::Error> {
+        let total = self.1.len();
+        let (sender, receiver) = mpsc::sync_channel::<(usize, Vec<String>)>(self.3);
+        let receiver = Mutex::new(receiver);
+        let parsed = AtomicUsize::new(0);
+        let (read, mut results) = std::thread::scope(|scope| {
+            let workers = (0..self.3)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = vec![];
+                        loop {
+                            // The lock is only held while waiting for the next chunk
+                            let next = receiver.lock().unwrap().recv();
+                            let (index, chunk) = match next {
+                                Ok(next) => next,
+                                Err(_) => break,
+                            };
+                            let addresses = self.parse_chunk(&chunk, migrate);
+                            let done =
+                                parsed.fetch_add(chunk.len(), Ordering::SeqCst) + chunk.len();
+                            if total >= LOAD_PROGRESS_THRESHOLD {
+                                info!("Loaded {done} of {total} addresses");
+                            }
+                            results.push((index, addresses));
+                        }
+                        results
+                    })
+                })
+                .collect::<Vec<_>>();
+            let read = self.send_chunks(sender);
+            let results = workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("Address parser panicked"))
+                .collect::<Vec<_>>();
+            (read, results)
+        });
+        read?;
+        results.sort_unstable_by_key(|(index, _)| *index);
+        let mut addresses = Vec::with_capacity(total);
+        for (_, chunk) in results {
+            addresses.extend(chunk?);
+        }
+        Ok(addresses)
+    }
+    /// Sends our stored addresses to `sender`, [LOAD_CHUNK_SIZE] at a time, numbered in the
+    /// order they are stored
+    fn send_chunks(
+        &self,
+        sender: mpsc::SyncSender<(usize, Vec<String>)>,
+    ) -> Result<(), crate::error::Error> {
+        let mut chunk = Vec::with_capacity(LOAD_CHUNK_SIZE);
+        let mut index = 0;
+        for item in self.1.iter() {
+            chunk.push(item?.value::<String>()?);
+            if chunk.len() == LOAD_CHUNK_SIZE {
+                let full = std::mem::replace(&mut chunk, Vec::with_capacity(LOAD_CHUNK_SIZE));
+                sender
+                    .send((index, full))
+                    .expect("Address parsers stopped early");
+                index += 1;
+            }
+        }
+        if !chunk.is_empty() {
+            sender
+                .send((index, chunk))
+                .expect("Address parsers stopped early");
+        }
+        Ok(())
+    }
+    /// Parses a chunk of stored addresses. If `migrate`, the transaction bodies of the ones
+    /// older versions wrote are moved to their own bucket.
+    fn parse_chunk(
+        &self,
+        values: &[String],
+        migrate: bool,
+    ) -> Result<Vec<CachedAddress>, crate::error::Error> {
+        values
+            .iter()
+            .map(|value| {
+                let address = CachedAddress::try_from(value.clone())?;
+                if migrate && self.migrate_legacy_bodies(value)? {
+                    self.save(&address);
+                }
+                Ok(address)
+            })
+            .collect()
+    }
+    /// Older versions stored whole transactions inside the address entry. This moves them
+    /// into their own bucket, returning whether we found any.
+    fn migrate_legacy_bodies(&self, value: &str) -> Result<bool, crate::error::Error> {
+        if value.starts_with('{') {
+            return Ok(false);
+        }
+        let mut migrated = false;
+        for entry in value.split(':').skip(3) {
+            if let Some(body) = TransactionBody::from_legacy(entry)? {
+                self.save_tx_body(&body.tx.txid(), &body)?;
+                migrated = true;
+            }
+        }
+        Ok(migrated)
     }
 }
+
 impl AddressCacheDatabase for KvDatabase {
     fn load<E>(&self) -> Result<Vec<super::CachedAddress>, E>
     where
         E: From<crate::error::Error> + std::convert::From<kv::Error>,
     {
-        let mut addresses = vec![];
-        for item in self.1.iter() {
-            let item = item?;
-            let key = item.key::<String>()?;
-            if *"height" == key || *"desc" == key {
-                continue;
-            }
-            let value: String = item.value().unwrap();
-            let value = CachedAddress::try_from(value)?;
-            addresses.push(value);
-        }
-        Ok(addresses)
+        Ok(self.load_addresses(true)?)
     }
     fn save(&self, address: &super::CachedAddress) {
-        let key = address.script_hash.to_string();
-        let mut transactions = String::new();
-        for transaction in address.transactions.iter() {
-            let tx = transaction.to_string() + ":";
-            transactions.extend(tx.chars().into_iter());
-        }
-        let value = format!(
-            "{}:{}:{}:{transactions}",
-            address.script_hash,
-            address.balance,
-            address.script.to_hex(),
-        );
+        let (key, value) = Self::serialize_address(address);
 
         self.1
             .set(&key, &value)
@@ -54,35 +402,552 @@ impl AddressCacheDatabase for KvDatabase {
     fn update(&self, address: &super::CachedAddress) {
         self.save(address);
     }
+    fn update_many(&self, addresses: &[CachedAddress]) {
+        let mut batch = Batch::<String, String>::new();
+        for address in addresses {
+            let (key, value) = Self::serialize_address(address);
+            batch
+                .set(&key, &value)
+                .expect("Fatal: Database isn't working");
+        }
+        self.1.batch(batch).expect("Fatal: Database isn't working");
+        self.1.flush().expect("Could not write to disk");
+    }
     fn get_cache_height(&self) -> Result<u32, crate::error::Error> {
-        self.0.bucket::<String, String>(Some("meta"))?;
-        let height = self.1.get(&"height".to_string())?;
-        if let Some(height) = height {
-            return Ok(height.parse::<u32>()?);
+        match self.2.get(&HEIGHT_KEY.to_string())? {
+            Some(height) => Ok(height.parse()?),
+            None => Err(crate::error::Error::WalletNotInitialized),
         }
-        Err(crate::error::Error::WalletNotInitialized)
     }
     fn set_cache_height(&self, height: u32) -> Result<(), crate::error::Error> {
-        self.0.bucket::<String, String>(Some("meta"))?;
-        self.1.set(&"height".to_string(), &height.to_string())?;
-        self.1.flush()?;
+        self.2.set(&HEIGHT_KEY.to_string(), &height.to_string())?;
+        self.2.flush()?;
         Ok(())
     }
 
     fn desc_save(&self, descriptor: String) -> Result<(), crate::error::Error> {
-        self.0.bucket::<String, String>(Some("meta"))?;
-        self.1.set(&"desc".to_string(), &descriptor)?;
-        self.1.flush()?;
+        let mut meta = self.load_meta()?;
+        meta.descriptor = Some(descriptor);
+        self.save_meta(&meta)
+    }
+
+    fn desc_get(&self) -> Result<String, crate::error::Error> {
+        self.load_meta()?
+            .descriptor
+            .ok_or(crate::error::Error::WalletNotInitialized)
+    }
+
+    fn journal_save(&self, entry: &JournalEntry) -> Result<(), crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("broadcast_journal"))?;
+        let value = serde_json::to_string(&StoredJournalEntry::from(entry))?;
+        bucket.set(&entry.transaction.txid().to_string(), &value)?;
+        bucket.flush()?;
 
         Ok(())
     }
 
-    fn desc_get(&self) -> Result<String, crate::error::Error> {
-        self.0.bucket::<String, String>(Some("meta"))?;
-        let res = self.1.get(&"desc".to_string())?;
-        if let Some(res) = res {
-            return Ok(res);
+    fn journal_remove(&self, txid: &Txid) -> Result<(), crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("broadcast_journal"))?;
+        bucket.remove(&txid.to_string())?;
+        bucket.flush()?;
+
+        Ok(())
+    }
+
+    fn journal_load(&self) -> Result<Vec<JournalEntry>, crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("broadcast_journal"))?;
+        let mut entries = vec![];
+        for item in bucket.iter() {
+            let entry = item?.value::<String>()?;
+            entries.push(JournalEntry::try_from(entry)?);
+        }
+        Ok(entries)
+    }
+
+    fn save_tx_body(&self, txid: &Txid, body: &TransactionBody) -> Result<(), crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("transactions"))?;
+        bucket.set(
+            &txid.to_string(),
+            &serde_json::to_string(&StoredBody::from(body))?,
+        )?;
+        bucket.flush()?;
+
+        Ok(())
+    }
+
+    fn archive_append(
+        &self,
+        script_hash: &sha256::Hash,
+        count: usize,
+        transactions: &[CachedTransaction],
+    ) -> Result<(), crate::error::Error> {
+        self.migrate_legacy_archive(script_hash)?;
+        self.write_archive_entries(script_hash, count, transactions)
+    }
+
+    fn archive_load(
+        &self,
+        script_hash: &sha256::Hash,
+        count: usize,
+    ) -> Result<Vec<CachedTransaction>, crate::error::Error> {
+        self.migrate_legacy_archive(script_hash)?;
+        let bucket = self.0.bucket::<String, String>(Some("archive"))?;
+        let mut archive = Vec::with_capacity(count);
+        for position in 0..count {
+            let entry = bucket
+                .get(&format!("{script_hash}:{position}"))?
+                .ok_or(crate::error::Error::DbParseError)?;
+            archive.push(CachedTransaction::from(serde_json::from_str::<StoredTx>(
+                &entry,
+            )?));
+        }
+        Ok(archive)
+    }
+
+    fn archive_find(
+        &self,
+        script_hash: &sha256::Hash,
+        count: usize,
+        txid: &Txid,
+    ) -> Result<Option<CachedTransaction>, crate::error::Error> {
+        self.migrate_legacy_archive(script_hash)?;
+        let index = self.0.bucket::<String, String>(Some("archive_index"))?;
+        let position = match index.get(&format!("{script_hash}:{txid}"))? {
+            Some(position) => position.parse::<usize>()?,
+            None => return Ok(None),
+        };
+        // Entries past `count` were left by an archival that didn't finish, and may have been
+        // overwritten since
+        if position >= count {
+            return Ok(None);
+        }
+        let bucket = self.0.bucket::<String, String>(Some("archive"))?;
+        let entry = match bucket.get(&format!("{script_hash}:{position}"))? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let transaction = CachedTransaction::from(serde_json::from_str::<StoredTx>(&entry)?);
+        Ok((transaction.hash == *txid).then_some(transaction))
+    }
+
+    fn load_tx_body(&self, txid: &Txid) -> Result<Option<TransactionBody>, crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("transactions"))?;
+        if let Some(body) = bucket.get(&txid.to_string())? {
+            return Ok(Some(TransactionBody::try_from(body)?));
+        }
+        Ok(None)
+    }
+
+    fn tx_index_save(&self, entries: &[(Txid, TxLocation)]) -> Result<(), crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("tx_index"))?;
+        let mut batch = Batch::<String, String>::new();
+        for (txid, script_hash) in entries {
+            let location = StoredTxLocation {
+                script_hash: *script_hash,
+            };
+            let location =
+                serde_json::to_string(&location).expect("Locations are always serializable");
+            batch.set(&txid.to_string(), &location)?;
+        }
+        bucket.batch(batch)?;
+        bucket.flush()?;
+
+        Ok(())
+    }
+
+    fn tx_index_load(&self, txid: &Txid) -> Result<Option<TxLocation>, crate::error::Error> {
+        let bucket = self.0.bucket::<String, String>(Some("tx_index"))?;
+        let location = match bucket.get(&txid.to_string())? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        if location.starts_with('{') {
+            let location = serde_json::from_str::<StoredTxLocation>(&location)?;
+            return Ok(Some(location.script_hash));
+        }
+        // Older versions saved the script hash, then the transaction's position after a `;`
+        let script_hash = location.split(';').next().unwrap_or_default();
+        Ok(Some(sha256::Hash::from_hex(script_hash)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        consensus::encode::serialize_hex,
+        hashes::{hex::FromHex, sha256, Hash},
+        Script, Txid,
+    };
+
+    use super::{KvDatabase, LOAD_CHUNK_SIZE};
+    use crate::{
+        address_cache::{
+            test::paying_block, AddressCacheDatabase, CachedAddress, CachedTransaction,
+        },
+        electrum::electrum_protocol::get_spk_hash,
+    };
+    use kv::{Config, Store};
+
+    fn transactions() -> Vec<CachedTransaction> {
+        (1..=4u8)
+            .map(|n| CachedTransaction {
+                height: n as u32 * 10,
+                hash: Txid::from_slice(&[n; 32]).unwrap(),
+                position: 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_archive_entries() {
+        let dir = "/tmp/utreexo_archive_entries/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        let script_hash = sha256::Hash::hash(b"address");
+        let transactions = transactions();
+
+        database
+            .archive_append(&script_hash, 0, &transactions[..2])
+            .unwrap();
+        // An archival that didn't finish, so we still have two
+        database
+            .archive_append(&script_hash, 2, &transactions[2..])
+            .unwrap();
+        database
+            .archive_append(&script_hash, 2, &transactions[3..])
+            .unwrap();
+        let expected = vec![
+            transactions[0].clone(),
+            transactions[1].clone(),
+            transactions[3].clone(),
+        ];
+        assert_eq!(database.archive_load(&script_hash, 3).unwrap(), expected);
+        assert_eq!(
+            database
+                .archive_find(&script_hash, 3, &transactions[3].hash)
+                .unwrap(),
+            Some(transactions[3].clone())
+        );
+        // Its entry was overwritten
+        assert_eq!(
+            database
+                .archive_find(&script_hash, 3, &transactions[2].hash)
+                .unwrap(),
+            None
+        );
+        // Only the first `count` are ours
+        assert_eq!(
+            database
+                .archive_find(&script_hash, 1, &transactions[1].hash)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_legacy_archive() {
+        let dir = "/tmp/utreexo_legacy_archive/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        let transactions = transactions();
+        let json = sha256::Hash::hash(b"json");
+        let joined = sha256::Hash::hash(b"joined");
+        let bucket = database
+            .0
+            .bucket::<String, String>(Some("archive"))
+            .unwrap();
+        let value = transactions
+            .iter()
+            .map(|tx| {
+                format!(
+                    r#"{{"txid":"{}","height":{},"position":1}}"#,
+                    tx.hash, tx.height
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        bucket
+            .set(&json.to_string(), &format!("[{value}]"))
+            .unwrap();
+        let value = transactions
+            .iter()
+            .map(|tx| format!("{};{};1", tx.hash, tx.height))
+            .collect::<Vec<_>>()
+            .join(":");
+        bucket.set(&joined.to_string(), &value).unwrap();
+
+        for script_hash in [json, joined] {
+            assert_eq!(
+                database
+                    .archive_find(&script_hash, 4, &transactions[2].hash)
+                    .unwrap(),
+                Some(transactions[2].clone())
+            );
+            assert_eq!(
+                database.archive_load(&script_hash, 4).unwrap(),
+                transactions
+            );
+            // Appending after a migration only adds to it
+            let next = CachedTransaction {
+                height: 50,
+                hash: Txid::from_slice(&[5; 32]).unwrap(),
+                position: 1,
+            };
+            database
+                .archive_append(&script_hash, 4, &[next.clone()])
+                .unwrap();
+            assert_eq!(database.archive_load(&script_hash, 5).unwrap()[4], next);
+            assert!(bucket.get(&script_hash.to_string()).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_parallel_load_order() {
+        let dir = "/tmp/utreexo_parallel_load/";
+        let _ = std::fs::remove_dir_all(dir);
+        let mut database = KvDatabase::new(dir.into(), 64 * 1024 * 1024).unwrap();
+        database.set_load_workers(4);
+        let addresses = (0..LOAD_CHUNK_SIZE * 2 + 1)
+            .map(|n| {
+                let script = Script::from(n.to_be_bytes().to_vec());
+                CachedAddress::_new(get_spk_hash(&script), n as u64, vec![], script)
+            })
+            .collect::<Vec<_>>();
+        database.update_many(&addresses);
+
+        // Three chunks, parsed by different threads, come back as they are stored
+        let stored = database
+            .1
+            .iter()
+            .map(|item| item.unwrap().key::<String>().unwrap())
+            .collect::<Vec<_>>();
+        let loaded = database
+            .load::<crate::error::Error>()
+            .unwrap()
+            .iter()
+            .map(|address| address.script_hash.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(loaded.len(), addresses.len());
+        assert_eq!(loaded, stored);
+    }
+
+    #[test]
+    fn test_legacy_addresses() {
+        let dir = "/tmp/utreexo_legacy_addresses/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let script_hash = get_spk_hash(&script);
+        let (transaction, _, merkle_block) = paying_block(&script, 1_000);
+        let txid = transactions()[0].hash;
+        // Activity heights, an entry with only the txid, and a whole transaction with its
+        // merkle block, like the first versions wrote
+        let value = format!(
+            "{script_hash}:1000:{script:x}:10;20:{txid};10;1:{};20;1;{}",
+            serialize_hex(&transaction),
+            serialize_hex(&merkle_block)
+        );
+        database.1.set(&script_hash.to_string(), &value).unwrap();
+
+        let addresses = database.load::<crate::error::Error>().unwrap();
+        let address = &addresses[0];
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(address.script, script);
+        assert_eq!(address.balance, 1_000);
+        assert_eq!(address.first_seen_height, Some(10));
+        assert_eq!(address.last_active_height, Some(20));
+        assert_eq!(address.transactions[0].hash, txid);
+        assert_eq!(address.transactions[1].hash, transaction.txid());
+        // The whole transaction moved to its own bucket, and the address is a record now
+        let body = database.load_tx_body(&transaction.txid()).unwrap().unwrap();
+        assert_eq!(body.tx, transaction);
+        assert_eq!(body.merkle_block, Some(merkle_block));
+        let stored = database.1.get(&script_hash.to_string()).unwrap().unwrap();
+        assert!(stored.starts_with('{'));
+        let parsed = CachedAddress::try_from(stored).unwrap();
+        assert_eq!(parsed.transactions, address.transactions);
+    }
+
+    #[test]
+    fn test_find_orphaned_bodies_only_reads() {
+        let dir = "/tmp/utreexo_orphaned_bodies/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let script_hash = get_spk_hash(&script);
+        let (transaction, _, merkle_block) = paying_block(&script, 1_000);
+        let value = format!(
+            "{script_hash}:1000:{script:x}:10;10:{};10;1;{}",
+            serialize_hex(&transaction),
+            serialize_hex(&merkle_block)
+        );
+        database.1.set(&script_hash.to_string(), &value).unwrap();
+
+        // It runs next to our main loop, so it mustn't write the address it loaded
+        assert!(database.find_orphaned_bodies().unwrap().is_empty());
+        assert_eq!(
+            database.1.get(&script_hash.to_string()).unwrap(),
+            Some(value)
+        );
+        assert!(database
+            .load_tx_body(&transaction.txid())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_legacy_meta_keys() {
+        let dir = "/tmp/utreexo_legacy_meta/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        // First in the `addresses` bucket, then in the `meta` one, which is newer
+        database
+            .1
+            .set(&"height".to_string(), &"5".to_string())
+            .unwrap();
+        database
+            .1
+            .set(&"desc".to_string(), &"wpkh(old)".to_string())
+            .unwrap();
+        database
+            .2
+            .set(&"height".to_string(), &"9".to_string())
+            .unwrap();
+        database.migrate_meta_keys().unwrap();
+        assert_eq!(database.get_cache_height().unwrap(), 9);
+        assert_eq!(database.desc_get().unwrap(), "wpkh(old)");
+        for key in ["height", "desc"] {
+            assert!(database.1.get(&key.to_string()).unwrap().is_none());
+            assert!(database.2.get(&key.to_string()).unwrap().is_none());
+        }
+        // Not taken for an address anymore
+        assert!(database.load::<crate::error::Error>().unwrap().is_empty());
+        // Our height has its own key, so saving it leaves our descriptor alone
+        database.set_cache_height(10).unwrap();
+        assert_eq!(database.get_cache_height().unwrap(), 10);
+        assert_eq!(database.desc_get().unwrap(), "wpkh(old)");
+    }
+
+    #[test]
+    fn test_meta_keys_migrate_on_open() {
+        let dir = "/tmp/utreexo_meta_migration/";
+        let _ = std::fs::remove_dir_all(dir);
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let script_hash = get_spk_hash(&script);
+        let (transaction, _, merkle_block) = paying_block(&script, 1_000);
+        {
+            // Like the first versions left it, metadata next to our addresses
+            let store = Store::new(Config::new(dir)).unwrap();
+            let addresses = store.bucket::<String, String>(Some("addresses")).unwrap();
+            let address = format!(
+                "{script_hash}:1000:{script:x}:10;10:{};10;1;{}",
+                serialize_hex(&transaction),
+                serialize_hex(&merkle_block)
+            );
+            addresses.set(&script_hash.to_string(), &address).unwrap();
+            addresses
+                .set(&"height".to_string(), &"12".to_string())
+                .unwrap();
+            addresses
+                .set(&"desc".to_string(), &"wpkh(old)".to_string())
+                .unwrap();
+            addresses.flush().unwrap();
+        }
+        let check = |database: &KvDatabase| {
+            assert_eq!(database.get_cache_height().unwrap(), 12);
+            assert_eq!(database.desc_get().unwrap(), "wpkh(old)");
+            let addresses = database.load::<crate::error::Error>().unwrap();
+            assert_eq!(addresses.len(), 1);
+            assert_eq!(addresses[0].script_hash, script_hash);
+            assert_eq!(addresses[0].balance, 1_000);
+            let keys = database
+                .1
+                .iter()
+                .map(|item| item.unwrap().key::<String>().unwrap());
+            assert_eq!(keys.collect::<Vec<_>>(), vec![script_hash.to_string()]);
+        };
+        // Opening the database moves them out of the way
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        check(&database);
+        drop(database);
+        // And opening it again changes nothing
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        check(&database);
+    }
+
+    #[test]
+    fn test_legacy_records() {
+        let dir = "/tmp/utreexo_legacy_records/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), 1024 * 1024).unwrap();
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let (transaction, _, merkle_block) = paying_block(&script, 1_000);
+        let (unproven, _, _) = paying_block(&script, 2_000);
+        let txid = transaction.txid();
+        let script_hash = get_spk_hash(&script);
+
+        // Bodies as `tx;merkle_block;prevouts`, before that without prevouts
+        let bodies = database
+            .0
+            .bucket::<String, String>(Some("transactions"))
+            .unwrap();
+        let prevouts = vec![transaction.output[0].clone()];
+        let value = format!(
+            "{};{};{}",
+            serialize_hex(&transaction),
+            serialize_hex(&merkle_block),
+            serialize_hex(&prevouts)
+        );
+        bodies.set(&txid.to_string(), &value).unwrap();
+        let value = format!("{};", serialize_hex(&unproven));
+        bodies.set(&unproven.txid().to_string(), &value).unwrap();
+        // Journal entries as `tx;broadcast_at;dropped`, before that only the transaction
+        let journal = database
+            .0
+            .bucket::<String, String>(Some("broadcast_journal"))
+            .unwrap();
+        let value = format!("{};100;1", serialize_hex(&transaction));
+        journal.set(&txid.to_string(), &value).unwrap();
+        let value = serialize_hex(&unproven);
+        journal.set(&unproven.txid().to_string(), &value).unwrap();
+        // Locations as `script_hash;position`
+        let index = database
+            .0
+            .bucket::<String, String>(Some("tx_index"))
+            .unwrap();
+        index
+            .set(&txid.to_string(), &format!("{script_hash};3"))
+            .unwrap();
+
+        // They load as they are, and the same once compacted into records
+        for compacted in [false, true] {
+            if compacted {
+                database.compact().unwrap();
+                for bucket in [&bodies, &journal, &index] {
+                    for item in bucket.iter() {
+                        assert!(item.unwrap().value::<String>().unwrap().starts_with('{'));
+                    }
+                }
+            }
+            let body = database.load_tx_body(&txid).unwrap().unwrap();
+            assert_eq!(body.tx, transaction);
+            assert_eq!(body.merkle_block, Some(merkle_block.clone()));
+            assert_eq!(body.prevouts, prevouts);
+            let body = database.load_tx_body(&unproven.txid()).unwrap().unwrap();
+            assert_eq!(body.tx, unproven);
+            assert!(body.merkle_block.is_none());
+            assert!(body.prevouts.is_empty());
+
+            let mut entries = database.journal_load().unwrap();
+            entries.sort_by_key(|entry| entry.broadcast_at);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].transaction, transaction);
+            assert_eq!(entries[0].broadcast_at, 100);
+            assert!(entries[0].dropped);
+            assert_eq!(entries[1].transaction, unproven);
+            assert!(!entries[1].dropped);
+
+            assert_eq!(database.tx_index_load(&txid).unwrap(), Some(script_hash));
         }
-        Err(crate::error::Error::WalletNotInitialized)
     }
 }
@@ -1,59 +1,145 @@
+pub mod archive;
+pub mod attestation;
+pub mod block_export;
+pub mod block_log;
+pub mod chainstate_dump;
+pub mod event_log;
+pub mod filters;
 pub mod kv_database;
+pub mod op_return;
+pub mod script_type;
+pub mod silent_payments;
+pub mod stored;
+pub mod transports;
+pub mod tx_index;
+pub mod wallet_export;
+pub mod webhooks;
 use std::{
-    collections::{HashMap, HashSet},
-    fmt::Write,
+    collections::{BTreeMap, HashMap, HashSet},
+    num::NonZeroUsize,
     ops::RangeInclusive,
+    path::Path,
     str::Split,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
     vec,
 };
 
 use crate::{
-    blockchain::{chainstore::ChainStore, sync::BlockchainSync},
-    electrum::electrum_protocol::get_spk_hash,
+    blockchain::{
+        chainstore::ChainStore, headers::HeaderStore, stored::StoredAcc, sync::BlockchainSync,
+        udata::BlockProof,
+    },
+    crash::StateFingerprint,
+    disk::DiskSpace,
+    electrum::{
+        electrum_protocol::{extend_status, get_spk_hash},
+        identity::ServerIdentity,
+    },
+    sharded::ShardedLru,
 };
+use archive::ArchivedHistory;
+use attestation::UnusedAttestation;
 use bitcoin::{
     consensus::deserialize,
-    consensus::encode::serialize_hex,
     hash_types::Txid,
     hashes::{
         hex::{FromHex, ToHex},
         sha256::{self, Hash},
-        Hash as HashTrait,
+        Hash as HashTrait, HashEngine,
     },
-    Block, MerkleBlock, Script, Transaction, TxOut,
+    util::hash::bitcoin_merkle_root,
+    Block, BlockHash, BlockHeader, MerkleBlock, Network, OutPoint, Script, Transaction,
+    TxMerkleNode, TxOut,
 };
+use block_export::{BlockExporter, BlockRecord};
+use block_log::{BlockLogEntry, BLOCK_LOG_DEPTH};
+use chainstate_dump::{AccumulatorState, ChainStateDump, CHAINSTATE_DUMP_VERSION};
+use event_log::{EventLog, LogEvent};
+use filters::{BlockFilter, FilterEvent, OpReturnFilter, OutpointFilter, ScriptFilter, WalletView};
+use kv_database::KvDatabase;
+use log::{error, info, warn};
+use miniscript::{Descriptor, DescriptorPublicKey};
+use op_return::OpReturnMatch;
 use rustreexo::accumulator::{proof::Proof, stump::Stump};
+use script_type::{AddressFormat, ScriptType};
+use serde::Serialize;
+use silent_payments::SilentPayment;
+use stored::{StoredAddress, StoredBody, StoredJournalEntry};
+use tx_index::{TxIndex, TxLocation};
+use wallet_export::{
+    ExportedAddress, ExportedRoots, ExportedTransaction, WalletExport, WALLET_EXPORT_VERSION,
+};
+use webhooks::{Alerts, WalletEvent};
+
+/// How many blocks back we keep accumulator snapshots for. Older states are pruned.
+pub const ROOTS_HISTORY_DEPTH: u32 = 1_000;
+
+/// How many transaction bodies we keep in memory by default
+pub const DEFAULT_TX_CACHE_SIZE: usize = 1_000;
+
+/// Domain separation for wallet commitments
+const WALLET_COMMITMENT_TAG: &[u8] = b"utreexo-electrum-server/wallet-commitment";
+
+/// How many outpoints clients may ask us to watch, across all of them
+pub const MAX_WATCHED_OUTPOINTS: usize = 10_000;
+
+/// A transaction in an address history. This is kept in memory for every transaction we
+/// know about, so it only holds what we need for building histories and status hashes. The
+/// actual transaction lives in a [TransactionBody], loaded from the database on demand.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CachedTransaction {
-    pub tx_hex: String,
     pub height: u32,
-    pub merkle_block: Option<MerkleBlock>,
-    pub hash: String,
+    pub hash: Txid,
     pub position: u32,
 }
 impl Default for CachedTransaction {
     fn default() -> Self {
         CachedTransaction {
-            tx_hex: sha256::Hash::all_zeros().to_string(),
             height: 0,
-            merkle_block: None,
-            hash: sha256::Hash::all_zeros().to_string(),
+            hash: Txid::all_zeros(),
             position: 0,
         }
     }
 }
-impl std::fmt::Display for CachedTransaction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let merkle_block = if let Some(merkle_block) = &self.merkle_block {
-            serialize_hex(merkle_block)
-        } else {
-            "".to_string()
-        };
-        write!(
-            f,
-            "{};{};{};{}",
-            self.tx_hex, self.height, self.position, merkle_block
-        )
+/// An entry in an address history. Mined and unconfirmed transactions carry different
+/// data, so they are kept apart by type instead of using special heights.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HistoryEntry {
+    /// A transaction mined at `position` in the block at `height`. The proof for it is in
+    /// its [TransactionBody].
+    Confirmed {
+        #[serde(rename = "tx_hash")]
+        hash: Txid,
+        height: u32,
+        position: u32,
+    },
+    /// A transaction our clients broadcast, that didn't confirm yet
+    Mempool {
+        #[serde(rename = "tx_hash")]
+        hash: Txid,
+        /// When we first saw it, in seconds since the Unix epoch
+        first_seen: u64,
+        /// The fee it pays, if we know all its prevouts
+        fee: Option<u64>,
+        has_unconfirmed_parents: bool,
+    },
+}
+impl HistoryEntry {
+    pub fn hash(&self) -> Txid {
+        match self {
+            HistoryEntry::Confirmed { hash, .. } | HistoryEntry::Mempool { hash, .. } => *hash,
+        }
+    }
+}
+impl From<&CachedTransaction> for HistoryEntry {
+    fn from(transaction: &CachedTransaction) -> Self {
+        HistoryEntry::Confirmed {
+            hash: transaction.hash,
+            height: transaction.height,
+            position: transaction.position,
+        }
     }
 }
 /// TODO: Clean this function up
@@ -63,35 +149,151 @@ fn get_arg(mut split: Split<char>) -> Result<(&'_ str, Split<char>), crate::erro
     }
     Err(crate::error::Error::DbParseError)
 }
+/// How older versions wrote it, `txid;height;position`
 impl TryFrom<String> for CachedTransaction {
     type Error = crate::error::Error;
     fn try_from(value: String) -> Result<Self, Self::Error> {
         let transaction = value.split(';');
 
-        let (tx_hex, transaction) = get_arg(transaction)?;
+        let (hash, transaction) = get_arg(transaction)?;
 
         let (height, transaction) = get_arg(transaction)?;
-        let (position, transaction) = get_arg(transaction)?;
-
-        let (merkle_block, _) = get_arg(transaction)?;
-        let merkle_block = Vec::from_hex(merkle_block)?;
-        let merkle_block = deserialize(&merkle_block)?;
+        let (position, _) = get_arg(transaction)?;
 
-        let tx = Vec::from_hex(tx_hex)?;
-        let tx = deserialize::<Transaction>(&tx)?;
+        // Older databases stored the whole transaction here, instead of only its id
+        let hash = if hash.len() == 64 {
+            Txid::from_hex(hash)?
+        } else {
+            deserialize::<Transaction>(&Vec::from_hex(hash)?)?.txid()
+        };
 
         Ok(CachedTransaction {
-            tx_hex: tx_hex.to_string(),
+            hash,
             height: height.parse::<u32>()?,
-            merkle_block: Some(merkle_block),
-            hash: tx.txid().to_string(),
             position: position.parse::<u32>()?,
         })
     }
 }
+/// The heavy part of a cached transaction: the transaction itself, the proof that it
+/// was included in a block and the outputs it spends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionBody {
+    pub tx: Transaction,
+    pub merkle_block: Option<MerkleBlock>,
+    /// The output spent by each input, in input order. We only see these while processing
+    /// the block, and we don't keep old blocks, so they have to be saved then. Empty for
+    /// transactions cached by older versions.
+    pub prevouts: Vec<TxOut>,
+}
+impl TransactionBody {
+    /// Older databases stored transaction bodies inside the address entry, as
+    /// `tx_hex;height;position;merkle_block`. Returns the body if this is one such entry.
+    pub fn from_legacy(entry: &str) -> Result<Option<TransactionBody>, crate::error::Error> {
+        let fields = entry.split(';').collect::<Vec<_>>();
+        if fields.len() != 4 {
+            return Ok(None);
+        }
+        let body = format!("{};{}", fields[0], fields[3]);
+        Ok(Some(TransactionBody::try_from(body)?))
+    }
+}
+/// Reads a [StoredBody], or what older versions wrote, `tx;merkle_block;prevouts`
+impl TryFrom<String> for TransactionBody {
+    type Error = crate::error::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.starts_with('{') {
+            return serde_json::from_str::<StoredBody>(&value)?.try_into();
+        }
+        let body = value.split(';');
+
+        let (tx_hex, body) = get_arg(body)?;
+        let tx = Vec::from_hex(tx_hex)?;
+        let tx = deserialize::<Transaction>(&tx)?;
+
+        let (merkle_block, body) = get_arg(body)?;
+        let merkle_block = if merkle_block.is_empty() {
+            None
+        } else {
+            Some(deserialize(&Vec::from_hex(merkle_block)?)?)
+        };
+        // Older versions didn't save prevouts
+        let prevouts = match get_arg(body) {
+            Ok((prevouts, _)) => deserialize(&Vec::from_hex(prevouts)?)?,
+            Err(_) => vec![],
+        };
+
+        Ok(TransactionBody {
+            tx,
+            merkle_block,
+            prevouts,
+        })
+    }
+}
+/// Whether `merkle_block` proves `txid` is at `position` in its block. Its partial merkle
+/// tree must commit to the header's merkle root, and have `txid` at that position, since
+/// Electrum clients check our merkle proofs using the position we give them.
+pub fn proves_position(merkle_block: &MerkleBlock, txid: &Txid, position: u32) -> bool {
+    let mut matches = vec![];
+    let mut indexes = vec![];
+    match merkle_block.txn.extract_matches(&mut matches, &mut indexes) {
+        Ok(root) if root == merkle_block.header.merkle_root => matches
+            .iter()
+            .zip(indexes.iter())
+            .any(|(matched, index)| matched == txid && *index == position),
+        _ => false,
+    }
+}
+/// Our wallet balance after a block that changed it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BalanceCheckpoint {
+    pub height: u32,
+    pub balance: u64,
+}
+/// A transaction in our broadcast journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub transaction: Transaction,
+    /// When our client broadcast it, in seconds since the Unix epoch
+    pub broadcast_at: u64,
+    /// Whether we gave up on it, after it went unconfirmed for too long
+    pub dropped: bool,
+}
+/// Reads a [StoredJournalEntry], or what older versions wrote, `tx;broadcast_at;dropped`
+impl TryFrom<String> for JournalEntry {
+    type Error = crate::error::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.starts_with('{') {
+            return serde_json::from_str::<StoredJournalEntry>(&value)?.try_into();
+        }
+        let entry = value.split(';');
+        let (tx_hex, entry) = get_arg(entry)?;
+        let transaction = deserialize::<Transaction>(&Vec::from_hex(tx_hex)?)?;
+        // Older versions only saved the transaction, so we count from when we've loaded it
+        let (broadcast_at, dropped) = match get_arg(entry) {
+            Ok((broadcast_at, entry)) => (broadcast_at.parse()?, get_arg(entry)?.0 == "1"),
+            Err(_) => (unix_time(), false),
+        };
+        Ok(JournalEntry {
+            transaction,
+            broadcast_at,
+            dropped,
+        })
+    }
+}
+/// Seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+/// Reads a [StoredAddress], or what older versions wrote, fields separated by `:`
 impl TryFrom<String> for CachedAddress {
     type Error = crate::error::Error;
     fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.starts_with('{') {
+            return serde_json::from_str::<StoredAddress>(&value)?.try_into();
+        }
         let address = value.split(':');
         let (script_hash, address) = get_arg(address)?;
         let script_hash = sha256::Hash::from_hex(script_hash)?;
@@ -101,6 +303,24 @@ impl TryFrom<String> for CachedAddress {
         let (script, address) = get_arg(address)?;
         let script = Script::from_hex(script)?;
 
+        // Activity heights are stored as `first;last`, older databases don't have them
+        let mut address = address.peekable();
+        let mut activity = match address.peek().map(|entry| entry.split(';').count()) {
+            Some(2) => {
+                let mut heights = address.next().unwrap_or_default().split(';');
+                (
+                    parse_height(heights.next().unwrap_or_default())?,
+                    parse_height(heights.next().unwrap_or_default())?,
+                )
+            }
+            _ => (None, None),
+        };
+        // Only addresses with archived transactions have this
+        let archived = match address.peek().map(|entry| entry.split(';').count()) {
+            Some(5) => ArchivedHistory::try_from(address.next().unwrap_or_default())?,
+            _ => ArchivedHistory::default(),
+        };
+
         let mut transactions = vec![];
 
         for transaction in address {
@@ -113,21 +333,42 @@ impl TryFrom<String> for CachedAddress {
 
             transactions.push(transaction);
         }
+        if activity.0.is_none() {
+            activity = (
+                transactions.iter().map(|tx| tx.height).min(),
+                transactions.iter().map(|tx| tx.height).max(),
+            );
+        }
 
         Ok(CachedAddress {
             balance: balance.parse()?,
             script_hash,
             transactions,
             script,
+            first_seen_height: activity.0,
+            last_active_height: activity.1,
+            archived,
         })
     }
 }
+fn parse_height(height: &str) -> Result<Option<u32>, crate::error::Error> {
+    if height.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(height.parse()?))
+}
 #[derive(Debug, Clone)]
 pub struct CachedAddress {
     script_hash: Hash,
     balance: u64,
     transactions: Vec<CachedTransaction>,
     script: Script,
+    /// The height of the first block with a transaction touching this address
+    first_seen_height: Option<u32>,
+    /// The height of the last block with a transaction touching this address
+    last_active_height: Option<u32>,
+    /// Our oldest transactions, if there are too many to keep in `transactions`
+    archived: ArchivedHistory,
 }
 
 impl CachedAddress {
@@ -138,12 +379,171 @@ impl CachedAddress {
         script: Script,
     ) -> CachedAddress {
         CachedAddress {
+            first_seen_height: transactions.iter().map(|tx| tx.height).min(),
+            last_active_height: transactions.iter().map(|tx| tx.height).max(),
             script_hash,
             balance,
             transactions,
             script,
+            archived: ArchivedHistory::default(),
+        }
+    }
+    /// Moves our oldest mined transactions to the archive, if we have more than
+    /// `max_hot_history` of them, keeping half of that. Returns the ones to write to it.
+    /// We only do this while our history is in the order it was mined in, since the archived
+    /// part must come first in status hashes.
+    fn archive_oldest(&mut self, max_hot_history: usize) -> Option<Vec<CachedTransaction>> {
+        if max_hot_history == 0 || self.transactions.len() <= max_hot_history {
+            return None;
+        }
+        let in_order = self
+            .transactions
+            .windows(2)
+            .all(|pair| (pair[0].height, pair[0].position) <= (pair[1].height, pair[1].position));
+        if !in_order {
+            return None;
+        }
+        let archived = self
+            .transactions
+            .drain(..self.transactions.len() - max_hot_history / 2)
+            .collect::<Vec<_>>();
+        self.archived.extend(&archived);
+        Some(archived)
+    }
+    /// Records that a transaction touching this address was confirmed at `height`
+    fn record_activity(&mut self, height: u32) {
+        self.first_seen_height = Some(self.first_seen_height.map_or(height, |h| h.min(height)));
+        self.last_active_height = Some(self.last_active_height.map_or(height, |h| h.max(height)));
+    }
+    pub fn summary(&self, network: Network, format: AddressFormat) -> AddressSummary {
+        AddressSummary {
+            script_hash: self.script_hash,
+            script: self.script.to_hex(),
+            script_type: ScriptType::classify(&self.script),
+            address: script_type::get_address(&self.script, network, format),
+            balance: self.balance,
+            transactions: self.archived.count + self.transactions.len(),
+            first_seen_height: self.first_seen_height,
+            last_active_height: self.last_active_height,
+            last_active_confirmations: 0,
+        }
+    }
+}
+/// What we know about an outpoint: the height of the block creating it and the transaction
+/// spending it, if any. Unconfirmed spenders have height zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutpointStatus {
+    pub height: Option<u32>,
+    pub spender: Option<(Txid, u32)>,
+}
+/// Where one of our addresses comes from, see [get_derivations]
+#[derive(Debug, Serialize)]
+pub struct AddressDerivation {
+    pub script_hash: Hash,
+    /// The descriptor deriving it, `None` if none of the ones we were given does
+    pub descriptor: Option<String>,
+    pub index: Option<u32>,
+}
+/// Finds which of `descriptors` derives each of `script_hashes`, and at which index below
+/// `last`. Returns the position of the descriptor in `descriptors`, and the index, of each
+/// one we've found.
+fn match_derivations(
+    descriptors: &[Descriptor<DescriptorPublicKey>],
+    script_hashes: &HashSet<Hash>,
+    last: u32,
+) -> HashMap<Hash, (usize, u32)> {
+    let mut found = HashMap::new();
+    'descriptors: for (n, descriptor) in descriptors.iter().enumerate() {
+        for index in 0..last {
+            if found.len() == script_hashes.len() {
+                break 'descriptors;
+            }
+            let script_hash = get_spk_hash(&descriptor.at_derivation_index(index).script_pubkey());
+            if script_hashes.contains(&script_hash) {
+                found.entry(script_hash).or_insert((n, index));
+            }
+            // Descriptors without a wildcard only have one address
+            if !descriptor.has_wildcard() {
+                break;
+            }
         }
     }
+    found
+}
+/// Tells which of `descriptors` derives each of `script_hashes`, the addresses we watch, and
+/// at which index. We look up to index `lookahead` past how many addresses we watch, so
+/// addresses watched by mistake show up without a descriptor. Those come first. This takes
+/// a while for big wallets, so it doesn't need our cache, only a copy of
+/// [AddressCache::get_watched_script_hashes].
+pub fn get_derivations(
+    descriptors: &[Descriptor<DescriptorPublicKey>],
+    script_hashes: HashSet<Hash>,
+    lookahead: u32,
+) -> Vec<AddressDerivation> {
+    let last = (script_hashes.len() as u32).saturating_add(lookahead);
+    let mut found = match_derivations(descriptors, &script_hashes, last);
+    let mut derivations = script_hashes
+        .into_iter()
+        .map(|script_hash| {
+            let (descriptor, index) = found
+                .remove(&script_hash)
+                .map(|(n, index)| (descriptors[n].to_string(), index))
+                .unzip();
+            AddressDerivation {
+                script_hash,
+                descriptor,
+                index,
+            }
+        })
+        .collect::<Vec<_>>();
+    derivations.sort_by(|a, b| (&a.descriptor, a.index).cmp(&(&b.descriptor, b.index)));
+    derivations
+}
+/// Finds which of `descriptors` derives each of `script_hashes`, and at which index. We look
+/// up to index `lookahead` past the `watched` addresses we watch. Returns the position of the
+/// descriptor in `descriptors`, and the index, of each one we've found. Like
+/// [get_derivations], it takes a while, so it only needs [AddressCache::watched_count].
+pub fn find_derivations(
+    descriptors: &[Descriptor<DescriptorPublicKey>],
+    script_hashes: &HashSet<Hash>,
+    watched: usize,
+    lookahead: u32,
+) -> HashMap<Hash, (usize, u32)> {
+    let last = (watched as u32).saturating_add(lookahead);
+    match_derivations(descriptors, script_hashes, last)
+}
+/// An address whose balance, or unspent outputs, don't match its history
+#[derive(Debug)]
+pub struct BalanceDiscrepancy {
+    pub script_hash: Hash,
+    /// The balance we had
+    pub stored: u64,
+    /// The balance its unspent outputs add up to
+    pub computed: u64,
+    /// How many unspent outputs we had for it
+    pub stored_utxos: usize,
+    /// How many unspent outputs its history has
+    pub computed_utxos: usize,
+    /// What its transactions add up to. Negative if it spends outputs it never received,
+    /// so transactions are missing from its history
+    pub history: i64,
+    /// Transactions in its history our transaction index lost. We couldn't find them, so
+    /// their outputs were missing from its unspent outputs
+    pub unindexed: Vec<Txid>,
+}
+/// What we know about one of our addresses, without its whole history
+#[derive(Debug, Serialize)]
+pub struct AddressSummary {
+    pub script_hash: Hash,
+    pub script: String,
+    pub script_type: ScriptType,
+    pub address: Option<String>,
+    pub balance: u64,
+    pub transactions: usize,
+    pub first_seen_height: Option<u32>,
+    pub last_active_height: Option<u32>,
+    /// How many confirmations its last activity has, zero if it was never active
+    pub last_active_confirmations: u32,
 }
 pub trait AddressCacheDatabase {
     /// Saves a new address to the database. If the address already exists, `update` should
@@ -155,6 +555,8 @@ pub trait AddressCacheDatabase {
         E: From<crate::error::Error> + Into<crate::error::Error> + std::convert::From<kv::Error>;
     /// Updates an address, probably because a new transaction arrived
     fn update(&self, address: &CachedAddress);
+    /// Updates several addresses at once. Either all of them are written, or none is.
+    fn update_many(&self, addresses: &[CachedAddress]);
     /// TODO: Maybe turn this into another db
     /// Returns the height of the last block we filtered
     fn get_cache_height(&self) -> Result<u32, crate::error::Error>;
@@ -164,6 +566,43 @@ pub trait AddressCacheDatabase {
     fn desc_save(&self, descriptor: String) -> Result<(), crate::error::Error>;
     /// Get associated descriptor
     fn desc_get(&self) -> Result<String, crate::error::Error>;
+    /// Saves a transaction we've broadcast, so we can rebroadcast it after a restart
+    fn journal_save(&self, entry: &JournalEntry) -> Result<(), crate::error::Error>;
+    /// Removes a transaction from the broadcast journal, because it's confirmed or conflicted
+    fn journal_remove(&self, txid: &Txid) -> Result<(), crate::error::Error>;
+    /// Loads all transactions in the broadcast journal
+    fn journal_load(&self) -> Result<Vec<JournalEntry>, crate::error::Error>;
+    /// Saves the body of a transaction we've cached
+    fn save_tx_body(&self, txid: &Txid, body: &TransactionBody) -> Result<(), crate::error::Error>;
+    /// Loads the body of a transaction we've cached
+    fn load_tx_body(&self, txid: &Txid) -> Result<Option<TransactionBody>, crate::error::Error>;
+    /// Saves where some transactions are in our address histories
+    fn tx_index_save(&self, entries: &[(Txid, TxLocation)]) -> Result<(), crate::error::Error>;
+    /// Finds where a transaction is in our address histories
+    fn tx_index_load(&self, txid: &Txid) -> Result<Option<TxLocation>, crate::error::Error>;
+    /// Adds transactions to an address' archive, after the first `count` it has. Each one is
+    /// its own entry, so only these are written. Anything after the first `count` was left by
+    /// an archival that didn't finish, and is overwritten
+    fn archive_append(
+        &self,
+        script_hash: &Hash,
+        count: usize,
+        transactions: &[CachedTransaction],
+    ) -> Result<(), crate::error::Error>;
+    /// Loads the first `count` transactions in an address' archive
+    fn archive_load(
+        &self,
+        script_hash: &Hash,
+        count: usize,
+    ) -> Result<Vec<CachedTransaction>, crate::error::Error>;
+    /// Finds a transaction among the first `count` in an address' archive, without loading
+    /// the others
+    fn archive_find(
+        &self,
+        script_hash: &Hash,
+        count: usize,
+        txid: &Txid,
+    ) -> Result<Option<CachedTransaction>, crate::error::Error>;
 }
 /// Holds all addresses and associated transactions. We need a database with some basic
 /// methods, to store all data
@@ -177,290 +616,3756 @@ pub struct AddressCache<D: AddressCacheDatabase, S: ChainStore> {
     /// Holds all scripts we are interested in.
     script_set: HashSet<Script>,
     /// Maps transaction ids to a script hash and the position of this transaction in a block
-    tx_index: HashMap<Txid, (Hash, usize)>,
+    tx_index: TxIndex,
+    /// The most recently used transaction bodies, already parsed. Everything else stays on
+    /// disk. Handlers share them, so a hit costs us a reference count, not a copy.
+    tx_bodies: ShardedLru<Txid, Arc<TransactionBody>>,
+    /// Status hashes of our addresses, computed the first time they are asked for, and
+    /// forgotten when their history changes. See [AddressCache::get_status]
+    statuses: Mutex<HashMap<Hash, Option<Hash>>>,
     /// Our utreexo accumulator
     acc: Stump,
+    /// The height of the last block we've processed. This is also in our database, but
+    /// we keep it here so handlers can compute confirmations cheaply.
+    height: u32,
+    /// Transactions broadcast by our clients that we haven't seen in a block yet. We keep
+    /// rebroadcasting them until they get either confirmed or conflicted.
+    broadcast_journal: HashMap<Txid, Transaction>,
+    /// When each transaction in our journal was broadcast
+    broadcast_times: HashMap<Txid, u64>,
+    /// Transactions we've stopped rebroadcasting, because they went unconfirmed for too long.
+    /// They are kept until they confirm or get conflicted, so clients can tell what happened
+    dropped_broadcasts: HashMap<Txid, Transaction>,
+    /// If set, we write a record of what changed in our wallet for every block we process
+    block_exporter: Option<BlockExporter>,
+    /// The first and last block we didn't export, while our disk is almost full. The ones
+    /// that didn't touch our wallet or replace another block weren't logged either
+    skipped_records: Option<(u32, u32)>,
+    /// If set, we keep the header of every block we process here
+    headers: Option<HeaderStore>,
+    /// If set, we tell these endpoints about things happening to our wallet
+    alerts: Option<Alerts>,
+    /// Whether we're catching up with our node. Events of these blocks happened a while ago,
+    /// so our alerts skip them
+    catching_up: bool,
+    /// Whether we should check the balance of every address we update against its history
+    check_balances: bool,
+    /// We keep every OP_RETURN output whose payload starts with one of these
+    op_return_prefixes: Vec<Vec<u8>>,
+    /// What we look for in each block's transactions
+    filters: Vec<Box<dyn BlockFilter>>,
+    /// Outpoints our clients asked us to watch, and what we know about them
+    watched_outpoints: HashMap<OutPoint, OutpointStatus>,
+    /// Watched outpoints that changed since the last time someone asked
+    changed_outpoints: HashSet<OutPoint>,
+    /// While our disk is almost full, we don't watch anything new and skip the block log,
+    /// block exports and block proofs. Accumulator snapshots are still saved, since we need
+    /// them to recover from a reorg.
+    disk: DiskSpace,
+    /// Where our chain state is, for crash reports
+    fingerprint: StateFingerprint,
+    /// What happened to our wallet lately, for clients catching up
+    events: EventLog,
+    /// The hashes of the last [ROOTS_HISTORY_DEPTH] blocks we've applied, so we notice one
+    /// being replaced even while our block log is paused
+    recent_blocks: BTreeMap<u32, BlockHash>,
+    /// We don't apply blocks after this one, so our wallet stays as it was at that height
+    stop_height: Option<u32>,
+    /// Addresses with more mined transactions than this get the oldest ones archived. 0
+    /// means we never archive
+    max_hot_history: usize,
+    /// While a compaction runs, the transactions we cached since it started. It may not
+    /// know they're referenced, so they're kept
+    compacting: Option<HashSet<Txid>>,
     /// Since address_cache hold an acc and might need some other blockchain related data
     /// it's nice to give it a chainstore.
     chain_store: S,
 }
 impl<D: AddressCacheDatabase, S: ChainStore> AddressCache<D, S> {
-    /// Iterates through a block, finds transactions destined to ourselves.
-    /// Returns all transactions we found.
+    /// Iterates through a block, finds transactions destined to ourselves. `utxos` has the
+    /// outputs spent in this block, if we have them.
+    /// Returns all transactions we found. If one of them can't be cached, nothing of this
+    /// block is applied, so it can be processed again.
     pub fn block_process(
         &mut self,
         block: &Block,
         height: u32,
         proof: Proof,
         del_hashes: Vec<sha256::Hash>,
-    ) -> Vec<(Transaction, TxOut)> {
+        utxos: &HashMap<OutPoint, TxOut>,
+    ) -> Result<Vec<(Transaction, TxOut)>, crate::error::Error> {
+        let block_txids = block
+            .txdata
+            .iter()
+            .map(Transaction::txid)
+            .collect::<Vec<_>>();
+        // The proofs we cache our transactions with are built from these txids, and only
+        // prove their position if they add up to the header's merkle root, each once. We
+        // check before changing anything, so a block can't be half applied
+        let unique = block_txids.iter().collect::<HashSet<_>>().len() == block_txids.len();
+        let merkle_root = bitcoin_merkle_root(
+            block_txids
+                .iter()
+                .map(|txid| TxMerkleNode::from_hash(txid.as_hash())),
+        );
+        if !unique || merkle_root != Some(block.header.merkle_root) {
+            return Err(crate::error::Error::InvalidProof);
+        }
+        // Clients replaying our events must drop what came from the replaced block before
+        // they see what this one brings
+        let replaced = self.find_replaced_block(height, block.block_hash());
+        if let Some(replaced) = replaced {
+            warn!(
+                "Block {replaced} at height {height} was replaced by {}",
+                block.block_hash()
+            );
+            // What the replaced blocks did to our balance is gone with them
+            self.chain_store
+                .delete_balances_from(height)
+                .expect("Chain store is not working");
+            self.events.push(LogEvent::Reorg {
+                height,
+                block_hash: block.block_hash(),
+                replaced,
+            });
+        }
+        self.recent_blocks.insert(height, block.block_hash());
+        if let Some(pruned) = height.checked_sub(ROOTS_HISTORY_DEPTH) {
+            self.recent_blocks = self.recent_blocks.split_off(&(pruned + 1));
+        }
         let mut my_transactions = vec![];
         self.acc = BlockchainSync::update_acc(&self.acc, block, height, proof, del_hashes)
             .unwrap_or_else(|_| panic!("Could not update the accumulator at {height}"));
+        let confirmed_broadcasts = self.prune_broadcast_journal(block);
 
+        let mut record = BlockRecord {
+            height,
+            block_hash: block.block_hash(),
+            transactions: vec![],
+            created: vec![],
+            spent: vec![],
+            addresses: vec![],
+            leaves: self.acc.leafs,
+            roots: self.acc.roots.clone(),
+        };
+        let mut op_returns = vec![];
+        let mut balance_change = 0;
+        // Only formatted into addresses if we export this block
+        let mut scripts = vec![];
         for (position, transaction) in block.txdata.iter().enumerate() {
-            for output in transaction.output.iter() {
-                if self.script_set.contains(&output.script_pubkey) {
-                    my_transactions.push((transaction.clone(), output.clone()));
-                    let my_txid = transaction.txid();
-                    let merkle_block =
-                        MerkleBlock::from_block_with_predicate(block, |txid| *txid == my_txid);
-                    self.cache_transaction(
-                        transaction,
-                        height,
+            let events = self
+                .filters
+                .iter()
+                .flat_map(|filter| filter.scan(&*self, transaction, utxos))
+                .collect::<Vec<_>>();
+            let mut spent = vec![];
+            let mut created = vec![];
+            for event in events {
+                match event {
+                    FilterEvent::Received { vout, output } => created.push((vout, output)),
+                    FilterEvent::Spent { outpoint, prevout } => spent.push((outpoint, prevout)),
+                    FilterEvent::OutpointCreated(outpoint) => self
+                        .update_watched_outpoint(outpoint, |status| status.height = Some(height)),
+                    FilterEvent::OutpointSpent(outpoint) => {
+                        let txid = transaction.txid();
+                        self.update_watched_outpoint(outpoint, |status| {
+                            status.spender = Some((txid, height))
+                        })
+                    }
+                    FilterEvent::OpReturn(entry) => op_returns.push(entry),
+                    FilterEvent::SilentPayment {
+                        vout,
                         output,
-                        merkle_block,
-                        position as u32,
-                    );
+                        tweak,
+                    } => {
+                        let outpoint = OutPoint {
+                            txid: transaction.txid(),
+                            vout,
+                        };
+                        if let Err(err) = self.add_address(output.script_pubkey.clone()) {
+                            error!("Could not add silent payment {outpoint}: {err}");
+                            continue;
+                        }
+                        info!("Found silent payment {outpoint}");
+                        self.save_silent_payment(SilentPayment {
+                            outpoint,
+                            value: output.value,
+                            height,
+                            tweak: tweak.to_hex(),
+                        });
+                        created.push((vout, output));
+                    }
+                }
+            }
+            if spent.is_empty() && created.is_empty() {
+                continue;
+            }
+
+            let my_txid = block_txids[position];
+            let merkle_block = MerkleBlock::from_header_txids_with_predicate(
+                &block.header,
+                &block_txids,
+                |txid| *txid == my_txid,
+            );
+            let prevouts = transaction
+                .input
+                .iter()
+                .map(|input| utxos.get(&input.previous_output).cloned())
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_default();
+            balance_change += self.cache_transaction(
+                transaction,
+                height,
+                merkle_block,
+                position as u32,
+                prevouts,
+            )?;
+            let mut script_hashes = created
+                .iter()
+                .map(|(_, output)| get_spk_hash(&output.script_pubkey))
+                .chain(
+                    spent
+                        .iter()
+                        .map(|(_, prevout)| get_spk_hash(&prevout.script_pubkey)),
+                )
+                .collect::<Vec<_>>();
+            script_hashes.sort();
+            script_hashes.dedup();
+            self.events
+                .push(if confirmed_broadcasts.contains(&my_txid) {
+                    LogEvent::TxConfirmed {
+                        txid: my_txid,
+                        height,
+                        script_hashes,
+                    }
+                } else {
+                    LogEvent::TxAdded {
+                        txid: my_txid,
+                        height,
+                        script_hashes,
+                    }
+                });
+            for (vout, output) in created {
+                let outpoint = OutPoint {
+                    txid: my_txid,
+                    vout,
+                };
+                record.created.push(outpoint);
+                scripts.push(output.script_pubkey.clone());
+                self.notify(WalletEvent::Received {
+                    outpoint,
+                    script_hash: get_spk_hash(&output.script_pubkey),
+                    value: output.value,
+                    height,
+                });
+                my_transactions.push((transaction.clone(), output));
+            }
+            for (outpoint, prevout) in spent {
+                record.spent.push(outpoint);
+                scripts.push(prevout.script_pubkey.clone());
+                self.events.push(LogEvent::UtxoSpent {
+                    outpoint,
+                    spending_txid: my_txid,
+                    height,
+                    script_hash: get_spk_hash(&prevout.script_pubkey),
+                });
+                self.notify(WalletEvent::Spent {
+                    outpoint,
+                    script_hash: get_spk_hash(&prevout.script_pubkey),
+                    value: prevout.value,
+                    spending_txid: my_txid,
+                    height,
+                });
+            }
+            record.transactions.push(my_txid);
+        }
+        if balance_change != 0 {
+            self.chain_store
+                .save_balance(height, self.get_wallet_balance())
+                .expect("Chain store is not working");
+        }
+        self.index_op_returns(height, &op_returns);
+        // Reorgs and our wallet's own blocks are what our block log is for, so they're still
+        // logged when our disk is almost full. Each is a single small entry.
+        if replaced.is_some() || !record.transactions.is_empty() || !self.disk.is_low() {
+            self.log_block(&record, replaced);
+        }
+        if self.disk.is_low() {
+            let first = self.skipped_records.map_or(height, |(first, _)| first);
+            self.skipped_records = Some((first, height));
+            return Ok(my_transactions);
+        }
+        if let Some((first, last)) = self.skipped_records.take() {
+            warn!(
+                "Blocks {first} to {last} are missing from our exports, and the ones that \
+                 didn't touch our wallet from our block log, our disk was almost full"
+            );
+            if let Some(exporter) = self.block_exporter.as_mut() {
+                if let Err(err) = exporter.write_gap(first, last) {
+                    error!("Could not mark blocks {first} to {last} as skipped: {err}");
+                }
+            }
+        }
+        if let Some(exporter) = self.block_exporter.as_mut() {
+            if !record.is_empty() {
+                record.addresses = exporter.addresses(&scripts);
+                if let Err(err) = exporter.write(&record) {
+                    error!("Could not export block {height}: {err}");
                 }
             }
         }
-        my_transactions
+        Ok(my_transactions)
     }
-    pub fn save_acc(&self) {
-        let mut acc = String::new();
-        acc.write_fmt(format_args!("{} ", self.acc.leafs))
-            .expect("String formatting should not err");
-        for root in self.acc.roots.iter() {
-            acc.write_fmt(format_args!("{root}"))
-                .expect("String formatting should not err");
+    /// Starts watching an outpoint, returns false if we are already watching too many, or
+    /// our disk is almost full
+    pub fn watch_outpoint(&mut self, outpoint: OutPoint) -> bool {
+        if self.watched_outpoints.contains_key(&outpoint) {
+            return true;
         }
-
+        if self.watched_outpoints.len() >= MAX_WATCHED_OUTPOINTS || self.disk.is_low() {
+            return false;
+        }
+        // We may already know about our own outputs
+        let status = OutpointStatus {
+            height: self
+                .get_wallet_output(&outpoint)
+                .and(self.get_height(&outpoint.txid)),
+            spender: self.find_wallet_spender(&outpoint),
+        };
+        self.watched_outpoints.insert(outpoint, status);
+        true
+    }
+    pub fn unwatch_outpoint(&mut self, outpoint: &OutPoint) {
+        self.watched_outpoints.remove(outpoint);
+        self.changed_outpoints.remove(outpoint);
+    }
+    /// Returns what we know about a watched outpoint, including unconfirmed spends
+    pub fn get_outpoint_status(&self, outpoint: &OutPoint) -> Option<OutpointStatus> {
+        let mut status = self.watched_outpoints.get(outpoint)?.clone();
+        if status.spender.is_none() {
+            status.spender = self
+                .broadcast_journal
+                .values()
+                .find(|tx| {
+                    tx.input
+                        .iter()
+                        .any(|input| input.previous_output == *outpoint)
+                })
+                .map(|tx| (tx.txid(), 0));
+        }
+        Some(status)
+    }
+    /// Returns the watched outpoints that changed since we were last called
+    pub fn take_changed_outpoints(&mut self) -> Vec<OutPoint> {
+        self.changed_outpoints.drain().collect()
+    }
+    /// Updates what we know about a watched outpoint, and remembers that it changed
+    fn update_watched_outpoint(
+        &mut self,
+        outpoint: OutPoint,
+        update: impl FnOnce(&mut OutpointStatus),
+    ) {
+        if let Some(status) = self.watched_outpoints.get_mut(&outpoint) {
+            update(status);
+            self.changed_outpoints.insert(outpoint);
+        }
+    }
+    /// Looks for a transaction in our history spending one of our outputs
+    fn find_wallet_spender(&self, outpoint: &OutPoint) -> Option<(Txid, u32)> {
+        let output = self.get_wallet_output(outpoint)?;
+        let address = self.address_map.get(&get_spk_hash(&output.script_pubkey))?;
+        self.get_transactions(address)
+            .iter()
+            .find_map(|transaction| {
+                let body = self.get_tx_body(&transaction.hash)?;
+                body.tx
+                    .input
+                    .iter()
+                    .any(|input| input.previous_output == *outpoint)
+                    .then_some((transaction.hash, transaction.height))
+            })
+    }
+    /// The block we had applied at `height`, if it isn't `block_hash`. Blocks from before a
+    /// restart are only found in our block log.
+    fn find_replaced_block(&self, height: u32, block_hash: BlockHash) -> Option<BlockHash> {
+        self.recent_blocks
+            .get(&height)
+            .copied()
+            .or_else(|| self.get_block_log(height).map(|entry| entry.block_hash))
+            .filter(|hash| *hash != block_hash)
+    }
+    /// Adds a processed block, which replaced `replaced` if any, to our block log, and drops
+    /// the entries past [BLOCK_LOG_DEPTH]. Blocks we're catching up with aren't logged, most
+    /// would be pruned before our initial sync is done.
+    fn log_block(&mut self, record: &BlockRecord, replaced: Option<BlockHash>) {
+        if self.catching_up {
+            return;
+        }
+        let entry = BlockLogEntry {
+            height: record.height,
+            block_hash: record.block_hash,
+            transactions: record.transactions.len(),
+            leaves: record.leaves,
+            replaced,
+        };
+        let entry = serde_json::to_string(&entry).expect("Log entries are always serializable");
         self.chain_store
-            .save_roots(acc)
+            .save_block_log(record.height, entry)
             .expect("Chain store is not working");
+        // Heights we skipped, or didn't log, would never be pruned one by one
+        if let Some(pruned) = record.height.checked_sub(BLOCK_LOG_DEPTH) {
+            self.chain_store
+                .delete_block_log_before(pruned + 1)
+                .expect("Chain store is not working");
+        }
     }
-
-    fn load_acc(chain_store: &S) -> Stump {
-        let acc = chain_store.load_roots().expect("Could not load roots");
-        if let Some(acc) = acc {
-            let acc = acc.split(' ').collect::<Vec<_>>();
-            let leaves = acc.first().expect("Missing leaves count");
-
-            let leaves = leaves
-                .parse::<u64>()
-                .expect("Invalid number, maybe the accumulator got corrupted?");
-            let acc = acc.get(1);
-            let mut roots = vec![];
-
-            if let Some(acc) = acc {
-                let mut acc = acc.to_string();
-                while acc.len() >= 64 {
-                    let hash = acc.drain(0..64).collect::<String>();
-                    let hash =
-                        sha256::Hash::from_hex(hash.as_str()).expect("Invalid hash provided");
-                    roots.push(hash);
-                }
-            }
-
-            Stump {
-                leafs: leaves,
-                roots,
-            }
+    /// Sets which OP_RETURN payload prefixes we keep outputs for
+    pub fn set_op_return_prefixes(&mut self, prefixes: Vec<Vec<u8>>) {
+        self.op_return_prefixes = prefixes;
+    }
+    /// Adds something else to look for in every block we process
+    pub fn add_filter(&mut self, filter: Box<dyn BlockFilter>) {
+        self.filters.push(filter);
+    }
+    /// Whether we keep OP_RETURN outputs starting with this prefix
+    pub fn watches_op_return_prefix(&self, prefix: &[u8]) -> bool {
+        self.op_return_prefixes
+            .iter()
+            .any(|watched| watched == prefix)
+    }
+    /// Saves the OP_RETURN outputs we've matched in the block at `height`, replacing
+    /// whatever a block we've processed at this height before had
+    fn index_op_returns(&self, height: u32, matches: &[OpReturnMatch]) {
+        if self.op_return_prefixes.is_empty() {
+            return;
+        }
+        let result = if matches.is_empty() {
+            self.chain_store.delete_op_return_matches(height)
         } else {
-            Stump::new()
+            let matches = serde_json::to_string(matches).expect("Matches are always serializable");
+            self.chain_store.save_op_return_matches(height, matches)
+        };
+        result.expect("Chain store is not working");
+    }
+    /// Returns the OP_RETURN outputs we've matched in the block at `height`
+    pub fn get_op_return_matches(&self, height: u32) -> Vec<OpReturnMatch> {
+        self.chain_store
+            .load_op_return_matches(height)
+            .expect("Chain store is not working")
+            .and_then(|matches| serde_json::from_str(&matches).ok())
+            .unwrap_or_default()
+    }
+    /// Returns our balance after each block between `from` and `to` that changed it
+    pub fn get_balance_history(&self, from: u32, to: u32) -> Vec<BalanceCheckpoint> {
+        if from > to {
+            return vec![];
         }
+        self.chain_store
+            .load_balances(from, to)
+            .expect("Chain store is not working")
+            .into_iter()
+            .map(|(height, balance)| BalanceCheckpoint { height, balance })
+            .collect()
     }
-    pub fn bump_height(&self, height: u32) {
-        self.database
-            .set_cache_height(height)
-            .expect("Database is not working");
+    /// The sum of all our addresses' balances
+    pub fn get_wallet_balance(&self) -> u64 {
+        self.address_map
+            .values()
+            .map(|address| address.balance)
+            .sum()
     }
-    pub fn new(database: D, chain_store: S) -> AddressCache<D, S> {
-        let scripts = database
-            .load::<crate::error::Error>()
-            .expect("Could not load database");
-
-        let mut address_map = HashMap::new();
-        let mut script_set = HashSet::new();
-        let mut tx_index = HashMap::new();
-        for address in scripts {
-            for (pos, tx) in address.transactions.iter().enumerate() {
-                let txid = Txid::from_hex(&tx.hash).expect("Cached an invalid txid");
-                tx_index.insert(txid, (address.script_hash, pos));
-            }
-            script_set.insert(address.script.clone());
-            address_map.insert(address.script_hash, address);
+    fn save_silent_payment(&self, payment: SilentPayment) {
+        let entry = serde_json::to_string(&payment).expect("Payments are always serializable");
+        self.chain_store
+            .save_silent_payment(payment.outpoint.to_string(), entry)
+            .expect("Chain store is not working");
+    }
+    /// Returns every silent payment we've found
+    pub fn get_silent_payments(&self) -> Vec<SilentPayment> {
+        self.chain_store
+            .load_silent_payments()
+            .expect("Chain store is not working")
+            .iter()
+            .filter_map(|entry| serde_json::from_str(entry).ok())
+            .collect()
+    }
+    /// Returns the block log entry for `height`, if we still have it
+    pub fn get_block_log(&self, height: u32) -> Option<BlockLogEntry> {
+        let entry = self
+            .chain_store
+            .load_block_log(height)
+            .expect("Chain store is not working")?;
+        serde_json::from_str(&entry).ok()
+    }
+    /// Returns the output spent by `outpoint`, if it's one of our wallet's outputs
+    pub fn get_wallet_output(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        let transaction = self.get_tx_body(&outpoint.txid)?;
+        let output = transaction.tx.output.get(outpoint.vout as usize)?;
+        if self.script_set.contains(&output.script_pubkey) {
+            return Some(output.clone());
         }
-
-        let acc = AddressCache::<D, S>::load_acc(&chain_store);
-        AddressCache {
-            database,
-            chain_store,
-            address_map,
-            script_set,
-            tx_index,
-            acc,
+        None
+    }
+    /// Sets how many transaction bodies we keep in memory
+    pub fn set_tx_cache_size(&mut self, size: NonZeroUsize) {
+        self.tx_bodies.resize(size);
+    }
+    /// Returns our `limit` most recent transactions, oldest first
+    pub fn get_recent_transactions(&self, limit: usize) -> Vec<Txid> {
+        let mut transactions = self
+            .address_map
+            .values()
+            .flat_map(|address| address.transactions.iter())
+            .map(|transaction| (transaction.height, transaction.position, transaction.hash))
+            .collect::<Vec<_>>();
+        transactions.sort_unstable();
+        transactions.dedup();
+        let skip = transactions.len().saturating_sub(limit);
+        transactions
+            .into_iter()
+            .skip(skip)
+            .map(|(_, _, txid)| txid)
+            .collect()
+    }
+    /// Loads the bodies of these transactions into memory, so we don't have to go to disk
+    /// when a client asks for them
+    pub fn warm_up(&self, txids: &[Txid]) {
+        for txid in txids {
+            self.get_tx_body(txid);
         }
     }
-    fn get_transaction(&self, txid: &Txid) -> Option<CachedTransaction> {
-        if let Some((address, idx)) = self.tx_index.get(txid) {
-            if let Some(address) = self.address_map.get(address) {
-                if let Some(tx) = address.transactions.get(*idx) {
-                    return Some(tx.clone());
-                }
-            }
+    /// Computes the status hash of these addresses, and loads the transactions creating
+    /// their unspent outputs, so neither waits for a client asking about them
+    pub fn warm_up_addresses(&self, script_hashes: &[Hash]) {
+        for script_hash in script_hashes {
+            self.get_status(script_hash);
+            self.get_address_utxos(script_hash);
         }
-        None
     }
-    /// Returns all transactions this address has, both input and outputs
-    pub fn get_address_history(&self, script_hash: &sha256::Hash) -> Vec<CachedTransaction> {
-        if let Some(cached_script) = self.address_map.get(script_hash) {
-            return cached_script.transactions.clone();
+    /// The script hashes of every address we watch
+    pub fn get_script_hashes(&self) -> Vec<Hash> {
+        self.address_map.keys().copied().collect()
+    }
+    /// How many transaction bodies we may keep in memory
+    pub fn get_tx_cache_size(&self) -> usize {
+        self.tx_bodies.cap()
+    }
+    /// Moves our transaction index to disk, keeping only `hot_size` entries in memory
+    pub fn use_disk_tx_index(&mut self, hot_size: NonZeroUsize) -> Result<(), crate::error::Error> {
+        self.tx_index.spill_to_disk(hot_size, &self.database)
+    }
+    /// Returns a transaction's body, loading it from our database if it's not in memory
+    pub fn get_tx_body(&self, txid: &Txid) -> Option<Arc<TransactionBody>> {
+        self.tx_index.get(txid, &self.database)?;
+        if let Some(body) = self.tx_bodies.get(txid) {
+            return Some(body);
         }
-        vec![]
+        let body = Arc::new(
+            self.database
+                .load_tx_body(txid)
+                .expect("Database is not working")?,
+        );
+        self.tx_bodies.put(*txid, body.clone());
+        Some(body)
     }
-    /// Returns the balance of this address, debts (spends) are taken in account
-    pub fn get_address_balance(&self, script_hash: &sha256::Hash) -> u64 {
-        if let Some(cached_script) = self.address_map.get(script_hash) {
-            return cached_script.balance;
+    /// Makes us check every balance we update against the address' history. This is
+    /// expensive, so it's only meant for debugging.
+    pub fn set_check_balances(&mut self, check_balances: bool) {
+        self.check_balances = check_balances;
+    }
+    /// Computes an address' balance from its history, instead of trusting the one we keep.
+    /// Negative if it spends more than it received, when transactions are missing from it.
+    fn compute_balance(&self, address: &CachedAddress) -> i64 {
+        let mut balance = 0_i64;
+        for transaction in self.get_transactions(address).iter() {
+            let body = match self.get_tx_body(&transaction.hash) {
+                Some(body) => body,
+                None => {
+                    warn!("Missing transaction {} in our database", transaction.hash);
+                    continue;
+                }
+            };
+            for output in body.tx.output.iter() {
+                if output.script_pubkey == address.script {
+                    balance += output.value as i64;
+                }
+            }
+            for input in body.tx.input.iter() {
+                if let Some(prevout) = self.get_wallet_output(&input.previous_output) {
+                    if prevout.script_pubkey == address.script {
+                        balance -= prevout.value as i64;
+                    }
+                }
+            }
         }
-
-        0
+        balance
     }
-    /// Returns the Merkle Proof for a given address
-    pub fn get_merkle_proof(&self, txid: &Txid) -> Option<(Vec<String>, u32)> {
-        let mut hashes = vec![];
-        if let Some(tx) = self.get_transaction(txid) {
-            for hash in tx.merkle_block.unwrap().txn.hashes() {
-                // Rust Bitcoin (and Bitcoin Core) includes the target hash, but Electrum
-                // doesn't like this.
-                if hash.as_hash() != txid.as_hash() {
-                    hashes.push(hash.to_hex());
+    /// Rebuilds an address' unspent outputs from the transactions in its history. Their
+    /// bodies are loaded straight from our database, so a transaction our index lost still
+    /// counts. Returns them, and the transactions our index doesn't find
+    fn rebuild_utxos(&self, address: &CachedAddress) -> (Vec<(OutPoint, TxOut)>, Vec<Txid>) {
+        let transactions = self.get_transactions(address);
+        let bodies = transactions
+            .iter()
+            .filter_map(|transaction| {
+                let body = self
+                    .database
+                    .load_tx_body(&transaction.hash)
+                    .expect("Database is not working");
+                if body.is_none() {
+                    warn!("Missing transaction {} in our database", transaction.hash);
+                }
+                Some((transaction.hash, body?))
+            })
+            .collect::<Vec<_>>();
+        // Anything spending from this address is also in its history
+        let spent = bodies
+            .iter()
+            .flat_map(|(_, body)| body.tx.input.iter())
+            .map(|input| input.previous_output)
+            .collect::<HashSet<_>>();
+        let mut utxos = vec![];
+        for (txid, body) in bodies.iter() {
+            for (vout, output) in body.tx.output.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid: *txid,
+                    vout: vout as u32,
+                };
+                if output.script_pubkey == address.script && !spent.contains(&outpoint) {
+                    utxos.push((outpoint, output.clone()));
                 }
             }
-
-            return Some((hashes, tx.position));
         }
-
-        None
+        let unindexed = transactions
+            .iter()
+            .map(|transaction| transaction.hash)
+            .filter(|txid| self.tx_index.get(txid, &self.database).is_none())
+            .collect();
+        (utxos, unindexed)
     }
-    pub fn get_height(&self, txid: &Txid) -> Option<u32> {
-        if let Some(tx) = self.get_transaction(txid) {
-            return Some(tx.height);
+    /// Rebuilds every address' unspent outputs, and balance, from its history, returning the
+    /// addresses where either was wrong. Unless `dry_run` is set, they are fixed: lost
+    /// transaction index entries are added back, and balances set to what the unspent
+    /// outputs add up to.
+    pub fn recompute_balances(&mut self, dry_run: bool) -> Vec<BalanceDiscrepancy> {
+        let discrepancies = self
+            .address_map
+            .values()
+            .filter_map(|address| {
+                let (utxos, unindexed) = self.rebuild_utxos(address);
+                let computed = utxos.iter().map(|(_, output)| output.value).sum::<u64>();
+                let stored_utxos = self.get_address_utxos(&address.script_hash).len();
+                let history = self.compute_balance(address);
+                if computed == address.balance
+                    && stored_utxos == utxos.len()
+                    && unindexed.is_empty()
+                    && history >= 0
+                {
+                    return None;
+                }
+                Some(BalanceDiscrepancy {
+                    script_hash: address.script_hash,
+                    stored: address.balance,
+                    computed,
+                    stored_utxos,
+                    computed_utxos: utxos.len(),
+                    unindexed,
+                    history,
+                })
+            })
+            .collect::<Vec<_>>();
+        if dry_run {
+            return discrepancies;
         }
-
-        None
+        let locations = discrepancies
+            .iter()
+            .flat_map(|discrepancy| {
+                discrepancy
+                    .unindexed
+                    .iter()
+                    .map(|txid| (*txid, discrepancy.script_hash))
+            })
+            .collect::<Vec<_>>();
+        self.tx_index.insert(locations, &self.database);
+        let mut fixed = vec![];
+        for discrepancy in discrepancies.iter() {
+            if let Some(address) = self.address_map.get_mut(&discrepancy.script_hash) {
+                address.balance = discrepancy.computed;
+                fixed.push(address.clone());
+            }
+        }
+        self.database.update_many(&fixed);
+        discrepancies
     }
-    pub fn get_sync_limits(
-        &self,
-        current_hight: u32,
-    ) -> Result<RangeInclusive<u32>, crate::error::Error> {
-        let height = self.database.get_cache_height()?;
-        Ok((height + 1)..=current_hight)
+    /// Sets where we should send events about our wallet to
+    pub fn set_alerts(&mut self, alerts: Alerts) {
+        self.alerts = Some(alerts);
     }
-    pub fn get_cached_transaction(&self, txid: &Txid) -> Option<String> {
-        if let Some(tx) = self.get_transaction(txid) {
-            return Some(tx.tx_hex);
+    /// Tells whether the blocks we process from now on are old ones we're catching up with,
+    /// or new ones from our node's tip
+    pub fn set_catching_up(&mut self, catching_up: bool) {
+        self.catching_up = catching_up;
+    }
+    fn notify(&self, event: WalletEvent) {
+        if self.catching_up {
+            return;
+        }
+        if let Some(alerts) = &self.alerts {
+            alerts.notify(event);
         }
-        None
     }
-    pub fn cache_address(&mut self, script_pk: Script) {
-        let hash = get_spk_hash(&script_pk);
-        let new_address = CachedAddress {
-            balance: 0,
-            script_hash: hash,
-            transactions: vec![],
-            script: script_pk.clone(),
-        };
-        self.database.save(&new_address);
-
-        self.address_map.insert(hash, new_address);
-        self.script_set.insert(script_pk);
+    /// What happened to our wallet lately
+    pub fn get_event_log(&self) -> &EventLog {
+        &self.events
     }
-    /// Setup is the first command that should be executed. In a new cache. It sets our wallet's
-    /// state, like the height we should start scanning and the wallet's descriptor.
-    pub fn setup(&self, descriptor: String) -> Result<(), crate::error::Error> {
-        self.database.set_cache_height(0)?;
-        self.database.desc_save(descriptor)
+    /// Sets where we learn whether our disk is almost full
+    pub fn set_disk_space(&mut self, disk: DiskSpace) {
+        self.disk = disk;
     }
-    /// Caches a new transaction. This method may be called for addresses we don't follow yet,
-    /// this automatically makes we follow this address.
-    pub fn cache_transaction(
-        &mut self,
-        transaction: &Transaction,
-        height: u32,
-        out: &TxOut,
-        merkle_block: MerkleBlock,
-        position: u32,
-    ) {
+    pub fn set_fingerprint(&mut self, fingerprint: StateFingerprint) {
+        self.fingerprint = fingerprint;
+    }
+    /// Pins our wallet at `height`, blocks after it are never applied
+    pub fn set_stop_height(&mut self, height: u32) {
+        self.stop_height = Some(height);
+    }
+    pub fn get_stop_height(&self) -> Option<u32> {
+        self.stop_height
+    }
+    pub fn set_max_hot_history(&mut self, max_hot_history: usize) {
+        self.max_hot_history = max_hot_history;
+    }
+    /// Sets where we keep the headers of the blocks we process
+    pub fn set_header_store(&mut self, headers: HeaderStore) {
+        self.headers = Some(headers);
+    }
+    /// Keeps the header of the block at `height`, if we keep headers. Fails with
+    /// [crate::error::Error::InvalidHeaders] if it completes a retarget window that breaks
+    /// the difficulty rules
+    pub fn save_header(
+        &self,
+        height: u32,
+        header: &BlockHeader,
+    ) -> Result<(), crate::error::Error> {
+        match &self.headers {
+            Some(headers) => headers.save(height, header),
+            None => Ok(()),
+        }
+    }
+    /// Replaces the headers we keep around the retarget window starting at `start` with the
+    /// ones `fetch` returns, see [HeaderStore::refetch_window]
+    pub fn refetch_headers(
+        &self,
+        start: u32,
+        fetch: impl Fn(u32) -> Result<BlockHeader, crate::error::Error>,
+    ) -> Result<(), crate::error::Error> {
+        match &self.headers {
+            Some(headers) => headers.refetch_window(start, fetch),
+            None => Ok(()),
+        }
+    }
+    /// The header of the block at `height`, if we keep headers and have it. Headers past our
+    /// tip may be from blocks we've rolled back, so they aren't returned
+    pub fn get_header(&self, height: u32) -> Option<BlockHeader> {
+        if height > self.height {
+            return None;
+        }
+        self.headers
+            .as_ref()?
+            .get(height)
+            .expect("Header store is not working")
+    }
+    /// Sets where we should export per-block records of our wallet's changes to
+    pub fn set_block_exporter(&mut self, exporter: BlockExporter) {
+        self.block_exporter = Some(exporter);
+    }
+    /// Removes from the broadcast journal every transaction that had one of its inputs spent
+    /// in this block. If the spending transaction is the journaled one, it got confirmed,
+    /// otherwise it got conflicted and rebroadcasting it is pointless. Returns the ones that
+    /// confirmed.
+    fn prune_broadcast_journal(&mut self, block: &Block) -> HashSet<Txid> {
+        let mut confirmed = HashSet::new();
+        if self.broadcast_journal.is_empty() && self.dropped_broadcasts.is_empty() {
+            return confirmed;
+        }
+        let mut spent = HashMap::new();
+        for transaction in block.txdata.iter() {
+            for input in transaction.input.iter() {
+                spent.insert(input.previous_output, transaction.txid());
+            }
+        }
+        let mut finished = vec![];
+        let journal = self
+            .broadcast_journal
+            .iter()
+            .chain(self.dropped_broadcasts.iter());
+        for (txid, transaction) in journal {
+            for input in transaction.input.iter() {
+                if let Some(spender) = spent.get(&input.previous_output) {
+                    if spender == txid {
+                        info!("Broadcast transaction {txid} confirmed");
+                        confirmed.insert(*txid);
+                    } else {
+                        info!("Broadcast transaction {txid} conflicted by {spender}");
+                        self.notify(WalletEvent::Conflicted {
+                            txid: *txid,
+                            conflicting_txid: *spender,
+                        });
+                    }
+                    finished.push(*txid);
+                    break;
+                }
+            }
+        }
+        for txid in finished {
+            self.broadcast_journal.remove(&txid);
+            self.broadcast_times.remove(&txid);
+            self.dropped_broadcasts.remove(&txid);
+            self.database
+                .journal_remove(&txid)
+                .expect("Database is not working");
+        }
+        confirmed
+    }
+    /// Records a transaction we've broadcast, so we can keep rebroadcasting it until it
+    /// confirms. The journal is persisted, so this survives restarts. Broadcasting a
+    /// transaction we've dropped starts tracking it again.
+    pub fn journal_broadcast(&mut self, transaction: Transaction) {
+        let entry = JournalEntry {
+            transaction,
+            broadcast_at: unix_time(),
+            dropped: false,
+        };
+        self.database
+            .journal_save(&entry)
+            .expect("Database is not working");
+        let txid = entry.transaction.txid();
+        let mut script_hashes = self
+            .get_script_hashes(&entry.transaction)
+            .into_iter()
+            .filter(|script_hash| self.address_map.contains_key(script_hash))
+            .collect::<Vec<_>>();
+        script_hashes.sort();
+        // Broadcasting it again isn't news
+        if !script_hashes.is_empty() && !self.broadcast_journal.contains_key(&txid) {
+            self.events.push(LogEvent::TxAdded {
+                txid,
+                height: 0,
+                script_hashes,
+            });
+        }
+        self.dropped_broadcasts.remove(&txid);
+        self.broadcast_times.insert(txid, entry.broadcast_at);
+        self.broadcast_journal.insert(txid, entry.transaction);
+    }
+    /// Stops tracking transactions broadcast more than `expiry` ago, that still didn't
+    /// confirm. They are most likely gone from our node's mempool, so we stop rebroadcasting
+    /// them and report them as dropped. Returns the transactions we've dropped.
+    pub fn expire_broadcasts(&mut self, expiry: Duration) -> Vec<Transaction> {
+        let deadline = unix_time().saturating_sub(expiry.as_secs());
+        let expired = self
+            .broadcast_times
+            .iter()
+            .filter(|(_, broadcast_at)| **broadcast_at < deadline)
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+        let mut dropped = vec![];
+        for txid in expired {
+            let broadcast_at = self.broadcast_times.remove(&txid).unwrap_or_default();
+            let transaction = match self.broadcast_journal.remove(&txid) {
+                Some(transaction) => transaction,
+                None => continue,
+            };
+            info!("Broadcast transaction {txid} didn't confirm in time, dropping it");
+            let entry = JournalEntry {
+                transaction,
+                broadcast_at,
+                dropped: true,
+            };
+            self.database
+                .journal_save(&entry)
+                .expect("Database is not working");
+            self.notify(WalletEvent::Dropped { txid });
+            self.dropped_broadcasts
+                .insert(txid, entry.transaction.clone());
+            dropped.push(entry.transaction);
+        }
+        dropped
+    }
+    /// Returns a transaction from our broadcast journal, and whether we've dropped it
+    pub fn get_broadcast(&self, txid: &Txid) -> Option<(Transaction, bool)> {
+        if let Some(transaction) = self.broadcast_journal.get(txid) {
+            return Some((transaction.clone(), false));
+        }
+        self.dropped_broadcasts
+            .get(txid)
+            .map(|transaction| (transaction.clone(), true))
+    }
+    /// Returns all transactions we've broadcast that are still unconfirmed
+    pub fn get_unconfirmed_broadcasts(&self) -> impl Iterator<Item = &Transaction> {
+        self.broadcast_journal.values()
+    }
+    /// Returns the unconfirmed transactions that pay to, or spend from, this script hash,
+    /// oldest first. The only unconfirmed transactions we know about are the ones our
+    /// clients broadcast.
+    pub fn get_address_mempool(&self, script_hash: &sha256::Hash) -> Vec<HistoryEntry> {
+        let mut mempool = self
+            .broadcast_journal
+            .iter()
+            .filter(|(_, transaction)| self.get_script_hashes(transaction).contains(script_hash))
+            .map(|(txid, transaction)| {
+                let first_seen = self.broadcast_times.get(txid).copied().unwrap_or_default();
+                (first_seen, *txid, transaction)
+            })
+            .collect::<Vec<_>>();
+        mempool.sort_by_key(|(first_seen, txid, _)| (*first_seen, *txid));
+        mempool
+            .into_iter()
+            .map(|(first_seen, hash, transaction)| HistoryEntry::Mempool {
+                hash,
+                first_seen,
+                fee: self.get_mempool_fee(transaction),
+                has_unconfirmed_parents: self.has_unconfirmed_parents(transaction),
+            })
+            .collect()
+    }
+    /// Returns the script hash of every output this unconfirmed transaction creates or
+    /// spends, as far as we know them
+    pub fn get_script_hashes(&self, transaction: &Transaction) -> HashSet<sha256::Hash> {
+        let spent = transaction
+            .input
+            .iter()
+            .filter_map(|input| self.get_prevout(&input.previous_output))
+            .map(|prevout| get_spk_hash(&prevout.script_pubkey));
+        transaction
+            .output
+            .iter()
+            .map(|output| get_spk_hash(&output.script_pubkey))
+            .chain(spent)
+            .collect()
+    }
+    /// Returns the fee paid by an unconfirmed transaction, if we know all its prevouts
+    pub fn get_mempool_fee(&self, transaction: &Transaction) -> Option<u64> {
+        let mut input_value = 0;
+        for input in transaction.input.iter() {
+            input_value += self.get_prevout(&input.previous_output)?.value;
+        }
+        let output_value = transaction
+            .output
+            .iter()
+            .map(|output| output.value)
+            .sum::<u64>();
+        input_value.checked_sub(output_value)
+    }
+    /// How many unconfirmed transactions we know about this one depends on
+    pub fn count_unconfirmed_ancestors(&self, transaction: &Transaction) -> usize {
+        let mut ancestors = HashSet::new();
+        let mut pending = vec![transaction];
+        while let Some(transaction) = pending.pop() {
+            for input in transaction.input.iter() {
+                let txid = input.previous_output.txid;
+                if let Some(parent) = self.broadcast_journal.get(&txid) {
+                    if ancestors.insert(txid) {
+                        pending.push(parent);
+                    }
+                }
+            }
+        }
+        ancestors.len()
+    }
+    /// Whether this unconfirmed transaction spends outputs from another unconfirmed one
+    fn has_unconfirmed_parents(&self, transaction: &Transaction) -> bool {
+        transaction.input.iter().any(|input| {
+            self.broadcast_journal
+                .contains_key(&input.previous_output.txid)
+        })
+    }
+    /// Returns the output spent by `outpoint`, if it's either ours or it was created by an
+    /// unconfirmed transaction we know about.
+    fn get_prevout(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        if let Some(parent) = self.broadcast_journal.get(&outpoint.txid) {
+            return parent.output.get(outpoint.vout as usize).cloned();
+        }
+        self.get_wallet_output(outpoint)
+    }
+    fn serialize_acc(acc: &Stump) -> String {
+        serde_json::to_string(&StoredAcc::from(acc)).expect("Accumulators are always serializable")
+    }
+    /// Reads a [StoredAcc], or what older versions wrote, the leaf count and the roots after
+    /// a space
+    fn deserialize_acc(acc: &str) -> Stump {
+        if acc.starts_with('{') {
+            return serde_json::from_str::<StoredAcc>(acc)
+                .expect("Invalid accumulator, maybe it got corrupted?")
+                .into();
+        }
+        let acc = acc.split(' ').collect::<Vec<_>>();
+        let leaves = acc.first().expect("Missing leaves count");
+
+        let leaves = leaves
+            .parse::<u64>()
+            .expect("Invalid number, maybe the accumulator got corrupted?");
+        let acc = acc.get(1);
+        let mut roots = vec![];
+
+        if let Some(acc) = acc {
+            let mut acc = acc.to_string();
+            while acc.len() >= 64 {
+                let hash = acc.drain(0..64).collect::<String>();
+                let hash = sha256::Hash::from_hex(hash.as_str()).expect("Invalid hash provided");
+                roots.push(hash);
+            }
+        }
+
+        Stump {
+            leafs: leaves,
+            roots,
+        }
+    }
+    pub fn save_acc(&self) {
+        self.chain_store
+            .save_roots(Self::serialize_acc(&self.acc))
+            .expect("Chain store is not working");
+        self.chain_store
+            .flush()
+            .expect("Chain store is not working");
+    }
+    /// Saves a snapshot of our current accumulator as the state at `height`, and drops
+    /// the snapshot that just went past [ROOTS_HISTORY_DEPTH].
+    pub fn save_acc_at(&self, height: u32) {
+        self.chain_store
+            .save_roots_at(height, Self::serialize_acc(&self.acc))
+            .expect("Chain store is not working");
+        if let Some(pruned) = height.checked_sub(ROOTS_HISTORY_DEPTH) {
+            self.chain_store
+                .delete_roots_at(pruned)
+                .expect("Chain store is not working");
+        }
+    }
+    /// Keeps the proof for the block at `height`, so we can serve it later. Like accumulator
+    /// snapshots, we only keep the last [ROOTS_HISTORY_DEPTH] of these.
+    pub fn save_block_proof(&self, height: u32, proof: &BlockProof) {
+        if self.disk.is_low() {
+            return;
+        }
+        let proof = serde_json::to_string(proof).expect("Proofs are always serializable");
+        self.chain_store
+            .save_block_proof(height, proof)
+            .expect("Chain store is not working");
+        if let Some(pruned) = height.checked_sub(ROOTS_HISTORY_DEPTH) {
+            self.chain_store
+                .delete_block_proof(pruned)
+                .expect("Chain store is not working");
+        }
+    }
+    /// Returns the proof for the block at `height`, if we still have it
+    pub fn get_block_proof(&self, height: u32) -> Option<BlockProof> {
+        let proof = self
+            .chain_store
+            .load_block_proof(height)
+            .expect("Chain store is not working")?;
+        serde_json::from_str(&proof).ok()
+    }
+    /// Remembers the header of the last block we processed, so we can tell clients about our
+    /// tip without asking our node
+    pub fn save_tip_header(&self, height: u32, header: String) {
+        let tip = Vec::from_hex(&header)
+            .ok()
+            .and_then(|header| deserialize::<BlockHeader>(&header).ok());
+        if let Some(tip) = tip {
+            self.fingerprint
+                .update(height, tip.block_hash(), self.acc.leafs);
+        }
+        self.chain_store
+            .save_tip_header(height, header)
+            .expect("Chain store is not working");
+    }
+    /// Returns the height and header of the last block we processed, if we know it
+    pub fn get_tip_header(&self) -> Option<(u32, String)> {
+        self.chain_store
+            .load_tip_header()
+            .expect("Chain store is not working")
+    }
+    /// Returns our accumulator as it was after processing the block at `height`. We only
+    /// keep the last [ROOTS_HISTORY_DEPTH] states, so older heights return `None`.
+    pub fn get_acc_at(&self, height: u32) -> Option<Stump> {
+        Self::load_acc_at(&self.chain_store, height).expect("Chain store is not working")
+    }
+    /// Like [AddressCache::get_acc_at], straight from a chain store, without loading a
+    /// whole wallet
+    pub fn load_acc_at(chain_store: &S, height: u32) -> Result<Option<Stump>, kv::Error> {
+        let acc = chain_store.load_roots_at(height)?;
+        Ok(acc.map(|acc| Self::deserialize_acc(&acc)))
+    }
+
+    fn load_acc(chain_store: &S) -> Stump {
+        let acc = chain_store.load_roots().expect("Could not load roots");
+        if let Some(acc) = acc {
+            Self::deserialize_acc(&acc)
+        } else {
+            Stump::new()
+        }
+    }
+    /// Returns our current accumulator
+    pub fn get_acc(&self) -> &Stump {
+        &self.acc
+    }
+    /// Returns the height of the last block we've processed
+    pub fn get_cache_height(&self) -> Result<u32, crate::error::Error> {
+        self.database.get_cache_height()
+    }
+    /// Moves our cache height up to `height`, once its blocks are processed. Lower heights
+    /// are ignored, our height only goes back through [AddressCache::reset_to].
+    pub fn bump_height(&mut self, height: u32) {
+        if height < self.height {
+            warn!(
+                "Not moving our height back from {} to {height}",
+                self.height
+            );
+            return;
+        }
+        self.set_height(height);
+    }
+    fn set_height(&mut self, height: u32) {
+        // The leaf count goes first, so a crash in between can't make us trust a height
+        // with the wrong accumulator.
+        self.chain_store
+            .save_leaf_count(height, self.acc.leafs)
+            .expect("Chain store is not working");
+        // This also writes everything else we saved for this block
+        self.chain_store
+            .flush()
+            .expect("Chain store is not working");
+        self.database
+            .set_cache_height(height)
+            .expect("Database is not working");
+        // Only the count at our cache height is ever checked, so the one before it can go
+        if self.height != height {
+            self.chain_store
+                .delete_leaf_count(self.height)
+                .expect("Chain store is not working");
+        }
+        self.height = height;
+    }
+    /// How many confirmations a transaction at `height` has. Unconfirmed transactions
+    /// have zero.
+    pub fn get_confirmations(&self, height: u32) -> u32 {
+        if height == 0 || height > self.height {
+            return 0;
+        }
+        self.height - height + 1
+    }
+    pub fn new(database: D, chain_store: S) -> AddressCache<D, S> {
+        let scripts = database
+            .load::<crate::error::Error>()
+            .expect("Could not load database");
+
+        let mut address_map = HashMap::with_capacity(scripts.len());
+        let mut script_set = HashSet::with_capacity(scripts.len());
+        let mut tx_index = HashMap::with_capacity(
+            scripts
+                .iter()
+                .map(|address| address.archived.count + address.transactions.len())
+                .sum(),
+        );
+        for address in scripts {
+            // Archived transactions are only loaded to be indexed
+            let archived = match address.archived.count {
+                0 => vec![],
+                count => database
+                    .archive_load(&address.script_hash, count)
+                    .expect("Could not load an archived history"),
+            };
+            for tx in archived.iter().chain(address.transactions.iter()) {
+                tx_index.insert(tx.hash, address.script_hash);
+            }
+            script_set.insert(address.script.clone());
+            address_map.insert(address.script_hash, address);
+        }
+
+        let mut broadcast_journal = HashMap::new();
+        let mut broadcast_times = HashMap::new();
+        let mut dropped_broadcasts = HashMap::new();
+        for entry in database
+            .journal_load()
+            .expect("Could not load the broadcast journal")
+        {
+            let txid = entry.transaction.txid();
+            if entry.dropped {
+                dropped_broadcasts.insert(txid, entry.transaction);
+            } else {
+                broadcast_times.insert(txid, entry.broadcast_at);
+                broadcast_journal.insert(txid, entry.transaction);
+            }
+        }
+
+        let acc = AddressCache::<D, S>::load_acc(&chain_store);
+        let height = database.get_cache_height().unwrap_or(0);
+        let mut cache = AddressCache {
+            database,
+            chain_store,
+            address_map,
+            script_set,
+            tx_index: TxIndex::Memory(tx_index),
+            tx_bodies: ShardedLru::new(
+                NonZeroUsize::new(DEFAULT_TX_CACHE_SIZE).expect("Cache size is not zero"),
+            ),
+            statuses: Mutex::new(HashMap::new()),
+            acc,
+            height,
+            broadcast_journal,
+            broadcast_times,
+            dropped_broadcasts,
+            block_exporter: None,
+            skipped_records: None,
+            headers: None,
+            alerts: None,
+            catching_up: false,
+            check_balances: false,
+            op_return_prefixes: vec![],
+            filters: vec![
+                Box::new(ScriptFilter),
+                Box::new(OutpointFilter),
+                Box::new(OpReturnFilter),
+            ],
+            watched_outpoints: HashMap::new(),
+            changed_outpoints: HashSet::new(),
+            disk: DiskSpace::default(),
+            fingerprint: StateFingerprint::default(),
+            events: EventLog::new(unix_time()),
+            recent_blocks: BTreeMap::new(),
+            stop_height: None,
+            max_hot_history: 0,
+            compacting: None,
+        };
+        cache.check_consistency();
+        cache
+    }
+    /// Our accumulator and the height it's at are saved separately, so a crash between the
+    /// two writes leaves them out of sync. This checks that the accumulator has as many leaves
+    /// as it had when we've processed the block at our cache height, and if not, rolls back to
+    /// the most recent accumulator snapshot, so the next sync picks up from there.
+    fn check_consistency(&mut self) {
+        let height = match self.database.get_cache_height() {
+            Ok(height) => height,
+            // Nothing to check before setup
+            Err(_) => return,
+        };
+        let leaves = self
+            .chain_store
+            .load_leaf_count(height)
+            .expect("Chain store is not working");
+        match leaves {
+            Some(leaves) if leaves == self.acc.leafs => return,
+            Some(leaves) => {
+                warn!(
+                    "Accumulator has {} leaves, but we expected {leaves} at height {height}",
+                    self.acc.leafs
+                );
+            }
+            // This database was created before we've started recording leaf counts
+            None => {
+                self.chain_store
+                    .save_leaf_count(height, self.acc.leafs)
+                    .expect("Chain store is not working");
+                return;
+            }
+        }
+
+        let snapshot = (height.saturating_sub(ROOTS_HISTORY_DEPTH)..=height)
+            .rev()
+            .find_map(|height| Some((height, self.get_acc_at(height)?)));
+        let (height, acc) = match snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                warn!("No usable accumulator snapshot, we'll have to resync from genesis");
+                (0, Stump::new())
+            }
+        };
+        info!("Rolling back to height {height}");
+        self.drop_history_after(height);
+        // Counts past where we roll back to were for blocks we'll process again
+        for stale in height + 1..=self.height {
+            self.chain_store
+                .delete_leaf_count(stale)
+                .expect("Chain store is not working");
+        }
+        self.reset_to(height, acc);
+    }
+    /// Forgets the transactions mined after `height`, both the ones we keep in memory and
+    /// archived ones, and recomputes the balances they changed. For rolling back, since the
+    /// blocks after `height` will be processed again.
+    fn drop_history_after(&mut self, height: u32) {
+        let mut changed = vec![];
+        for address in self.address_map.values_mut() {
+            let before = address.transactions.len();
+            address
+                .transactions
+                .retain(|transaction| transaction.height <= height);
+            let mut dropped = address.transactions.len() != before;
+            if address.archived.count > 0 && address.archived.last_height > height {
+                // Archives are in the order they were mined in, so what we keep is still
+                // their first entries, and only the summary needs rebuilding
+                let kept = self
+                    .database
+                    .archive_load(&address.script_hash, address.archived.count)
+                    .expect("Database is not working")
+                    .into_iter()
+                    .filter(|transaction| transaction.height <= height)
+                    .collect::<Vec<_>>();
+                address.archived = ArchivedHistory::default();
+                address.archived.extend(&kept);
+                dropped = true;
+            }
+            if dropped {
+                self.statuses
+                    .get_mut()
+                    .expect("Poisoned lock")
+                    .remove(&address.script_hash);
+                changed.push(address.script_hash);
+            }
+        }
+        if changed.is_empty() {
+            return;
+        }
+        info!(
+            "Dropped transactions mined after height {height} from {} addresses",
+            changed.len()
+        );
+        let mut updated = vec![];
+        for script_hash in changed {
+            let mut address = self.address_map[&script_hash].clone();
+            let computed = self.compute_balance(&address);
+            address.balance = u64::try_from(computed).unwrap_or_else(|_| {
+                warn!(
+                    "History of {script_hash} adds up to {computed}, transactions are missing \
+                     from it. Run recompute-balances to find them"
+                );
+                0
+            });
+            self.address_map.insert(script_hash, address.clone());
+            updated.push(address);
+        }
+        self.database.update_many(&updated);
+    }
+    /// Returns our accumulator and every snapshot we've got, so they can be loaded on
+    /// another machine
+    pub fn dump_chainstate(&self) -> ChainStateDump {
+        let checkpoints = (self.height.saturating_sub(ROOTS_HISTORY_DEPTH)..self.height)
+            .filter_map(|height| {
+                let acc = self.get_acc_at(height)?;
+                Some(AccumulatorState::new(
+                    height,
+                    &acc,
+                    self.get_header(height).as_ref(),
+                ))
+            })
+            .collect();
+        // Without a header store, we still know our tip's
+        let tip_header = self.get_header(self.height).or_else(|| {
+            let (_, header) = self
+                .get_tip_header()
+                .filter(|(height, _)| *height == self.height)?;
+            deserialize::<BlockHeader>(&Vec::from_hex(&header).ok()?).ok()
+        });
+        ChainStateDump {
+            version: CHAINSTATE_DUMP_VERSION,
+            tip: AccumulatorState::new(self.height, &self.acc, tip_header.as_ref()),
+            checkpoints,
+        }
+    }
+    /// Starts from a chain state dumped by another instance. Only a wallet that didn't
+    /// process any block yet may do this, otherwise we would miss part of its history. The
+    /// headers in it are kept like the ones of blocks we processed.
+    pub fn load_chainstate(&mut self, dump: ChainStateDump) -> Result<(), crate::error::Error> {
+        if dump.version != CHAINSTATE_DUMP_VERSION {
+            return Err(crate::error::Error::DbParseError);
+        }
+        if self.database.get_cache_height()? != 0 {
+            return Err(crate::error::Error::WalletAlreadySynced);
+        }
+        // Parsed before anything is saved, so a broken dump leaves us untouched
+        let mut headers = vec![];
+        for state in dump.checkpoints.iter().chain(std::iter::once(&dump.tip)) {
+            if let Some(header) = state.header()? {
+                headers.push((state.height, header));
+            }
+        }
+        for checkpoint in dump.checkpoints.iter() {
+            self.chain_store.save_roots_at(
+                checkpoint.height,
+                Self::serialize_acc(&checkpoint.to_stump()),
+            )?;
+        }
+        for (height, header) in headers.iter() {
+            self.save_header(*height, header)?;
+        }
+        self.reset_to(dump.tip.height, dump.tip.to_stump());
+        if let Some(header) = dump.tip.header {
+            self.save_tip_header(dump.tip.height, header);
+        }
+        Ok(())
+    }
+    /// Makes `acc` our accumulator, as of the block at `height`. The next sync starts
+    /// right after it.
+    pub fn reset_to(&mut self, height: u32, acc: Stump) {
+        // The blocks after it will be processed again, and save their balances again
+        self.chain_store
+            .delete_balances_from(height + 1)
+            .expect("Chain store is not working");
+        self.acc = acc;
+        self.save_acc();
+        self.set_height(height);
+    }
+    fn get_transaction(&self, txid: &Txid) -> Option<CachedTransaction> {
+        let script_hash = self.tx_index.get(txid, &self.database)?;
+        let address = self.address_map.get(&script_hash)?;
+        if let Some(tx) = address.transactions.iter().find(|tx| tx.hash == *txid) {
+            return Some(tx.clone());
+        }
+        if address.archived.count == 0 {
+            return None;
+        }
+        self.database
+            .archive_find(&address.script_hash, address.archived.count, txid)
+            .expect("Database is not working")
+    }
+    /// Every mined transaction an address has, archived ones first
+    fn get_transactions(&self, address: &CachedAddress) -> Vec<CachedTransaction> {
+        if address.archived.count == 0 {
+            return address.transactions.clone();
+        }
+        let mut transactions = self
+            .database
+            .archive_load(&address.script_hash, address.archived.count)
+            .expect("Database is not working");
+        transactions.extend(address.transactions.iter().cloned());
+        transactions
+    }
+    /// Returns the mined transactions this address has, both input and outputs
+    pub fn get_address_history(&self, script_hash: &sha256::Hash) -> Vec<CachedTransaction> {
+        if let Some(cached_script) = self.address_map.get(script_hash) {
+            return self.get_transactions(cached_script);
+        }
+        vec![]
+    }
+    /// The mined transactions of this address we didn't archive, by height and position in
+    /// their block. What status hashes need from the archived ones is in the returned
+    /// [ArchivedHistory]. Unconfirmed transactions don't count towards statuses, so they
+    /// aren't here.
+    pub fn get_recent_history(
+        &self,
+        script_hash: &sha256::Hash,
+    ) -> (ArchivedHistory, Vec<HistoryEntry>) {
+        let (archived, mut confirmed) = match self.address_map.get(script_hash) {
+            Some(address) => (address.archived.clone(), address.transactions.clone()),
+            None => (ArchivedHistory::default(), vec![]),
+        };
+        confirmed.sort_by_key(|tx| (tx.height, tx.position));
+        let history = confirmed.iter().map(HistoryEntry::from).collect::<Vec<_>>();
+        (archived, history)
+    }
+    /// The Electrum status hash of this address, from its mined transactions, None if it
+    /// has none. It's kept until the address gets, or loses, a transaction.
+    pub fn get_status(&self, script_hash: &Hash) -> Option<Hash> {
+        if let Some(status) = self
+            .statuses
+            .lock()
+            .expect("Poisoned lock")
+            .get(script_hash)
+        {
+            return *status;
+        }
+        // Only our own addresses, clients can ask about as many others as they like
+        if !self.address_map.contains_key(script_hash) {
+            return None;
+        }
+        let (archived, history) = self.get_recent_history(script_hash);
+        let status = if history.is_empty() && archived.count == 0 {
+            None
+        } else {
+            Some(extend_status(archived.status_engine(), &history))
+        };
+        self.statuses
+            .lock()
+            .expect("Poisoned lock")
+            .insert(*script_hash, status);
+        status
+    }
+    /// Returns the outputs every address we watch has that aren't spent yet, with their
+    /// script hash and the height of the transaction creating them
+    pub fn get_wallet_utxos(&self) -> Vec<(sha256::Hash, OutPoint, TxOut, u32)> {
+        self.address_map
+            .keys()
+            .flat_map(|script_hash| {
+                self.get_address_utxos(script_hash)
+                    .into_iter()
+                    .map(|(outpoint, output, height)| (*script_hash, outpoint, output, height))
+            })
+            .collect()
+    }
+    /// Returns the whole history of this address: mined transactions by height and position
+    /// in their block, followed by unconfirmed ones
+    pub fn get_full_history(&self, script_hash: &sha256::Hash) -> Vec<HistoryEntry> {
+        let mut confirmed = self.get_address_history(script_hash);
+        confirmed.sort_by_key(|tx| (tx.height, tx.position));
+        let mut history = confirmed.iter().map(HistoryEntry::from).collect::<Vec<_>>();
+        history.extend(self.get_address_mempool(script_hash));
+        history
+    }
+    /// Returns the outputs this address has that aren't spent yet, with the height of the
+    /// transaction creating them
+    pub fn get_address_utxos(&self, script_hash: &sha256::Hash) -> Vec<(OutPoint, TxOut, u32)> {
+        let address = match self.address_map.get(script_hash) {
+            Some(address) => address,
+            None => return vec![],
+        };
+        let bodies = self
+            .get_transactions(address)
+            .into_iter()
+            .filter_map(|transaction| Some((self.get_tx_body(&transaction.hash)?, transaction)))
+            .collect::<Vec<_>>();
+        // Anything spending from this address is also in its history
+        let spent = bodies
+            .iter()
+            .flat_map(|(body, _)| body.tx.input.iter())
+            .map(|input| input.previous_output)
+            .collect::<HashSet<_>>();
+        let mut utxos = vec![];
+        for (body, transaction) in bodies.iter() {
+            for (vout, output) in body.tx.output.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid: transaction.hash,
+                    vout: vout as u32,
+                };
+                if output.script_pubkey == address.script && !spent.contains(&outpoint) {
+                    utxos.push((outpoint, output.clone(), transaction.height));
+                }
+            }
+        }
+        utxos
+    }
+    /// Tells which of our unspent outputs, and the height they were mined at, a wallet can
+    /// spend now. Those a transaction in our broadcast journal spends are taken already, and
+    /// coinbase outputs need `coinbase_maturity` confirmations first.
+    pub fn spendable(&self, coinbase_maturity: u32) -> impl Fn(&OutPoint, u32) -> bool + '_ {
+        let pending = self
+            .broadcast_journal
+            .values()
+            .flat_map(|transaction| transaction.input.iter())
+            .map(|input| input.previous_output)
+            .collect::<HashSet<_>>();
+        move |outpoint, height| {
+            if pending.contains(outpoint) {
+                return false;
+            }
+            let coinbase = self
+                .get_tx_body(&outpoint.txid)
+                .map_or(false, |body| body.tx.is_coin_base());
+            !coinbase || self.get_confirmations(height) >= coinbase_maturity
+        }
+    }
+    /// Commits to our whole wallet state: height, accumulator, and every address' balance
+    /// and history. Two servers that followed the same chain with the same wallet always
+    /// get the same commitment, so it can be used to check they agree.
+    pub fn get_wallet_commitment(&self) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        engine.input(WALLET_COMMITMENT_TAG);
+        engine.input(&self.height.to_le_bytes());
+        engine.input(&self.acc.leafs.to_le_bytes());
+        for root in self.acc.roots.iter() {
+            engine.input(&root[..]);
+        }
+
+        let mut addresses = self.address_map.values().collect::<Vec<_>>();
+        addresses.sort_unstable_by_key(|address| address.script_hash);
+        for address in addresses {
+            engine.input(&address.script_hash[..]);
+            engine.input(&address.balance.to_le_bytes());
+            let mut transactions = self.get_transactions(address);
+            engine.input(&(transactions.len() as u64).to_le_bytes());
+            transactions.sort_unstable_by_key(|tx| (tx.height, tx.position, tx.hash));
+            for transaction in transactions {
+                engine.input(&transaction.hash[..]);
+                engine.input(&transaction.height.to_le_bytes());
+                engine.input(&transaction.position.to_le_bytes());
+            }
+        }
+        sha256::Hash::from_engine(engine)
+    }
+    /// Returns a summary of each of our addresses
+    pub fn get_wallet_summary(
+        &self,
+        network: Network,
+        format: AddressFormat,
+    ) -> Vec<AddressSummary> {
+        self.address_map
+            .values()
+            .map(|address| {
+                let mut summary = address.summary(network, format);
+                summary.last_active_confirmations = summary
+                    .last_active_height
+                    .map_or(0, |height| self.get_confirmations(height));
+                summary
+            })
+            .collect()
+    }
+    /// Returns the balance of this address, debts (spends) are taken in account
+    pub fn get_address_balance(&self, script_hash: &sha256::Hash) -> u64 {
+        if let Some(cached_script) = self.address_map.get(script_hash) {
+            return cached_script.balance;
+        }
+
+        0
+    }
+    /// Returns the Merkle Proof for a given address
+    pub fn get_merkle_proof(&self, txid: &Txid) -> Option<(Vec<String>, u32)> {
+        let mut hashes = vec![];
+        if let (Some(tx), Some(body)) = (self.get_transaction(txid), self.get_tx_body(txid)) {
+            for hash in body.merkle_block.as_ref()?.txn.hashes() {
+                // Rust Bitcoin (and Bitcoin Core) includes the target hash, but Electrum
+                // doesn't like this.
+                if hash.as_hash() != txid.as_hash() {
+                    hashes.push(hash.to_hex());
+                }
+            }
+
+            return Some((hashes, tx.position));
+        }
+
+        None
+    }
+    pub fn get_height(&self, txid: &Txid) -> Option<u32> {
+        if let Some(tx) = self.get_transaction(txid) {
+            return Some(tx.height);
+        }
+
+        None
+    }
+    pub fn get_sync_limits(
+        &self,
+        current_hight: u32,
+    ) -> Result<RangeInclusive<u32>, crate::error::Error> {
+        let height = self.database.get_cache_height()?;
+        let end = current_hight.min(self.stop_height.unwrap_or(u32::MAX));
+        Ok((height + 1)..=end)
+    }
+    /// Whether `script` is one of our addresses
+    pub fn is_watched(&self, script: &Script) -> bool {
+        self.script_set.contains(script)
+    }
+    /// Starts watching a script, returning its entry. If we already watch it, the entry we
+    /// have is kept as is.
+    pub fn cache_address(
+        &mut self,
+        script_pk: Script,
+    ) -> Result<&CachedAddress, crate::error::Error> {
+        // Addresses we already watch don't grow our database
+        if self.disk.is_low() && !self.script_set.contains(&script_pk) {
+            return Err(crate::error::Error::LowDiskSpace);
+        }
+        self.add_address(script_pk)
+    }
+    /// Like [AddressCache::cache_address], even if our disk is almost full. Only for
+    /// addresses we found money in, which we can't skip
+    fn add_address(&mut self, script_pk: Script) -> Result<&CachedAddress, crate::error::Error> {
+        let hash = get_spk_hash(&script_pk);
+        if let Some(existing) = self.address_map.get(&hash) {
+            // Two scripts with the same hash would mix their histories up, so we never let
+            // that happen, no matter how unlikely it is
+            if existing.script != script_pk {
+                error!(
+                    "Scripts {} and {} have the same hash {hash}",
+                    existing.script.to_hex(),
+                    script_pk.to_hex()
+                );
+                return Err(crate::error::Error::ScriptHashCollision(hash));
+            }
+        } else {
+            let new_address = CachedAddress {
+                balance: 0,
+                script_hash: hash,
+                transactions: vec![],
+                script: script_pk.clone(),
+                first_seen_height: None,
+                last_active_height: None,
+                archived: ArchivedHistory::default(),
+            };
+            self.database.save(&new_address);
+            self.address_map.insert(hash, new_address);
+            self.script_set.insert(script_pk);
+        }
+        Ok(&self.address_map[&hash])
+    }
+    /// Setup is the first command that should be executed. In a new cache. It sets our wallet's
+    /// state, like the height we should start scanning and the wallet's descriptor.
+    pub fn setup(&self, descriptor: String) -> Result<(), crate::error::Error> {
+        self.database.set_cache_height(0)?;
+        self.database.desc_save(descriptor)
+    }
+    /// Returns the descriptor this wallet was set up with
+    pub fn get_descriptor(&self) -> Result<String, crate::error::Error> {
+        self.database.desc_get()
+    }
+    /// Caches a new transaction. It's added to the history of every address it pays to, or
+    /// spends from, and their balances are credited or debited accordingly. All affected
+    /// addresses are written to our database at once. Returns how much our wallet balance
+    /// changed. `merkle_block` must prove the transaction is at `position` in its block, or
+    /// nothing is cached.
+    pub fn cache_transaction(
+        &mut self,
+        transaction: &Transaction,
+        height: u32,
+        merkle_block: MerkleBlock,
+        position: u32,
+        prevouts: Vec<TxOut>,
+    ) -> Result<i64, crate::error::Error> {
+        if !proves_position(&merkle_block, &transaction.txid(), position) {
+            return Err(crate::error::Error::InvalidProof);
+        }
+        // How much each of our addresses gains (or loses) with this transaction
+        let mut deltas = HashMap::<Hash, i64>::new();
+        for input in transaction.input.iter() {
+            if let Some(prevout) = self.get_wallet_output(&input.previous_output) {
+                *deltas
+                    .entry(get_spk_hash(&prevout.script_pubkey))
+                    .or_default() -= prevout.value as i64;
+            }
+        }
+        for output in transaction.output.iter() {
+            if self.script_set.contains(&output.script_pubkey) {
+                *deltas
+                    .entry(get_spk_hash(&output.script_pubkey))
+                    .or_default() += output.value as i64;
+            }
+        }
+        if deltas.is_empty() {
+            return Ok(0);
+        }
+
+        let txid = transaction.txid();
         let transaction_to_cache = CachedTransaction {
             height,
-            merkle_block: Some(merkle_block),
-            tx_hex: serialize_hex(transaction),
-            hash: transaction.txid().to_string(),
+            hash: txid,
             position,
         };
-        let hash = get_spk_hash(&out.script_pubkey);
-        if let Some(address) = self.address_map.get_mut(&hash) {
-            if address.transactions.contains(&transaction_to_cache) {
-                return;
-            }
-            self.tx_index.insert(
-                transaction.txid(),
-                (address.script_hash, address.transactions.len()),
-            );
-            address.transactions.push(transaction_to_cache);
-            self.database.update(address);
-        } else {
-            // This means `cache_transaction` have been called with an address we don't
-            // follow. This may be useful for caching new addresses without re-scanning.
-            // We can track this address from now onwards, but the past history is only
-            // available with full rescan
-            let new_address = CachedAddress {
-                balance: 0,
-                script_hash: hash,
-                transactions: vec![transaction_to_cache],
-                script: out.script_pubkey.clone(),
+        let body = TransactionBody {
+            tx: transaction.clone(),
+            merkle_block: Some(merkle_block),
+            prevouts,
+        };
+        self.database
+            .save_tx_body(&txid, &body)
+            .expect("Database is not working");
+        self.tx_bodies.put(txid, Arc::new(body));
+        if let Some(fresh) = self.compacting.as_mut() {
+            fresh.insert(txid);
+        }
+
+        let mut updated = vec![];
+        let mut locations = vec![];
+        let mut balance_change = 0;
+        for (script_hash, delta) in deltas {
+            let address = match self.address_map.get_mut(&script_hash) {
+                Some(address) => address,
+                None => continue,
+            };
+            if address.transactions.contains(&transaction_to_cache) {
+                continue;
+            }
+            // Only transactions as old as our archive may be in it
+            let mut unarchived = false;
+            if address.archived.count > 0 && height <= address.archived.last_height {
+                let archived = self
+                    .database
+                    .archive_find(&script_hash, address.archived.count, &txid)
+                    .expect("Database is not working");
+                if archived.as_ref() == Some(&transaction_to_cache) {
+                    continue;
+                }
+                let archive = self
+                    .database
+                    .archive_load(&script_hash, address.archived.count)
+                    .expect("Database is not working");
+                // Status hashes would hash it after archived transactions it comes before,
+                // so we take the archive back, and archive again in the right order
+                info!("Unarchiving the history of {script_hash}, it got an older transaction");
+                address.transactions.splice(0..0, archive);
+                address.archived = ArchivedHistory::default();
+                unarchived = true;
+            }
+            locations.push((txid, script_hash));
+            self.statuses
+                .get_mut()
+                .expect("Poisoned lock")
+                .remove(&script_hash);
+            address.transactions.push(transaction_to_cache.clone());
+            if unarchived {
+                address
+                    .transactions
+                    .sort_by_key(|transaction| (transaction.height, transaction.position));
+            }
+            address.record_activity(height);
+            balance_change += delta;
+            address.balance = if delta >= 0 {
+                address.balance + delta as u64
+            } else {
+                address.balance.saturating_sub(delta.unsigned_abs())
+            };
+            let archived_count = address.archived.count;
+            if let Some(archived) = address.archive_oldest(self.max_hot_history) {
+                info!(
+                    "Archiving {} old transactions of {script_hash}",
+                    archived.len()
+                );
+                self.database
+                    .archive_append(&script_hash, archived_count, &archived)
+                    .expect("Database is not working");
+            }
+            updated.push(address.clone());
+        }
+        self.tx_index.insert(locations, &self.database);
+        if self.check_balances {
+            for address in updated.iter() {
+                let computed = self.compute_balance(address);
+                if computed != address.balance as i64 {
+                    warn!(
+                        "Balance of {} is {}, but its history says {computed}",
+                        address.script_hash, address.balance
+                    );
+                }
+            }
+        }
+        self.database.update_many(&updated);
+        Ok(balance_change)
+    }
+    /// Adds every transaction `other` found to our wallet, in the order they were mined, to
+    /// the history of each of our addresses it pays to or spends from. Addresses already
+    /// having it are skipped, so importing the same history twice changes nothing. Returns
+    /// how many transactions `other` found.
+    pub fn import_history<OD: AddressCacheDatabase, OS: ChainStore>(
+        &mut self,
+        other: &AddressCache<OD, OS>,
+    ) -> usize {
+        let mut transactions = other
+            .address_map
+            .values()
+            .flat_map(|address| other.get_transactions(address))
+            .collect::<Vec<_>>();
+        transactions.sort_by_key(|tx| (tx.height, tx.position));
+        transactions.dedup_by_key(|tx| tx.hash);
+        for transaction in transactions.iter() {
+            let body = match other.get_tx_body(&transaction.hash) {
+                Some(body) => body,
+                None => continue,
+            };
+            let merkle_block = match &body.merkle_block {
+                Some(merkle_block) => merkle_block.clone(),
+                None => continue,
+            };
+            if let Err(err) = self.cache_transaction(
+                &body.tx,
+                transaction.height,
+                merkle_block,
+                transaction.position,
+                body.prevouts.clone(),
+            ) {
+                warn!("Could not import {}: {err}", transaction.hash);
+            }
+        }
+        transactions.len()
+    }
+    /// Looks for transactions paying to, or spending from, our addresses in a block we've
+    /// already processed, and caches the ones we didn't have. For addresses we started
+    /// watching after this block, so blocks must be rescanned in order. Returns how many
+    /// transactions we found.
+    pub fn rescan_block(&mut self, block: &Block, height: u32) -> usize {
+        let mut block_txids = None;
+        let mut found = 0;
+        for (position, transaction) in block.txdata.iter().enumerate() {
+            let txid = transaction.txid();
+            let prevouts = transaction
+                .input
+                .iter()
+                .map(|input| self.get_wallet_output(&input.previous_output))
+                .collect::<Vec<_>>();
+            let ours = prevouts.iter().any(Option::is_some)
+                || transaction
+                    .output
+                    .iter()
+                    .any(|output| self.script_set.contains(&output.script_pubkey));
+            if !ours || self.get_height(&txid).is_some() {
+                continue;
+            }
+            let block_txids = block_txids.get_or_insert_with(|| {
+                block
+                    .txdata
+                    .iter()
+                    .map(Transaction::txid)
+                    .collect::<Vec<_>>()
+            });
+            let merkle_block =
+                MerkleBlock::from_header_txids_with_predicate(&block.header, block_txids, |id| {
+                    *id == txid
+                });
+            // Like an import, we only have the prevouts if every input is ours
+            let prevouts = prevouts
+                .into_iter()
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_default();
+            match self.cache_transaction(
+                transaction,
+                height,
+                merkle_block,
+                position as u32,
+                prevouts,
+            ) {
+                Ok(_) => found += 1,
+                Err(err) => error!("Could not cache {txid} at {height}, skipping it: {err}"),
+            }
+        }
+        found
+    }
+    /// Writes the history of some of our addresses in a portable form, proofs included, so
+    /// another server can serve them without a rescan. Addresses are shown in `format`.
+    pub fn export_wallet(
+        &self,
+        script_hashes: &[sha256::Hash],
+        network: Network,
+        format: AddressFormat,
+        with_roots: bool,
+    ) -> Result<WalletExport, crate::error::Error> {
+        let mut addresses = vec![];
+        let mut transactions = HashMap::new();
+        for script_hash in script_hashes {
+            let address = self
+                .address_map
+                .get(script_hash)
+                .ok_or(crate::error::Error::AddressNotFound(*script_hash))?;
+            for transaction in self.get_transactions(address).iter() {
+                if transactions.contains_key(&transaction.hash) {
+                    continue;
+                }
+                let body = self
+                    .get_tx_body(&transaction.hash)
+                    .ok_or(crate::error::Error::TxNotFound)?;
+                let merkle_block = body
+                    .merkle_block
+                    .as_ref()
+                    .ok_or(crate::error::Error::TxNotFound)?;
+                let roots = if with_roots {
+                    self.get_acc_at(transaction.height)
+                        .as_ref()
+                        .map(ExportedRoots::from)
+                } else {
+                    None
+                };
+                transactions.insert(
+                    transaction.hash,
+                    ExportedTransaction::new(
+                        transaction,
+                        &body,
+                        merkle_block,
+                        self.get_confirmations(transaction.height),
+                        roots,
+                    ),
+                );
+            }
+            let utxos = self
+                .get_address_utxos(script_hash)
+                .into_iter()
+                .map(|(outpoint, _, _)| outpoint)
+                .collect();
+            addresses.push(ExportedAddress {
+                script: address.script.clone(),
+                address: script_type::get_address(&address.script, network, format),
+                utxos,
+            });
+        }
+        let mut transactions = transactions.into_values().collect::<Vec<_>>();
+        transactions.sort_by_key(|transaction| (transaction.height, transaction.position));
+        Ok(WalletExport {
+            version: WALLET_EXPORT_VERSION,
+            network,
+            height: self.height,
+            addresses,
+            transactions,
+            roots: with_roots.then(|| ExportedRoots::from(&self.acc)),
+        })
+    }
+    /// Looks at which of `script_hashes`, the addresses `descriptor` derives starting at
+    /// `from`, were ever used, and signs what we found. Unconfirmed transactions count as use.
+    pub fn attest_unused(
+        &self,
+        descriptor: String,
+        from: u32,
+        script_hashes: &[sha256::Hash],
+        identity: &ServerIdentity,
+    ) -> Result<UnusedAttestation, crate::error::Error> {
+        // We can only vouch for the tip our accumulator is at
+        let (height, header) = self
+            .get_tip_header()
+            .ok_or(crate::error::Error::BlockNotFound)?;
+        if height != self.height {
+            return Err(crate::error::Error::BlockNotFound);
+        }
+        let block_hash = deserialize::<BlockHeader>(&Vec::from_hex(&header)?)?.block_hash();
+        let mut used = vec![];
+        let mut unwatched = vec![];
+        for (index, script_hash) in (from..).zip(script_hashes) {
+            if !self.address_map.contains_key(script_hash) {
+                unwatched.push(index);
+            } else if !self.get_full_history(script_hash).is_empty() {
+                used.push(index);
+            }
+        }
+        Ok(UnusedAttestation::new(
+            descriptor,
+            from,
+            from + script_hashes.len() as u32,
+            height,
+            block_hash,
+            ServerIdentity::tip_commitment(height, &block_hash, &self.acc),
+            used,
+            unwatched,
+            identity,
+        ))
+    }
+    /// How many addresses we watch
+    pub fn watched_count(&self) -> usize {
+        self.address_map.len()
+    }
+    /// The script hashes of every address we watch
+    pub fn get_watched_script_hashes(&self) -> HashSet<Hash> {
+        self.address_map.keys().copied().collect()
+    }
+    /// Writes a dump of our chain state to `path`. It's written next to it first, so a
+    /// crash halfway never leaves a broken dump behind.
+    pub fn snapshot_chainstate(&self, path: &Path) -> Result<u32, crate::error::Error> {
+        let dump = self.dump_chainstate();
+        let partial = path.with_extension("partial");
+        let mut file = std::fs::File::create(&partial)?;
+        serde_json::to_writer(&mut file, &dump)?;
+        // Otherwise a crash could leave the rename on disk, but not what it points to
+        file.sync_all()?;
+        std::fs::rename(partial, path)?;
+        // And the rename itself is only durable once its directory is synced
+        #[cfg(unix)]
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(dump.tip.height)
+    }
+    /// Starts watching the addresses in an export, with the history it has. Every proof is
+    /// checked before anything is added. The export must reach at least our height, or the
+    /// blocks in between would be missing from their history. Returns how many transactions
+    /// were imported.
+    pub fn import_wallet(
+        &mut self,
+        export: WalletExport,
+        network: Network,
+        block_hash: impl Fn(u32) -> Result<BlockHash, crate::error::Error>,
+    ) -> Result<usize, crate::error::Error> {
+        if !(1..=WALLET_EXPORT_VERSION).contains(&export.version) {
+            return Err(crate::error::Error::DbParseError);
+        }
+        if export.network != network {
+            return Err(crate::error::Error::WrongNetwork(export.network));
+        }
+        if export.height < self.database.get_cache_height()? {
+            return Err(crate::error::Error::StaleExport(export.height));
+        }
+        let mut transactions = export
+            .transactions
+            .iter()
+            .map(|transaction| {
+                let (tx, merkle_block) = transaction.decode()?;
+                Ok((transaction, tx, merkle_block))
+            })
+            .collect::<Result<Vec<_>, crate::error::Error>>()?;
+        transactions.sort_by_key(|(transaction, _, _)| (transaction.height, transaction.position));
+        // A proof only shows a transaction is in some block, which must be the one our node
+        // has at that height
+        let mut our_blocks = HashMap::<u32, BlockHash>::new();
+        for (transaction, _, merkle_block) in transactions.iter() {
+            let ours = match our_blocks.get(&transaction.height) {
+                Some(hash) => *hash,
+                None => {
+                    let hash = block_hash(transaction.height)?;
+                    our_blocks.insert(transaction.height, hash);
+                    hash
+                }
+            };
+            if merkle_block.header.block_hash() != ours {
+                return Err(crate::error::Error::NotInOurChain(transaction.height));
+            }
+        }
+        // Prevouts are whatever the export says, so we only keep the ones we can check
+        // against the transactions they spend. Like in blocks spending outputs we don't
+        // know, a transaction with any other input gets none
+        let outputs = transactions
+            .iter()
+            .map(|(_, tx, _)| (tx.txid(), &tx.output))
+            .collect::<HashMap<_, _>>();
+        for address in export.addresses.iter() {
+            self.cache_address(address.script.clone())?;
+        }
+        // Spends are only found if what they spend is already cached, so these go in the
+        // order they were mined
+        for (transaction, tx, merkle_block) in transactions.iter() {
+            let prevouts = tx
+                .input
+                .iter()
+                .map(|input| {
+                    outputs
+                        .get(&input.previous_output.txid)?
+                        .get(input.previous_output.vout as usize)
+                        .cloned()
+                })
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_default();
+            self.cache_transaction(
+                tx,
+                transaction.height,
+                merkle_block.clone(),
+                transaction.position,
+                prevouts,
+            )?;
+        }
+        for address in export.addresses.iter() {
+            let script_hash = get_spk_hash(&address.script);
+            let utxos = self
+                .get_address_utxos(&script_hash)
+                .into_iter()
+                .map(|(outpoint, _, _)| outpoint)
+                .collect::<HashSet<_>>();
+            if utxos != address.utxos.iter().copied().collect() {
+                warn!("Unspent outputs of {script_hash} don't match the export, its history may be incomplete");
+            }
+        }
+        Ok(transactions.len())
+    }
+}
+
+impl<D: AddressCacheDatabase, S: ChainStore> WalletView for AddressCache<D, S> {
+    fn is_wallet_script(&self, script: &Script) -> bool {
+        self.script_set.contains(script)
+    }
+    fn get_wallet_output(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        AddressCache::get_wallet_output(self, outpoint)
+    }
+    fn is_watched_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.watched_outpoints.contains_key(outpoint)
+    }
+    fn has_watched_outpoints(&self) -> bool {
+        !self.watched_outpoints.is_empty()
+    }
+    fn op_return_prefixes(&self) -> &[Vec<u8>] {
+        &self.op_return_prefixes
+    }
+}
+
+impl<S: ChainStore> AddressCache<KvDatabase, S> {
+    /// Starts looking for transaction bodies no address refers to anymore, on another
+    /// thread, so we keep serving meanwhile. What it finds is given to `found`, to be
+    /// dropped with [AddressCache::drop_orphaned_bodies]. Returns false if a compaction is
+    /// already running.
+    pub fn start_compaction(
+        &mut self,
+        found: impl FnOnce(Result<Vec<Txid>, crate::error::Error>) + Send + 'static,
+    ) -> bool {
+        if self.compacting.is_some() {
+            return false;
+        }
+        self.compacting = Some(HashSet::new());
+        let database = self.database.clone();
+        std::thread::spawn(move || found(database.find_orphaned_bodies()));
+        true
+    }
+    /// Drops the bodies a compaction found, except the ones we cached since it started.
+    /// Returns how many were dropped.
+    pub fn drop_orphaned_bodies(
+        &mut self,
+        orphaned: Vec<Txid>,
+    ) -> Result<usize, crate::error::Error> {
+        let fresh = self.compacting.take().unwrap_or_default();
+        let orphaned = orphaned
+            .into_iter()
+            .filter(|txid| !fresh.contains(txid))
+            .collect::<Vec<_>>();
+        self.database.drop_tx_bodies(&orphaned)?;
+        for txid in orphaned.iter() {
+            self.tx_bodies.pop(txid);
+        }
+        Ok(orphaned.len())
+    }
+    /// Like [AddressCache::get_full_history], but what's in memory is taken now and the
+    /// archive is read by the returned closure, so long histories can be loaded on another
+    /// thread
+    pub fn history_loader(
+        &self,
+        script_hash: &sha256::Hash,
+    ) -> impl FnOnce() -> Result<Vec<HistoryEntry>, crate::error::Error> + Send + 'static {
+        let (archived, recent) = match self.address_map.get(script_hash) {
+            Some(address) => (address.archived.count, address.transactions.clone()),
+            None => (0, vec![]),
+        };
+        let mempool = self.get_address_mempool(script_hash);
+        let database = self.database.clone();
+        let script_hash = *script_hash;
+        move || {
+            let mut confirmed = match archived {
+                0 => vec![],
+                count => database.archive_load(&script_hash, count)?,
             };
-            self.database.save(&new_address);
-
-            self.address_map.insert(hash, new_address);
-            self.script_set.insert(out.script_pubkey.clone());
+            confirmed.extend(recent);
+            confirmed.sort_by_key(|tx| (tx.height, tx.position));
+            let mut history = confirmed.iter().map(HistoryEntry::from).collect::<Vec<_>>();
+            history.extend(mempool);
+            Ok(history)
         }
     }
 }
-
 #[cfg(test)]
-mod test {
-    use super::{kv_database::KvDatabase, AddressCache};
-    use crate::{blockchain::chainstore::KvChainStore, electrum::electrum_protocol::get_spk_hash};
-    use bitcoin::{hashes::hex::FromHex, Script};
+pub(crate) mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        num::NonZeroUsize,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{
+        block_export::BlockExporter,
+        block_log::BLOCK_LOG_DEPTH,
+        event_log::LogEvent,
+        get_derivations,
+        kv_database::KvDatabase,
+        script_type::AddressFormat,
+        wallet_export::{
+            ExportedAddress, ExportedTransaction, WalletExport, WALLET_EXPORT_VERSION,
+        },
+        webhooks::{AlertTransport, Alerts, WalletEvent},
+        AddressCache, AddressCacheDatabase, HistoryEntry, JournalEntry, OutpointStatus,
+        TransactionBody, TxIndex, ROOTS_HISTORY_DEPTH,
+    };
+    use crate::{
+        blockchain::{
+            chain_params::BitcoinParams,
+            chainstore::{ChainStore, KvChainStore},
+            headers::HeaderStore,
+        },
+        disk::DiskSpace,
+        electrum::electrum_protocol::{extend_status, get_spk_hash, get_status},
+    };
+    use bitcoin::{
+        blockdata::constants::genesis_block,
+        consensus::encode::serialize_hex,
+        hashes::{
+            hex::{FromHex, ToHex},
+            sha256, Hash,
+        },
+        Block, BlockHeader, MerkleBlock, Network, OutPoint, PackedLockTime, Script, Transaction,
+        TxIn, TxOut,
+    };
+    use rustreexo::accumulator::{proof::Proof, stump::Stump};
+
+    const TEST_DB_CACHE: u64 = 64 * 1024 * 1024;
+
+    /// A regtest block with a transaction paying `value` to `script` after the genesis
+    /// coinbase, and a proof for that transaction
+    pub(crate) fn paying_block(script: &Script, value: u64) -> (Transaction, Block, MerkleBlock) {
+        let transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let (block, merkle_block) = mined_block(&transaction);
+        (transaction, block, merkle_block)
+    }
+    /// A regtest block with `transaction` after the genesis coinbase, and a proof for it
+    pub(crate) fn mined_block(transaction: &Transaction) -> (Block, MerkleBlock) {
+        let txid = transaction.txid();
+        let mut block = genesis_block(Network::Regtest);
+        block.txdata.push(transaction.clone());
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let merkle_block = MerkleBlock::from_block_with_predicate(&block, |id| *id == txid);
+        (block, merkle_block)
+    }
 
     #[test]
     fn test_create_cache() {
         // None of this should fail
-        let database = KvDatabase::new("/tmp/utreexo/".into()).unwrap();
+        let database = KvDatabase::new("/tmp/utreexo/".into(), TEST_DB_CACHE).unwrap();
         let chain_store = KvChainStore::new("/tmp/utreexo/".to_owned()).unwrap();
         let _ = AddressCache::new(database, chain_store);
     }
     #[test]
     fn cache_address() {
-        let database = KvDatabase::new("/tmp/utreexo/".into()).unwrap();
+        let database = KvDatabase::new("/tmp/utreexo/".into(), TEST_DB_CACHE).unwrap();
         let chain_store = KvChainStore::new("/tmp/utreexo/".to_owned()).unwrap();
 
         let mut cache = AddressCache::new(database, chain_store);
         let script_pk = Script::from_hex("00").unwrap();
         let hash = &get_spk_hash(&script_pk);
 
-        cache.cache_address(script_pk);
+        cache.cache_address(script_pk).unwrap();
         assert_eq!(cache.address_map.len(), 1);
         assert_eq!(cache.get_address_balance(hash), 0);
     }
     #[test]
+    fn test_duplicate_address() {
+        let database = KvDatabase::new("/tmp/utreexo_duplicate/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_duplicate/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        // The example from Electrum's protocol docs
+        let script =
+            Script::from_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap();
+        let hash = get_spk_hash(&script);
+        assert_eq!(
+            hash.to_string(),
+            "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161"
+        );
+
+        cache.cache_address(script.clone()).unwrap();
+        cache.address_map.get_mut(&hash).unwrap().balance = 1_000;
+        // Watching it again must not reset what we know about it
+        let address = cache.cache_address(script).unwrap();
+        assert_eq!(address.balance, 1_000);
+        assert_eq!(cache.address_map.len(), 1);
+
+        // A different script under the same hash is refused
+        cache.address_map.get_mut(&hash).unwrap().script = Script::from_hex("00").unwrap();
+        let script =
+            Script::from_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap();
+        assert!(cache.cache_address(script).is_err());
+    }
+    #[test]
     fn test_persistency() {
         {
-            let database = KvDatabase::new("/tmp/utreexo/".into()).unwrap();
+            let database = KvDatabase::new("/tmp/utreexo/".into(), TEST_DB_CACHE).unwrap();
             let chain_store = KvChainStore::new("/tmp/utreexo/".to_owned()).unwrap();
 
             let mut cache = AddressCache::new(database, chain_store);
             let script_pk = Script::from_hex("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac").unwrap();
-            cache.cache_address(script_pk);
+            cache.cache_address(script_pk).unwrap();
         }
-        let database = KvDatabase::new("/tmp/utreexo/".into()).unwrap();
+        let database = KvDatabase::new("/tmp/utreexo/".into(), TEST_DB_CACHE).unwrap();
         let chain_store = KvChainStore::new("/tmp/utreexo/".to_owned()).unwrap();
 
         let cache = AddressCache::new(database, chain_store);
         assert_eq!(cache.script_set.len(), 1);
     }
+    #[test]
+    fn test_inconsistent_acc_rolls_back() {
+        {
+            let database =
+                KvDatabase::new("/tmp/utreexo_consistency/".into(), TEST_DB_CACHE).unwrap();
+            let chain_store = KvChainStore::new("/tmp/utreexo_consistency/".to_owned()).unwrap();
+            // We claim to be at height 10 with 5 leaves, but our accumulator is empty
+            database.set_cache_height(10).unwrap();
+            chain_store.save_leaf_count(10, 5).unwrap();
+        }
+        let database = KvDatabase::new("/tmp/utreexo_consistency/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_consistency/".to_owned()).unwrap();
+
+        let cache = AddressCache::new(database, chain_store);
+        assert_eq!(cache.get_cache_height().unwrap(), 0);
+    }
+    #[test]
+    fn test_legacy_acc() {
+        let dir = "/tmp/utreexo_legacy_acc/";
+        let _ = std::fs::remove_dir_all(dir);
+        let roots = vec![sha256::Hash::hash(b"first"), sha256::Hash::hash(b"second")];
+        {
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            // Older versions wrote the leaf count, then every root in hex, back to back
+            chain_store
+                .save_roots(format!("3 {}{}", roots[0], roots[1]))
+                .unwrap();
+            chain_store.flush().unwrap();
+        }
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let cache = AddressCache::new(database, chain_store);
+        assert_eq!(cache.get_acc().leafs, 3);
+        assert_eq!(cache.get_acc().roots, roots);
+        // It's saved as a record from then on
+        cache.save_acc();
+        let stored = cache.chain_store.load_roots().unwrap().unwrap();
+        assert!(stored.starts_with('{'));
+        let acc = AddressCache::<KvDatabase, KvChainStore>::deserialize_acc(&stored);
+        assert_eq!(acc.leafs, 3);
+        assert_eq!(acc.roots, roots);
+    }
+    #[test]
+    fn test_acc_snapshots() {
+        let dir = "/tmp/utreexo_acc_snapshots/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let state = |acc: Option<Stump>| acc.map(|acc| (acc.leafs, acc.roots));
+        let first = vec![sha256::Hash::hash(b"first")];
+        let second = vec![sha256::Hash::hash(b"second")];
+        cache.acc = Stump {
+            leafs: 1,
+            roots: first.clone(),
+        };
+        cache.save_acc_at(1);
+        cache.acc = Stump {
+            leafs: 2,
+            roots: second.clone(),
+        };
+        cache.save_acc_at(2);
+        assert_eq!(state(cache.get_acc_at(1)), Some((1, first)));
+        assert_eq!(state(cache.get_acc_at(2)), Some((2, second)));
+        assert!(cache.get_acc_at(3).is_none());
+
+        // Snapshots past ROOTS_HISTORY_DEPTH are dropped as we go
+        cache.save_acc_at(ROOTS_HISTORY_DEPTH + 1);
+        assert!(cache.get_acc_at(1).is_none());
+        assert!(cache.get_acc_at(2).is_some());
+    }
+    #[test]
+    fn test_expire_broadcasts() {
+        let transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![TxOut::default()],
+        };
+        let txid = transaction.txid();
+        // Old entries only have the transaction
+        let legacy = JournalEntry::try_from(serialize_hex(&transaction)).unwrap();
+        assert_eq!(legacy.transaction, transaction);
+        assert!(!legacy.dropped);
+
+        let database = KvDatabase::new("/tmp/utreexo_expire/".into(), TEST_DB_CACHE).unwrap();
+        database
+            .journal_save(&JournalEntry {
+                transaction: transaction.clone(),
+                broadcast_at: 0,
+                dropped: false,
+            })
+            .unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_expire/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let dropped = cache.expire_broadcasts(Duration::from_secs(14 * 24 * 60 * 60));
+        assert_eq!(dropped, vec![transaction.clone()]);
+        assert_eq!(cache.get_unconfirmed_broadcasts().count(), 0);
+        assert_eq!(
+            cache.get_broadcast(&txid),
+            Some((transaction.clone(), true))
+        );
+
+        // Broadcasting it again makes it pending, with a fresh deadline
+        cache.journal_broadcast(transaction.clone());
+        assert!(cache.expire_broadcasts(Duration::from_secs(60)).is_empty());
+        assert_eq!(cache.get_broadcast(&txid), Some((transaction, false)));
+    }
+    #[test]
+    fn test_broadcast_journal() {
+        let dir = "/tmp/utreexo_journal/";
+        let _ = std::fs::remove_dir_all(dir);
+        let spending = |txid, vout| Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(txid, vout),
+                ..TxIn::default()
+            }],
+            output: vec![TxOut::default()],
+        };
+        let (funding, ..) = paying_block(&Script::new(), 2);
+        let funding = funding.txid();
+        let confirmed = spending(funding, 0);
+        let conflicted = spending(funding, 1);
+        {
+            let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            let mut cache = AddressCache::new(database, chain_store);
+            cache.journal_broadcast(confirmed.clone());
+            cache.journal_broadcast(conflicted.clone());
+        }
+        // The journal survives a restart, so we keep rebroadcasting
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let mut pending = cache
+            .get_unconfirmed_broadcasts()
+            .cloned()
+            .collect::<Vec<_>>();
+        pending.sort_by_key(|transaction| transaction.txid());
+        let mut expected = vec![confirmed.clone(), conflicted.clone()];
+        expected.sort_by_key(|transaction| transaction.txid());
+        assert_eq!(pending, expected);
+
+        // A block confirming one and double spending the other finishes both
+        let double_spend = Transaction {
+            output: vec![TxOut {
+                value: 1,
+                ..TxOut::default()
+            }],
+            ..conflicted.clone()
+        };
+        let mut block = genesis_block(Network::Regtest);
+        block.txdata.push(confirmed.clone());
+        block.txdata.push(double_spend);
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        cache
+            .block_process(
+                &block,
+                1,
+                Proof::new(vec![], vec![]),
+                vec![],
+                &HashMap::new(),
+            )
+            .unwrap();
+        assert_eq!(cache.get_unconfirmed_broadcasts().count(), 0);
+        assert!(cache.get_broadcast(&conflicted.txid()).is_none());
+        assert!(cache.database.journal_load().unwrap().is_empty());
+    }
+    #[test]
+    fn test_lazy_tx_bodies() {
+        let dir = "/tmp/utreexo_lazy_bodies/";
+        let _ = std::fs::remove_dir_all(dir);
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let (transaction, _, merkle_block) = paying_block(&script, 1_000);
+        let txid = transaction.txid();
+        {
+            let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            let mut cache = AddressCache::new(database, chain_store);
+            cache.cache_address(script).unwrap();
+            cache
+                .cache_transaction(&transaction, 1, merkle_block, 1, vec![])
+                .unwrap();
+        }
+        // Bodies stay on disk until someone asks for them
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        cache.set_tx_cache_size(NonZeroUsize::new(1).unwrap());
+        assert!(cache.tx_bodies.get(&txid).is_none());
+        assert_eq!(cache.get_tx_body(&txid).unwrap().tx, transaction);
+        assert!(cache.tx_bodies.get(&txid).is_some());
+
+        // And are loaded again once evicted
+        cache.tx_bodies.pop(&txid);
+        assert_eq!(cache.get_tx_body(&txid).unwrap().tx, transaction);
+        assert!(cache.get_tx_body(&bitcoin::Txid::all_zeros()).is_none());
+    }
+    #[test]
+    fn test_disk_tx_index() {
+        let dir = "/tmp/utreexo_disk_tx_index/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        let (first, _, merkle_block) = paying_block(&script, 1_000);
+        cache
+            .cache_transaction(&first, 1, merkle_block, 1, vec![])
+            .unwrap();
+
+        // What we had in memory moves to the database
+        cache
+            .use_disk_tx_index(NonZeroUsize::new(1).unwrap())
+            .unwrap();
+        assert!(matches!(cache.tx_index, TxIndex::Disk(_)));
+        assert_eq!(
+            cache.database.tx_index_load(&first.txid()).unwrap(),
+            Some(hash)
+        );
+        // And what we find later goes straight there
+        let (second, _, merkle_block) = paying_block(&script, 2_000);
+        cache
+            .cache_transaction(&second, 2, merkle_block, 1, vec![])
+            .unwrap();
+        assert_eq!(
+            cache.database.tx_index_load(&second.txid()).unwrap(),
+            Some(hash)
+        );
+        // Entries we don't have in memory are read back from disk
+        if let TxIndex::Disk(hot) = &cache.tx_index {
+            hot.pop(&first.txid());
+        }
+        assert_eq!(cache.get_height(&first.txid()), Some(1));
+        assert_eq!(cache.get_height(&second.txid()), Some(2));
+    }
+    #[test]
+    fn test_watch_outpoint() {
+        let dir = "/tmp/utreexo_watch_outpoint/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let process =
+            |cache: &mut AddressCache<KvDatabase, KvChainStore>, block: &Block, height| {
+                cache
+                    .block_process(
+                        block,
+                        height,
+                        Proof::new(vec![], vec![]),
+                        vec![],
+                        &HashMap::new(),
+                    )
+                    .unwrap();
+            };
+
+        // Outpoints don't need to be ours
+        let script = Script::from_hex("0014000000000000000000000000000000000000000a").unwrap();
+        let (funding, block, _) = paying_block(&script, 1_000);
+        let outpoint = OutPoint::new(funding.txid(), 0);
+        assert!(cache.watch_outpoint(outpoint));
+        assert_eq!(
+            cache.get_outpoint_status(&outpoint),
+            Some(OutpointStatus::default())
+        );
+        process(&mut cache, &block, 1);
+        assert_eq!(cache.take_changed_outpoints(), vec![outpoint]);
+        assert_eq!(
+            cache.get_outpoint_status(&outpoint).unwrap().height,
+            Some(1)
+        );
+
+        // Spends we've broadcast show up before they confirm, at height zero
+        let spender = Transaction {
+            input: vec![TxIn {
+                previous_output: outpoint,
+                ..TxIn::default()
+            }],
+            ..funding
+        };
+        let txid = spender.txid();
+        cache.journal_broadcast(spender.clone());
+        assert_eq!(
+            cache.get_outpoint_status(&outpoint).unwrap().spender,
+            Some((txid, 0))
+        );
+        let (block, _) = mined_block(&spender);
+        process(&mut cache, &block, 2);
+        assert_eq!(cache.take_changed_outpoints(), vec![outpoint]);
+        assert_eq!(
+            cache.get_outpoint_status(&outpoint),
+            Some(OutpointStatus {
+                height: Some(1),
+                spender: Some((txid, 2)),
+            })
+        );
+
+        cache.unwatch_outpoint(&outpoint);
+        assert!(cache.get_outpoint_status(&outpoint).is_none());
+    }
+    #[test]
+    fn test_wallet_commitment() {
+        let open = |dir: &str| {
+            let _ = std::fs::remove_dir_all(dir);
+            let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            AddressCache::new(database, chain_store)
+        };
+        let first = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let second = Script::from_hex("0014000000000000000000000000000000000000000a").unwrap();
+        let (transaction, _, merkle_block) = paying_block(&first, 1_000);
+
+        // The order we learn about addresses in doesn't matter
+        let mut ours = open("/tmp/utreexo_commitment_ours/");
+        ours.cache_address(first.clone()).unwrap();
+        ours.cache_address(second.clone()).unwrap();
+        let mut theirs = open("/tmp/utreexo_commitment_theirs/");
+        theirs.cache_address(second).unwrap();
+        theirs.cache_address(first.clone()).unwrap();
+        assert_eq!(ours.get_wallet_commitment(), theirs.get_wallet_commitment());
+
+        // But what we know about them does
+        ours.cache_transaction(&transaction, 1, merkle_block.clone(), 1, vec![])
+            .unwrap();
+        assert_ne!(ours.get_wallet_commitment(), theirs.get_wallet_commitment());
+        theirs
+            .cache_transaction(&transaction, 1, merkle_block, 1, vec![])
+            .unwrap();
+        assert_eq!(ours.get_wallet_commitment(), theirs.get_wallet_commitment());
+        theirs.bump_height(2);
+        assert_ne!(ours.get_wallet_commitment(), theirs.get_wallet_commitment());
+    }
+    #[test]
+    fn test_pay_to_many() {
+        let database = KvDatabase::new("/tmp/utreexo_pay_to_many/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_pay_to_many/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let first = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let second = Script::from_hex("0014000000000000000000000000000000000000000a").unwrap();
+        cache.cache_address(first.clone()).unwrap();
+        cache.cache_address(second.clone()).unwrap();
+
+        // A single transaction paying to both addresses should credit both
+        let transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![
+                TxOut {
+                    value: 1_000,
+                    script_pubkey: first.clone(),
+                },
+                TxOut {
+                    value: 2_000,
+                    script_pubkey: second.clone(),
+                },
+            ],
+        };
+        let (_, merkle_block) = mined_block(&transaction);
+        cache
+            .cache_transaction(&transaction, 1, merkle_block, 1, vec![])
+            .unwrap();
+
+        assert_eq!(cache.get_address_balance(&get_spk_hash(&first)), 1_000);
+        assert_eq!(cache.get_address_balance(&get_spk_hash(&second)), 2_000);
+        assert_eq!(cache.get_address_history(&get_spk_hash(&second)).len(), 1);
+    }
+    #[test]
+    fn test_address_activity() {
+        let database = KvDatabase::new("/tmp/utreexo_activity/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_activity/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        cache.cache_address(script.clone()).unwrap();
+        for (height, value) in [(9, 1_000), (5, 2_000)] {
+            let (transaction, _, merkle_block) = paying_block(&script, value);
+            cache
+                .cache_transaction(&transaction, height, merkle_block, 1, vec![])
+                .unwrap();
+        }
+
+        // Activity heights must survive a round trip through our database
+        let addresses = cache.database.load::<crate::error::Error>().unwrap();
+        let address = addresses
+            .iter()
+            .find(|address| address.script == script)
+            .unwrap();
+        assert_eq!(address.first_seen_height, Some(5));
+        assert_eq!(address.last_active_height, Some(9));
+    }
+    #[test]
+    fn test_recompute_balances() {
+        let database = KvDatabase::new("/tmp/utreexo_recompute/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_recompute/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        let (transaction, _, merkle_block) = paying_block(&script, 1_000);
+        let txid = transaction.txid();
+        cache
+            .cache_transaction(&transaction, 1, merkle_block, 1, vec![])
+            .unwrap();
+        assert!(cache.recompute_balances(true).is_empty());
+
+        cache.address_map.get_mut(&hash).unwrap().balance = 42;
+        let discrepancies = cache.recompute_balances(false);
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(discrepancies[0].computed, 1_000);
+        assert_eq!(cache.get_address_balance(&hash), 1_000);
+
+        // Without its index entry, the transaction's output is missing from the unspent ones
+        if let TxIndex::Memory(index) = &mut cache.tx_index {
+            index.remove(&txid);
+        }
+        assert!(cache.get_address_utxos(&hash).is_empty());
+        let discrepancies = cache.recompute_balances(false);
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(discrepancies[0].stored_utxos, 0);
+        assert_eq!(discrepancies[0].computed_utxos, 1);
+        assert_eq!(discrepancies[0].unindexed, vec![txid]);
+        assert_eq!(cache.get_address_utxos(&hash).len(), 1);
+
+        // Spending it elsewhere leaves nothing, which its history agrees with...
+        let spend = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(txid, 0),
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: 400,
+                script_pubkey: Script::from_hex("00").unwrap(),
+            }],
+        };
+        let (_, merkle_block) = mined_block(&spend);
+        cache
+            .cache_transaction(&spend, 2, merkle_block, 1, vec![])
+            .unwrap();
+        assert!(cache.recompute_balances(true).is_empty());
+        // ...until the payment goes missing from it, and it spends more than it got
+        cache
+            .address_map
+            .get_mut(&hash)
+            .unwrap()
+            .transactions
+            .retain(|transaction| transaction.hash != txid);
+        let discrepancies = cache.recompute_balances(true);
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(discrepancies[0].history, -1_000);
+        assert_eq!(discrepancies[0].computed, 0);
+    }
+    #[test]
+    fn test_wrong_position() {
+        let database = KvDatabase::new("/tmp/utreexo_position/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_position/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        let (transaction, block, merkle_block) = paying_block(&script, 1_000);
+        let txid = transaction.txid();
+        let mut stale = block;
+        stale.header.merkle_root = genesis_block(Network::Regtest).header.merkle_root;
+        let stale = MerkleBlock::from_block_with_predicate(&stale, |id| *id == txid);
+        // The header still commits to the genesis coinbase only
+        assert!(cache
+            .cache_transaction(&transaction, 1, stale, 1, vec![])
+            .is_err());
+
+        assert!(cache
+            .cache_transaction(&transaction, 1, merkle_block.clone(), 0, vec![])
+            .is_err());
+        assert!(cache.get_address_history(&hash).is_empty());
+
+        cache
+            .cache_transaction(&transaction, 1, merkle_block, 1, vec![])
+            .unwrap();
+        assert_eq!(cache.get_address_history(&hash)[0].position, 1);
+    }
+    #[test]
+    fn test_block_process_all_or_nothing() {
+        let _ = std::fs::remove_dir_all("/tmp/utreexo_block_process/");
+        let database =
+            KvDatabase::new("/tmp/utreexo_block_process/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_block_process/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        let (_, block, _) = paying_block(&script, 1_000);
+        let process = |cache: &mut AddressCache<KvDatabase, KvChainStore>, block: &Block| {
+            cache.block_process(
+                block,
+                1,
+                Proof::new(vec![], vec![]),
+                vec![],
+                &HashMap::new(),
+            )
+        };
+
+        // Our transaction isn't in the merkle root, so its proof wouldn't prove anything
+        let mut stale = block.clone();
+        stale.header.merkle_root = genesis_block(Network::Regtest).header.merkle_root;
+        let leaves = cache.get_acc().leafs;
+        assert!(process(&mut cache, &stale).is_err());
+        assert!(cache.get_address_history(&hash).is_empty());
+        assert_eq!(cache.get_acc().leafs, leaves);
+        // Nor would it with a transaction in there twice
+        let mut duplicated = block.clone();
+        duplicated.txdata.push(duplicated.txdata[1].clone());
+        duplicated.header.merkle_root = duplicated.compute_merkle_root().unwrap();
+        assert!(process(&mut cache, &duplicated).is_err());
+        assert_eq!(cache.get_acc().leafs, leaves);
+
+        // So the same block, once we get it right, is applied
+        assert_eq!(process(&mut cache, &block).unwrap().len(), 1);
+        assert_eq!(cache.get_address_balance(&hash), 1_000);
+        assert_eq!(cache.get_address_history(&hash)[0].position, 1);
+        assert_eq!(cache.get_acc().leafs, leaves + 2);
+    }
+    #[test]
+    fn test_balance_history() {
+        let _ = std::fs::remove_dir_all("/tmp/utreexo_balance_history/");
+        let database =
+            KvDatabase::new("/tmp/utreexo_balance_history/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_balance_history/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        cache.cache_address(script.clone()).unwrap();
+        let other = Script::from_hex("0014000000000000000000000000000000000000000a").unwrap();
+        let blocks = [
+            (1, paying_block(&script, 1_000).1),
+            // Doesn't change our balance, so there's no checkpoint for it
+            (2, paying_block(&other, 5_000).1),
+            (3, paying_block(&script, 2_000).1),
+        ];
+        for (height, block) in blocks {
+            cache
+                .block_process(
+                    &block,
+                    height,
+                    Proof::new(vec![], vec![]),
+                    vec![],
+                    &HashMap::new(),
+                )
+                .unwrap();
+        }
+        let checkpoints = |cache: &AddressCache<KvDatabase, KvChainStore>, from, to| {
+            cache
+                .get_balance_history(from, to)
+                .into_iter()
+                .map(|checkpoint| (checkpoint.height, checkpoint.balance))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(checkpoints(&cache, 0, 10), vec![(1, 1_000), (3, 3_000)]);
+        assert_eq!(checkpoints(&cache, 2, 3), vec![(3, 3_000)]);
+        assert!(checkpoints(&cache, 3, 2).is_empty());
+
+        // Blocks after where we go back to will be processed again
+        let acc = cache.get_acc().clone();
+        cache.reset_to(2, acc);
+        assert_eq!(checkpoints(&cache, 0, 10), vec![(1, 1_000)]);
+    }
+    #[test]
+    fn test_alerts_skip_catching_up() {
+        struct Recorder(Arc<Mutex<Vec<u64>>>);
+        impl AlertTransport for Recorder {
+            fn name(&self) -> String {
+                "recorder".into()
+            }
+            fn send(&self, event: &WalletEvent) -> Result<(), String> {
+                if let WalletEvent::Received { value, .. } = event {
+                    self.0.lock().unwrap().push(*value);
+                }
+                Ok(())
+            }
+        }
+        let _ = std::fs::remove_dir_all("/tmp/utreexo_catching_up/");
+        let database = KvDatabase::new("/tmp/utreexo_catching_up/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_catching_up/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let sent = Arc::new(Mutex::new(vec![]));
+        cache.set_alerts(Alerts::new(vec![Box::new(Recorder(sent.clone()))]));
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        cache.cache_address(script.clone()).unwrap();
+        let process = |cache: &mut AddressCache<KvDatabase, KvChainStore>, block: Block| {
+            cache
+                .block_process(
+                    &block,
+                    1,
+                    Proof::new(vec![], vec![]),
+                    vec![],
+                    &HashMap::new(),
+                )
+                .unwrap();
+        };
+        // An old payment, found while catching up, isn't news
+        cache.set_catching_up(true);
+        process(&mut cache, paying_block(&script, 1_000).1);
+        cache.set_catching_up(false);
+        process(&mut cache, paying_block(&script, 2_000).1);
+        // Alerts are sent from another thread, in order
+        for _ in 0..50 {
+            if !sent.lock().unwrap().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(*sent.lock().unwrap(), vec![2_000]);
+    }
+    #[test]
+    fn test_block_log() {
+        let dir = "/tmp/utreexo_block_log/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        cache.cache_address(script.clone()).unwrap();
+        let process = |cache: &mut AddressCache<KvDatabase, KvChainStore>, value, height| {
+            let (_, block, _) = paying_block(&script, value);
+            cache
+                .block_process(
+                    &block,
+                    height,
+                    Proof::new(vec![], vec![]),
+                    vec![],
+                    &HashMap::new(),
+                )
+                .unwrap();
+        };
+        // Blocks of our initial sync aren't logged
+        cache.set_catching_up(true);
+        process(&mut cache, 1_000, 1);
+        assert!(cache.get_block_log(1).is_none());
+        cache.set_catching_up(false);
+        process(&mut cache, 2_000, 2);
+        assert_eq!(cache.get_block_log(2).unwrap().transactions, 1);
+        // Blocks past our depth go, even if we never logged the one right at it
+        process(&mut cache, 3_000, BLOCK_LOG_DEPTH + 10);
+        assert!(cache.get_block_log(2).is_none());
+        assert!(cache.get_block_log(BLOCK_LOG_DEPTH + 10).is_some());
+    }
+    #[test]
+    fn test_compaction_keeps_fresh_bodies() {
+        let dir = "/tmp/utreexo_compaction/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        cache.cache_address(script.clone()).unwrap();
+        // A body left behind, that no address refers to
+        let (stale, _, merkle_block) = paying_block(&Script::new(), 1_000);
+        let body = TransactionBody {
+            tx: stale.clone(),
+            merkle_block: Some(merkle_block),
+            prevouts: vec![],
+        };
+        cache.database.save_tx_body(&stale.txid(), &body).unwrap();
+
+        let (found_tx, found_rx) = std::sync::mpsc::channel();
+        assert!(cache.start_compaction(move |found| found_tx.send(found).unwrap()));
+        assert!(!cache.start_compaction(|_| {}));
+        // We keep serving meanwhile
+        let (fresh, _, merkle_block) = paying_block(&script, 2_000);
+        cache
+            .cache_transaction(&fresh, 1, merkle_block, 1, vec![])
+            .unwrap();
+        let mut orphaned = found_rx
+            .recv_timeout(Duration::from_secs(30))
+            .unwrap()
+            .unwrap();
+        assert!(orphaned.contains(&stale.txid()));
+        // Depending on when it read them, our compaction may have seen the fresh body
+        // before the address referring to it
+        orphaned.push(fresh.txid());
+        assert_eq!(cache.drop_orphaned_bodies(orphaned).unwrap(), 1);
+        assert!(cache
+            .database
+            .load_tx_body(&stale.txid())
+            .unwrap()
+            .is_none());
+        assert!(cache.get_tx_body(&fresh.txid()).is_some());
+    }
+    #[test]
+    fn test_block_process_events() {
+        let _ = std::fs::remove_dir_all("/tmp/utreexo_block_events/");
+        let database = KvDatabase::new("/tmp/utreexo_block_events/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_block_events/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        // Our block log is paused, but clients still get every event
+        cache.set_disk_space(DiskSpace::fixed(true));
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        let process = |cache: &mut AddressCache<KvDatabase, KvChainStore>, block: &Block| {
+            cache
+                .block_process(
+                    block,
+                    1,
+                    Proof::new(vec![], vec![]),
+                    vec![],
+                    &HashMap::new(),
+                )
+                .unwrap();
+        };
+        let (transaction, block, _) = paying_block(&script, 1_000);
+        process(&mut cache, &block);
+        let events = cache
+            .get_event_log()
+            .since(0)
+            .unwrap()
+            .map(|logged| logged.event.clone())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            events.as_slice(),
+            [LogEvent::TxAdded { txid, height: 1, script_hashes }]
+                if *txid == transaction.txid() && *script_hashes == vec![hash]
+        ));
+
+        // A block replacing it is announced before anything it brings
+        let (replacing_tx, replacing, _) = paying_block(&script, 2_000);
+        process(&mut cache, &replacing);
+        let events = cache
+            .get_event_log()
+            .since(1)
+            .unwrap()
+            .map(|logged| logged.event.clone())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                LogEvent::Reorg { height: 1, block_hash, replaced },
+                LogEvent::TxAdded { txid, .. },
+            ] if *block_hash == replacing.block_hash()
+                && *replaced == block.block_hash()
+                && *txid == replacing_tx.txid()
+        ));
+    }
+    #[test]
+    fn test_low_disk_block_log() {
+        let dir = "/tmp/utreexo_low_disk_log/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        cache.set_disk_space(DiskSpace::fixed(true));
+        let ours = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let theirs = Script::from_hex("00").unwrap();
+        cache.cache_address(ours.clone()).unwrap();
+        let process =
+            |cache: &mut AddressCache<KvDatabase, KvChainStore>, script: &Script, height| {
+                let (_, block, _) = paying_block(script, 1_000);
+                cache
+                    .block_process(
+                        &block,
+                        height,
+                        Proof::new(vec![], vec![]),
+                        vec![],
+                        &HashMap::new(),
+                    )
+                    .unwrap();
+                block.block_hash()
+            };
+        // Blocks that don't concern us can wait for more room
+        process(&mut cache, &theirs, 1);
+        assert!(cache.get_block_log(1).is_none());
+        let paying = process(&mut cache, &ours, 2);
+        assert_eq!(cache.get_block_log(2).unwrap().transactions, 1);
+        // A reorg is logged even if the new block has nothing of ours
+        let replacing = process(&mut cache, &theirs, 2);
+        let entry = cache.get_block_log(2).unwrap();
+        assert_eq!(entry.block_hash, replacing);
+        assert_eq!(entry.replaced, Some(paying));
+    }
+    #[test]
+    fn test_spendable() {
+        let _ = std::fs::remove_dir_all("/tmp/utreexo_spendable/");
+        let database = KvDatabase::new("/tmp/utreexo_spendable/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_spendable/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        // paying_block's transaction has no inputs to speak of, so it's a coinbase
+        let (coinbase, _, merkle_block) = paying_block(&script, 1_000);
+        cache
+            .cache_transaction(&coinbase, 1, merkle_block, 1, vec![])
+            .unwrap();
+        let payment = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint::new(coinbase.txid(), 7),
+                ..TxIn::default()
+            }],
+            ..coinbase.clone()
+        };
+        let (_, merkle_block) = mined_block(&payment);
+        cache
+            .cache_transaction(&payment, 2, merkle_block, 1, vec![])
+            .unwrap();
+        cache.bump_height(2);
+        let spendable = |cache: &AddressCache<KvDatabase, KvChainStore>| {
+            let spendable = cache.spendable(100);
+            cache
+                .get_address_utxos(&hash)
+                .into_iter()
+                .filter(|(outpoint, _, height)| spendable(outpoint, *height))
+                .map(|(outpoint, ..)| outpoint.txid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(spendable(&cache), vec![payment.txid()]);
+
+        // Spending it in a transaction we broadcast takes it
+        cache.journal_broadcast(Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint::new(payment.txid(), 0),
+                ..TxIn::default()
+            }],
+            ..coinbase.clone()
+        });
+        assert!(spendable(&cache).is_empty());
+        assert_eq!(cache.get_address_utxos(&hash).len(), 2);
+
+        // And the coinbase matures 100 blocks in
+        cache.bump_height(100);
+        assert_eq!(spendable(&cache), vec![coinbase.txid()]);
+    }
+    #[test]
+    fn test_address_mempool() {
+        let _ = std::fs::remove_dir_all("/tmp/utreexo_address_mempool/");
+        let database =
+            KvDatabase::new("/tmp/utreexo_address_mempool/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_address_mempool/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        let (funding, _, merkle_block) = paying_block(&script, 1_000);
+        cache
+            .cache_transaction(&funding, 1, merkle_block, 1, vec![])
+            .unwrap();
+        let spending = |outpoint: OutPoint, value: u64| Transaction {
+            input: vec![TxIn {
+                previous_output: outpoint,
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script.clone(),
+            }],
+            ..funding.clone()
+        };
+        let payment = spending(OutPoint::new(funding.txid(), 0), 900);
+        let child = spending(OutPoint::new(payment.txid(), 0), 850);
+        // Spends an output we know nothing about, so we can't tell what it pays
+        let foreign = spending(OutPoint::new(bitcoin::Txid::all_zeros(), 7), 100);
+        for transaction in [&payment, &child, &foreign] {
+            cache.journal_broadcast(transaction.clone());
+        }
+
+        let mempool = cache.get_address_mempool(&hash);
+        assert_eq!(mempool.len(), 3);
+        let entry = |txid| {
+            mempool
+                .iter()
+                .find_map(|entry| match entry {
+                    HistoryEntry::Mempool {
+                        hash,
+                        fee,
+                        has_unconfirmed_parents,
+                        ..
+                    } if *hash == txid => Some((*fee, *has_unconfirmed_parents)),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(entry(payment.txid()), (Some(100), false));
+        assert_eq!(entry(child.txid()), (Some(50), true));
+        assert_eq!(entry(foreign.txid()).0, None);
+        // They're in its history, but not in what its status is built from
+        assert_eq!(cache.get_full_history(&hash).len(), 4);
+        assert_eq!(cache.get_recent_history(&hash).1.len(), 1);
+    }
+    #[test]
+    fn test_late_transaction_before_archive() {
+        let database = KvDatabase::new("/tmp/utreexo_unarchive/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_unarchive/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        cache.set_max_hot_history(2);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        for (height, value) in [(10, 1_000), (20, 2_000), (30, 3_000), (40, 4_000)] {
+            let (transaction, _, merkle_block) = paying_block(&script, value);
+            cache
+                .cache_transaction(&transaction, height, merkle_block, 1, vec![])
+                .unwrap();
+        }
+        assert!(cache.get_recent_history(&hash).0.count > 0);
+
+        // Mined before everything we archived, like ones found by a rescan
+        let (transaction, _, merkle_block) = paying_block(&script, 500);
+        cache
+            .cache_transaction(&transaction, 5, merkle_block, 1, vec![])
+            .unwrap();
+        let (archived, recent) = cache.get_recent_history(&hash);
+        let full = cache.get_full_history(&hash);
+        assert_eq!(full.len(), 5);
+        assert_eq!(
+            extend_status(archived.status_engine(), &recent),
+            get_status(&full)
+        );
+        // Loading it off our loop gives the same history
+        let load = cache.history_loader(&hash);
+        assert_eq!(get_status(&load().unwrap()), get_status(&full));
+    }
+    #[test]
+    fn test_status_cache() {
+        let dir = "/tmp/utreexo_status_cache/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        cache.cache_address(script.clone()).unwrap();
+        cache.warm_up_addresses(&cache.get_script_hashes());
+        assert_eq!(cache.statuses.lock().unwrap().get(&hash), Some(&None));
+        assert_eq!(cache.get_status(&hash), None);
+
+        // Each new transaction replaces the status we kept
+        for (height, value) in [(10, 1_000), (20, 2_000)] {
+            let (transaction, _, merkle_block) = paying_block(&script, value);
+            cache
+                .cache_transaction(&transaction, height, merkle_block, 1, vec![])
+                .unwrap();
+            let full = cache.get_full_history(&hash);
+            assert_eq!(cache.get_status(&hash), Some(get_status(&full)));
+        }
+        // So does rolling back
+        cache.drop_history_after(10);
+        let full = cache.get_full_history(&hash);
+        assert_eq!(full.len(), 1);
+        assert_eq!(cache.get_status(&hash), Some(get_status(&full)));
+        // Addresses we don't watch aren't kept
+        assert_eq!(cache.get_status(&sha256::Hash::hash(b"other")), None);
+        assert_eq!(cache.statuses.lock().unwrap().len(), 1);
+    }
+    #[test]
+    fn test_rollback_drops_history() {
+        let dir = "/tmp/utreexo_rollback_history/";
+        let _ = std::fs::remove_dir_all(dir);
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        {
+            let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            let mut cache = AddressCache::new(database, chain_store);
+            cache.set_max_hot_history(2);
+            cache.cache_address(script.clone()).unwrap();
+            for (height, value) in [(10, 1_000), (20, 2_000), (30, 3_000), (40, 4_000)] {
+                let (transaction, _, merkle_block) = paying_block(&script, value);
+                cache
+                    .cache_transaction(&transaction, height, merkle_block, 1, vec![])
+                    .unwrap();
+            }
+            assert_eq!(cache.get_recent_history(&hash).0.count, 2);
+            cache.save_acc_at(10);
+            cache.bump_height(40);
+            // A crash left our accumulator behind the one we had at height 40
+            cache.chain_store.save_leaf_count(40, 5).unwrap();
+        }
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let cache = AddressCache::new(database, chain_store);
+        assert_eq!(cache.get_cache_height().unwrap(), 10);
+        // Everything mined after our snapshot is gone, archived or not
+        let (archived, recent) = cache.get_recent_history(&hash);
+        let full = cache.get_full_history(&hash);
+        assert_eq!(archived.count, 1);
+        assert!(recent.is_empty());
+        assert_eq!(full.len(), 1);
+        assert_eq!(
+            extend_status(archived.status_engine(), &recent),
+            get_status(&full)
+        );
+        assert_eq!(cache.get_address_balance(&hash), 1_000);
+        // Only the leaf count we roll back to is left
+        assert_eq!(cache.chain_store.load_leaf_count(10).unwrap(), Some(0));
+        assert_eq!(cache.chain_store.load_leaf_count(40).unwrap(), None);
+    }
+    #[test]
+    fn test_import_checks_our_chain() {
+        let database = KvDatabase::new("/tmp/utreexo_import/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_import/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        cache.database.set_cache_height(0).unwrap();
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        let (transaction, block, merkle_block) = paying_block(&script, 1_000);
+        let txid = transaction.txid();
+        let export = || {
+            let export = WalletExport {
+                version: WALLET_EXPORT_VERSION,
+                network: Network::Regtest,
+                height: 1,
+                addresses: vec![ExportedAddress {
+                    script: script.clone(),
+                    address: None,
+                    utxos: vec![OutPoint::new(txid, 0)],
+                }],
+                transactions: vec![ExportedTransaction {
+                    height: 1,
+                    position: 1,
+                    tx: serialize_hex(&transaction),
+                    merkle_block: serialize_hex(&merkle_block),
+                    confirmations: 1,
+                    roots: None,
+                }],
+                roots: None,
+            };
+            // Like the first version wrote it, with prevouts nothing in the export backs
+            let mut export = serde_json::to_value(export).unwrap();
+            export["version"] = serde_json::json!(1);
+            export["transactions"][0]["prevouts"] = serde_json::json!([TxOut::default()]);
+            export["transactions"][0]
+                .as_object_mut()
+                .unwrap()
+                .remove("confirmations");
+            serde_json::from_value::<WalletExport>(export).unwrap()
+        };
+
+        // Our node has another block at that height
+        let other = genesis_block(Network::Regtest).block_hash();
+        assert!(matches!(
+            cache.import_wallet(export(), Network::Regtest, |_| Ok(other)),
+            Err(crate::error::Error::NotInOurChain(1))
+        ));
+        assert!(cache.get_address_history(&hash).is_empty());
+
+        let imported = cache
+            .import_wallet(export(), Network::Regtest, |_| Ok(block.block_hash()))
+            .unwrap();
+        assert_eq!(imported, 1);
+        assert_eq!(cache.get_address_balance(&hash), 1_000);
+        let body = cache.database.load_tx_body(&txid).unwrap().unwrap();
+        assert!(body.prevouts.is_empty());
+    }
+    #[test]
+    fn test_block_export() {
+        let dir = "/tmp/utreexo_block_export/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let file = format!("{dir}blocks.jsonl");
+        let exporter = BlockExporter::new(file.clone(), Network::Regtest, AddressFormat::Script);
+        cache.set_block_exporter(exporter.unwrap());
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        cache.cache_address(script.clone()).unwrap();
+        let (transaction, block, _) = paying_block(&script, 1_000);
+        cache
+            .block_process(
+                &block,
+                1,
+                Proof::new(vec![], vec![]),
+                vec![],
+                &HashMap::new(),
+            )
+            .unwrap();
+        let leaves = cache.get_acc().leafs;
+        // Blocks that don't touch our wallet aren't written
+        let other = Script::from_hex("0014000000000000000000000000000000000000000a").unwrap();
+        let (_, block, _) = paying_block(&other, 1_000);
+        cache
+            .block_process(
+                &block,
+                2,
+                Proof::new(vec![], vec![]),
+                vec![],
+                &HashMap::new(),
+            )
+            .unwrap();
+
+        let records = std::fs::read_to_string(file).unwrap();
+        let records = records.lines().collect::<Vec<_>>();
+        assert_eq!(records.len(), 1);
+        let record = serde_json::from_str::<serde_json::Value>(records[0]).unwrap();
+        let txid = transaction.txid();
+        assert_eq!(record["height"], 1);
+        assert_eq!(record["transactions"], serde_json::json!([txid]));
+        assert_eq!(
+            record["created"],
+            serde_json::json!([OutPoint::new(txid, 0)])
+        );
+        assert_eq!(record["spent"], serde_json::json!([]));
+        assert_eq!(record["leaves"], leaves);
+    }
+    #[test]
+    fn test_exports_show_addresses() {
+        let dir = "/tmp/utreexo_export_addresses/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let file = format!("{dir}blocks.jsonl");
+        let exporter = BlockExporter::new(file.clone(), Network::Regtest, AddressFormat::Address);
+        cache.set_block_exporter(exporter.unwrap());
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        let address = "bcrt1qya04va59hlsgpercn6k2xmv67vpj02avexy35m";
+        cache.cache_address(script.clone()).unwrap();
+        let (_, block, _) = paying_block(&script, 1_000);
+        cache
+            .block_process(
+                &block,
+                1,
+                Proof::new(vec![], vec![]),
+                vec![],
+                &HashMap::new(),
+            )
+            .unwrap();
+        let records = std::fs::read_to_string(file).unwrap();
+        let record = serde_json::from_str::<serde_json::Value>(records.trim()).unwrap();
+        assert_eq!(record["addresses"], serde_json::json!([address]));
+        // Confirmations follow our tip
+        cache.bump_height(3);
+        let summary = cache.get_wallet_summary(Network::Regtest, AddressFormat::Address);
+        assert_eq!(summary[0].last_active_confirmations, 3);
+        let export = cache
+            .export_wallet(&[hash], Network::Regtest, AddressFormat::Address, false)
+            .unwrap();
+        assert_eq!(export.transactions[0].confirmations, 3);
+
+        let export = |format| {
+            cache
+                .export_wallet(&[hash], Network::Regtest, format, false)
+                .unwrap()
+                .addresses
+                .remove(0)
+                .address
+        };
+        assert_eq!(export(AddressFormat::Address), Some(address.to_string()));
+        // A segwit script has no base58 address
+        assert_eq!(export(AddressFormat::Base58), None);
+        assert_eq!(export(AddressFormat::Script), None);
+    }
+    #[test]
+    fn test_chainstate_round_trip() {
+        let open = |dir: &str| {
+            let _ = std::fs::remove_dir_all(dir);
+            let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            let mut cache = AddressCache::new(database, chain_store);
+            let headers = HeaderStore::open(
+                &Path::new(dir).join("headers"),
+                Box::new(BitcoinParams::new(Network::Regtest)),
+            )
+            .unwrap();
+            cache.set_header_store(headers);
+            cache
+        };
+        let genesis = genesis_block(Network::Regtest).header;
+        let header = |height: u32| BlockHeader {
+            nonce: height,
+            ..genesis
+        };
+        let mut ours = open("/tmp/utreexo_chainstate_dump/");
+        let leaf = sha256::Hash::hash(b"leaf");
+        for height in 1..=3 {
+            ours.acc = ours
+                .acc
+                .modify(&[leaf], &[], &Proof::new(vec![], vec![]))
+                .unwrap()
+                .0;
+            ours.bump_height(height);
+            ours.save_acc_at(height);
+            ours.save_header(height, &header(height)).unwrap();
+        }
+        ours.save_tip_header(3, serialize_hex(&header(3)));
+
+        // Like a dump copied to another machine
+        let dump = serde_json::to_string(&ours.dump_chainstate()).unwrap();
+        let mut theirs = open("/tmp/utreexo_chainstate_load/");
+        theirs.database.set_cache_height(0).unwrap();
+        theirs
+            .load_chainstate(serde_json::from_str(&dump).unwrap())
+            .unwrap();
+        let state = |acc: Option<Stump>| acc.map(|acc| (acc.leafs, acc.roots));
+        assert_eq!(theirs.get_cache_height().unwrap(), 3);
+        assert_eq!(
+            state(Some(theirs.get_acc().clone())),
+            state(Some(ours.get_acc().clone()))
+        );
+        // Our tip's accumulator is the dump's tip, not one of its checkpoints
+        for height in 1..3 {
+            assert_eq!(
+                state(theirs.get_acc_at(height)),
+                state(ours.get_acc_at(height))
+            );
+        }
+        for height in 1..=3 {
+            assert_eq!(theirs.get_header(height), Some(header(height)));
+        }
+        assert_eq!(theirs.get_tip_header(), ours.get_tip_header());
+        // A wallet that already synced can't take one
+        assert!(matches!(
+            theirs.load_chainstate(serde_json::from_str(&dump).unwrap()),
+            Err(crate::error::Error::WalletAlreadySynced)
+        ));
+    }
+    #[test]
+    fn test_stop_height() {
+        let dir = "/tmp/utreexo_stop_height/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        assert_eq!(cache.get_sync_limits(10).unwrap(), 1..=10);
+
+        // We sync up to the block we stop at, however far our node is
+        cache.set_stop_height(5);
+        assert_eq!(cache.get_stop_height(), Some(5));
+        assert_eq!(cache.get_sync_limits(10).unwrap(), 1..=5);
+        assert_eq!(cache.get_sync_limits(3).unwrap(), 1..=3);
+        // And have nothing left once we're there
+        cache.bump_height(5);
+        assert!(cache.get_sync_limits(10).unwrap().is_empty());
+    }
+    #[test]
+    fn test_bump_height() {
+        let dir = "/tmp/utreexo_bump_height/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        cache.bump_height(10);
+        // A late save of an older height must not take us back
+        cache.bump_height(5);
+        assert_eq!(cache.get_cache_height().unwrap(), 10);
+        assert_eq!(cache.chain_store.load_leaf_count(10).unwrap(), Some(0));
+        assert_eq!(cache.chain_store.load_leaf_count(5).unwrap(), None);
+        cache.bump_height(12);
+        assert_eq!(cache.get_cache_height().unwrap(), 12);
+        // Going back is for rollbacks
+        cache.reset_to(3, Stump::new());
+        assert_eq!(cache.get_cache_height().unwrap(), 3);
+        assert_eq!(cache.chain_store.load_leaf_count(12).unwrap(), None);
+    }
+    #[test]
+    fn test_import_history() {
+        let (ours, scratch) = (
+            "/tmp/utreexo_import_history/",
+            "/tmp/utreexo_import_scratch/",
+        );
+        let _ = std::fs::remove_dir_all(ours);
+        let _ = std::fs::remove_dir_all(scratch);
+        let open = |dir: &str| {
+            let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            AddressCache::new(database, chain_store)
+        };
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        let mut cache = open(ours);
+        cache.cache_address(script.clone()).unwrap();
+        let (transaction, _, merkle_block) = paying_block(&script, 1_000);
+        cache
+            .cache_transaction(&transaction, 5, merkle_block, 1, vec![])
+            .unwrap();
+
+        // A rescan found a later payment, and the one we already have
+        let mut other = open(scratch);
+        other.cache_address(script.clone()).unwrap();
+        let (earlier, _, merkle_block) = paying_block(&script, 1_000);
+        other
+            .cache_transaction(&earlier, 5, merkle_block, 1, vec![])
+            .unwrap();
+        let (later, _, merkle_block) = paying_block(&script, 2_000);
+        other
+            .cache_transaction(&later, 10, merkle_block, 1, vec![])
+            .unwrap();
+
+        // Our address had history, and still gets the payment it was missing
+        assert_eq!(cache.import_history(&other), 2);
+        assert_eq!(cache.get_address_history(&hash).len(), 2);
+        assert_eq!(cache.get_address_balance(&hash), 3_000);
+        assert_eq!(cache.import_history(&other), 2);
+        assert_eq!(cache.get_address_history(&hash).len(), 2);
+        assert_eq!(cache.get_address_balance(&hash), 3_000);
+    }
+    #[test]
+    fn test_rescan_block() {
+        let database = KvDatabase::new("/tmp/utreexo_rescan/".into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new("/tmp/utreexo_rescan/".to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let hash = get_spk_hash(&script);
+        let (_, block, _) = paying_block(&script, 1_000);
+        // Not ours yet
+        assert_eq!(cache.rescan_block(&block, 1), 0);
+
+        cache.cache_address(script).unwrap();
+        assert_eq!(cache.rescan_block(&block, 1), 1);
+        assert_eq!(cache.get_address_balance(&hash), 1_000);
+        // Rescanning again finds nothing new
+        assert_eq!(cache.rescan_block(&block, 1), 0);
+        assert_eq!(cache.get_address_balance(&hash), 1_000);
+    }
+    #[test]
+    fn test_set_op_return_prefixes_replaces() {
+        let dir = "/tmp/utreexo_op_return/";
+        let _ = std::fs::remove_dir_all(dir);
+        let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let mut cache = AddressCache::new(database, chain_store);
+        let filters = cache.filters.len();
+
+        cache.set_op_return_prefixes(vec![b"old".to_vec()]);
+        cache.set_op_return_prefixes(vec![b"new".to_vec()]);
+        assert_eq!(cache.filters.len(), filters);
+        assert!(cache.watches_op_return_prefix(b"new"));
+        assert!(!cache.watches_op_return_prefix(b"old"));
+
+        // Blocks are matched against the new prefixes only, and each output once
+        let transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn::default()],
+            output: vec![
+                TxOut {
+                    value: 0,
+                    script_pubkey: Script::new_op_return(b"old data"),
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: Script::new_op_return(b"new data"),
+                },
+            ],
+        };
+        let (block, _) = mined_block(&transaction);
+        cache
+            .block_process(
+                &block,
+                1,
+                Proof::new(vec![], vec![]),
+                vec![],
+                &HashMap::new(),
+            )
+            .unwrap();
+        let matches = cache.get_op_return_matches(1);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].txid, transaction.txid());
+        assert_eq!(matches[0].vout, 1);
+        assert_eq!(matches[0].payload, b"new data".to_hex());
+    }
+    #[test]
+    fn test_get_derivations() {
+        // The master key of BIP 32's first test vector
+        let descriptor = crate::parse_descriptor(
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+        )
+        .unwrap();
+        let derived = |index| get_spk_hash(&descriptor.at_derivation_index(index).script_pubkey());
+        let stray = get_spk_hash(&Script::from_hex("00").unwrap());
+
+        let script_hashes = HashSet::from([derived(3), derived(0), stray]);
+        let derivations = get_derivations(&[descriptor.clone()], script_hashes, 1);
+        // Addresses none of our descriptors derive come first
+        assert_eq!(derivations[0].script_hash, stray);
+        assert!(derivations[0].descriptor.is_none());
+        assert_eq!(
+            derivations
+                .iter()
+                .map(|derivation| (derivation.script_hash, derivation.index))
+                .collect::<Vec<_>>(),
+            vec![(stray, None), (derived(0), Some(0)), (derived(3), Some(3))]
+        );
+        // We only look `lookahead` past how many addresses we watch
+        let derivations = get_derivations(&[descriptor], HashSet::from([derived(3)]), 0);
+        assert_eq!(derivations[0].index, None);
+    }
 }
@@ -0,0 +1,49 @@
+//! Watch rules for OP_RETURN outputs. Operators indexing some protocol's on-chain data
+//! register the prefixes its payloads start with, like a protocol tag, and every matching
+//! output we see in a block is kept, keyed by the block's height. Clients get them with
+//! `blockchain.opreturn.get_matches`.
+
+use bitcoin::{blockdata::script::Instruction, Script, Txid};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpReturnMatch {
+    pub txid: Txid,
+    pub vout: u32,
+    /// The data pushed after OP_RETURN, hex encoded
+    pub payload: String,
+}
+
+/// Returns the data an OP_RETURN output carries, with all its pushes concatenated. None for
+/// any other output.
+pub fn get_payload(script: &Script) -> Option<Vec<u8>> {
+    if !script.is_op_return() {
+        return None;
+    }
+    let mut payload = vec![];
+    for instruction in script.instructions().skip(1) {
+        if let Ok(Instruction::PushBytes(data)) = instruction {
+            payload.extend_from_slice(data);
+        }
+    }
+    Some(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{hashes::hex::FromHex, Script};
+
+    use super::get_payload;
+
+    #[test]
+    fn test_get_payload() {
+        // OP_RETURN OP_PUSHBYTES_4 6f6d6e69 OP_PUSHBYTES_2 0001
+        let script = Script::from_hex("6a046f6d6e69020001").unwrap();
+        assert_eq!(
+            get_payload(&script),
+            Some(vec![0x6f, 0x6d, 0x6e, 0x69, 0x00, 0x01])
+        );
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        assert_eq!(get_payload(&script), None);
+    }
+}
@@ -0,0 +1,129 @@
+//! Tells what kind of output a script is, so clients and admins don't have to decode raw
+//! script hex themselves.
+
+use bitcoin::{util::address::Payload, Address, Network, Script};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    Unknown,
+}
+
+impl ScriptType {
+    pub fn classify(script: &Script) -> ScriptType {
+        if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_v0_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_v0_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_v1_p2tr() {
+            ScriptType::P2tr
+        } else if script.is_op_return() {
+            ScriptType::OpReturn
+        } else {
+            ScriptType::Unknown
+        }
+    }
+    /// The name we use for this type, same as its JSON form
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::OpReturn => "op_return",
+            ScriptType::Unknown => "unknown",
+        }
+    }
+    /// About how many virtual bytes an input spending this type takes, for single key
+    /// outputs. P2SH is taken to wrap P2WPKH. None for the ones we can't tell
+    pub fn input_vsize(&self) -> Option<u64> {
+        match self {
+            ScriptType::P2pkh => Some(148),
+            ScriptType::P2sh => Some(91),
+            ScriptType::P2wpkh => Some(68),
+            ScriptType::P2tr => Some(58),
+            ScriptType::P2wsh | ScriptType::OpReturn | ScriptType::Unknown => None,
+        }
+    }
+    /// The name Bitcoin Core uses for this type in verbose transactions
+    pub fn core_name(&self) -> &'static str {
+        match self {
+            ScriptType::P2pkh => "pubkeyhash",
+            ScriptType::P2sh => "scripthash",
+            ScriptType::P2wpkh => "witness_v0_keyhash",
+            ScriptType::P2wsh => "witness_v0_scripthash",
+            ScriptType::P2tr => "witness_v1_taproot",
+            ScriptType::OpReturn => "nulldata",
+            ScriptType::Unknown => "nonstandard",
+        }
+    }
+}
+
+/// How we show the addresses of scripts, wherever we show them. The script hex is always
+/// shown next to them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFormat {
+    /// Each script in the encoding its type uses: base58 for P2PKH and P2SH, bech32 for
+    /// segwit v0 and bech32m for later versions
+    Address,
+    /// Only base58 addresses, for tools that don't know segwit
+    Base58,
+    /// Only bech32 and bech32m addresses
+    Bech32,
+    /// No addresses, only script hex
+    Script,
+}
+
+impl Default for AddressFormat {
+    fn default() -> Self {
+        AddressFormat::Address
+    }
+}
+
+/// Encodes `script` as an address for `network`, with its network's prefix or HRP, if it
+/// has one `format` allows
+pub fn get_address(script: &Script, network: Network, format: AddressFormat) -> Option<String> {
+    let address = Address::from_script(script, network)?;
+    let allowed = match (format, &address.payload) {
+        (AddressFormat::Address, _) => true,
+        (AddressFormat::Base58, Payload::PubkeyHash(_) | Payload::ScriptHash(_)) => true,
+        (AddressFormat::Bech32, Payload::WitnessProgram { .. }) => true,
+        _ => false,
+    };
+    allowed.then(|| address.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{hashes::Hash, Network, PubkeyHash, Script, WPubkeyHash};
+
+    use super::{get_address, AddressFormat};
+
+    #[test]
+    fn test_address_format() {
+        let segwit = Script::new_v0_p2wpkh(&WPubkeyHash::from_slice(&[1; 20]).unwrap());
+        let legacy = Script::new_p2pkh(&PubkeyHash::from_slice(&[1; 20]).unwrap());
+        // Each network has its own HRP
+        let address = get_address(&segwit, Network::Regtest, AddressFormat::Address).unwrap();
+        assert!(address.starts_with("bcrt1q"));
+        let address = get_address(&segwit, Network::Bitcoin, AddressFormat::Bech32).unwrap();
+        assert!(address.starts_with("bc1q"));
+        assert!(get_address(&segwit, Network::Testnet, AddressFormat::Base58).is_none());
+        assert!(get_address(&legacy, Network::Testnet, AddressFormat::Base58).is_some());
+        assert!(get_address(&legacy, Network::Testnet, AddressFormat::Bech32).is_none());
+        assert!(get_address(&legacy, Network::Testnet, AddressFormat::Script).is_none());
+    }
+}
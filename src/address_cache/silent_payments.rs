@@ -0,0 +1,328 @@
+//! BIP352 silent payments scanning. Senders derive a fresh taproot output for each payment,
+//! from our scan and spend keys and the keys of the inputs they spend, so there's no address
+//! to watch. Instead, for each transaction with taproot outputs, we compute the shared secret
+//! from its inputs and our scan key, and check whether any output is the one a sender would
+//! have derived for us. Outputs we find are added to our wallet, and the tweak needed to
+//! spend them is kept, see `admin.getsilentpayments`.
+//!
+//! Labels aren't supported, only outputs paying to our plain silent payment address are found.
+
+use std::collections::HashMap;
+
+use bitcoin::{
+    consensus::serialize,
+    hashes::{hash160, sha256, Hash, HashEngine},
+    secp256k1::{All, Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey},
+    OutPoint, Script, Transaction, TxIn, TxOut,
+};
+use serde::{Deserialize, Serialize};
+
+use super::filters::{BlockFilter, FilterEvent, WalletView};
+
+/// The x coordinate of BIP341's unspendable internal key. Script path spends using it have no
+/// key we could use.
+const NUMS_KEY: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+/// How many outputs for us we look for in a single transaction
+const MAX_OUTPUTS_PER_TRANSACTION: u32 = 2_323;
+
+/// An output someone sent to our silent payment address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SilentPayment {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub height: u32,
+    /// What must be added to our spend key to spend this output, hex encoded
+    pub tweak: String,
+}
+
+pub struct SilentPaymentsFilter {
+    scan_key: SecretKey,
+    spend_key: PublicKey,
+    secp: Secp256k1<All>,
+}
+
+impl SilentPaymentsFilter {
+    pub fn new(scan_key: SecretKey, spend_key: PublicKey) -> SilentPaymentsFilter {
+        SilentPaymentsFilter {
+            scan_key,
+            spend_key,
+            secp: Secp256k1::new(),
+        }
+    }
+    /// Returns the tweak for each output of `transaction` paying to us, by output index
+    fn find_outputs(
+        &self,
+        transaction: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Vec<(u32, [u8; 32])> {
+        let taproot_outputs = transaction
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| output.script_pubkey.is_v1_p2tr())
+            .filter_map(|(vout, output)| {
+                let key =
+                    XOnlyPublicKey::from_slice(&output.script_pubkey.as_bytes()[2..34]).ok()?;
+                Some((key, vout as u32))
+            })
+            .collect::<HashMap<_, _>>();
+        if taproot_outputs.is_empty() || transaction.is_coin_base() {
+            return vec![];
+        }
+        let mut input_keys = vec![];
+        for input in transaction.input.iter() {
+            let prevout = match prevouts.get(&input.previous_output) {
+                Some(prevout) => prevout,
+                // We can't tell whether it's eligible, so we can't compute the shared secret
+                None => return vec![],
+            };
+            // Transactions spending future segwit versions can't carry silent payments
+            if let Some(version) = prevout.script_pubkey.witness_version() {
+                if version.to_num() > 1 {
+                    return vec![];
+                }
+            }
+            input_keys.extend(get_input_key(input, &prevout.script_pubkey));
+        }
+        let input_keys = input_keys.iter().collect::<Vec<_>>();
+        let input_sum = match PublicKey::combine_keys(&input_keys) {
+            Ok(sum) => sum,
+            // No eligible inputs, or they cancel out
+            Err(_) => return vec![],
+        };
+        let smallest_outpoint = transaction
+            .input
+            .iter()
+            .map(|input| serialize(&input.previous_output))
+            .min()
+            .unwrap_or_default();
+        let input_hash = tagged_hash(
+            "BIP0352/Inputs",
+            &[&smallest_outpoint, &input_sum.serialize()],
+        );
+        let shared_secret = Scalar::from_be_bytes(input_hash)
+            .ok()
+            .and_then(|input_hash| self.scan_key.mul_tweak(&input_hash).ok())
+            .and_then(|tweak| input_sum.mul_tweak(&self.secp, &Scalar::from(tweak)).ok());
+        let shared_secret = match shared_secret {
+            Some(shared_secret) => shared_secret.serialize(),
+            None => return vec![],
+        };
+
+        let mut found = vec![];
+        for k in 0..MAX_OUTPUTS_PER_TRANSACTION {
+            let tweak = tagged_hash("BIP0352/SharedSecret", &[&shared_secret, &k.to_be_bytes()]);
+            let output_key = Scalar::from_be_bytes(tweak)
+                .ok()
+                .and_then(|scalar| self.spend_key.add_exp_tweak(&self.secp, &scalar).ok());
+            let output_key = match output_key {
+                Some(output_key) => output_key.x_only_public_key().0,
+                None => break,
+            };
+            match taproot_outputs.get(&output_key) {
+                Some(vout) => found.push((*vout, tweak)),
+                None => break,
+            }
+        }
+        found
+    }
+}
+
+impl BlockFilter for SilentPaymentsFilter {
+    fn scan(
+        &self,
+        wallet: &dyn WalletView,
+        transaction: &Transaction,
+        prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Vec<FilterEvent> {
+        self.find_outputs(transaction, prevouts)
+            .into_iter()
+            .filter_map(|(vout, tweak)| {
+                let output = transaction.output.get(vout as usize)?.clone();
+                // Once found, the output's script is ours, and we already see it
+                if wallet.is_wallet_script(&output.script_pubkey) {
+                    return None;
+                }
+                Some(FilterEvent::SilentPayment {
+                    vout,
+                    output,
+                    tweak,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Returns the public key an input contributes to the shared secret, if it's eligible
+fn get_input_key(input: &TxIn, prevout: &Script) -> Option<PublicKey> {
+    if prevout.is_v1_p2tr() {
+        let mut witness = input.witness.to_vec();
+        // Drop the annex, if there's one
+        if witness.len() > 1 && witness.last()?.first() == Some(&0x50) {
+            witness.pop();
+        }
+        if witness.len() > 1 {
+            let control_block = witness.last()?;
+            if control_block.get(1..33) == Some(&NUMS_KEY[..]) {
+                return None;
+            }
+        }
+        let key = XOnlyPublicKey::from_slice(&prevout.as_bytes()[2..34]).ok()?;
+        return Some(PublicKey::from_x_only_public_key(key, Parity::Even));
+    }
+    if prevout.is_v0_p2wpkh() || prevout.is_p2sh() {
+        let key = input.witness.last()?;
+        if key.len() != 33 {
+            return None;
+        }
+        // Only P2SH wrapping P2WPKH is eligible
+        if prevout.is_p2sh() {
+            let redeem_script = input.script_sig.as_bytes().get(1..)?;
+            if !Script::from(redeem_script.to_vec()).is_v0_p2wpkh() {
+                return None;
+            }
+        }
+        return PublicKey::from_slice(key).ok();
+    }
+    if prevout.is_p2pkh() {
+        // The key is whatever compressed key in the script sig hashes to the one we pay to
+        let key_hash = &prevout.as_bytes()[3..23];
+        return input
+            .script_sig
+            .instructions()
+            .filter_map(|instruction| match instruction {
+                Ok(bitcoin::blockdata::script::Instruction::PushBytes(data)) => Some(data),
+                _ => None,
+            })
+            .filter(|data| data.len() == 33)
+            .find(|data| hash160::Hash::hash(data)[..] == *key_hash)
+            .and_then(|key| PublicKey::from_slice(key).ok());
+    }
+    None
+}
+
+/// BIP340 tagged hash
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag);
+    engine.input(&tag);
+    for data in data {
+        engine.input(data);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, str::FromStr};
+
+    use bitcoin::{
+        blockdata::{opcodes::all::OP_PUSHNUM_1, script::Builder},
+        hashes::{hex::FromHex, Hash},
+        secp256k1::{PublicKey, Secp256k1, SecretKey},
+        OutPoint, PackedLockTime, PubkeyHash, Script, Transaction, TxIn, TxOut, Txid,
+    };
+
+    use super::SilentPaymentsFilter;
+
+    fn taproot_output(key: &[u8]) -> TxOut {
+        TxOut {
+            value: 1_000,
+            script_pubkey: Builder::new()
+                .push_opcode(OP_PUSHNUM_1)
+                .push_slice(key)
+                .into_script(),
+        }
+    }
+
+    /// BIP352's first sending and receiving test vector, "Simple send: two inputs". Its
+    /// inputs spend P2PKH outputs, and their signatures are left out, since we don't check
+    /// them. The vectors using labels don't apply, we don't support them.
+    #[test]
+    fn test_find_outputs() {
+        let secp = Secp256k1::new();
+        let scan_key =
+            SecretKey::from_str("0f694e068028a717f8af6b9411f9a133dd3565258714cc226594b34db90c1f2c")
+                .unwrap();
+        let spend_key = PublicKey::from_secret_key(
+            &secp,
+            &SecretKey::from_str(
+                "9d6ad855ce3417ef84e836892e5a56392bfba05fa5d97ccea30e266f540e08b3",
+            )
+            .unwrap(),
+        );
+        let inputs = [
+            (
+                "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+                "eadc78165ff1f8ea94ad7cfdc54990738a4c53f6e0507b42154201b8e5dff3b1",
+            ),
+            (
+                "a1075db55d416d3ca199f55b6084e2115b9345e16c5cf302fc80e9d5fbf5d48d",
+                "93f5ed907ad5b2bdbbdcb5d9116ebc0a4e1f92f910d5260237fa45a9408aad16",
+            ),
+        ];
+        let output_key =
+            Vec::from_hex("3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1")
+                .unwrap();
+        let tweak: [u8; 32] =
+            Vec::from_hex("f438b40179a3c4262de12986c0e6cce0634007cdc79c1dcd3e20b9ebc2e7eef6")
+                .unwrap()
+                .try_into()
+                .unwrap();
+
+        let mut prevouts = HashMap::new();
+        let mut input = vec![];
+        for (txid, key) in inputs {
+            let previous_output = OutPoint {
+                txid: Txid::from_str(txid).unwrap(),
+                vout: 0,
+            };
+            let key = PublicKey::from_secret_key(&secp, &SecretKey::from_str(key).unwrap());
+            let script_pubkey = Script::new_p2pkh(&PubkeyHash::hash(&key.serialize()));
+            prevouts.insert(
+                previous_output,
+                TxOut {
+                    value: 10_000,
+                    script_pubkey,
+                },
+            );
+            input.push(TxIn {
+                previous_output,
+                script_sig: Builder::new()
+                    .push_slice(&[0x30; 71])
+                    .push_slice(&key.serialize())
+                    .into_script(),
+                ..Default::default()
+            });
+        }
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input,
+            output: vec![taproot_output(&[2; 32]), taproot_output(&output_key)],
+        };
+
+        let filter = SilentPaymentsFilter::new(scan_key, spend_key);
+        assert_eq!(
+            filter.find_outputs(&transaction, &prevouts),
+            vec![(1, tweak)]
+        );
+        // The order of the inputs doesn't matter, like in the vector that reverses them
+        transaction.input.reverse();
+        assert_eq!(
+            filter.find_outputs(&transaction, &prevouts),
+            vec![(1, tweak)]
+        );
+        // Someone else's scan key finds nothing
+        let other = SilentPaymentsFilter::new(SecretKey::from_slice(&[1; 32]).unwrap(), spend_key);
+        assert!(other.find_outputs(&transaction, &prevouts).is_empty());
+        // Nor do we, if we can't see what the inputs spend
+        assert!(filter
+            .find_outputs(&transaction, &HashMap::new())
+            .is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! What we keep in our wallet database. Each value is one of these records, serialized as
+//! JSON, so a new field only needs adding here, with a default for records written before it.
+//! Bitcoin data keeps its consensus encoding, in hex. What our chain store keeps is in
+//! [crate::blockchain::stored].
+//!
+//! Older versions wrote their own string formats. Those are still read, by the
+//! `TryFrom<String>` impls of the types these records convert to, and rewritten as records
+//! by `compact`.
+
+use bitcoin::{
+    consensus::{deserialize, encode::serialize_hex},
+    hashes::{hex::FromHex, sha256},
+    Script, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    archive::ArchivedHistory, CachedAddress, CachedTransaction, JournalEntry, TransactionBody,
+};
+
+/// A transaction in an address history, see [CachedTransaction]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredTx {
+    pub txid: Txid,
+    pub height: u32,
+    pub position: u32,
+}
+
+/// The archived part of an address history, see [ArchivedHistory]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredArchive {
+    pub count: usize,
+    pub last_height: u32,
+    pub length: usize,
+    pub midstate: String,
+    pub tail: String,
+}
+
+/// An address in our `addresses` bucket, keyed by its script hash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredAddress {
+    pub script_hash: sha256::Hash,
+    pub balance: u64,
+    pub script: Script,
+    #[serde(default)]
+    pub first_seen_height: Option<u32>,
+    #[serde(default)]
+    pub last_active_height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived: Option<StoredArchive>,
+    pub transactions: Vec<StoredTx>,
+}
+
+/// What we know about our wallet as a whole, in our `meta` bucket. The last block we
+/// filtered changes every block, so it's a bare number under a key of its own.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoredMeta {
+    pub descriptor: Option<String>,
+}
+
+/// A transaction and its proofs, in our `transactions` bucket. See [TransactionBody]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredBody {
+    pub tx: String,
+    #[serde(default)]
+    pub merkle_block: Option<String>,
+    #[serde(default)]
+    pub prevouts: Option<String>,
+}
+
+/// A transaction in our `broadcast_journal` bucket, see [JournalEntry]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredJournalEntry {
+    pub transaction: String,
+    pub broadcast_at: u64,
+    #[serde(default)]
+    pub dropped: bool,
+}
+
+/// Where a transaction is, in our `tx_index` bucket, keyed by its txid
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredTxLocation {
+    /// One of our addresses with this transaction in its history
+    pub script_hash: sha256::Hash,
+}
+
+impl From<&CachedTransaction> for StoredTx {
+    fn from(transaction: &CachedTransaction) -> Self {
+        StoredTx {
+            txid: transaction.hash,
+            height: transaction.height,
+            position: transaction.position,
+        }
+    }
+}
+
+impl From<StoredTx> for CachedTransaction {
+    fn from(stored: StoredTx) -> Self {
+        CachedTransaction {
+            hash: stored.txid,
+            height: stored.height,
+            position: stored.position,
+        }
+    }
+}
+
+impl From<&CachedAddress> for StoredAddress {
+    fn from(address: &CachedAddress) -> Self {
+        StoredAddress {
+            script_hash: address.script_hash,
+            balance: address.balance,
+            script: address.script.clone(),
+            first_seen_height: address.first_seen_height,
+            last_active_height: address.last_active_height,
+            archived: (address.archived.count > 0).then(|| StoredArchive::from(&address.archived)),
+            transactions: address.transactions.iter().map(StoredTx::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<StoredAddress> for CachedAddress {
+    type Error = crate::error::Error;
+    fn try_from(stored: StoredAddress) -> Result<Self, Self::Error> {
+        let archived = match stored.archived {
+            Some(archived) => ArchivedHistory::try_from(archived)?,
+            None => ArchivedHistory::default(),
+        };
+        Ok(CachedAddress {
+            script_hash: stored.script_hash,
+            balance: stored.balance,
+            script: stored.script,
+            first_seen_height: stored.first_seen_height,
+            last_active_height: stored.last_active_height,
+            archived,
+            transactions: stored
+                .transactions
+                .into_iter()
+                .map(CachedTransaction::from)
+                .collect(),
+        })
+    }
+}
+
+impl From<&TransactionBody> for StoredBody {
+    fn from(body: &TransactionBody) -> Self {
+        StoredBody {
+            tx: serialize_hex(&body.tx),
+            merkle_block: body.merkle_block.as_ref().map(serialize_hex),
+            prevouts: Some(serialize_hex(&body.prevouts)),
+        }
+    }
+}
+
+impl TryFrom<StoredBody> for TransactionBody {
+    type Error = crate::error::Error;
+    fn try_from(stored: StoredBody) -> Result<Self, Self::Error> {
+        let merkle_block = match stored.merkle_block {
+            Some(merkle_block) => Some(deserialize(&Vec::from_hex(&merkle_block)?)?),
+            None => None,
+        };
+        let prevouts = match stored.prevouts {
+            Some(prevouts) => deserialize(&Vec::from_hex(&prevouts)?)?,
+            None => vec![],
+        };
+        Ok(TransactionBody {
+            tx: deserialize(&Vec::from_hex(&stored.tx)?)?,
+            merkle_block,
+            prevouts,
+        })
+    }
+}
+
+impl From<&JournalEntry> for StoredJournalEntry {
+    fn from(entry: &JournalEntry) -> Self {
+        StoredJournalEntry {
+            transaction: serialize_hex(&entry.transaction),
+            broadcast_at: entry.broadcast_at,
+            dropped: entry.dropped,
+        }
+    }
+}
+
+impl TryFrom<StoredJournalEntry> for JournalEntry {
+    type Error = crate::error::Error;
+    fn try_from(stored: StoredJournalEntry) -> Result<Self, Self::Error> {
+        Ok(JournalEntry {
+            transaction: deserialize(&Vec::from_hex(&stored.transaction)?)?,
+            broadcast_at: stored.broadcast_at,
+            dropped: stored.dropped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        hashes::{sha256, Hash},
+        Script, Txid,
+    };
+
+    use super::StoredAddress;
+    use crate::address_cache::{CachedAddress, CachedTransaction};
+
+    #[test]
+    fn test_address_record() {
+        let transactions = (1..=3u8)
+            .map(|n| CachedTransaction {
+                height: n as u32,
+                hash: Txid::from_slice(&[n; 32]).unwrap(),
+                position: 0,
+            })
+            .collect::<Vec<_>>();
+        let mut address = CachedAddress::_new(
+            sha256::Hash::hash(b"address"),
+            1_000,
+            transactions.clone(),
+            Script::new(),
+        );
+        address.archive_oldest(2);
+
+        let record = serde_json::to_string(&StoredAddress::from(&address)).unwrap();
+        let parsed = CachedAddress::try_from(record).unwrap();
+        assert_eq!(parsed.transactions, transactions[2..]);
+        assert_eq!(parsed.archived, address.archived);
+        assert_eq!(parsed.first_seen_height, Some(1));
+
+        // Records written before a field existed still load
+        let record = format!(
+            r#"{{"script_hash":"{}","balance":5,"script":"","transactions":[]}}"#,
+            address.script_hash
+        );
+        let parsed = CachedAddress::try_from(record).unwrap();
+        assert_eq!(parsed.balance, 5);
+        assert!(parsed.last_active_height.is_none());
+    }
+}
@@ -0,0 +1,113 @@
+//! Alert transports for people rather than programs: a Telegram chat, or an inbox. Both
+//! send a short text, rendered from a template, for each wallet event.
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+
+use super::webhooks::{render, AlertTransport, WalletEvent, WEBHOOK_TIMEOUT};
+
+/// What we send when no template was configured
+pub const DEFAULT_TEMPLATE: &str = "Wallet event: {event}";
+
+/// Posts each event to a Telegram chat, through a bot
+pub struct Telegram {
+    bot_token: String,
+    chat_id: String,
+    template: String,
+}
+
+impl Telegram {
+    pub fn new(bot_token: String, chat_id: String, template: Option<String>) -> Telegram {
+        Telegram {
+            bot_token,
+            chat_id,
+            template: template.unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+        }
+    }
+}
+
+impl AlertTransport for Telegram {
+    fn name(&self) -> String {
+        format!("Telegram chat {}", self.chat_id)
+    }
+    fn send(&self, event: &WalletEvent) -> Result<(), String> {
+        // The token is part of the URL, so it must stay out of our errors
+        let url = format!("https://api.telegram.org/bot{}/sendMessage", self.bot_token);
+        ureq::post(&url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .send_json(serde_json::json!({
+                "chat_id": self.chat_id,
+                "text": render(&self.template, event)
+            }))
+            .map(|_| ())
+            .map_err(|err| match err {
+                ureq::Error::Status(status, _) => format!("Telegram answered with {status}"),
+                ureq::Error::Transport(_) => "could not reach Telegram".to_string(),
+            })
+    }
+}
+
+/// Emails each event, through an SMTP server
+pub struct Email {
+    mailer: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    subject: String,
+    template: String,
+}
+
+impl Email {
+    /// Connects to `server` with STARTTLS. `from` and `to` must be valid addresses.
+    pub fn new(
+        server: &str,
+        port: u16,
+        credentials: Option<(String, String)>,
+        from: &str,
+        to: &[String],
+        subject: Option<String>,
+        template: Option<String>,
+    ) -> Result<Email, String> {
+        let mut mailer = SmtpTransport::starttls_relay(server)
+            .map_err(|err| err.to_string())?
+            .port(port)
+            .timeout(Some(WEBHOOK_TIMEOUT));
+        if let Some((username, password)) = credentials {
+            mailer = mailer.credentials(Credentials::new(username, password));
+        }
+        let to = to
+            .iter()
+            .map(|to| to.parse::<Mailbox>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        Ok(Email {
+            mailer: mailer.build(),
+            from: from.parse::<Mailbox>().map_err(|err| err.to_string())?,
+            to,
+            subject: subject.unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+            template: template.unwrap_or_else(|| DEFAULT_TEMPLATE.to_string()),
+        })
+    }
+}
+
+impl AlertTransport for Email {
+    fn name(&self) -> String {
+        format!("email from {}", self.from)
+    }
+    fn send(&self, event: &WalletEvent) -> Result<(), String> {
+        let message = self
+            .to
+            .iter()
+            .fold(Message::builder().from(self.from.clone()), |message, to| {
+                message.to(to.clone())
+            })
+            .subject(render(&self.subject, event))
+            .body(render(&self.template, event))
+            .map_err(|err| err.to_string())?;
+        self.mailer
+            .send(&message)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
@@ -0,0 +1,70 @@
+//! Finds where a transaction is in our address histories. Small wallets keep this whole
+//! index in memory, but for wallets with millions of transactions it may be moved to our
+//! database, keeping only the most recently used entries in memory.
+//!
+//! Entries only tell which address has a transaction, not where in its history, so sorting,
+//! deduplicating or archiving a history never leaves them pointing at the wrong entry.
+
+use std::{collections::HashMap, num::NonZeroUsize};
+
+use bitcoin::{hashes::sha256::Hash, Txid};
+
+use super::AddressCacheDatabase;
+use crate::sharded::ShardedLru;
+
+/// The script hash of an address with this transaction
+pub type TxLocation = Hash;
+
+pub enum TxIndex {
+    /// Every entry is kept in memory
+    Memory(HashMap<Txid, TxLocation>),
+    /// Entries live in our database, this holds the most recently used ones
+    Disk(ShardedLru<Txid, TxLocation>),
+}
+
+impl TxIndex {
+    pub fn get<D: AddressCacheDatabase>(&self, txid: &Txid, database: &D) -> Option<TxLocation> {
+        match self {
+            TxIndex::Memory(index) => index.get(txid).copied(),
+            TxIndex::Disk(hot) => {
+                if let Some(location) = hot.get(txid) {
+                    return Some(location);
+                }
+                let location = database
+                    .tx_index_load(txid)
+                    .expect("Database is not working")?;
+                hot.put(*txid, location);
+                Some(location)
+            }
+        }
+    }
+    pub fn insert<D: AddressCacheDatabase>(
+        &mut self,
+        entries: Vec<(Txid, TxLocation)>,
+        database: &D,
+    ) {
+        match self {
+            TxIndex::Memory(index) => index.extend(entries),
+            TxIndex::Disk(hot) => {
+                database
+                    .tx_index_save(&entries)
+                    .expect("Database is not working");
+                for (txid, location) in entries {
+                    hot.put(txid, location);
+                }
+            }
+        }
+    }
+    /// Moves this index into our database, keeping at most `hot_size` entries in memory
+    pub fn spill_to_disk<D: AddressCacheDatabase>(
+        &mut self,
+        hot_size: NonZeroUsize,
+        database: &D,
+    ) -> Result<(), crate::error::Error> {
+        if let TxIndex::Memory(index) = self {
+            database.tx_index_save(&index.drain().collect::<Vec<_>>())?;
+        }
+        *self = TxIndex::Disk(ShardedLru::new(hot_size));
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+//! A portable copy of some of our addresses: their history, unspent outputs, and the proof
+//! that each transaction was mined. Importing one into another server lets it serve those
+//! addresses right away, instead of rescanning the chain for them.
+//!
+//! Exports also stand on their own: each proof carries its block's header, so anyone can
+//! check a transaction is in a block, and that the block is the one their own node has at
+//! that height. With `--with-roots`, our utreexo accumulator after each block is included
+//! too, which a utreexo node can compare against its own.
+
+use bitcoin::{
+    consensus::{deserialize, encode::serialize_hex},
+    hashes::{hex::FromHex, sha256},
+    BlockHash, BlockHeader, MerkleBlock, Network, OutPoint, Script, Transaction,
+};
+use rustreexo::accumulator::stump::Stump;
+use serde::{Deserialize, Serialize};
+
+use super::{proves_position, CachedTransaction, TransactionBody};
+use crate::blockchain::{chain_params::ChainParams, headers::check_work};
+
+/// Bumped whenever the export format changes in an incompatible way. Version 1 exports also
+/// had the outputs each transaction spends, which nothing could check, so we still import
+/// them and ignore those.
+pub const WALLET_EXPORT_VERSION: u32 = 2;
+
+/// Our utreexo accumulator after some block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedRoots {
+    pub leaves: u64,
+    pub roots: Vec<sha256::Hash>,
+}
+
+impl From<&Stump> for ExportedRoots {
+    fn from(acc: &Stump) -> Self {
+        ExportedRoots {
+            leaves: acc.leafs,
+            roots: acc.roots.clone(),
+        }
+    }
+}
+
+/// A block the exported transactions are in, as checked by [WalletExport::verify]
+#[derive(Debug, Serialize)]
+pub struct VerifiedBlock {
+    pub height: u32,
+    pub block_hash: BlockHash,
+    pub roots: Option<ExportedRoots>,
+}
+
+/// A mined transaction touching one of the exported addresses
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedTransaction {
+    pub height: u32,
+    /// Where it is in its block
+    pub position: u32,
+    /// The transaction itself, hex encoded
+    pub tx: String,
+    /// Proves this transaction is in the block at `height`, hex encoded
+    pub merkle_block: String,
+    /// How many confirmations it had when exported, it's only informative, importing
+    /// doesn't use it
+    #[serde(default)]
+    pub confirmations: u32,
+    /// Our accumulator after its block, if asked for and we still had it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roots: Option<ExportedRoots>,
+}
+
+impl ExportedTransaction {
+    pub fn new(
+        transaction: &CachedTransaction,
+        body: &TransactionBody,
+        merkle_block: &MerkleBlock,
+        confirmations: u32,
+        roots: Option<ExportedRoots>,
+    ) -> ExportedTransaction {
+        ExportedTransaction {
+            height: transaction.height,
+            position: transaction.position,
+            tx: serialize_hex(&body.tx),
+            merkle_block: serialize_hex(merkle_block),
+            confirmations,
+            roots,
+        }
+    }
+    /// Parses this transaction and its proof, checking that the proof really commits to it,
+    /// at the position we were told.
+    pub fn decode(&self) -> Result<(Transaction, MerkleBlock), crate::error::Error> {
+        let tx = deserialize::<Transaction>(&Vec::from_hex(&self.tx)?)?;
+        let merkle_block = deserialize::<MerkleBlock>(&Vec::from_hex(&self.merkle_block)?)?;
+        if !proves_position(&merkle_block, &tx.txid(), self.position) {
+            return Err(crate::error::Error::InvalidProof);
+        }
+        Ok((tx, merkle_block))
+    }
+}
+
+/// One of the exported addresses
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedAddress {
+    pub script: Script,
+    /// Its address, in the format our operator chose, for whoever reads the export. Imports
+    /// only go by `script`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Its unspent outputs when it was exported. These follow from the history, we only
+    /// keep them to check the import against.
+    pub utxos: Vec<OutPoint>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WalletExport {
+    pub version: u32,
+    pub network: Network,
+    /// The history is complete up to this block
+    pub height: u32,
+    pub addresses: Vec<ExportedAddress>,
+    /// Every transaction touching one of our addresses, each once
+    pub transactions: Vec<ExportedTransaction>,
+    /// Our accumulator at `height`, if asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roots: Option<ExportedRoots>,
+}
+
+impl WalletExport {
+    /// Checks every proof in this export, without trusting whoever made it, and that the
+    /// blocks they are in are the ones a chain we trust has. `trusted` returns that chain's
+    /// header at a height. Returns those blocks.
+    pub fn verify(
+        &self,
+        params: &dyn ChainParams,
+        trusted: impl Fn(u32) -> Result<BlockHeader, crate::error::Error>,
+    ) -> Result<Vec<VerifiedBlock>, crate::error::Error> {
+        if self.network != params.network() {
+            return Err(crate::error::Error::WrongNetwork(self.network));
+        }
+        let mut blocks = Vec::<VerifiedBlock>::new();
+        for transaction in self.transactions.iter() {
+            let height = transaction.height;
+            let (_, merkle_block) = transaction.decode()?;
+            let header = merkle_block.header;
+            let invalid = |reason: &str| {
+                crate::error::Error::ConsensusError(format!("header at height {height} {reason}"))
+            };
+            let block_hash = check_work(params, &header).map_err(invalid)?;
+            match blocks.iter().find(|block| block.height == height) {
+                Some(block) if block.block_hash != block_hash => {
+                    return Err(crate::error::Error::InvalidProof)
+                }
+                Some(_) => continue,
+                None => {}
+            }
+            let ours = trusted(height)?;
+            if header.target() > ours.target() {
+                return Err(invalid(
+                    "claims less work than the block our chain has there",
+                ));
+            }
+            if block_hash != ours.block_hash() {
+                return Err(crate::error::Error::NotInOurChain(height));
+            }
+            blocks.push(VerifiedBlock {
+                height,
+                block_hash,
+                roots: transaction.roots.clone(),
+            });
+        }
+        blocks.sort_by_key(|block| block.height);
+        Ok(blocks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        blockdata::constants::genesis_block, consensus::encode::serialize_hex,
+        hashes::hex::FromHex, Block, MerkleBlock, Network, Script,
+    };
+
+    use super::{ExportedTransaction, WalletExport, WALLET_EXPORT_VERSION};
+    use crate::{address_cache::test::paying_block, blockchain::chain_params::BitcoinParams};
+
+    #[test]
+    fn test_verify() {
+        let script = Script::from_hex("0014275f567685bfe080e4789eaca36d9af30327abac").unwrap();
+        let (transaction, mut block, _) = paying_block(&script, 1_000);
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+        let txid = transaction.txid();
+        let export = |block: &Block| WalletExport {
+            version: WALLET_EXPORT_VERSION,
+            network: Network::Regtest,
+            height: 1,
+            addresses: vec![],
+            transactions: vec![ExportedTransaction {
+                height: 1,
+                position: 1,
+                tx: serialize_hex(&transaction),
+                merkle_block: serialize_hex(&MerkleBlock::from_block_with_predicate(block, |id| {
+                    *id == txid
+                })),
+                confirmations: 1,
+                roots: None,
+            }],
+            roots: None,
+        };
+
+        let blocks = export(&block)
+            .verify(&BitcoinParams::new(Network::Regtest), |_| Ok(block.header))
+            .unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].block_hash, block.block_hash());
+        // Valid work alone isn't enough, the block must be the one our chain has
+        let other = genesis_block(Network::Regtest).header;
+        assert!(matches!(
+            export(&block).verify(&BitcoinParams::new(Network::Regtest), |_| Ok(other)),
+            Err(crate::error::Error::NotInOurChain(1))
+        ));
+        assert!(matches!(
+            export(&block).verify(&BitcoinParams::new(Network::Bitcoin), |_| Ok(block.header)),
+            Err(crate::error::Error::WrongNetwork(Network::Regtest))
+        ));
+        // A target easier than our network allows is refused, whatever the hash
+        let mut easy = block.clone();
+        easy.header.bits = 0x2100ffff;
+        assert!(export(&easy)
+            .verify(&BitcoinParams::new(Network::Regtest), |_| Ok(easy.header))
+            .is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Tells external systems about things happening to our wallet. Each event goes to every
+//! configured transport: webhooks get a JSON POST, chat and email transports get a message
+//! rendered from a template. Calls are made from a separate thread, so a slow or unreachable
+//! endpoint never holds back our sync.
+
+use std::{
+    sync::mpsc::{channel, Sender},
+    time::Duration,
+};
+
+use bitcoin::{hashes::sha256, OutPoint, Txid};
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+
+/// How long we wait for an endpoint before giving up on an event
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WalletEvent {
+    /// One of our addresses received an output
+    Received {
+        outpoint: OutPoint,
+        script_hash: sha256::Hash,
+        value: u64,
+        height: u32,
+    },
+    /// One of our outputs got spent
+    Spent {
+        outpoint: OutPoint,
+        script_hash: sha256::Hash,
+        value: u64,
+        spending_txid: Txid,
+        height: u32,
+    },
+    /// A transaction we've broadcast got one of its inputs spent by another transaction
+    Conflicted { txid: Txid, conflicting_txid: Txid },
+    /// A transaction we've broadcast didn't confirm for too long, so we've stopped
+    /// rebroadcasting it
+    Dropped { txid: Txid },
+}
+
+/// Somewhere we can send wallet events to
+pub trait AlertTransport: Send {
+    /// Where this transport sends events, for our logs
+    fn name(&self) -> String;
+    fn send(&self, event: &WalletEvent) -> Result<(), String>;
+}
+
+/// POSTs each event, as JSON, to a URL
+pub struct Webhook(pub String);
+
+impl AlertTransport for Webhook {
+    fn name(&self) -> String {
+        format!("webhook {}", self.0)
+    }
+    fn send(&self, event: &WalletEvent) -> Result<(), String> {
+        ureq::post(&self.0)
+            .timeout(WEBHOOK_TIMEOUT)
+            .send_json(event)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Fills a template with an event's fields. `{name}` is replaced by the field called
+/// `name`, e.g. `{event}`, `{value}` or `{outpoint}`. Anything else is left as is.
+pub fn render(template: &str, event: &WalletEvent) -> String {
+    let fields = match serde_json::to_value(event) {
+        Ok(Value::Object(fields)) => fields,
+        _ => return template.to_string(),
+    };
+    fields
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            text.replace(&format!("{{{name}}}"), &value)
+        })
+}
+
+pub struct Alerts(Sender<WalletEvent>);
+
+impl Alerts {
+    pub fn new(transports: Vec<Box<dyn AlertTransport>>) -> Alerts {
+        let (sender, receiver) = channel::<WalletEvent>();
+        std::thread::spawn(move || {
+            for event in receiver {
+                for transport in transports.iter() {
+                    if let Err(err) = transport.send(&event) {
+                        warn!("Could not call {}: {err}", transport.name());
+                    }
+                }
+            }
+        });
+        Alerts(sender)
+    }
+    pub fn notify(&self, event: WalletEvent) {
+        let _ = self.0.send(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, WalletEvent};
+    use bitcoin::{hashes::hex::FromHex, Txid};
+
+    #[test]
+    fn test_render() {
+        let txid =
+            Txid::from_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap();
+        let event = WalletEvent::Dropped { txid };
+        assert_eq!(
+            render("{event}: {txid} {unknown}", &event),
+            format!("dropped: {txid} {{unknown}}")
+        );
+    }
+}
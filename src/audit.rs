@@ -0,0 +1,204 @@
+//! Cross-checks what we believe against a Bitcoin Core node, for debugging our utreexo
+//! integration. Every output our wallet thinks is unspent should be in Core's UTXO set, and
+//! each recent block should have grown our accumulator by as many leaves as the block, as
+//! Core sees it, creates. We only report what we disagree on, nothing is changed.
+
+use std::time::Duration;
+
+use bitcoin::{consensus::deserialize, hashes::hex::FromHex, Block, OutPoint};
+use log::{info, warn};
+use serde_json::{json, Value};
+
+/// How long we wait for Core to answer a call
+const CORE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What we believe at some block. It's taken on our main loop, and checked against Core
+/// elsewhere, since Core may take a while to answer
+pub struct AuditSnapshot {
+    pub height: u32,
+    /// Our confirmed unspent outputs, with the block each was confirmed in
+    pub utxos: Vec<(OutPoint, u32)>,
+    /// Recent blocks, with how many leaves our accumulator had before and after each
+    pub leaves: Vec<(u32, u64, u64)>,
+}
+
+/// A Bitcoin Core node, reached over its JSON-RPC interface
+pub struct CoreRpc {
+    url: String,
+    /// The value of our `Authorization` header
+    auth: String,
+}
+
+impl CoreRpc {
+    pub fn new(url: &str, user: &str, password: &str) -> CoreRpc {
+        CoreRpc {
+            url: url.to_string(),
+            auth: format!(
+                "Basic {}",
+                bitcoin::base64::encode(format!("{user}:{password}"))
+            ),
+        }
+    }
+    fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let response = ureq::post(&self.url)
+            .timeout(CORE_TIMEOUT)
+            .set("Authorization", &self.auth)
+            .send_json(json!({
+                "jsonrpc": "1.0",
+                "id": method,
+                "method": method,
+                "params": params
+            }))
+            .map_err(|err| match err {
+                ureq::Error::Status(status, _) => format!("{method} failed with {status}"),
+                ureq::Error::Transport(_) => "could not reach Bitcoin Core".to_string(),
+            })?
+            .into_json::<Value>()
+            .map_err(|err| format!("{method} returned an invalid response: {err}"))?;
+        Ok(response["result"].clone())
+    }
+    pub fn get_block_count(&self) -> Result<u32, String> {
+        serde_json::from_value(self.call("getblockcount", json!([]))?)
+            .map_err(|err| err.to_string())
+    }
+    /// Whether this output is in Core's UTXO set. Spends in its mempool don't count, since
+    /// we only audit confirmed state.
+    pub fn is_unspent(&self, outpoint: &OutPoint) -> Result<bool, String> {
+        let output = self.call(
+            "gettxout",
+            json!([outpoint.txid.to_string(), outpoint.vout, false]),
+        )?;
+        Ok(!output.is_null())
+    }
+    pub fn get_block(&self, height: u32) -> Result<Block, String> {
+        let hash = self.call("getblockhash", json!([height]))?;
+        let block = self.call("getblock", json!([hash, 0]))?;
+        let block = block
+            .as_str()
+            .ok_or_else(|| format!("getblock returned no block for height {height}"))?;
+        let block = Vec::<u8>::from_hex(block).map_err(|err| err.to_string())?;
+        deserialize(&block).map_err(|err| err.to_string())
+    }
+    /// Checks `snapshot` against what Core says, logging anything we disagree on
+    pub fn audit(&self, snapshot: AuditSnapshot) {
+        let height = snapshot.height;
+        let mut discrepancies = 0;
+        // If Core is at another height, outputs may be spent in a block only one of us has
+        match self.get_block_count() {
+            Ok(core_height) if core_height == height => {
+                for (outpoint, confirmed) in snapshot.utxos {
+                    match self.is_unspent(&outpoint) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            warn!(
+                                "Audit: {outpoint}, confirmed at {confirmed}, is unspent for us \
+                                 but not in Bitcoin Core's UTXO set"
+                            );
+                            discrepancies += 1;
+                        }
+                        Err(err) => {
+                            warn!("Audit: {err}");
+                            return;
+                        }
+                    }
+                }
+            }
+            Ok(core_height) => info!(
+                "Audit: Bitcoin Core is at block {core_height} and we are at {height}, not \
+                 checking our unspent outputs"
+            ),
+            Err(err) => {
+                warn!("Audit: {err}");
+                return;
+            }
+        }
+        for (block_height, before, after) in snapshot.leaves {
+            let expected = match self.get_block(block_height) {
+                Ok(block) => count_new_leaves(&block),
+                Err(err) => {
+                    warn!("Audit: {err}");
+                    return;
+                }
+            };
+            if after != before + expected {
+                warn!(
+                    "Audit: block {block_height} took our accumulator from {before} to {after} \
+                     leaves, but Bitcoin Core's block creates {expected}"
+                );
+                discrepancies += 1;
+            }
+        }
+        match discrepancies {
+            0 => info!("Audit: we agree with Bitcoin Core at block {height}"),
+            n => warn!("Audit: found {n} discrepancies with Bitcoin Core at block {height}"),
+        }
+    }
+}
+
+/// How many leaves `block` adds to a utreexo accumulator: the outputs it creates, except
+/// unspendable ones and ones spent in the same block. Counted here on its own, and not by the
+/// code building our accumulator, so a bug there shows up as a discrepancy.
+pub fn count_new_leaves(block: &Block) -> u64 {
+    let spent = block
+        .txdata
+        .iter()
+        .flat_map(|transaction| transaction.input.iter())
+        .map(|input| input.previous_output)
+        .collect::<std::collections::HashSet<_>>();
+    block
+        .txdata
+        .iter()
+        .flat_map(|transaction| {
+            let txid = transaction.txid();
+            transaction
+                .output
+                .iter()
+                .enumerate()
+                .map(move |(vout, output)| (OutPoint::new(txid, vout as u32), output))
+        })
+        .filter(|(outpoint, output)| {
+            !output.script_pubkey.is_provably_unspendable() && !spent.contains(outpoint)
+        })
+        .count() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        blockdata::{constants::genesis_block, script::Builder},
+        Network, OutPoint, PackedLockTime, Transaction, TxIn, TxOut,
+    };
+
+    use super::count_new_leaves;
+
+    #[test]
+    fn test_count_new_leaves() {
+        let mut block = genesis_block(Network::Regtest);
+        assert_eq!(count_new_leaves(&block), 1);
+
+        let coinbase = block.txdata[0].clone();
+        let spend = Transaction {
+            version: 2,
+            lock_time: PackedLockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(coinbase.txid(), 0),
+                ..TxIn::default()
+            }],
+            output: vec![
+                TxOut {
+                    value: 1,
+                    script_pubkey: coinbase.output[0].script_pubkey.clone(),
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: Builder::new()
+                        .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+                        .into_script(),
+                },
+            ],
+        };
+        block.txdata.push(spend);
+        // The coinbase output is spent in its own block, and the OP_RETURN is unspendable
+        assert_eq!(count_new_leaves(&block), 1);
+    }
+}
@@ -0,0 +1,204 @@
+//! Everything that changes from one chain to another. Sync and protocol code only talks to
+//! [ChainParams], so following a different chain only takes a new implementation of it.
+
+use std::str::FromStr;
+
+use bitcoin::{
+    bitcoinconsensus,
+    blockdata::constants::{genesis_block, COIN_VALUE},
+    consensus::params::Params,
+    util::uint::Uint256,
+    BlockHash, Network,
+};
+
+pub trait ChainParams: Send + Sync {
+    /// The network our addresses are encoded for
+    fn network(&self) -> Network;
+    /// The hash of the first block in this chain
+    fn genesis_hash(&self) -> BlockHash;
+    /// The easiest target a block may have
+    fn pow_limit(&self) -> Uint256;
+    /// How many blocks share a target
+    fn retarget_interval(&self) -> u32;
+    /// How many seconds we want between two blocks
+    fn pow_target_spacing(&self) -> u64;
+    /// Whether a block may drop to the lowest difficulty, when none was found for twice
+    /// the block interval
+    fn allow_min_difficulty_blocks(&self) -> bool;
+    /// Whether every block keeps the target of the one before it
+    fn no_pow_retargeting(&self) -> bool;
+    /// The height BIP16 (P2SH) is enforced from
+    fn bip16_height(&self) -> u32;
+    /// The height BIP66 (strict DER signatures) is enforced from
+    fn bip66_height(&self) -> u32;
+    /// The height BIP65 (CHECKLOCKTIMEVERIFY) is enforced from
+    fn bip65_height(&self) -> u32;
+    /// The height BIP112 (CHECKSEQUENCEVERIFY) is enforced from
+    fn csv_height(&self) -> u32;
+    /// The height segwit, and with it BIP147 (NULLDUMMY), is enforced from
+    fn segwit_height(&self) -> u32;
+    /// A block we know to be valid, and its height. Scripts in it and in the blocks before
+    /// it aren't checked, as long as it is the block our node has at that height.
+    fn assume_valid(&self) -> Option<(u32, BlockHash)>;
+    /// How many blocks a coinbase output must wait before it can be spent
+    fn coinbase_maturity(&self) -> u32;
+    /// How many new coins a block at `height` may create
+    fn block_subsidy(&self, height: u32) -> u64;
+    /// How many seconds a retarget window should take
+    fn pow_target_timespan(&self) -> u64 {
+        self.retarget_interval() as u64 * self.pow_target_spacing()
+    }
+    /// The script rules in force at `height`, as `libbitcoinconsensus` flags
+    fn script_flags(&self, height: u32) -> u32 {
+        [
+            (self.bip16_height(), bitcoinconsensus::VERIFY_P2SH),
+            (self.bip66_height(), bitcoinconsensus::VERIFY_DERSIG),
+            (
+                self.bip65_height(),
+                bitcoinconsensus::VERIFY_CHECKLOCKTIMEVERIFY,
+            ),
+            (
+                self.csv_height(),
+                bitcoinconsensus::VERIFY_CHECKSEQUENCEVERIFY,
+            ),
+            (self.segwit_height(), bitcoinconsensus::VERIFY_WITNESS),
+            (self.segwit_height(), bitcoinconsensus::VERIFY_NULLDUMMY),
+        ]
+        .into_iter()
+        .filter(|(activation, _)| height >= *activation)
+        .fold(bitcoinconsensus::VERIFY_NONE, |flags, (_, flag)| {
+            flags | flag
+        })
+    }
+}
+
+/// Bitcoin and its test networks
+pub struct BitcoinParams {
+    network: Network,
+    params: Params,
+}
+
+impl BitcoinParams {
+    pub fn new(network: Network) -> BitcoinParams {
+        BitcoinParams {
+            network,
+            params: Params::new(network),
+        }
+    }
+}
+
+impl ChainParams for BitcoinParams {
+    fn network(&self) -> Network {
+        self.network
+    }
+    fn genesis_hash(&self) -> BlockHash {
+        genesis_block(self.network).block_hash()
+    }
+    fn pow_limit(&self) -> Uint256 {
+        self.params.pow_limit
+    }
+    fn retarget_interval(&self) -> u32 {
+        (self.params.pow_target_timespan / self.params.pow_target_spacing) as u32
+    }
+    fn pow_target_spacing(&self) -> u64 {
+        self.params.pow_target_spacing
+    }
+    fn allow_min_difficulty_blocks(&self) -> bool {
+        self.params.allow_min_difficulty_blocks
+    }
+    fn no_pow_retargeting(&self) -> bool {
+        self.params.no_pow_retargeting
+    }
+    fn bip16_height(&self) -> u32 {
+        // BIP16 activated by time, these are the first blocks past it
+        match self.network {
+            Network::Bitcoin => 173_805,
+            Network::Testnet => 514,
+            _ => 0,
+        }
+    }
+    fn bip66_height(&self) -> u32 {
+        self.params.bip66_height
+    }
+    fn bip65_height(&self) -> u32 {
+        self.params.bip65_height
+    }
+    fn csv_height(&self) -> u32 {
+        match self.network {
+            Network::Bitcoin => 419_328,
+            Network::Testnet => 770_112,
+            Network::Signet => 1,
+            Network::Regtest => 432,
+        }
+    }
+    fn segwit_height(&self) -> u32 {
+        match self.network {
+            Network::Bitcoin => 481_824,
+            Network::Testnet => 834_624,
+            Network::Signet => 1,
+            Network::Regtest => 0,
+        }
+    }
+    fn assume_valid(&self) -> Option<(u32, BlockHash)> {
+        let (height, hash) = match self.network {
+            Network::Bitcoin => (
+                724_466,
+                "000000000000000000052d314a259755ca65944e68df6b12a067ea8f1f5a7091",
+            ),
+            Network::Testnet => (
+                2_344_474,
+                "0000000000004ae2f3896ca8ecd41c460a35bf6184e145d91558cece1c688a76",
+            ),
+            Network::Signet => (
+                47_200,
+                "000000187d4440e5bff91488b700a140441e089a8aaea707414982460edbfe54",
+            ),
+            Network::Regtest => return None,
+        };
+        Some((height, BlockHash::from_str(hash).expect("Valid block hash")))
+    }
+    fn coinbase_maturity(&self) -> u32 {
+        100
+    }
+    fn block_subsidy(&self, height: u32) -> u64 {
+        let halving_interval = match self.network {
+            Network::Regtest => 150,
+            _ => 210_000,
+        };
+        let halvings = height / halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        (50 * COIN_VALUE) >> halvings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{bitcoinconsensus, Network};
+
+    use super::{BitcoinParams, ChainParams};
+
+    #[test]
+    fn test_script_flags() {
+        let params = BitcoinParams::new(Network::Bitcoin);
+        assert_eq!(params.retarget_interval(), 2016);
+        assert_eq!(params.pow_target_timespan(), 14 * 24 * 60 * 60);
+        assert_eq!(params.script_flags(0), bitcoinconsensus::VERIFY_NONE);
+        assert_eq!(params.script_flags(200_000), bitcoinconsensus::VERIFY_P2SH);
+        assert_eq!(
+            params.script_flags(400_000),
+            bitcoinconsensus::VERIFY_P2SH
+                | bitcoinconsensus::VERIFY_DERSIG
+                | bitcoinconsensus::VERIFY_CHECKLOCKTIMEVERIFY
+        );
+        assert_eq!(params.script_flags(800_000), bitcoinconsensus::VERIFY_ALL);
+        // Segwit is active from the start on regtest
+        let regtest = BitcoinParams::new(Network::Regtest);
+        assert_ne!(
+            regtest.script_flags(0) & bitcoinconsensus::VERIFY_WITNESS,
+            0
+        );
+        assert!(regtest.assume_valid().is_none());
+    }
+}
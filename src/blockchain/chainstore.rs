@@ -3,35 +3,457 @@
 //! This is a basic kv database that stores all metadata about our blockchain and utreexo
 //! state.
 //! Author: Davidson Souza
+//!
+//! Every method takes `&self`, and stores must be `Send + Sync`, so the sync pipeline and
+//! Electrum handlers can share one. Writes aren't flushed to disk one by one, but together
+//! with [ChainStore::flush], once per block. Writes to many keys at once, like pruning a
+//! range of heights, go in a single batch.
 
-use kv::{Config, Store};
-pub trait ChainStore {
+use std::{num::NonZeroUsize, path::Path, sync::RwLock};
+
+use kv::{Batch, Bucket, Config, Store};
+
+use super::stored::StoredTip;
+use crate::sharded::ShardedLru;
+
+/// How many of the latest accumulator snapshots we keep in memory
+const RECENT_ROOTS: usize = 64;
+
+pub trait ChainStore: Send + Sync {
+    /// Writes everything saved so far to disk
+    fn flush(&self) -> Result<(), kv::Error>;
     /// Saves the current state of our accumulator.
     fn save_roots(&self, roots: String) -> Result<(), kv::Error>;
     /// Loads the state of our accumulator.
     fn load_roots(&self) -> Result<Option<String>, kv::Error>;
+    /// Saves the state of our accumulator after processing the block at `height`.
+    fn save_roots_at(&self, height: u32, roots: String) -> Result<(), kv::Error>;
+    /// Loads the state of our accumulator after processing the block at `height`, if
+    /// we still have it.
+    fn load_roots_at(&self, height: u32) -> Result<Option<String>, kv::Error>;
+    /// Deletes the accumulator state saved for `height`.
+    fn delete_roots_at(&self, height: u32) -> Result<(), kv::Error>;
+    /// Saves how many leaves our accumulator had after processing the block at `height`.
+    fn save_leaf_count(&self, height: u32, leaves: u64) -> Result<(), kv::Error>;
+    /// Loads how many leaves our accumulator had after processing the block at `height`.
+    fn load_leaf_count(&self, height: u32) -> Result<Option<u64>, kv::Error>;
+    /// Deletes the leaf count saved for `height`.
+    fn delete_leaf_count(&self, height: u32) -> Result<(), kv::Error>;
+    /// Saves the block log entry for `height`.
+    fn save_block_log(&self, height: u32, entry: String) -> Result<(), kv::Error>;
+    /// Loads the block log entry for `height`, if we still have it.
+    fn load_block_log(&self, height: u32) -> Result<Option<String>, kv::Error>;
+    /// Deletes the block log entries for every height below `height`.
+    fn delete_block_log_before(&self, height: u32) -> Result<(), kv::Error>;
+    /// Saves the utreexo proof for the block at `height`.
+    fn save_block_proof(&self, height: u32, proof: String) -> Result<(), kv::Error>;
+    /// Loads the utreexo proof for the block at `height`, if we still have it.
+    fn load_block_proof(&self, height: u32) -> Result<Option<String>, kv::Error>;
+    /// Deletes the utreexo proof saved for `height`.
+    fn delete_block_proof(&self, height: u32) -> Result<(), kv::Error>;
+    /// Saves the OP_RETURN outputs matching our watch rules in the block at `height`.
+    fn save_op_return_matches(&self, height: u32, matches: String) -> Result<(), kv::Error>;
+    /// Loads the OP_RETURN outputs matching our watch rules in the block at `height`.
+    fn load_op_return_matches(&self, height: u32) -> Result<Option<String>, kv::Error>;
+    /// Deletes the OP_RETURN outputs saved for `height`.
+    fn delete_op_return_matches(&self, height: u32) -> Result<(), kv::Error>;
+    /// Saves a silent payment we've found, keyed by its outpoint.
+    fn save_silent_payment(&self, outpoint: String, payment: String) -> Result<(), kv::Error>;
+    /// Loads every silent payment we've found.
+    fn load_silent_payments(&self) -> Result<Vec<String>, kv::Error>;
+    /// Saves our wallet balance after the block at `height`.
+    fn save_balance(&self, height: u32, balance: u64) -> Result<(), kv::Error>;
+    /// Deletes the wallet balances saved for `height` and the blocks after it.
+    fn delete_balances_from(&self, height: u32) -> Result<(), kv::Error>;
+    /// Loads the wallet balances saved for blocks from `from` to `to`, by height.
+    fn load_balances(&self, from: u32, to: u32) -> Result<Vec<(u32, u64)>, kv::Error>;
+    /// Saves the header of the last block we processed, and its height.
+    fn save_tip_header(&self, height: u32, header: String) -> Result<(), kv::Error>;
+    /// Loads the header of the last block we processed, and its height.
+    fn load_tip_header(&self) -> Result<Option<(u32, String)>, kv::Error>;
 }
 
-pub struct KvChainStore(Store);
+pub struct KvChainStore {
+    store: Store,
+    /// Our current accumulator, and the last block we processed. Read for most requests,
+    /// so they never go to disk
+    roots: RwLock<Option<String>>,
+    tip: RwLock<Option<(u32, String)>>,
+    /// Our latest accumulator snapshots, by height
+    recent_roots: ShardedLru<u32, String>,
+}
 impl KvChainStore {
     pub fn new(datadir: String) -> Result<KvChainStore, kv::Error> {
         // Configure the database
-        let cfg = Config::new(datadir + "/chaindata");
+        let cfg = Config::new(Path::new(&datadir).join("chaindata"));
 
         // Open the key/value store
         let store = Store::new(cfg)?;
-        Ok(KvChainStore(store))
+        let roots = store
+            .bucket::<&str, String>(Some("addresses"))?
+            .get(&"roots")?;
+        let tip = store.bucket::<&str, String>(Some("tip"))?.get(&"header")?;
+        migrate_balances(&store)?;
+        migrate_block_log(&store)?;
+        Ok(KvChainStore {
+            store,
+            roots: RwLock::new(roots),
+            tip: RwLock::new(tip.and_then(|tip| parse_tip(&tip))),
+            recent_roots: ShardedLru::new(
+                NonZeroUsize::new(RECENT_ROOTS).expect("Cache size is not zero"),
+            ),
+        })
+    }
+}
+/// The key of what we save for `height`, in buckets we read by range. Heights are padded, so
+/// keys sort like the heights they're for and a range of them can be read without going
+/// through the others.
+fn height_key(height: u64) -> String {
+    format!("{height:010}")
+}
+/// Removes the keys `bucket` has from `from` up to, but not including, `to`, in one batch
+fn remove_range(
+    bucket: &Bucket<'_, String, String>,
+    from: String,
+    to: String,
+) -> Result<(), kv::Error> {
+    let mut batch = Batch::new();
+    for item in bucket.iter_range(from, to) {
+        batch.remove(&item?.key::<String>()?)?;
     }
+    bucket.batch(batch)
+}
+/// Older versions saved balances in `balance_history`, under unpadded heights. They're moved
+/// to `balances` the first time we open the store.
+fn migrate_balances(store: &Store) -> Result<(), kv::Error> {
+    let legacy = store.bucket::<String, String>(Some("balance_history"))?;
+    let balances = store.bucket::<String, String>(Some("balances"))?;
+    let mut batch = Batch::new();
+    for item in legacy.iter() {
+        let item = item?;
+        if let Ok(height) = item.key::<String>()?.parse::<u64>() {
+            batch.set(&height_key(height), &item.value::<String>()?)?;
+        }
+    }
+    balances.batch(batch)?;
+    legacy.clear()?;
+    Ok(())
+}
+/// Older versions keyed our block log by unpadded heights, which can't be pruned by range.
+/// They're keyed again the first time we open the store.
+fn migrate_block_log(store: &Store) -> Result<(), kv::Error> {
+    let bucket = store.bucket::<String, String>(Some("block_log"))?;
+    let mut batch = Batch::new();
+    for item in bucket.iter() {
+        let item = item?;
+        let key = item.key::<String>()?;
+        if key.len() == height_key(0).len() {
+            continue;
+        }
+        if let Ok(height) = key.parse::<u64>() {
+            batch.set(&height_key(height), &item.value::<String>()?)?;
+        }
+        batch.remove(&key)?;
+    }
+    bucket.batch(batch)
+}
+/// Reads a [StoredTip], or what older versions wrote, `height:header`
+fn parse_tip(tip: &str) -> Option<(u32, String)> {
+    if let Ok(tip) = serde_json::from_str::<StoredTip>(tip) {
+        return Some((tip.height, tip.header));
+    }
+    let (height, header) = tip.split_once(':')?;
+    Some((height.parse().ok()?, header.to_string()))
 }
 impl ChainStore for KvChainStore {
+    fn flush(&self) -> Result<(), kv::Error> {
+        // Flushing any bucket writes the whole store
+        self.store
+            .bucket::<&str, String>(Some("addresses"))?
+            .flush()?;
+        Ok(())
+    }
     fn load_roots(&self) -> Result<Option<String>, kv::Error> {
-        let bucket = self.0.bucket::<&str, String>(Some("addresses"))?;
-        bucket.get(&"roots")
+        Ok(self.roots.read().expect("Poisoned lock").clone())
     }
     fn save_roots(&self, roots: String) -> Result<(), kv::Error> {
-        let bucket = self.0.bucket::<&str, String>(Some("addresses"))?;
+        let bucket = self.store.bucket::<&str, String>(Some("addresses"))?;
         bucket.set(&"roots", &roots)?;
-        bucket.flush()?;
+        *self.roots.write().expect("Poisoned lock") = Some(roots);
+        Ok(())
+    }
+    fn save_roots_at(&self, height: u32, roots: String) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("roots_history"))?;
+        bucket.set(&height.to_string(), &roots)?;
+        self.recent_roots.put(height, roots);
+        Ok(())
+    }
+    fn load_roots_at(&self, height: u32) -> Result<Option<String>, kv::Error> {
+        if let Some(roots) = self.recent_roots.get(&height) {
+            return Ok(Some(roots));
+        }
+        let bucket = self.store.bucket::<String, String>(Some("roots_history"))?;
+        bucket.get(&height.to_string())
+    }
+    fn delete_roots_at(&self, height: u32) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("roots_history"))?;
+        bucket.remove(&height.to_string())?;
+        self.recent_roots.pop(&height);
+        Ok(())
+    }
+    fn save_leaf_count(&self, height: u32, leaves: u64) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("leaf_counts"))?;
+        let leaves = serde_json::to_string(&leaves).expect("Numbers are always serializable");
+        bucket.set(&height.to_string(), &leaves)?;
+        Ok(())
+    }
+    fn load_leaf_count(&self, height: u32) -> Result<Option<u64>, kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("leaf_counts"))?;
+        let leaves = bucket.get(&height.to_string())?;
+        Ok(leaves.and_then(|leaves| serde_json::from_str(&leaves).ok()))
+    }
+    fn delete_leaf_count(&self, height: u32) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("leaf_counts"))?;
+        bucket.remove(&height.to_string())?;
         Ok(())
     }
+    fn save_block_log(&self, height: u32, entry: String) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("block_log"))?;
+        bucket.set(&height_key(height as u64), &entry)?;
+        Ok(())
+    }
+    fn load_block_log(&self, height: u32) -> Result<Option<String>, kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("block_log"))?;
+        bucket.get(&height_key(height as u64))
+    }
+    fn delete_block_log_before(&self, height: u32) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("block_log"))?;
+        remove_range(&bucket, height_key(0), height_key(height as u64))
+    }
+    fn save_block_proof(&self, height: u32, proof: String) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("block_proofs"))?;
+        bucket.set(&height.to_string(), &proof)?;
+        Ok(())
+    }
+    fn load_block_proof(&self, height: u32) -> Result<Option<String>, kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("block_proofs"))?;
+        bucket.get(&height.to_string())
+    }
+    fn delete_block_proof(&self, height: u32) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("block_proofs"))?;
+        bucket.remove(&height.to_string())?;
+        Ok(())
+    }
+    fn save_op_return_matches(&self, height: u32, matches: String) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("op_return"))?;
+        bucket.set(&height.to_string(), &matches)?;
+        Ok(())
+    }
+    fn load_op_return_matches(&self, height: u32) -> Result<Option<String>, kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("op_return"))?;
+        bucket.get(&height.to_string())
+    }
+    fn delete_op_return_matches(&self, height: u32) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("op_return"))?;
+        bucket.remove(&height.to_string())?;
+        Ok(())
+    }
+    fn save_silent_payment(&self, outpoint: String, payment: String) -> Result<(), kv::Error> {
+        let bucket = self
+            .store
+            .bucket::<String, String>(Some("silent_payments"))?;
+        bucket.set(&outpoint, &payment)?;
+        Ok(())
+    }
+    fn load_silent_payments(&self) -> Result<Vec<String>, kv::Error> {
+        let bucket = self
+            .store
+            .bucket::<String, String>(Some("silent_payments"))?;
+        bucket.iter().map(|item| item?.value::<String>()).collect()
+    }
+    fn save_balance(&self, height: u32, balance: u64) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("balances"))?;
+        let balance = serde_json::to_string(&balance).expect("Numbers are always serializable");
+        bucket.set(&height_key(height as u64), &balance)?;
+        Ok(())
+    }
+    fn delete_balances_from(&self, height: u32) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("balances"))?;
+        remove_range(
+            &bucket,
+            height_key(height as u64),
+            height_key(u32::MAX as u64 + 1),
+        )
+    }
+    fn load_balances(&self, from: u32, to: u32) -> Result<Vec<(u32, u64)>, kv::Error> {
+        let bucket = self.store.bucket::<String, String>(Some("balances"))?;
+        let mut balances = vec![];
+        let range = bucket.iter_range(height_key(from as u64), height_key(to as u64 + 1));
+        for item in range {
+            let item = item?;
+            let (height, balance) = (item.key::<String>()?, item.value::<String>()?);
+            if let (Ok(height), Ok(balance)) = (height.parse(), serde_json::from_str(&balance)) {
+                balances.push((height, balance));
+            }
+        }
+        Ok(balances)
+    }
+    fn save_tip_header(&self, height: u32, header: String) -> Result<(), kv::Error> {
+        let bucket = self.store.bucket::<&str, String>(Some("tip"))?;
+        let stored = serde_json::to_string(&StoredTip {
+            height,
+            header: header.clone(),
+        })
+        .expect("Tips are always serializable");
+        bucket.set(&"header", &stored)?;
+        *self.tip.write().expect("Poisoned lock") = Some((height, header));
+        Ok(())
+    }
+    fn load_tip_header(&self) -> Result<Option<(u32, String)>, kv::Error> {
+        Ok(self.tip.read().expect("Poisoned lock").clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChainStore, KvChainStore};
+
+    #[test]
+    fn test_legacy_tip() {
+        let dir = "/tmp/utreexo_legacy_tip/";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            // Older versions wrote `height:header`
+            chain_store
+                .store
+                .bucket::<&str, String>(Some("tip"))
+                .unwrap()
+                .set(&"header", &"5:abcd".to_string())
+                .unwrap();
+            chain_store.flush().unwrap();
+        }
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        let tip = Some((5, "abcd".to_string()));
+        assert_eq!(chain_store.load_tip_header().unwrap(), tip);
+        // It's saved as a record from then on
+        chain_store.save_tip_header(5, "abcd".into()).unwrap();
+        let stored = chain_store
+            .store
+            .bucket::<&str, String>(Some("tip"))
+            .unwrap()
+            .get(&"header")
+            .unwrap()
+            .unwrap();
+        assert!(stored.starts_with('{'));
+        chain_store.flush().unwrap();
+        drop(chain_store);
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        assert_eq!(chain_store.load_tip_header().unwrap(), tip);
+    }
+    #[test]
+    fn test_balances() {
+        let dir = "/tmp/utreexo_balances/";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            // Older versions keyed them by unpadded heights, in another bucket
+            let legacy = chain_store
+                .store
+                .bucket::<String, String>(Some("balance_history"))
+                .unwrap();
+            legacy.set(&"9".to_string(), &"900".to_string()).unwrap();
+            legacy.set(&"10".to_string(), &"1000".to_string()).unwrap();
+            chain_store.flush().unwrap();
+        }
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        assert_eq!(
+            chain_store.load_balances(0, 100).unwrap(),
+            vec![(9, 900), (10, 1000)]
+        );
+        chain_store.save_balance(100, 1).unwrap();
+        chain_store.save_balance(1_000, 2).unwrap();
+        // Ranges include both ends, and go by height rather than by how the keys spell it
+        assert_eq!(
+            chain_store.load_balances(10, 100).unwrap(),
+            vec![(10, 1000), (100, 1)]
+        );
+        assert_eq!(chain_store.load_balances(0, u32::MAX).unwrap().len(), 4);
+        chain_store.delete_balances_from(100).unwrap();
+        assert_eq!(
+            chain_store.load_balances(0, u32::MAX).unwrap(),
+            vec![(9, 900), (10, 1000)]
+        );
+    }
+    #[test]
+    fn test_cache_coherence() {
+        let dir = "/tmp/utreexo_chain_store_cache/";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            chain_store.save_roots("tip roots".into()).unwrap();
+            chain_store.save_tip_header(7, "header".into()).unwrap();
+            chain_store.save_roots_at(6, "old roots".into()).unwrap();
+            chain_store.save_roots_at(7, "new roots".into()).unwrap();
+            assert_eq!(
+                chain_store.load_roots_at(6).unwrap(),
+                Some("old roots".to_string())
+            );
+            // A deleted snapshot is gone from our cache too, not only from disk
+            chain_store.delete_roots_at(6).unwrap();
+            assert_eq!(chain_store.load_roots_at(6).unwrap(), None);
+            // Saving again replaces what we cached
+            chain_store.save_roots_at(7, "replaced".into()).unwrap();
+            assert_eq!(
+                chain_store.load_roots_at(7).unwrap(),
+                Some("replaced".to_string())
+            );
+            chain_store.flush().unwrap();
+        }
+        // What we read from our cache is what's on disk
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        assert_eq!(
+            chain_store.load_roots().unwrap(),
+            Some("tip roots".to_string())
+        );
+        assert_eq!(
+            chain_store.load_tip_header().unwrap(),
+            Some((7, "header".to_string()))
+        );
+        assert_eq!(chain_store.load_roots_at(6).unwrap(), None);
+        assert_eq!(
+            chain_store.load_roots_at(7).unwrap(),
+            Some("replaced".to_string())
+        );
+    }
+    #[test]
+    fn test_block_log() {
+        let dir = "/tmp/utreexo_block_log_store/";
+        let _ = std::fs::remove_dir_all(dir);
+        {
+            let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+            // Older versions keyed entries by unpadded heights
+            let legacy = chain_store
+                .store
+                .bucket::<String, String>(Some("block_log"))
+                .unwrap();
+            legacy.set(&"9".to_string(), &"nine".to_string()).unwrap();
+            legacy.set(&"10".to_string(), &"ten".to_string()).unwrap();
+            chain_store.flush().unwrap();
+        }
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        assert_eq!(
+            chain_store.load_block_log(9).unwrap(),
+            Some("nine".to_string())
+        );
+        chain_store.save_block_log(100, "hundred".into()).unwrap();
+        // Everything below goes at once, whatever heights were skipped
+        chain_store.delete_block_log_before(100).unwrap();
+        assert_eq!(chain_store.load_block_log(9).unwrap(), None);
+        assert_eq!(chain_store.load_block_log(10).unwrap(), None);
+        assert_eq!(
+            chain_store.load_block_log(100).unwrap(),
+            Some("hundred".to_string())
+        );
+    }
 }
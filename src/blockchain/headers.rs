@@ -0,0 +1,260 @@
+//! The headers of the blocks we've processed, so clients asking for headers don't have to
+//! wait for our node. Each header is an 80-byte record in a file, at `height * 80`, so
+//! finding one is a single read and the file never needs an index. Blocks we skipped, like
+//! the ones before a chainstate we've loaded, are left as holes, which most filesystems
+//! don't even store.
+//!
+//! Difficulty is checked a retarget window at a time, with the rules [ChainParams] gives us:
+//! once the last header of a window is saved, we check the whole window links up, and has the
+//! target that follows from the window before it. Test networks may mine a block at the lowest difficulty when none
+//! was found for 20 minutes, which is allowed too. A window that fails is reported, and
+//! fetched again from our node with [HeaderStore::refetch_window].
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use bitcoin::{
+    consensus::{deserialize, serialize},
+    util::uint::Uint256,
+    BlockHash, BlockHeader,
+};
+
+use super::chain_params::ChainParams;
+use crate::error::Error;
+
+const HEADER_SIZE: u64 = 80;
+
+pub struct HeaderStore {
+    file: Mutex<File>,
+    params: Box<dyn ChainParams>,
+}
+
+impl HeaderStore {
+    pub fn open(path: &Path, params: Box<dyn ChainParams>) -> Result<HeaderStore, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        Ok(HeaderStore {
+            file: Mutex::new(file),
+            params,
+        })
+    }
+    /// Saves the header of the block at `height`, replacing whatever we had there. If it
+    /// completes a retarget window, the window is checked.
+    pub fn save(&self, height: u32, header: &BlockHeader) -> Result<(), Error> {
+        self.write(height, header)?;
+        let interval = self.params.retarget_interval();
+        if (height + 1) % interval == 0 {
+            self.check_window(height + 1 - interval)?;
+        }
+        Ok(())
+    }
+    /// Replaces the headers we have in the window starting at `start`, and in the one before
+    /// it, with the ones `fetch` returns, then checks the window again
+    pub fn refetch_window(
+        &self,
+        start: u32,
+        fetch: impl Fn(u32) -> Result<BlockHeader, Error>,
+    ) -> Result<(), Error> {
+        let interval = self.params.retarget_interval();
+        for height in start.saturating_sub(interval)..start + interval {
+            if self.get(height)?.is_some() {
+                self.write(height, &fetch(height)?)?;
+            }
+        }
+        self.check_window(start)
+    }
+    fn write(&self, height: u32, header: &BlockHeader) -> Result<(), Error> {
+        let mut file = self.file.lock().expect("Poisoned lock");
+        file.seek(SeekFrom::Start(height as u64 * HEADER_SIZE))?;
+        file.write_all(&serialize(header))?;
+        Ok(())
+    }
+    /// The header of the block at `height`, if we have it
+    pub fn get(&self, height: u32) -> Result<Option<BlockHeader>, Error> {
+        let mut file = self.file.lock().expect("Poisoned lock");
+        let offset = height as u64 * HEADER_SIZE;
+        if offset + HEADER_SIZE > file.metadata()?.len() {
+            return Ok(None);
+        }
+        let mut record = [0; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut record)?;
+        if record.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+        Ok(Some(deserialize(&record)?))
+    }
+    /// The headers from `start` to `end`, not included, if we have every one of them
+    fn get_range(&self, start: u32, end: u32) -> Result<Option<Vec<BlockHeader>>, Error> {
+        let headers = (start..end)
+            .map(|height| self.get(height))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(headers.into_iter().collect())
+    }
+    /// Checks the retarget window starting at `start`. Windows we only have part of can't
+    /// be checked, so they are skipped. Without the window before it, we can't tell which
+    /// target this one should have, so we only check it keeps the same one.
+    fn check_window(&self, start: u32) -> Result<(), Error> {
+        let params = &*self.params;
+        let interval = params.retarget_interval();
+        let headers = match self.get_range(start, start + interval)? {
+            Some(headers) => headers,
+            None => return Ok(()),
+        };
+        let invalid = |height: u32, reason: &str| {
+            Error::InvalidHeaders(start, format!("header at height {height} {reason}"))
+        };
+        let previous = match start {
+            0 => None,
+            start => self.get(start - 1)?,
+        };
+        let min_bits = BlockHeader::compact_target_from_u256(&params.pow_limit());
+        let expected = match start {
+            // The genesis block has the lowest difficulty on every network
+            0 => Some(min_bits),
+            _ if params.no_pow_retargeting() => previous.map(|last| last.bits),
+            _ => match (self.get(start - interval)?, previous) {
+                (Some(first), Some(last)) => {
+                    Some(next_bits(params, first.time, last.time, last.bits))
+                }
+                _ => None,
+            },
+        };
+        let window_bits = expected.unwrap_or_else(|| {
+            headers
+                .iter()
+                .map(|header| header.bits)
+                .find(|bits| !params.allow_min_difficulty_blocks() || *bits != min_bits)
+                .unwrap_or(headers[0].bits)
+        });
+        for (n, header) in headers.iter().enumerate() {
+            let height = start + n as u32;
+            let parent = match n {
+                0 => previous,
+                n => Some(headers[n - 1]),
+            };
+            if let Some(parent) = parent {
+                if header.prev_blockhash != parent.block_hash() {
+                    return Err(invalid(height, "doesn't follow the one before it"));
+                }
+            }
+            check_work(params, header).map_err(|reason| invalid(height, reason))?;
+            // Test networks let a block drop to the lowest difficulty, if the one before it
+            // is more than twice the block interval older
+            let min_difficulty = params.allow_min_difficulty_blocks()
+                && header.bits == min_bits
+                && parent.map_or(true, |parent| {
+                    header.time as u64 > parent.time as u64 + 2 * params.pow_target_spacing()
+                });
+            if header.bits != window_bits && !min_difficulty {
+                return Err(invalid(height, "doesn't have the target its window should"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks `header` has the work its bits claim, and that those bits are a target our network
+/// allows. Bits asking for little work make this cheap to meet, so the target must also be
+/// checked against what its chain expects. Returns the header's hash.
+pub fn check_work(
+    params: &dyn ChainParams,
+    header: &BlockHeader,
+) -> Result<BlockHash, &'static str> {
+    let target = header.target();
+    if target > params.pow_limit() {
+        return Err("has a target above our network's limit");
+    }
+    header
+        .validate_pow(&target)
+        .map_err(|_| "has too little work")
+}
+
+/// The target, in compact form, for the window after one that started at `first_time`, and
+/// ended at `last_time` with `last_bits`. The same as Bitcoin Core's `CalculateNextWorkRequired`.
+fn next_bits(params: &dyn ChainParams, first_time: u32, last_time: u32, last_bits: u32) -> u32 {
+    let expected = params.pow_target_timespan();
+    let timespan = (last_time as u64)
+        .saturating_sub(first_time as u64)
+        .clamp(expected / 4, expected * 4);
+    let target = BlockHeader::u256_from_compact_target(last_bits).mul_u32(timespan as u32)
+        / Uint256::from_u64(expected).expect("Fits in 256 bits");
+    BlockHeader::compact_target_from_u256(&target.min(params.pow_limit()))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        blockdata::constants::genesis_block, hashes::Hash, BlockHash, BlockHeader, Network,
+    };
+
+    use super::{next_bits, HeaderStore};
+    use crate::{
+        blockchain::chain_params::{BitcoinParams, ChainParams},
+        error::Error,
+    };
+
+    #[test]
+    fn test_next_bits() {
+        // From Bitcoin Core's pow_tests
+        let params = BitcoinParams::new(Network::Bitcoin);
+        assert_eq!(
+            next_bits(&params, 1261130161, 1262152739, 0x1d00ffff),
+            0x1d00d86a
+        );
+        assert_eq!(
+            next_bits(&params, 1231006505, 1233061996, 0x1d00ffff),
+            0x1d00ffff
+        );
+    }
+
+    #[test]
+    fn test_header_store() {
+        let path = "/tmp/utreexo_header_store";
+        let _ = std::fs::remove_file(path);
+        let params = BitcoinParams::new(Network::Regtest);
+        let interval = params.retarget_interval();
+        let store = HeaderStore::open(path.as_ref(), Box::new(params)).unwrap();
+        let mut headers = vec![genesis_block(Network::Regtest).header];
+        while headers.len() < interval as usize {
+            let mut header = BlockHeader {
+                prev_blockhash: headers.last().unwrap().block_hash(),
+                nonce: 0,
+                ..headers[0]
+            };
+            while header.validate_pow(&header.target()).is_err() {
+                header.nonce += 1;
+            }
+            headers.push(header);
+        }
+        // Heights we never saved are holes
+        store.save(10, &headers[10]).unwrap();
+        assert!(store.get(9).unwrap().is_none());
+        assert_eq!(store.get(10).unwrap(), Some(headers[10]));
+        assert!(store.get(5000).unwrap().is_none());
+
+        let last = headers.len() - 1;
+        for (height, header) in headers.iter().enumerate().take(last) {
+            store.save(height as u32, header).unwrap();
+        }
+        let mut orphan = headers[last];
+        orphan.prev_blockhash = BlockHash::all_zeros();
+        assert!(matches!(
+            store.save(last as u32, &orphan),
+            Err(Error::InvalidHeaders(0, _))
+        ));
+        // What our node has replaces what we had
+        store
+            .refetch_window(0, |height| Ok(headers[height as usize]))
+            .unwrap();
+        assert_eq!(store.get(last as u32).unwrap(), Some(headers[last]));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,231 @@
+//! Gets blocks and their proofs from a bridge node running on this machine, over a unix
+//! socket. Each block takes one round trip, without the overhead of our node's RPC, so the
+//! initial sync can go as fast as the bridge reads its own data.
+//!
+//! Requests and responses are JSON, one per line:
+//! `{"id": 1, "method": "getblockandproof", "params": [height]}` is answered with
+//! `{"id": 1, "result": {"block": "<hex>", "proof": <BlockProof>}, "error": null}`, where
+//! the proof is in the format [BlockProof] is serialized to.
+//!
+//! The bridge isn't trusted with blocks: each one must have the header our node has at that
+//! height, so a bridge on another branch, or a lying one, can't feed us its own chain.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bitcoin::{consensus::deserialize, hashes::hex::FromHex, Block};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{
+    sync::{BlockSource, HeaderSource},
+    udata::BlockProof,
+};
+use crate::error::Error;
+
+/// How long we wait for the bridge to answer
+const IPC_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct BlockAndProof {
+    block: String,
+    proof: BlockProof,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    id: u64,
+    result: Option<BlockAndProof>,
+    error: Option<String>,
+}
+
+/// An open connection, and the id of our next request on it
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    next_id: u64,
+}
+
+impl Connection {
+    fn open(path: &Path) -> Result<Connection, Error> {
+        let writer = UnixStream::connect(path)?;
+        writer.set_read_timeout(Some(IPC_TIMEOUT))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Connection {
+            reader,
+            writer,
+            next_id: 0,
+        })
+    }
+    fn request(&mut self, height: u32) -> Result<Response, Error> {
+        self.next_id += 1;
+        let request = json!({
+            "id": self.next_id,
+            "method": "getblockandproof",
+            "params": [height]
+        });
+        self.writer.write_all(format!("{request}\n").as_bytes())?;
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let response = serde_json::from_str::<Response>(&line)?;
+        if response.id != self.next_id {
+            return Err(Error::ProtocolError(format!(
+                "expected an answer to request {}, got {}",
+                self.next_id, response.id
+            )));
+        }
+        Ok(response)
+    }
+}
+
+/// A bridge node we talk to over a unix socket, and our node, to check its blocks against.
+/// We connect again if the connection breaks.
+pub struct IpcBlockSource {
+    node: Arc<dyn HeaderSource + Send>,
+    path: PathBuf,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl IpcBlockSource {
+    /// Connects to the bridge listening at `path`
+    pub fn connect(
+        node: Arc<dyn HeaderSource + Send>,
+        path: PathBuf,
+    ) -> Result<IpcBlockSource, Error> {
+        let connection = Connection::open(&path)?;
+        info!("Getting blocks from the bridge at {}", path.display());
+        Ok(IpcBlockSource {
+            node,
+            path,
+            connection: Mutex::new(Some(connection)),
+        })
+    }
+}
+
+impl BlockSource for IpcBlockSource {
+    fn get_block_and_proof(&self, height: u32) -> Result<(Block, BlockProof), Error> {
+        let mut connection = self.connection.lock().expect("Poisoned lock");
+        let response = match connection.as_mut() {
+            Some(open) => open.request(height),
+            None => Connection::open(&self.path)
+                .and_then(|open| connection.insert(open).request(height)),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                // We can't tell where the stream is at anymore, so we start a new one
+                warn!("Lost our connection to the bridge: {err}");
+                *connection = None;
+                return Err(err);
+            }
+        };
+        let BlockAndProof { block, proof } = match (response.result, response.error) {
+            (Some(result), None) => result,
+            (_, error) => {
+                warn!("The bridge could not give us block {height}: {error:?}");
+                return Err(Error::BlockNotFound);
+            }
+        };
+        let block = deserialize::<Block>(&Vec::from_hex(&block)?)?;
+        // A header is only as good as the work behind it, which our node checked
+        if block.header != self.node.get_header(height)? {
+            warn!(
+                "The bridge has block {} at height {height}, our node doesn't",
+                block.block_hash()
+            );
+            return Err(Error::UnexpectedBlock(height, block.block_hash()));
+        }
+        if proof.block_hash != block.block_hash() {
+            return Err(Error::InvalidProof);
+        }
+        Ok((block, proof))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixListener,
+        path::Path,
+        sync::Arc,
+    };
+
+    use bitcoin::{
+        blockdata::constants::genesis_block, consensus::encode::serialize_hex, BlockHeader, Network,
+    };
+    use serde_json::{json, Value};
+
+    use super::IpcBlockSource;
+    use crate::{
+        blockchain::sync::{BlockSource, HeaderSource},
+        error::Error,
+    };
+
+    /// A node that has `.0` at every height
+    struct TestNode(BlockHeader);
+    impl HeaderSource for TestNode {
+        fn get_header(&self, _height: u32) -> Result<BlockHeader, Error> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_blocks_must_be_our_nodes() {
+        let dir = "/tmp/utreexo_ipc/";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let path = Path::new(dir).join("bridge.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        // A bridge that always answers with the regtest genesis block
+        let block = genesis_block(Network::Regtest);
+        let result = json!({
+            "block": serialize_hex(&block),
+            "proof": {
+                "block_hash": block.block_hash(),
+                "targets": [],
+                "proof_hashes": [],
+                "target_hashes": [],
+                "target_preimages": [],
+            },
+        });
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut writer = stream.unwrap();
+                let result = result.clone();
+                std::thread::spawn(move || {
+                    let reader = BufReader::new(writer.try_clone().unwrap());
+                    for line in reader.lines() {
+                        let request = serde_json::from_str::<Value>(&line.unwrap()).unwrap();
+                        let response =
+                            json!({"id": request["id"], "result": result, "error": null});
+                        writer
+                            .write_all(format!("{response}\n").as_bytes())
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let ours = TestNode(block.header);
+        let source = IpcBlockSource::connect(Arc::new(ours), path.clone()).unwrap();
+        let (got, proof) = source.get_block_and_proof(0).unwrap();
+        assert_eq!(got, block);
+        assert_eq!(proof.block_hash, block.block_hash());
+
+        // Even with valid work, a block our node doesn't have is refused
+        let other = TestNode(genesis_block(Network::Testnet).header);
+        let source = IpcBlockSource::connect(Arc::new(other), path).unwrap();
+        assert!(matches!(
+            source.get_block_and_proof(0),
+            Err(Error::UnexpectedBlock(0, hash)) if hash == block.block_hash()
+        ));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
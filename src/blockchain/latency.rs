@@ -0,0 +1,165 @@
+//! Picks which of our nodes new tip blocks are downloaded from. Clients are only notified
+//! once a block is applied, so the time it takes to download it adds to every notification.
+//! We keep a rolling average of how long each node takes to send a block and its proof, and
+//! ask the fastest one first, moving on to the next if it fails. Nodes we've never asked
+//! are tried first, so each one gets measured.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bitcoin::{Block, BlockHash};
+use log::warn;
+
+use super::{sync::BlockSource, udata::BlockProof};
+use crate::error::Error;
+
+/// How much a new sample moves the average, as a fraction of it
+const LATENCY_SMOOTHING: u32 = 4;
+/// What a failed download counts as, on top of how long it took to fail
+const FAILURE_PENALTY: Duration = Duration::from_secs(30);
+
+pub struct LatencyRankedSource {
+    sources: Vec<Arc<dyn BlockSource + Send>>,
+    /// Each source's average latency, `None` until we've asked it for a block
+    latencies: Mutex<Vec<Option<Duration>>>,
+}
+
+impl LatencyRankedSource {
+    /// `sources` should start with our own node, which wins ties
+    pub fn new(sources: Vec<Arc<dyn BlockSource + Send>>) -> LatencyRankedSource {
+        let latencies = Mutex::new(vec![None; sources.len()]);
+        LatencyRankedSource { sources, latencies }
+    }
+    /// The order we ask our sources in
+    fn ranked(&self) -> Vec<usize> {
+        let latencies = self.latencies.lock().expect("Poisoned lock");
+        let mut ranked = (0..latencies.len()).collect::<Vec<_>>();
+        ranked.sort_by_key(|n| latencies[*n]);
+        ranked
+    }
+    fn record(&self, source: usize, latency: Duration) {
+        let mut latencies = self.latencies.lock().expect("Poisoned lock");
+        latencies[source] = Some(match latencies[source] {
+            Some(average) => average - average / LATENCY_SMOOTHING + latency / LATENCY_SMOOTHING,
+            None => latency,
+        });
+    }
+}
+
+impl LatencyRankedSource {
+    /// Asks our sources in turn, fastest first, until one of them gets us the block
+    fn first_found(
+        &self,
+        height: u32,
+        fetch: impl Fn(&dyn BlockSource) -> Result<(Block, BlockProof), Error>,
+    ) -> Result<(Block, BlockProof), Error> {
+        let mut last_error = Error::BlockNotFound;
+        for n in self.ranked() {
+            let start = Instant::now();
+            match fetch(&*self.sources[n]) {
+                Ok(found) => {
+                    self.record(n, start.elapsed());
+                    return Ok(found);
+                }
+                Err(err) => {
+                    warn!("Could not get block {height} from node {n}, trying the next one: {err}");
+                    self.record(n, start.elapsed() + FAILURE_PENALTY);
+                    last_error = err;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl BlockSource for LatencyRankedSource {
+    fn get_block_and_proof(&self, height: u32) -> Result<(Block, BlockProof), Error> {
+        self.first_found(height, |source| source.get_block_and_proof(height))
+    }
+    /// A node on another branch than ours, or behind it, gives us another block. It's
+    /// treated like a failure, so we move on to the next node
+    fn get_expected_block_and_proof(
+        &self,
+        height: u32,
+        expected: &BlockHash,
+    ) -> Result<(Block, BlockProof), Error> {
+        self.first_found(height, |source| {
+            source.get_expected_block_and_proof(height, expected)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use bitcoin::{blockdata::constants::genesis_block, Block, Network};
+
+    use super::LatencyRankedSource;
+    use crate::{
+        blockchain::{sync::BlockSource, udata::BlockProof},
+        error::Error,
+    };
+
+    struct Unreachable;
+    impl BlockSource for Unreachable {
+        fn get_block_and_proof(&self, _height: u32) -> Result<(Block, BlockProof), Error> {
+            Err(Error::BlockNotFound)
+        }
+    }
+
+    /// Always has the genesis block of its network
+    struct OnNetwork(Network);
+    impl BlockSource for OnNetwork {
+        fn get_block_and_proof(&self, _height: u32) -> Result<(Block, BlockProof), Error> {
+            let block = genesis_block(self.0);
+            let proof = BlockProof {
+                block_hash: block.block_hash(),
+                targets: vec![],
+                proof_hashes: vec![],
+                target_hashes: vec![],
+                target_preimages: vec![],
+            };
+            Ok((block, proof))
+        }
+    }
+
+    #[test]
+    fn test_ranking() {
+        let source = LatencyRankedSource::new(vec![
+            Arc::new(Unreachable),
+            Arc::new(Unreachable),
+            Arc::new(Unreachable),
+        ]);
+        source.record(0, Duration::from_millis(300));
+        source.record(2, Duration::from_millis(100));
+        // Ones we never asked come first, then the fastest
+        assert_eq!(source.ranked(), vec![1, 2, 0]);
+        // Every source is asked before giving up, and failures push them back
+        assert!(source.get_block_and_proof(1).is_err());
+        source.record(0, Duration::from_millis(0));
+        assert_eq!(source.ranked()[2], 1);
+    }
+    #[test]
+    fn test_expected_block() {
+        let source = LatencyRankedSource::new(vec![
+            Arc::new(OnNetwork(Network::Bitcoin)),
+            Arc::new(OnNetwork(Network::Regtest)),
+        ]);
+        // The regtest node is faster, but has another block
+        source.record(0, Duration::from_millis(300));
+        source.record(1, Duration::from_millis(100));
+        let expected = genesis_block(Network::Bitcoin).block_hash();
+        let (block, _) = source.get_expected_block_and_proof(0, &expected).unwrap();
+        assert_eq!(block.block_hash(), expected);
+        assert_eq!(source.ranked(), vec![0, 1]);
+
+        let unknown = genesis_block(Network::Testnet).block_hash();
+        assert!(matches!(
+            source.get_expected_block_and_proof(0, &unknown),
+            Err(Error::UnexpectedBlock(0, _))
+        ));
+    }
+}
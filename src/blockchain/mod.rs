@@ -1,6 +1,14 @@
 use std::sync::Arc;
+pub mod chain_params;
 pub mod chainstore;
+pub mod headers;
+#[cfg(unix)]
+pub mod ipc;
+pub mod latency;
+pub mod proof_peer;
+pub mod stored;
 pub mod sync;
+pub mod tip_check;
 pub mod udata;
 
 use bitcoin::{
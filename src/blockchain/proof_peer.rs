@@ -0,0 +1,220 @@
+//! Gets block proofs from another of these servers, with `blockchain.utreexo.get_block_proof`,
+//! so a cluster of them can share one bridge node. Blocks still come from our own node, which
+//! doesn't need to be a bridge, as long as the other server has the proofs we ask for. It
+//! only keeps the last few, so older ones, like the ones of our initial sync, come from our
+//! node.
+//!
+//! We speak plain Electrum to it: one JSON request per line, answered by one JSON line.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bitcoin::Block;
+use btcd_rpc::client::BTCDClient;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{
+    sync::{BlockSource, BlockchainSync},
+    udata::BlockProof,
+};
+use crate::error::Error;
+
+/// How long we wait for the other server to answer
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    id: Option<u64>,
+    result: Option<BlockProof>,
+    error: Option<Value>,
+}
+
+/// An open connection, and the id of our next request on it
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+}
+
+impl Connection {
+    fn open(address: &str) -> Result<Connection, Error> {
+        let writer = TcpStream::connect(address)?;
+        writer.set_read_timeout(Some(PEER_TIMEOUT))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Connection {
+            reader,
+            writer,
+            next_id: 0,
+        })
+    }
+    fn request(&mut self, height: u32) -> Result<Response, Error> {
+        self.next_id += 1;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id,
+            "method": "blockchain.utreexo.get_block_proof",
+            "params": [height]
+        });
+        self.writer.write_all(format!("{request}\n").as_bytes())?;
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let response = serde_json::from_str::<Response>(&line)?;
+        if response.id != Some(self.next_id) {
+            return Err(Error::ProtocolError(format!(
+                "expected an answer to request {}, got {:?}",
+                self.next_id, response.id
+            )));
+        }
+        Ok(response)
+    }
+}
+
+/// Our node, for blocks, and another server sharing its bridge's proofs with us. We connect
+/// to the server again if the connection breaks.
+pub struct PeerProofSource {
+    node: Arc<BTCDClient>,
+    address: String,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl PeerProofSource {
+    /// Gets proofs from the server at `address`, a `host:port`. We only connect on the first
+    /// request, so the other server doesn't have to be up before us.
+    pub fn new(node: Arc<BTCDClient>, address: String) -> PeerProofSource {
+        info!("Getting block proofs from {address}");
+        PeerProofSource {
+            node,
+            address,
+            connection: Mutex::new(None),
+        }
+    }
+    /// Asks the other server for the proof of the block at `height`, `None` if it doesn't
+    /// have it
+    fn get_proof(&self, height: u32) -> Result<Option<BlockProof>, Error> {
+        let mut connection = self.connection.lock().expect("Poisoned lock");
+        let response = match connection.as_mut() {
+            Some(open) => open.request(height),
+            None => Connection::open(&self.address)
+                .and_then(|open| connection.insert(open).request(height)),
+        };
+        match response {
+            Ok(response) => Ok(response.result.filter(|_| response.error.is_none())),
+            Err(err) => {
+                // We can't tell where the stream is at anymore, so we start a new one
+                *connection = None;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl BlockSource for PeerProofSource {
+    fn get_block_and_proof(&self, height: u32) -> Result<(Block, BlockProof), Error> {
+        let block = BlockchainSync::get_block(&*self.node, height)?;
+        let block_hash = block.block_hash();
+        match self.get_proof(height) {
+            Ok(Some(proof)) if proof.block_hash == block_hash => return Ok((block, proof)),
+            // It's on another branch than our node, or it didn't catch up yet
+            Ok(Some(proof)) => warn!(
+                "{} has block {} at height {height}, not {block_hash}, using our node's proof",
+                self.address, proof.block_hash
+            ),
+            Ok(None) => debug!(
+                "{} doesn't have the proof of block {height}, using our node's",
+                self.address
+            ),
+            Err(err) => warn!(
+                "Could not get the proof of block {height} from {}, using our node's: {err}",
+                self.address
+            ),
+        }
+        let proof = BlockchainSync::fetch_proof(&*self.node, &block_hash.to_string())?;
+        Ok((block, proof))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use bitcoin::{blockdata::constants::genesis_block, Network};
+    use btcd_rpc::client::{BTCDClient, BTCDConfigs};
+    use serde_json::{json, Value};
+
+    use super::PeerProofSource;
+    use crate::error::Error;
+
+    #[test]
+    fn test_get_proof() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let block_hash = genesis_block(Network::Regtest).block_hash();
+        // The ids of the requests each connection got
+        let connections = Arc::new(Mutex::new(Vec::<Vec<u64>>::new()));
+        let seen = connections.clone();
+        // A server that has the proof at height 7, not at 8, and answers the wrong request
+        // at 9
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut writer = stream.unwrap();
+                let reader = BufReader::new(writer.try_clone().unwrap());
+                seen.lock().unwrap().push(vec![]);
+                for line in reader.lines() {
+                    let request = match line {
+                        Ok(line) => serde_json::from_str::<Value>(&line).unwrap(),
+                        Err(_) => break,
+                    };
+                    let id = request["id"].as_u64().unwrap();
+                    seen.lock().unwrap().last_mut().unwrap().push(id);
+                    let proof = json!({
+                        "block_hash": block_hash,
+                        "targets": [],
+                        "proof_hashes": [],
+                        "target_hashes": [],
+                        "target_preimages": [],
+                    });
+                    let response = match request["params"][0].as_u64() {
+                        Some(7) => json!({"id": id, "result": proof, "error": null}),
+                        Some(9) => json!({"id": id + 1, "result": proof, "error": null}),
+                        _ => json!({"id": id, "result": null, "error": {"code": 1}}),
+                    };
+                    if writer
+                        .write_all(format!("{response}\n").as_bytes())
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        });
+        // Nothing listens there, and we only ask our node for blocks
+        let node = BTCDClient::new(BTCDConfigs::new(
+            false,
+            None,
+            None,
+            Some("127.0.0.1".into()),
+            Some(1),
+        ))
+        .unwrap();
+        let source = PeerProofSource::new(Arc::new(node), address);
+
+        let proof = source.get_proof(7).unwrap().unwrap();
+        assert_eq!(proof.block_hash, block_hash);
+        assert_eq!(source.get_proof(8).unwrap(), None);
+        // An answer to another request is a protocol error, not a missing proof
+        assert!(matches!(source.get_proof(9), Err(Error::ProtocolError(_))));
+        // We can't tell where that stream is at anymore, so we start a new one
+        assert!(source.get_proof(7).unwrap().is_some());
+        assert_eq!(*connections.lock().unwrap(), vec![vec![1, 2, 3], vec![1]]);
+    }
+}
@@ -0,0 +1,44 @@
+//! What we keep in our chain store. Like our wallet's records, in
+//! [crate::address_cache::stored], each value is serialized as JSON, so a new field only
+//! needs adding here, with a default for records written before it.
+//!
+//! Block log entries, block proofs, OP_RETURN matches and silent payments are stored as the
+//! JSON of the types we serve them as, which derive serde already. Leaf counts and balances
+//! are bare JSON numbers.
+
+use bitcoin::hashes::sha256;
+use rustreexo::accumulator::stump::Stump;
+use serde::{Deserialize, Serialize};
+
+/// Our accumulator, now or after some block
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredAcc {
+    pub leaves: u64,
+    pub roots: Vec<sha256::Hash>,
+}
+
+/// The last block we processed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredTip {
+    pub height: u32,
+    /// The block's header, in hex
+    pub header: String,
+}
+
+impl From<&Stump> for StoredAcc {
+    fn from(acc: &Stump) -> Self {
+        StoredAcc {
+            leaves: acc.leafs,
+            roots: acc.roots.clone(),
+        }
+    }
+}
+
+impl From<StoredAcc> for Stump {
+    fn from(stored: StoredAcc) -> Self {
+        Stump {
+            leafs: stored.leaves,
+            roots: stored.roots,
+        }
+    }
+}
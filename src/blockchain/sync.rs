@@ -1,22 +1,182 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ops::RangeInclusive;
+use std::sync::{mpsc::sync_channel, Arc, Condvar, Mutex};
+use std::time::Duration;
 use std::vec;
 
+use super::chain_params::ChainParams;
 use super::chainstore::ChainStore;
-use super::udata::LeafData;
-use crate::address_cache::{AddressCache, AddressCacheDatabase};
+use super::udata::{BlockProof, LeafData};
+use crate::address_cache::{AddressCache, AddressCacheDatabase, ROOTS_HISTORY_DEPTH};
+use crate::config::ResourceLimits;
 use crate::error::Error;
 use bitcoin::consensus::{deserialize_partial, Encodable};
 use bitcoin::hashes::hex::FromHex;
 use bitcoin::hashes::{sha256, Hash};
-use bitcoin::{Block, BlockHash};
+use bitcoin::{Block, BlockHash, BlockHeader};
 use bitcoin::{OutPoint, Transaction, TxOut};
 use btcd_rpc::client::BtcdRpc;
 use btcd_rpc::json_types::VerbosityOutput;
-use log::{info, log, Level};
+use log::{error, info, log, warn, Level};
 use rustreexo::accumulator::proof::Proof;
 use rustreexo::accumulator::stump::Stump;
 use sha2::{Digest, Sha512_256};
+/// How long we wait before retrying a sync that failed for the first time
+pub const MIN_SYNC_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest we wait between retries of a failed sync
+pub const MAX_SYNC_BACKOFF: Duration = Duration::from_secs(300);
+/// How many blocks below our node's tip we look for one all our nodes agree on. Past this,
+/// whoever disagrees with our node is on another chain altogether, and we follow our node.
+const MAX_CONTESTED_DEPTH: u32 = 6;
+
+/// Where we get blocks, and the proofs for them, from while syncing
+pub trait BlockSource: Sync {
+    fn get_block_and_proof(&self, height: u32) -> Result<(Block, BlockProof), Error>;
+    /// Like [BlockSource::get_block_and_proof], but fails if the block at `height` isn't
+    /// `expected`, e.g. when the source is on another branch than our node
+    fn get_expected_block_and_proof(
+        &self,
+        height: u32,
+        expected: &BlockHash,
+    ) -> Result<(Block, BlockProof), Error> {
+        let (block, proof) = self.get_block_and_proof(height)?;
+        if block.block_hash() != *expected {
+            return Err(Error::UnexpectedBlock(height, block.block_hash()));
+        }
+        Ok((block, proof))
+    }
+}
+/// Our node, or any other bridge node we talk to over RPC
+impl<T: BtcdRpc + Sync> BlockSource for T {
+    fn get_block_and_proof(&self, height: u32) -> Result<(Block, BlockProof), Error> {
+        let block = BlockchainSync::get_block(self, height)?;
+        let proof = BlockchainSync::fetch_proof(self, &block.block_hash().to_string())?;
+        Ok((block, proof))
+    }
+}
+/// Where we check the blocks we sync against: our own node, whichever source they come from
+pub trait HeaderSource: Sync {
+    /// The header of the block our node has at `height`
+    fn get_header(&self, height: u32) -> Result<BlockHeader, Error>;
+}
+impl<T: BtcdRpc + Sync> HeaderSource for T {
+    fn get_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        let hash = self.getblockhash(height as usize)?;
+        Ok(self.getblockheader(hash, false)?.get_simple())
+    }
+}
+/// A block we've downloaded, but didn't process yet
+struct DownloadedBlock {
+    height: u32,
+    block: Block,
+    proof: Proof,
+    del_hashes: Vec<sha256::Hash>,
+    leaves: Vec<LeafData>,
+    /// The proof as we got it, kept so we can serve it to others
+    raw_proof: BlockProof,
+}
+impl DownloadedBlock {
+    /// Roughly how much memory this takes. We hold the proof both as we got it and decoded.
+    fn size(&self) -> usize {
+        let proof = (self.raw_proof.proof_hashes.len() + self.raw_proof.target_hashes.len()) * 32
+            + self
+                .raw_proof
+                .target_preimages
+                .iter()
+                .map(String::len)
+                .sum::<usize>();
+        self.block.size() + 2 * proof
+    }
+}
+/// How many bytes of downloaded blocks are waiting to be processed. The downloader waits
+/// here when the processor falls behind, instead of piling blocks up in memory.
+#[derive(Debug, Default)]
+struct InflightBytes {
+    /// The bytes in flight, and whether the processor is gone
+    state: Mutex<(usize, bool)>,
+    changed: Condvar,
+}
+impl InflightBytes {
+    /// Waits until `size` more bytes fit in `limit`, and counts them. A block bigger than
+    /// the limit goes through once nothing else is in flight. Returns false if the processor
+    /// is gone, and nobody will take this block.
+    fn acquire(&self, size: usize, limit: usize) -> bool {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        while !state.1 && state.0 > 0 && state.0 + size > limit {
+            state = self.changed.wait(state).expect("Poisoned lock");
+        }
+        state.0 += size;
+        !state.1
+    }
+    fn release(&self, size: usize) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.0 = state.0.saturating_sub(size);
+        self.changed.notify_all();
+    }
+    /// Wakes the downloader up for good, once we stop processing blocks
+    fn close(&self) {
+        self.state.lock().expect("Poisoned lock").1 = true;
+        self.changed.notify_all();
+    }
+}
+/// Blocks our node and our fallback nodes disagree on. We hold on to every candidate until
+/// one branch wins, instead of applying one and rolling it back if the other wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContestedTip {
+    /// The lowest height they disagree at
+    pub height: u32,
+    /// The hash each node has at `height`, our node's first
+    pub candidates: Vec<String>,
+}
+/// Tips our node announced while we were still applying an earlier one. They are applied
+/// in height order, and each block only once, no matter how many times it's announced.
+#[derive(Debug, Default)]
+pub struct BlockQueue {
+    pending: VecDeque<(u32, String)>,
+    last_applied: Option<String>,
+    /// The tip we are holding back, because our nodes disagree on it
+    contested: Option<ContestedTip>,
+}
+impl BlockQueue {
+    /// Queues a tip, returns false if it's already queued or applied
+    pub fn push(&mut self, height: u32, hash: String) -> bool {
+        if self.last_applied.as_ref() == Some(&hash)
+            || self.pending.iter().any(|(_, pending)| *pending == hash)
+        {
+            return false;
+        }
+        let position = self
+            .pending
+            .iter()
+            .position(|(pending, _)| *pending > height)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(position, (height, hash));
+        true
+    }
+    /// The lowest tip we didn't apply yet
+    pub fn pop(&mut self) -> Option<(u32, String)> {
+        self.pending.pop_front()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+    /// Marks a tip as applied, so further announcements of it are ignored
+    pub fn applied(&mut self, hash: String) {
+        self.last_applied = Some(hash);
+    }
+    /// Holds a contested tip back, returns false if we were already holding it
+    pub fn hold(&mut self, contested: ContestedTip) -> bool {
+        if self.contested.as_ref() == Some(&contested) {
+            return false;
+        }
+        self.contested = Some(contested);
+        true
+    }
+    /// Our nodes agree again, returns the tip we were holding back, if any
+    pub fn resolve(&mut self) -> Option<ContestedTip> {
+        self.contested.take()
+    }
+}
 #[derive(Debug, Default)]
 pub struct BlockchainSync;
 impl BlockchainSync {
@@ -32,66 +192,261 @@ impl BlockchainSync {
         }
         Err(Error::BlockNotFound)
     }
+    /// Verifies all scripts in a block under the rules in `flags`, spread across `workers`
+    /// threads.
     pub fn verify_block_transactions(
-        mut utxos: HashMap<OutPoint, TxOut>,
+        utxos: &HashMap<OutPoint, TxOut>,
         transactions: &[Transaction],
+        workers: usize,
+        flags: u32,
     ) -> Result<bool, crate::error::Error> {
-        for transaction in transactions {
-            if !transaction.is_coin_base() {
-                transaction.verify(|outpoint| utxos.remove(outpoint))?;
+        // Each thread only reads the utxo set, so we have to make sure nothing is spent
+        // twice beforehand.
+        let mut spent = HashSet::new();
+        for transaction in transactions.iter().filter(|tx| !tx.is_coin_base()) {
+            for input in transaction.input.iter() {
+                if !spent.insert(input.previous_output) {
+                    return Err(Error::ValidationError(
+                        bitcoin::blockdata::script::Error::UnknownSpentOutput(
+                            input.previous_output,
+                        ),
+                    ));
+                }
             }
         }
-        Ok(true)
+        let workers = workers.max(1);
+        let chunk_size = ((transactions.len() + workers - 1) / workers).max(1);
+        std::thread::scope(|scope| {
+            let workers = transactions
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        for transaction in chunk.iter().filter(|tx| !tx.is_coin_base()) {
+                            transaction.verify_with_flags(
+                                |outpoint| utxos.get(outpoint).cloned(),
+                                flags,
+                            )?;
+                        }
+                        Ok::<_, bitcoin::blockdata::script::Error>(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            for worker in workers {
+                worker.join().expect("Verification worker panicked")?;
+            }
+            Ok(true)
+        })
     }
-    pub fn _sync_all<D: AddressCacheDatabase, Rpc: BtcdRpc, S: ChainStore>(
+    pub fn _sync_all<D: AddressCacheDatabase, Rpc: BtcdRpc + Sync, S: ChainStore>(
         rpc: &Rpc,
         address_cache: &mut AddressCache<D, S>,
+        params: &dyn ChainParams,
     ) -> Result<(), crate::error::Error> {
         let height = rpc.getbestblock().expect("sync_all: Rpc failed").height as u32;
-        Self::sync_range(rpc, address_cache, 1..=height, true)?;
+        Self::sync_range::<Rpc, D, S>(
+            rpc,
+            rpc,
+            &[],
+            address_cache,
+            1..=height,
+            None,
+            true,
+            &ResourceLimits::default(),
+            params,
+        )?;
         Ok(())
     }
-    pub fn sync_range<T: BtcdRpc, D: AddressCacheDatabase, S: ChainStore>(
+    /// Syncs up to our node's tip, retrying with exponential backoff if we hit an error that
+    /// may go away on its own, like our node being unreachable. Progress is saved as we go,
+    /// so each retry resumes from the last block we've saved. Fatal errors are returned.
+    pub fn sync_with_retry<T: BtcdRpc + Sync, D: AddressCacheDatabase, S: ChainStore>(
         rpc: &T,
+        source: &dyn BlockSource,
+        fallbacks: &[Arc<T>],
+        address_cache: &mut AddressCache<D, S>,
+        ibd: bool,
+        limits: &ResourceLimits,
+        params: &dyn ChainParams,
+    ) -> Result<(), crate::error::Error> {
+        let mut backoff = MIN_SYNC_BACKOFF;
+        loop {
+            let result = rpc
+                .getbestblock()
+                .map_err(Error::from)
+                .and_then(|best| {
+                    // A contested tip is left for our main loop, which waits for one branch
+                    // to win
+                    let tip = match Self::find_contested_tip(rpc, fallbacks, best.height as u32)? {
+                        Some(contested) => contested.height.saturating_sub(1),
+                        None => best.height as u32,
+                    };
+                    address_cache.get_sync_limits(tip.max(address_cache.get_cache_height()?))
+                })
+                .and_then(|range| {
+                    Self::sync_range(
+                        rpc,
+                        source,
+                        fallbacks,
+                        address_cache,
+                        range,
+                        None,
+                        ibd,
+                        limits,
+                        params,
+                    )
+                });
+            match result {
+                Ok(()) => return Ok(()),
+                Err(err) if err.is_transient() => {
+                    warn!("Sync failed: {err}, retrying in {}s", backoff.as_secs());
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_SYNC_BACKOFF);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    /// Looks for blocks our fallback nodes have at the same height as our node's, but with
+    /// another hash. We start at `tip` and go down until they agree again, so the lowest
+    /// height they disagree at is returned. Nodes that are behind or unreachable don't count.
+    pub fn find_contested_tip<T: BtcdRpc>(
+        rpc: &T,
+        fallbacks: &[Arc<T>],
+        tip: u32,
+    ) -> Result<Option<ContestedTip>, Error> {
+        if fallbacks.is_empty() {
+            return Ok(None);
+        }
+        let mut contested = None;
+        for height in (tip.saturating_sub(MAX_CONTESTED_DEPTH - 1)..=tip).rev() {
+            let ours = rpc.getblockhash(height as usize)?;
+            let mut candidates = fallbacks
+                .iter()
+                .filter_map(|fallback| fallback.getblockhash(height as usize).ok())
+                .filter(|theirs| *theirs != ours)
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                return Ok(contested);
+            }
+            candidates.sort();
+            candidates.dedup();
+            candidates.insert(0, ours);
+            contested = Some(ContestedTip { height, candidates });
+        }
+        warn!(
+            "Our fallback nodes disagree with our node on the last {MAX_CONTESTED_DEPTH} blocks, following our node"
+        );
+        Ok(None)
+    }
+    /// Downloads a block and everything we need to validate it
+    fn download_block(
+        source: &dyn BlockSource,
+        height: u32,
+        expected: Option<&BlockHash>,
+    ) -> Result<DownloadedBlock, Error> {
+        let (block, raw_proof) = match expected {
+            Some(expected) => source.get_expected_block_and_proof(height, expected)?,
+            None => source.get_block_and_proof(height)?,
+        };
+        let (proof, del_hashes, leaves) = raw_proof.decode()?;
+        Ok(DownloadedBlock {
+            height,
+            block,
+            proof,
+            del_hashes,
+            leaves,
+            raw_proof,
+        })
+    }
+    /// Syncs `range`, with blocks from `source`. If a block's proof doesn't fit our
+    /// accumulator, the same block is asked to each of `fallbacks`, see
+    /// [BlockchainSync::arbitrate_proof]. If `tip` is given, the last block in `range` must
+    /// have that hash, and the others the hash `node` has at their height. Either way, each
+    /// block must build on the one before it.
+    ///
+    /// If we stop at a block, the ones before it stay applied and saved, so syncing again
+    /// picks up from there. With `ibd`, we're catching up with our node, and don't alert
+    /// about what these blocks did to our wallet.
+    #[allow(clippy::too_many_arguments)]
+    pub fn sync_range<T: BtcdRpc + Sync, D: AddressCacheDatabase, S: ChainStore>(
+        node: &dyn HeaderSource,
+        source: &dyn BlockSource,
+        fallbacks: &[Arc<T>],
         address_cache: &mut AddressCache<D, S>,
         range: RangeInclusive<u32>,
+        tip: Option<&BlockHash>,
         ibd: bool,
+        limits: &ResourceLimits,
+        params: &dyn ChainParams,
     ) -> Result<(), crate::error::Error> {
         let current_height = *range.end();
-        for block_height in range {
-            let block = BlockchainSync::get_block(rpc, block_height)?;
-
-            let (proof, del_hashes, utxos) = Self::get_proof(rpc, &block.block_hash().to_string())
-                .expect("Could not get block proof");
-            let mut utxo_map = HashMap::new();
-            for utxo in utxos {
-                utxo_map.insert(utxo.prevout, utxo.utxo);
-            }
-            for transaction in block.txdata.iter() {
-                for (idx, out) in transaction.output.iter().enumerate() {
-                    utxo_map.insert(
-                        OutPoint {
-                            txid: transaction.txid(),
-                            vout: idx as u32,
-                        },
-                        out.clone(),
-                    );
+        address_cache.set_catching_up(ibd);
+        let assumed_valid = Self::assumed_valid_height(node, params, &range);
+        let inflight = InflightBytes::default();
+        let mut applied = None;
+        let result = std::thread::scope(|scope| {
+            // Blocks are downloaded in the background, up to `max_inflight_blocks` and
+            // `max_inflight_bytes` ahead of the one we are processing.
+            let (sender, receiver) = sync_channel(limits.max_inflight_blocks);
+            let inflight = &inflight;
+            scope.spawn(move || {
+                for block_height in range {
+                    let block = Self::expected_hash(node, tip, block_height, current_height)
+                        .and_then(|expected| {
+                            Self::download_block(source, block_height, expected.as_ref())
+                        });
+                    let failed = block.is_err();
+                    if let Ok(block) = &block {
+                        if !inflight.acquire(block.size(), limits.max_inflight_bytes) {
+                            break;
+                        }
+                    }
+                    if sender.send(block).is_err() || failed {
+                        break;
+                    }
                 }
-            }
-            Self::verify_block_transactions(utxo_map, &block.txdata)?;
-            address_cache.block_process(&block, block_height, proof, del_hashes);
-
-            if block_height % 1000 == 0 && ibd {
-                info!(
-                    "height {block_height:2.0} progress: {progress:<2}%",
-                    progress =
-                        ((block_height as f32 / current_height as f32) * 100_f32).round() as u32,
-                );
-                // These operations involves expensive db calls, only make it after some
-                // substantial progress
+            });
+            let mut previous = None;
+            let result = receiver.iter().try_for_each(|block| {
+                let block = block?;
+                let (height, hash) = (block.height, block.block.block_hash());
+                // Our source moved to another branch halfway through
+                if previous.map_or(false, |previous| {
+                    block.block.header.prev_blockhash != previous
+                }) {
+                    return Err(Error::UnexpectedBlock(height, hash));
+                }
+                let size = block.size();
+                let flags = match assumed_valid {
+                    Some(last) if block.height <= last => None,
+                    _ => Some(params.script_flags(block.height)),
+                };
+                Self::apply_block(
+                    address_cache,
+                    node,
+                    fallbacks,
+                    block,
+                    current_height,
+                    ibd,
+                    limits,
+                    flags,
+                )?;
+                previous = Some(hash);
+                applied = Some(height);
+                inflight.release(size);
+                Ok::<_, Error>(())
+            });
+            // If we failed, the downloader may be waiting for room that won't come
+            inflight.close();
+            drop(receiver);
+            result
+        });
+        if let Err(err) = result {
+            if let Some(last) = applied {
                 address_cache.save_acc();
-                address_cache.bump_height(block_height);
+                address_cache.bump_height(last);
             }
+            return Err(err);
         }
         if !ibd {
             info!("New block height {current_height}");
@@ -100,8 +455,237 @@ impl BlockchainSync {
         address_cache.bump_height(current_height);
         Ok(())
     }
+    /// The hash the block at `height` must have, if we know our node's tip: the tip itself
+    /// for the last block, and whatever our node has at that height for the others
+    fn expected_hash(
+        node: &dyn HeaderSource,
+        tip: Option<&BlockHash>,
+        height: u32,
+        last: u32,
+    ) -> Result<Option<BlockHash>, Error> {
+        match tip {
+            Some(tip) if height == last => Ok(Some(*tip)),
+            Some(_) => Ok(Some(node.get_header(height)?.block_hash())),
+            None => Ok(None),
+        }
+    }
+    /// The last height in `range` whose scripts we don't check. That's the block
+    /// [ChainParams::assume_valid] names, and the ones before it, if our node has that block
+    /// at its height. Otherwise we check every script.
+    fn assumed_valid_height(
+        node: &dyn HeaderSource,
+        params: &dyn ChainParams,
+        range: &RangeInclusive<u32>,
+    ) -> Option<u32> {
+        let (height, hash) = params.assume_valid()?;
+        if *range.start() > height {
+            return None;
+        }
+        match node.get_header(height) {
+            Ok(header) if header.block_hash() == hash => Some(height),
+            Ok(header) => {
+                warn!(
+                    "Our node has block {} at height {height}, not {hash}, checking every script",
+                    header.block_hash()
+                );
+                None
+            }
+            Err(err) => {
+                warn!("Could not get block {height} from our node, checking every script: {err}");
+                None
+            }
+        }
+    }
+    /// Validates and processes one block we've downloaded while syncing. Scripts are checked
+    /// under `flags`, or not at all if it's `None`.
+    #[allow(clippy::too_many_arguments)]
+    fn apply_block<T: BtcdRpc + Sync, D: AddressCacheDatabase, S: ChainStore>(
+        address_cache: &mut AddressCache<D, S>,
+        node: &dyn HeaderSource,
+        fallbacks: &[Arc<T>],
+        block: DownloadedBlock,
+        current_height: u32,
+        ibd: bool,
+        limits: &ResourceLimits,
+        flags: Option<u32>,
+    ) -> Result<(), Error> {
+        let block = if block
+            .proof
+            .verify(&block.del_hashes, address_cache.get_acc())?
+        {
+            block
+        } else {
+            Self::arbitrate_proof(fallbacks, address_cache.get_acc(), block)?
+        };
+        let DownloadedBlock {
+            height: block_height,
+            block,
+            proof,
+            del_hashes,
+            leaves,
+            raw_proof,
+        } = block;
+        Self::process_block(
+            address_cache,
+            node,
+            block_height,
+            &block,
+            proof,
+            del_hashes,
+            leaves,
+            limits,
+            flags,
+        )?;
+        if current_height - block_height < ROOTS_HISTORY_DEPTH {
+            address_cache.save_acc_at(block_height);
+            address_cache.save_block_proof(block_height, &raw_proof);
+        }
+
+        if block_height % 1000 == 0 && ibd {
+            info!(
+                "height {block_height:2.0} progress: {progress:<2}%",
+                progress = ((block_height as f32 / current_height as f32) * 100_f32).round() as u32,
+            );
+            // These operations involves expensive db calls, only make it after some
+            // substantial progress
+            address_cache.save_acc();
+            address_cache.bump_height(block_height);
+        }
+        Ok(())
+    }
+    /// Called when the proof our node gave us for `block` doesn't fit our accumulator. We
+    /// ask our fallback nodes for the same block: if one of them has a proof that fits, our
+    /// node is the one at fault, and we use that proof. We only blame our own accumulator
+    /// if most of our fallback nodes agree with our node on the proof, so one node sharing
+    /// our node's mistake isn't enough. Fallbacks we can't reach count as disagreeing.
+    fn arbitrate_proof<T: BlockSource>(
+        fallbacks: &[Arc<T>],
+        acc: &Stump,
+        block: DownloadedBlock,
+    ) -> Result<DownloadedBlock, Error> {
+        let height = block.height;
+        let block_hash = block.block.block_hash();
+        warn!("Proof for block {height} doesn't fit our accumulator, asking other nodes");
+        let mut agreeing = 0;
+        for fallback in fallbacks {
+            let other = match Self::download_block(&**fallback, height, None) {
+                Ok(other) => other,
+                Err(err) => {
+                    warn!("Could not get block {height} from a fallback node: {err}");
+                    continue;
+                }
+            };
+            if other.block.block_hash() != block_hash {
+                warn!(
+                    "A fallback node has block {} at height {height}, not {block_hash}",
+                    other.block.block_hash()
+                );
+                continue;
+            }
+            if matches!(other.proof.verify(&other.del_hashes, acc), Ok(true)) {
+                warn!("Our node gave us a bad proof for block {height}, using a fallback's");
+                return Ok(other);
+            }
+            if other.raw_proof == block.raw_proof {
+                agreeing += 1;
+            }
+        }
+        if agreeing * 2 > fallbacks.len() {
+            error!(
+                "{agreeing} of our {} fallback nodes agree on the proof for block {height}, but \
+                it doesn't fit our accumulator",
+                fallbacks.len()
+            );
+            return Err(Error::AccumulatorCorrupted(height));
+        }
+        if agreeing > 0 {
+            warn!(
+                "Only {agreeing} of our {} fallback nodes agree with our node on the proof for \
+                block {height}, not blaming our accumulator",
+                fallbacks.len()
+            );
+        }
+        Err(Error::InvalidProof)
+    }
+    /// Validates a block and hands it to our address cache. If its header completes a
+    /// retarget window that breaks the difficulty rules, that window is fetched again from
+    /// `node`, and the block is only applied once it checks out.
+    #[allow(clippy::too_many_arguments)]
+    fn process_block<D: AddressCacheDatabase, S: ChainStore>(
+        address_cache: &mut AddressCache<D, S>,
+        node: &dyn HeaderSource,
+        block_height: u32,
+        block: &Block,
+        proof: Proof,
+        del_hashes: Vec<sha256::Hash>,
+        leaves: Vec<LeafData>,
+        limits: &ResourceLimits,
+        flags: Option<u32>,
+    ) -> Result<(), Error> {
+        let utxo_map = Self::get_utxo_map(block, leaves);
+        if let Some(flags) = flags {
+            Self::verify_block_transactions(
+                &utxo_map,
+                &block.txdata,
+                limits.verification_workers,
+                flags,
+            )?;
+        }
+        match address_cache.save_header(block_height, &block.header) {
+            // It may be our file that got corrupted, so we ask our node for the window again
+            Err(Error::InvalidHeaders(start, reason)) => {
+                error!(
+                    "Our headers from block {start} on are invalid, fetching them again: {reason}"
+                );
+                address_cache
+                    .refetch_headers(start, |height| node.get_header(height))
+                    .map_err(|err| {
+                        error!("Could not fix our headers from block {start} on: {err}");
+                        err
+                    })?;
+            }
+            result => result?,
+        }
+        address_cache.block_process(block, block_height, proof, del_hashes, &utxo_map)?;
+        Ok(())
+    }
+    /// Returns every output `block` may spend: the ones its proof deletes, and the ones it
+    /// creates itself
+    pub fn get_utxo_map(block: &Block, leaves: Vec<LeafData>) -> HashMap<OutPoint, TxOut> {
+        let mut utxo_map = leaves
+            .into_iter()
+            .map(|leaf| (leaf.prevout, leaf.utxo))
+            .collect::<HashMap<_, _>>();
+        for transaction in block.txdata.iter() {
+            for (idx, out) in transaction.output.iter().enumerate() {
+                utxo_map.insert(
+                    OutPoint {
+                        txid: transaction.txid(),
+                        vout: idx as u32,
+                    },
+                    out.clone(),
+                );
+            }
+        }
+        utxo_map
+    }
+    /// Adds up the fees paid in `block`, given the outputs it spends
+    pub fn get_block_fees(utxos: &HashMap<OutPoint, TxOut>, block: &Block) -> u64 {
+        let mut fees = 0_u64;
+        for transaction in block.txdata.iter().filter(|tx| !tx.is_coin_base()) {
+            let spent = transaction
+                .input
+                .iter()
+                .filter_map(|input| utxos.get(&input.previous_output))
+                .map(|prevout| prevout.value)
+                .sum::<u64>();
+            let created = transaction.output.iter().map(|out| out.value).sum::<u64>();
+            fees += spent.saturating_sub(created);
+        }
+        fees
+    }
     // TODO: Move to LeafData
-    fn get_leaf_hashes(
+    pub fn get_leaf_hashes(
         transaction: &Transaction,
         vout: u32,
         height: u32,
@@ -170,32 +754,32 @@ impl BlockchainSync {
         rpc: &T,
         hash: &String,
     ) -> Result<(Proof, Vec<sha256::Hash>, Vec<LeafData>), crate::error::Error> {
+        Self::fetch_proof(rpc, hash)?.decode()
+    }
+    /// Asks our bridge node for a block's proof
+    pub(crate) fn fetch_proof<T: BtcdRpc>(
+        rpc: &T,
+        hash: &String,
+    ) -> Result<BlockProof, crate::error::Error> {
         let proof = rpc.getutreexoproof(hash.to_string(), true)?.get_verbose();
-        let preimages: Vec<_> = proof
-            .target_preimages
-            .iter()
-            .map(|preimage| {
-                deserialize_partial::<LeafData>(&Vec::from_hex(preimage).unwrap())
-                    .unwrap()
-                    .0
-            })
-            .collect();
-
-        let proof_hashes: Vec<_> = proof
+        let proof_hashes = proof
             .proofhashes
             .iter()
-            .map(|hash| sha256::Hash::from_hex(hash).unwrap())
-            .collect();
-        let targets = proof.prooftargets;
-
-        let targethashes: Vec<_> = proof
+            .map(|hash| sha256::Hash::from_hex(hash))
+            .collect::<Result<Vec<_>, _>>()?;
+        let target_hashes = proof
             .targethashes
             .iter()
-            .map(|hash| sha256::Hash::from_hex(hash).unwrap())
-            .collect();
-        let proof = Proof::new(targets, proof_hashes);
+            .map(|hash| sha256::Hash::from_hex(hash))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok((proof, targethashes, preimages))
+        Ok(BlockProof {
+            block_hash: BlockHash::from_hex(hash)?,
+            targets: proof.prooftargets,
+            proof_hashes,
+            target_hashes,
+            target_preimages: proof.target_preimages,
+        })
     }
     pub fn _sync_single<T: BtcdRpc, D: AddressCacheDatabase, S: ChainStore>(
         rpc: &T,
@@ -204,7 +788,7 @@ impl BlockchainSync {
     ) {
         for block_height in 0..blocks {
             let block = BlockchainSync::get_block(rpc, block_height).unwrap();
-            let (proof, del_hashes, _) = Self::get_proof(rpc, &block.block_hash().to_string())
+            let (proof, del_hashes, leaves) = Self::get_proof(rpc, &block.block_hash().to_string())
                 .expect("Could not get block proof");
 
             if block_height % 1000 == 0 {
@@ -214,8 +798,10 @@ impl BlockchainSync {
                     progress = (block_height as f32 / blocks as f32) * 100_f32,
                 );
             }
+            // Like a full sync, so our transactions keep the outputs they spend
+            let utxos = Self::get_utxo_map(&block, leaves);
             // FIXME
-            let _ = address_cache.block_process(&block, block_height, proof, del_hashes);
+            let _ = address_cache.block_process(&block, block_height, proof, del_hashes, &utxos);
         }
     }
 }
@@ -235,3 +821,313 @@ fn test_get_leaf_hashes() {
     let hash = BlockchainSync::get_leaf_hashes(&tx, 0, 117811, block_hash);
     assert_eq!(hash, expected)
 }
+#[test]
+fn test_get_utxo_map() {
+    use bitcoin::{blockdata::constants::genesis_block, Network, TxIn};
+    let spent = OutPoint::new(bitcoin::Txid::from_slice(&[1; 32]).unwrap(), 3);
+    let prevout = TxOut {
+        value: 5_000,
+        script_pubkey: bitcoin::Script::new(),
+    };
+    let mut block = genesis_block(Network::Regtest);
+    block.txdata.push(Transaction {
+        version: 2,
+        lock_time: bitcoin::PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: spent,
+            ..TxIn::default()
+        }],
+        output: vec![TxOut::default()],
+    });
+    let leaf = LeafData {
+        block_hash: block.block_hash(),
+        prevout: spent,
+        header_code: 2,
+        utxo: prevout.clone(),
+    };
+    let utxos = BlockchainSync::get_utxo_map(&block, vec![leaf]);
+    // What the proof spends, and what each transaction in the block creates
+    assert_eq!(utxos.len(), 3);
+    assert_eq!(utxos[&spent], prevout);
+    let created = OutPoint::new(block.txdata[1].txid(), 0);
+    assert_eq!(utxos[&created], TxOut::default());
+}
+#[test]
+fn test_inflight_bytes() {
+    let inflight = Arc::new(InflightBytes::default());
+    // A block bigger than the limit still goes through on its own
+    assert!(inflight.acquire(150, 100));
+    let waiting = {
+        let inflight = inflight.clone();
+        std::thread::spawn(move || inflight.acquire(50, 100))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!waiting.is_finished());
+    inflight.release(150);
+    assert!(waiting.join().unwrap());
+    inflight.release(50);
+
+    // Once we stop processing, the downloader is told to give up
+    assert!(inflight.acquire(100, 100));
+    let waiting = {
+        let inflight = inflight.clone();
+        std::thread::spawn(move || inflight.acquire(50, 100))
+    };
+    inflight.close();
+    assert!(!waiting.join().unwrap());
+}
+/// A chain of blocks paying their coinbase to `OP_TRUE`, each at its index
+#[cfg(test)]
+struct TestChain(Vec<Block>);
+#[cfg(test)]
+impl TestChain {
+    /// A regtest chain `length` blocks past genesis, with the work their headers claim.
+    /// Chains with different tags don't share any block past genesis.
+    fn new(length: u32, tag: u8) -> TestChain {
+        use bitcoin::{blockdata::constants::genesis_block, Network, PackedLockTime, Script, TxIn};
+
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        for height in 1..=length {
+            let parent = blocks.last().unwrap();
+            let coinbase = Transaction {
+                version: 1,
+                lock_time: PackedLockTime::ZERO,
+                input: vec![TxIn {
+                    script_sig: Script::from(vec![tag, height as u8]),
+                    ..TxIn::default()
+                }],
+                output: vec![TxOut {
+                    value: 5_000_000_000,
+                    script_pubkey: Script::from(vec![0x51]),
+                }],
+            };
+            let mut block = Block {
+                header: BlockHeader {
+                    prev_blockhash: parent.block_hash(),
+                    ..parent.header
+                },
+                txdata: vec![coinbase],
+            };
+            block.header.merkle_root = block.compute_merkle_root().unwrap();
+            while block.header.validate_pow(&block.header.target()).is_err() {
+                block.header.nonce += 1;
+            }
+            blocks.push(block);
+        }
+        TestChain(blocks)
+    }
+}
+#[cfg(test)]
+impl BlockSource for TestChain {
+    fn get_block_and_proof(&self, height: u32) -> Result<(Block, BlockProof), Error> {
+        let block = self
+            .0
+            .get(height as usize)
+            .ok_or(Error::BlockNotFound)?
+            .clone();
+        let proof = BlockProof {
+            block_hash: block.block_hash(),
+            targets: vec![],
+            proof_hashes: vec![],
+            target_hashes: vec![],
+            target_preimages: vec![],
+        };
+        Ok((block, proof))
+    }
+}
+#[cfg(test)]
+impl HeaderSource for TestChain {
+    fn get_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.0
+            .get(height as usize)
+            .map(|block| block.header)
+            .ok_or(Error::BlockNotFound)
+    }
+}
+/// Gives the block in the first field, if any, with a proof deleting the first leaf, which
+/// it says hashes to the second field
+#[cfg(test)]
+struct TestProofSource(Option<Block>, sha256::Hash);
+#[cfg(test)]
+impl BlockSource for TestProofSource {
+    fn get_block_and_proof(&self, _height: u32) -> Result<(Block, BlockProof), Error> {
+        let block = self.0.clone().ok_or(Error::BlockNotFound)?;
+        let proof = BlockProof {
+            block_hash: block.block_hash(),
+            targets: vec![0],
+            proof_hashes: vec![],
+            target_hashes: vec![self.1],
+            target_preimages: vec![],
+        };
+        Ok((block, proof))
+    }
+}
+#[test]
+fn test_arbitrate_proof_needs_majority() {
+    let block = TestChain::new(1, 0).0[1].clone();
+    let leaf = sha256::Hash::hash(b"leaf");
+    let wrong = sha256::Hash::hash(b"wrong");
+    let acc = Stump::new()
+        .modify(&[leaf], &[], &Proof::new(vec![], vec![]))
+        .unwrap()
+        .0;
+    let ours = TestProofSource(Some(block.clone()), wrong);
+    let agreeing = Arc::new(TestProofSource(Some(block.clone()), wrong));
+    let down = Arc::new(TestProofSource(None, wrong));
+    let fitting = Arc::new(TestProofSource(Some(block), leaf));
+    let arbitrate = |fallbacks: Vec<Arc<TestProofSource>>| {
+        let downloaded = BlockchainSync::download_block(&ours, 1, None).unwrap();
+        BlockchainSync::arbitrate_proof(&fallbacks, &acc, downloaded)
+    };
+
+    // One fallback sharing our node's proof isn't enough, when the others can't be reached
+    assert!(matches!(
+        arbitrate(vec![agreeing.clone(), down.clone(), down.clone()]),
+        Err(Error::InvalidProof)
+    ));
+    assert!(matches!(
+        arbitrate(vec![agreeing.clone(), agreeing.clone(), down]),
+        Err(Error::AccumulatorCorrupted(1))
+    ));
+    // A proof that fits wins, however many agree with our node
+    let fixed = arbitrate(vec![agreeing.clone(), agreeing, fitting]).unwrap();
+    assert_eq!(fixed.del_hashes, vec![leaf]);
+}
+#[test]
+fn test_sync_range_follows_one_chain() {
+    use crate::{
+        address_cache::kv_database::KvDatabase,
+        blockchain::{chain_params::BitcoinParams, chainstore::KvChainStore},
+    };
+    use bitcoin::Network;
+    use btcd_rpc::client::BTCDClient;
+
+    let dir = "/tmp/utreexo_sync_range/";
+    let _ = std::fs::remove_dir_all(dir);
+    let database = KvDatabase::new(dir.into(), 64 * 1024 * 1024).unwrap();
+    let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+    let mut cache = AddressCache::new(database, chain_store);
+    cache.setup(String::new()).unwrap();
+    let params = BitcoinParams::new(Network::Regtest);
+    let limits = ResourceLimits::default();
+    let ours = TestChain::new(2, 0);
+    let theirs = TestChain::new(2, 1);
+    let tip = ours.0[2].block_hash();
+    // A source on another branch than our node can't give us any block, not just the tip
+    let result = BlockchainSync::sync_range::<BTCDClient, _, _>(
+        &ours,
+        &theirs,
+        &[],
+        &mut cache,
+        1..=2,
+        Some(&tip),
+        false,
+        &limits,
+        &params,
+    );
+    assert!(
+        matches!(result, Err(Error::UnexpectedBlock(1, hash)) if hash == theirs.0[1].block_hash())
+    );
+    assert_eq!(cache.get_cache_height().unwrap(), 0);
+    assert_eq!(cache.get_acc().leafs, 0);
+
+    // Without a tip to check against, we still stop where the chain breaks, and keep what
+    // came before it
+    let switching = TestChain(vec![
+        ours.0[0].clone(),
+        ours.0[1].clone(),
+        theirs.0[2].clone(),
+    ]);
+    let result = BlockchainSync::sync_range::<BTCDClient, _, _>(
+        &ours,
+        &switching,
+        &[],
+        &mut cache,
+        1..=2,
+        None,
+        false,
+        &limits,
+        &params,
+    );
+    assert!(matches!(result, Err(Error::UnexpectedBlock(2, _))));
+    assert_eq!(cache.get_cache_height().unwrap(), 1);
+    assert_eq!(cache.get_acc().leafs, 1);
+
+    // So syncing again resumes from there
+    BlockchainSync::sync_range::<BTCDClient, _, _>(
+        &ours,
+        &ours,
+        &[],
+        &mut cache,
+        2..=2,
+        Some(&tip),
+        false,
+        &limits,
+        &params,
+    )
+    .unwrap();
+    assert_eq!(cache.get_cache_height().unwrap(), 2);
+    assert_eq!(cache.get_acc().leafs, 2);
+}
+#[test]
+fn test_invalid_headers_stop_block() {
+    use crate::{
+        address_cache::kv_database::KvDatabase,
+        blockchain::{chain_params::BitcoinParams, chainstore::KvChainStore, headers::HeaderStore},
+    };
+    use bitcoin::Network;
+    use std::path::Path;
+
+    let dir = "/tmp/utreexo_invalid_headers/";
+    let _ = std::fs::remove_dir_all(dir);
+    let database = KvDatabase::new(dir.into(), 64 * 1024 * 1024).unwrap();
+    let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+    let mut cache = AddressCache::new(database, chain_store);
+    cache.setup(String::new()).unwrap();
+    let params = BitcoinParams::new(Network::Regtest);
+    let interval = params.retarget_interval();
+    let headers = HeaderStore::open(&Path::new(dir).join("headers"), Box::new(params)).unwrap();
+    cache.set_header_store(headers);
+
+    // Block 5 doesn't follow block 4 in what we saved
+    let ours = TestChain::new(interval - 1, 0);
+    for (height, block) in ours.0.iter().enumerate().take(interval as usize - 1) {
+        let header = match height {
+            5 => ours.0[6].header,
+            _ => block.header,
+        };
+        cache.save_header(height as u32, &header).unwrap();
+    }
+    fn process(
+        cache: &mut AddressCache<KvDatabase, KvChainStore>,
+        node: &TestChain,
+        block: &Block,
+    ) -> Result<(), Error> {
+        BlockchainSync::process_block(
+            cache,
+            node,
+            node.0.len() as u32 - 1,
+            block,
+            Proof::new(vec![], vec![]),
+            vec![],
+            vec![],
+            &ResourceLimits::default(),
+            None,
+        )
+    }
+    let last = ours.0.last().unwrap();
+    // A node with a broken window can't fix ours, so the block that completes it isn't
+    // applied
+    let mut broken = TestChain::new(interval - 1, 1);
+    broken.0[5] = ours.0[5].clone();
+    assert!(matches!(
+        process(&mut cache, &broken, last),
+        Err(Error::InvalidHeaders(0, _))
+    ));
+    assert_eq!(cache.get_acc().leafs, 0);
+
+    // Once our node gives us a window that checks out, it is
+    process(&mut cache, &ours, last).unwrap();
+    assert_eq!(cache.get_acc().leafs, 1);
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,135 @@
+//! Compares our node's tip with our fallback nodes', off our main loop. That takes a few
+//! requests to every node for each tip, so our main loop only asks us to, and keeps serving
+//! clients until our verdict comes back as a [Message::TipChecked]. While the nodes disagree,
+//! we have our main loop look again every [RECHECK_INTERVAL], in case they agree by then
+//! without a new block.
+
+use std::{
+    sync::{mpsc::Sender, Arc},
+    time::Duration,
+};
+
+use async_std::{channel::Receiver, future::timeout, task};
+use btcd_rpc::client::BTCDClient;
+
+use super::sync::{BlockchainSync, ContestedTip};
+use crate::electrum::electrum_protocol::Message;
+
+/// How often we look at a contested tip again, to see if our nodes agree on it by then
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What our nodes think of a tip: the blocks they disagree on, if any, or why we couldn't
+/// tell
+pub type TipVerdict = Result<Option<ContestedTip>, String>;
+
+/// Where our main loop is with checking the tip it wants to apply
+#[derive(Debug)]
+pub enum TipCheck {
+    Idle,
+    /// We've asked about the tip with this hash, and wait for the verdict
+    Asked(String),
+    /// The verdict on the tip with this hash, until it's applied
+    Checked(String, TipVerdict),
+}
+
+/// Checks the tips our main loop sends us. Clones share the same requests, so our
+/// supervisor can restart us without losing any
+#[derive(Clone)]
+pub struct TipChecker {
+    node: Arc<BTCDClient>,
+    fallbacks: Vec<Arc<BTCDClient>>,
+    requests: Receiver<(u32, String)>,
+    notify_tx: Sender<Message>,
+}
+
+impl TipChecker {
+    pub fn new(
+        node: Arc<BTCDClient>,
+        fallbacks: Vec<Arc<BTCDClient>>,
+        requests: Receiver<(u32, String)>,
+        notify_tx: Sender<Message>,
+    ) -> TipChecker {
+        TipChecker {
+            node,
+            fallbacks,
+            requests,
+            notify_tx,
+        }
+    }
+    /// Checks tips until our main loop stops
+    pub async fn run(self) -> Result<(), String> {
+        let mut contested = false;
+        loop {
+            let request = if contested {
+                match timeout(RECHECK_INTERVAL, self.requests.recv()).await {
+                    Ok(request) => request,
+                    // No new tip meanwhile, so our main loop asks about the same one again
+                    Err(_) => {
+                        contested = false;
+                        let _ = self.notify_tx.send(Message::NewBlock);
+                        continue;
+                    }
+                }
+            } else {
+                self.requests.recv().await
+            };
+            let (height, hash) = match request {
+                Ok(request) => request,
+                Err(_) => return Ok(()),
+            };
+            let (node, fallbacks) = (self.node.clone(), self.fallbacks.clone());
+            let verdict: TipVerdict = task::spawn_blocking(move || {
+                BlockchainSync::find_contested_tip(&*node, &fallbacks, height)
+                    .map_err(|err| err.to_string())
+            })
+            .await;
+            contested = matches!(verdict, Ok(Some(_)));
+            if self
+                .notify_tx
+                .send(Message::TipChecked { hash, verdict })
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{mpsc::channel, Arc};
+
+    use async_std::task::block_on;
+    use btcd_rpc::client::{BTCDClient, BTCDConfigs};
+
+    use super::TipChecker;
+    use crate::electrum::electrum_protocol::Message;
+
+    #[test]
+    fn test_tip_checker() {
+        // Nothing listens there, but without fallbacks we never ask our node anything
+        let node = BTCDClient::new(BTCDConfigs::new(
+            false,
+            None,
+            None,
+            Some("127.0.0.1".into()),
+            Some(1),
+        ))
+        .unwrap();
+        let (requests, receiver) = async_std::channel::unbounded();
+        let (notify_tx, notify_rx) = channel();
+        let checker = TipChecker::new(Arc::new(node), vec![], receiver, notify_tx);
+        requests.try_send((1, "tip".to_string())).unwrap();
+        drop(requests);
+        // We stop once our main loop stops asking
+        assert_eq!(block_on(checker.run()), Ok(()));
+        match notify_rx.try_recv() {
+            Ok(Message::TipChecked { hash, verdict }) => {
+                assert_eq!(hash, "tip");
+                assert!(matches!(verdict, Ok(None)));
+            }
+            _ => panic!("No verdict"),
+        }
+        assert!(notify_rx.try_recv().is_err());
+    }
+}
@@ -1,7 +1,13 @@
 //! UData is the serialized data used for proof propagation in utreexo. It contains all
 //! data needed for validating some piece of information, like a transaction and a block.
 
-use bitcoin::{consensus::Decodable, BlockHash, OutPoint, TxOut};
+use bitcoin::{
+    consensus::{deserialize_partial, Decodable},
+    hashes::{hex::FromHex, sha256},
+    BlockHash, OutPoint, TxOut,
+};
+use rustreexo::accumulator::proof::Proof;
+use serde::{Deserialize, Serialize};
 
 /// Leaf data is the data that is hashed when adding to utreexo state. It contains validation
 /// data and some commitments to make it harder to attack an utreexo-only node.
@@ -47,3 +53,30 @@ impl Decodable for LeafData {
         })
     }
 }
+
+/// A block's utreexo proof, as our bridge node gave it to us. We keep the last few of
+/// these around, so other utreexo clients can get them from us instead of the bridge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockProof {
+    pub block_hash: BlockHash,
+    /// Positions of the leaves this block deletes
+    pub targets: Vec<u64>,
+    pub proof_hashes: Vec<sha256::Hash>,
+    /// Hashes of the leaves this block deletes
+    pub target_hashes: Vec<sha256::Hash>,
+    /// Each deleted leaf's [LeafData], hex encoded
+    pub target_preimages: Vec<String>,
+}
+
+impl BlockProof {
+    /// Returns the proof itself, the hashes it deletes and their preimages
+    pub fn decode(&self) -> Result<(Proof, Vec<sha256::Hash>, Vec<LeafData>), crate::error::Error> {
+        let mut preimages = vec![];
+        for preimage in self.target_preimages.iter() {
+            let (leaf, _) = deserialize_partial::<LeafData>(&Vec::from_hex(preimage)?)?;
+            preimages.push(leaf);
+        }
+        let proof = Proof::new(self.targets.clone(), self.proof_hashes.clone());
+        Ok((proof, self.target_hashes.clone(), preimages))
+    }
+}
@@ -1,5 +1,7 @@
-use std::path::PathBuf;
+use std::{num::NonZeroUsize, ops::Range, path::PathBuf};
 
+use crate::address_cache::DEFAULT_TX_CACHE_SIZE;
+use bitcoin::hashes::sha256;
 use clap::{arg, command, Parser, Subcommand, ValueEnum};
 #[derive(Clone, Debug, ValueEnum)]
 pub enum Network {
@@ -23,15 +25,48 @@ impl std::fmt::Display for Network {
         }
     }
 }
+/// A preset for our config, applied before the config file, so anything there still wins
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Profile {
+    /// For a Raspberry Pi, or any machine with about 1GB of RAM: small caches, one
+    /// verification thread, a transaction index on disk and fewer clients
+    LowMemory,
+}
+/// One of the two branches a wallet derives addresses from
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Branch {
+    /// Addresses we give out to get paid, `/0/*`
+    Receive,
+    /// Addresses our own transactions send change to, `/1/*`
+    Change,
+}
+/// Parses a range of derivation indexes, like `0..5000`. The end isn't included
+fn parse_range(range: &str) -> Result<Range<u32>, String> {
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| format!("{range} is not a range like 0..100"))?;
+    let start = start.parse::<u32>().map_err(|err| err.to_string())?;
+    let end = end.parse::<u32>().map_err(|err| err.to_string())?;
+    if start >= end {
+        return Err(format!("{range} is empty"));
+    }
+    Ok(start..end)
+}
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     /// Sets a custom config file
     #[arg(short, long, value_name = "FILE")]
+    #[arg(env = "UES_CONFIG")]
     pub config: Option<PathBuf>,
     /// Which network should we use
     #[arg(short, long, default_value_t=Network::Bitcoin)]
+    #[arg(env = "UES_NETWORK")]
     pub network: Network,
+    /// Starts from a preset config, before reading the config file
+    #[arg(long, value_enum)]
+    #[arg(env = "UES_PROFILE")]
+    pub profile: Option<Profile>,
     /// Turn debugging information on
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub debug: u8,
@@ -44,27 +79,244 @@ pub struct Cli {
 pub enum Commands {
     /// Starts your wallet and server
     Run {
-        /// Where should we store data
-        data_dir: String,
+        /// Where should we store data. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
         /// Your rpc user, as set in Utreexod
         #[arg(long)]
         #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_USER")]
         rpc_user: String,
         /// Your rpc password, as set in Utreexod
         #[arg(long)]
         #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_PASSWORD")]
         rpc_password: String,
         /// The hostname:port of Utreexod
         #[arg(short, long)]
         #[arg(default_value = "localhost:18332")]
+        #[arg(env = "UES_RPC_HOST")]
         rpc_host: String,
+        /// Appends a JSON record of wallet changes for every block we process to this file
+        #[arg(long)]
+        #[arg(env = "UES_EXPORT_BLOCKS")]
+        export_blocks: Option<String>,
+        /// How many transactions we keep in memory, everything else is loaded from disk
+        #[arg(long)]
+        #[arg(default_value_t = NonZeroUsize::new(DEFAULT_TX_CACHE_SIZE).unwrap())]
+        #[arg(env = "UES_TX_CACHE_SIZE")]
+        tx_cache_size: NonZeroUsize,
+        /// After starting, while already serving clients, finds the status hash and unspent
+        /// outputs of each of our addresses, and loads our most recent transactions into
+        /// memory
+        #[arg(long)]
+        #[arg(env = "UES_WARMUP")]
+        warmup: bool,
+        /// Stops applying blocks after this one, serving our wallet as it was then. Running
+        /// again without it picks up from there
+        #[arg(long)]
+        #[arg(env = "UES_STOP_AT_HEIGHT")]
+        stop_at_height: Option<u32>,
     },
     /// Setups you wallet, creating the local database and initializing the local cache
     /// must be executed exactly once.
     Setup {
-        /// Your wallet's descriptor
+        /// Your wallet's descriptor, its checksum is verified if present. An extended public key
+        /// alone is taken as `wpkh(xpub/0/*)`
         wallet_descriptor: String,
-        /// Where should we store data
-        data_dir: String,
+        /// Where should we store data. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+        /// Which receiving addresses we should watch
+        #[arg(long, value_parser = parse_range)]
+        #[arg(default_value = "0..100")]
+        receive: Range<u32>,
+        /// Which change addresses we should watch, if any. Change addresses are derived from
+        /// `/1/*` instead of our descriptor's `/0/*`
+        #[arg(long, value_parser = parse_range)]
+        change: Option<Range<u32>>,
+    },
+    /// Starts watching more addresses from one branch of our wallet, and looks for their
+    /// history up to where our wallet is synced. The rest of the wallet is left untouched.
+    /// Useful to recover funds sent to deep addresses. The server must not be running
+    Rescan {
+        /// Which branch the addresses are from
+        #[arg(long, value_enum)]
+        #[arg(default_value = "receive")]
+        branch: Branch,
+        /// Which addresses we should look for, like `1000..5000`
+        #[arg(long, value_parser = parse_range)]
+        range: Range<u32>,
+        /// The first block we should look at. Looking from anywhere but the start needs the
+        /// accumulator at the previous block, taken from a recent snapshot in our data dir
+        #[arg(long)]
+        #[arg(default_value_t = 1)]
+        from: u32,
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(long)]
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+        /// Your rpc user, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_USER")]
+        rpc_user: String,
+        /// Your rpc password, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_PASSWORD")]
+        rpc_password: String,
+        /// The hostname:port of Utreexod
+        #[arg(short, long)]
+        #[arg(default_value = "localhost:18332")]
+        #[arg(env = "UES_RPC_HOST")]
+        rpc_host: String,
+    },
+    /// Rewrites the wallet database, dropping stale data. The server must not be running
+    Compact {
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+    },
+    /// Prints a JSON summary of each address in our wallet, including the first and last
+    /// heights it was active at
+    Summary {
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+    },
+    /// Finds a wallet's history and balance in a range of blocks, prints them as JSON and
+    /// exits. Nothing is kept after we are done
+    Scan {
+        /// Your wallet's descriptor, its checksum is verified if present. An extended public key
+        /// alone is taken as `wpkh(xpub/0/*)`
+        #[arg(long)]
+        descriptor: String,
+        /// The first block we should look at. Scanning from anywhere but the start needs the
+        /// accumulator at the previous block, taken from a recent snapshot in our data dir.
+        /// A server using that data dir must be stopped first
+        #[arg(long)]
+        #[arg(default_value_t = 1)]
+        from: u32,
+        /// The last block we should look at. Defaults to our node's tip
+        #[arg(long)]
+        to: Option<u32>,
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(long)]
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+        /// Your rpc user, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_USER")]
+        rpc_user: String,
+        /// Your rpc password, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_PASSWORD")]
+        rpc_password: String,
+        /// The hostname:port of Utreexod
+        #[arg(short, long)]
+        #[arg(default_value = "localhost:18332")]
+        #[arg(env = "UES_RPC_HOST")]
+        rpc_host: String,
+    },
+    /// Checks that this build works on this platform, using vectors built into the binary:
+    /// utreexo leaf hashes and proofs, Electrum status hashes, our serialization and a
+    /// database in a scratch directory. Exits with an error if any of them fails
+    Selftest,
+    /// Prints our configuration, as resolved for the network we run on: network defaults,
+    /// then the config file, then its section for this network, then `UES_*` environment
+    /// variables
+    DumpConfig,
+    /// Writes our chain state to a file, so another machine can start from it
+    DumpChainstate {
+        /// Where the chain state should be written to
+        file: PathBuf,
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+    },
+    /// Starts a freshly set up wallet from a chain state written by `dump-chainstate`. Only
+    /// transactions after the dumped height will be found
+    LoadChainstate {
+        /// The file written by `dump-chainstate`
+        file: PathBuf,
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+    },
+    /// Writes the history of some of our addresses to a file, with the proofs for it, so
+    /// another server can import them without a rescan
+    ExportWallet {
+        /// The script hash of an address to export. May be given more than once
+        #[arg(long = "scripthash", required = true)]
+        script_hashes: Vec<sha256::Hash>,
+        /// Where the export should be written to
+        file: PathBuf,
+        /// Also include our utreexo roots after each transaction's block, for the last
+        /// 1000 blocks, and at our tip
+        #[arg(long)]
+        with_roots: bool,
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+    },
+    /// Checks every proof in a file written by `export-wallet`, without any of our data,
+    /// and that the blocks its transactions are in are the ones our node has. Prints those
+    /// blocks
+    VerifyExport {
+        /// The file written by `export-wallet`
+        file: PathBuf,
+        /// Your rpc user, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_USER")]
+        rpc_user: String,
+        /// Your rpc password, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_PASSWORD")]
+        rpc_password: String,
+        /// The hostname:port of Utreexod
+        #[arg(short, long)]
+        #[arg(default_value = "localhost:18332")]
+        #[arg(env = "UES_RPC_HOST")]
+        rpc_host: String,
+    },
+    /// Starts watching the addresses in a file written by `export-wallet`, with their
+    /// history. The export must be from a server synced at least as far as ours, and its
+    /// blocks must be the ones our node has. The server must not be running
+    ImportWallet {
+        /// The file written by `export-wallet`
+        file: PathBuf,
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+        /// Your rpc user, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_USER")]
+        rpc_user: String,
+        /// Your rpc password, as set in Utreexod
+        #[arg(long)]
+        #[arg(default_value = "")]
+        #[arg(env = "UES_RPC_PASSWORD")]
+        rpc_password: String,
+        /// The hostname:port of Utreexod
+        #[arg(short, long)]
+        #[arg(default_value = "localhost:18332")]
+        #[arg(env = "UES_RPC_HOST")]
+        rpc_host: String,
+    },
+    /// Rebuilds every address' unspent outputs and balance from its history, reporting the
+    /// ones that were wrong. The server must not be running
+    RecomputeBalances {
+        /// Where our data is stored. Defaults to your platform's data directory
+        #[arg(env = "UES_DATA_DIR")]
+        data_dir: Option<String>,
+        /// Only report wrong addresses, without fixing them
+        #[arg(long)]
+        dry_run: bool,
     },
 }
@@ -0,0 +1,1061 @@
+//! Settings that can be loaded from a TOML config file, passed with `--config`. Everything
+//! here has a default, so the file and any of its sections are optional.
+//!
+//! Settings are resolved in layers: defaults for the network we run on, then the profile
+//! given with `--profile`, then the file, then the file's `[networks.<network>]` section,
+//! so one file can serve every network.
+
+use std::{
+    net::{Ipv4Addr, TcpListener},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use bitcoin::{
+    hashes::hex::FromHex,
+    secp256k1::{PublicKey, SecretKey},
+    Network,
+};
+use serde::{Deserialize, Serialize, Serializer};
+use sysinfo::{System, SystemExt};
+
+use crate::address_cache::script_type::AddressFormat;
+use crate::cli::Profile;
+use crate::electrum::{scope::ADMIN, tls::TlsListener};
+
+/// What `dump-config` shows instead of a secret
+const REDACTED: &str = "<redacted>";
+
+/// Serializes a secret as [REDACTED], so printing our config doesn't leak it. Empty ones
+/// are kept, to show they're unset
+fn redact<S: Serializer>(secret: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    if secret.is_empty() {
+        serializer.serialize_str("")
+    } else {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+fn redact_option<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_some(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub resources: ResourceLimits,
+    pub alerts: AlertConfig,
+    pub policy: RelayPolicy,
+    pub server: ServerConfig,
+    pub sync: SyncConfig,
+    pub mempool: MempoolConfig,
+    pub index: IndexConfig,
+    pub silent_payments: SilentPaymentsConfig,
+    pub maintenance: MaintenanceConfig,
+    pub audit: AuditConfig,
+    /// Wallets sharing this server. If any is set, each Electrum session only sees the
+    /// wallet it authenticated as
+    pub wallets: Vec<WalletConfig>,
+}
+
+/// Our config sections, so we can tell where one ends in an environment variable's name
+const SECTIONS: [&str; 10] = [
+    "resources",
+    "alerts",
+    "policy",
+    "server",
+    "sync",
+    "mempool",
+    "index",
+    "silent_payments",
+    "maintenance",
+    "audit",
+];
+/// Environment variables overriding our config start with this
+const ENV_PREFIX: &str = "UES_";
+
+/// Settings whose defaults depend on the network, in the same format as a config file
+fn network_defaults(network: Network) -> toml::Value {
+    // The same ports other Electrum servers use for each network
+    let electrum_port = match network {
+        Network::Bitcoin => 50001,
+        Network::Testnet => 60001,
+        Network::Signet => 60601,
+        Network::Regtest => 60401,
+    };
+    let mut server = toml::value::Table::new();
+    server.insert("electrum_port".into(), toml::Value::Integer(electrum_port));
+    let mut defaults = toml::value::Table::new();
+    defaults.insert("server".into(), toml::Value::Table(server));
+    // Bitcoin Core relays non-standard transactions on testnet and regtest, dust included
+    if matches!(network, Network::Testnet | Network::Regtest) {
+        let mut policy = toml::value::Table::new();
+        policy.insert("dust_threshold".into(), toml::Value::Integer(0));
+        defaults.insert("policy".into(), toml::Value::Table(policy));
+    }
+    toml::Value::Table(defaults)
+}
+
+/// What `--profile low-memory` sets. Keeps us within about 512MB, fine for a Raspberry Pi
+/// with 1GB of RAM next to its node
+const LOW_MEMORY_PROFILE: &str = r#"
+[resources]
+verification_workers = 1
+async_threads = 1
+max_inflight_blocks = 2
+max_inflight_bytes = 33554432
+db_cache_size = 16777216
+disk_tx_index = true
+tx_index_cache_size = 10000
+verbose_cache_size = 1048576
+max_hot_history = 1000
+max_clients = 16
+max_rss = 536870912
+"#;
+
+/// The settings `profile` presets, in the same format as a config file
+fn profile_defaults(profile: Profile) -> toml::Value {
+    let preset = match profile {
+        Profile::LowMemory => LOW_MEMORY_PROFILE,
+    };
+    toml::from_str(preset).expect("Our profiles are valid TOML")
+}
+
+/// Merges `overrides` into `base`. Tables are merged key by key, anything else in
+/// `overrides` replaces what `base` had.
+fn merge(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// Turns `UES_<SECTION>_<KEY>` environment variables into config overrides, like
+/// `UES_SERVER_ELECTRUM_PORT=50002` for `electrum_port` in `[server]`. Options in nested
+/// tables take `__` between every level instead, like `UES_ALERTS__TELEGRAM__BOT_TOKEN` for
+/// `bot_token` in `[alerts.telegram]`. Values are read as TOML, so arrays and inline tables
+/// work too, and anything that isn't TOML is a string. Variables not naming an option, like
+/// the `UES_RPC_*` ones our CLI reads, are skipped.
+fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> toml::Value {
+    let mut overrides = toml::value::Table::new();
+    for (name, value) in vars {
+        let name = match name.strip_prefix(ENV_PREFIX) {
+            Some(name) => name.to_lowercase(),
+            None => continue,
+        };
+        let value = toml::from_str::<toml::value::Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or(toml::Value::String(value));
+        let path = if name.contains("__") {
+            let path = name.split("__").map(str::to_string).collect::<Vec<_>>();
+            if !SECTIONS.contains(&path[0].as_str()) || path.iter().any(String::is_empty) {
+                continue;
+            }
+            path
+        } else if name == "wallets" {
+            vec![name]
+        } else {
+            let option = SECTIONS.iter().find_map(|section| {
+                name.strip_prefix(section)
+                    .and_then(|key| key.strip_prefix('_'))
+                    .map(|key| vec![section.to_string(), key.to_string()])
+            });
+            match option {
+                Some(path) => path,
+                None => continue,
+            }
+        };
+        let (key, tables) = path.split_last().expect("Paths aren't empty");
+        let mut table = &mut overrides;
+        for name in tables {
+            let entry = table
+                .entry(name.clone())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+            // A deeper variable wins over one setting the whole table
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::value::Table::new());
+            }
+            table = entry.as_table_mut().expect("Just made it a table");
+        }
+        table.insert(key.clone(), value);
+    }
+    toml::Value::Table(overrides)
+}
+
+impl Config {
+    pub fn load(
+        path: Option<PathBuf>,
+        network: Network,
+        profile: Option<Profile>,
+    ) -> Result<Config, crate::error::Error> {
+        let to_error = |err: toml::de::Error| crate::error::Error::ConfigError(err.to_string());
+        let mut config = network_defaults(network);
+        if let Some(profile) = profile {
+            merge(&mut config, profile_defaults(profile));
+        }
+        if let Some(path) = path {
+            let mut file =
+                toml::from_str::<toml::Value>(&std::fs::read_to_string(path)?).map_err(to_error)?;
+            let networks = file.as_table_mut().and_then(|file| file.remove("networks"));
+            merge(&mut config, file);
+            if let Some(section) = networks.and_then(|networks| {
+                networks
+                    .as_table()
+                    .and_then(|networks| networks.get(&network.to_string()).cloned())
+            }) {
+                merge(&mut config, section);
+            }
+        }
+        merge(&mut config, env_overrides(std::env::vars()));
+        config.try_into().map_err(to_error)
+    }
+    /// Checks everything we can before starting a server, so problems show up right away and
+    /// all at once, instead of one at a time deep inside some subsystem. Returns every
+    /// problem we found.
+    pub fn validate(&self, data_dir: &Path) -> Vec<String> {
+        let mut problems = vec![];
+
+        let probe = data_dir.join(".write_test");
+        match std::fs::write(&probe, b"") {
+            Ok(_) => {
+                let _ = std::fs::remove_file(&probe);
+            }
+            Err(err) => problems.push(format!(
+                "The data directory {} is not writable: {err}",
+                data_dir.display()
+            )),
+        }
+
+        let mut listeners = vec![];
+        if self.server.listen {
+            listeners.push(("Electrum", self.server.electrum_address()));
+            if let Some(address) = self.server.electrum_tls_address() {
+                listeners.push(("Electrum TLS", address));
+            }
+        }
+        // Only a TLS port is forwarded, so tokens aren't sent in the clear
+        if self.server.listen && self.server.map_port && self.server.tls_port.is_none() {
+            problems.push("map_port needs tls_port, only our TLS port is forwarded".to_string());
+        }
+        if self.server.listen && self.server.tls_port.is_some() {
+            if self.server.tls_cert.is_none() || self.server.tls_key.is_none() {
+                problems.push("tls_port is set, but tls_cert or tls_key isn't".to_string());
+            } else if let Err(err) = TlsListener::load(&self.server) {
+                problems.push(err);
+            }
+        }
+        if let Some(port) = self.server.rest_port {
+            listeners.push(("REST", format!("127.0.0.1:{port}")));
+        }
+        if let Some(port) = self.server.monitoring_port {
+            listeners.push(("monitoring", format!("127.0.0.1:{port}")));
+        }
+        if let Some(port) = self.server.grpc_port {
+            listeners.push(("gRPC", format!("127.0.0.1:{port}")));
+        }
+        if self.server.grpc_port.is_some() && !cfg!(feature = "grpc") {
+            problems.push(
+                "grpc_port is set, but we were built without gRPC, build with --features grpc"
+                    .into(),
+            );
+        }
+        for (n, (name, address)) in listeners.iter().enumerate() {
+            if let Some((other, _)) = listeners[..n].iter().find(|(_, other)| other == address) {
+                problems.push(format!(
+                    "The {name} and {other} listeners are both set to {address}"
+                ));
+                continue;
+            }
+            if address.ends_with(":0") {
+                problems.push(format!("The {name} listener needs a port other than 0"));
+                continue;
+            }
+            if let Err(err) = TcpListener::bind(address) {
+                problems.push(format!(
+                    "Can't listen for {name} on {address}: {err}. Is another server running?"
+                ));
+            }
+        }
+
+        for url in self.alerts.webhooks.iter() {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!("Webhook {url} must be an http:// or https:// URL"));
+            }
+        }
+        if let Some(email) = &self.alerts.email {
+            if email.to.is_empty() {
+                problems.push("alerts.email needs at least one recipient".to_string());
+            }
+            if email.username.is_some() != email.password.is_some() {
+                problems.push("alerts.email needs both a username and a password".to_string());
+            }
+        }
+
+        let resources = &self.resources;
+        for (name, value) in [
+            (
+                "resources.verification_workers",
+                resources.verification_workers,
+            ),
+            ("resources.async_threads", resources.async_threads),
+            (
+                "resources.max_inflight_blocks",
+                resources.max_inflight_blocks,
+            ),
+        ] {
+            if value == 0 {
+                problems.push(format!("{name} must be at least 1"));
+            }
+        }
+        if let Err(err) = self.index.get_op_return_prefixes() {
+            problems.push(err);
+        }
+        if let Err(err) = self.silent_payments.get_keys() {
+            problems.push(err);
+        }
+        if let Some(token) = &self.server.admin_token {
+            if token.is_empty() {
+                problems.push("server.admin_token can't be empty".to_string());
+            }
+        }
+        for (n, wallet) in self.wallets.iter().enumerate() {
+            if wallet.name == ADMIN {
+                problems.push(format!(
+                    "No wallet can be named {ADMIN}, our operator authenticates as it"
+                ));
+            }
+            if self.wallets[..n]
+                .iter()
+                .any(|other| other.name == wallet.name)
+            {
+                problems.push(format!("There are two wallets named {}", wallet.name));
+            }
+            if wallet.token.is_empty() {
+                problems.push(format!("Wallet {} needs a token", wallet.name));
+            }
+            if wallet.descriptors.is_empty() {
+                problems.push(format!(
+                    "Wallet {} needs at least one descriptor",
+                    wallet.name
+                ));
+            }
+            if let Some(port) = wallet.port {
+                let taken = [
+                    Some(self.server.electrum_port).filter(|_| self.server.listen),
+                    self.server.rest_port,
+                    self.server.monitoring_port,
+                    self.server.grpc_port,
+                ]
+                .contains(&Some(port))
+                    || self.wallets[..n]
+                        .iter()
+                        .any(|other| other.port == Some(port));
+                if taken {
+                    problems.push(format!(
+                        "Wallet {} can't have port {port}, it's already in use",
+                        wallet.name
+                    ));
+                }
+            }
+        }
+        if self.sync.ipc_socket.is_some() && self.sync.proof_server.is_some() {
+            problems.push("sync.ipc_socket and sync.proof_server can't both be set".to_string());
+        }
+        if self.mempool.expiry_days == 0 {
+            problems.push("mempool.expiry_days must be at least 1".to_string());
+        }
+        let feerate = self.policy.min_relay_feerate;
+        if !feerate.is_finite() || feerate < 0.0 {
+            problems.push(format!(
+                "policy.min_relay_feerate must be a positive number, not {feerate}"
+            ));
+        }
+
+        problems
+    }
+}
+
+/// How much of this machine we may use. Defaults are derived from the available CPUs and
+/// memory, so we behave on both small boards and big servers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// How many threads verify a block's transactions, and parse our addresses when loading
+    /// our wallet
+    pub verification_workers: usize,
+    /// How many threads serve our Electrum clients
+    pub async_threads: usize,
+    /// How many blocks we may download ahead of the one being processed
+    pub max_inflight_blocks: usize,
+    /// How many bytes of blocks and proofs we may hold waiting to be processed. Downloads
+    /// pause when we are past this, so a slow disk doesn't make us pile blocks up in memory
+    pub max_inflight_bytes: usize,
+    /// How much memory, in bytes, the database may use for caching
+    pub db_cache_size: u64,
+    /// Keep our transaction index on disk, instead of entirely in memory. Useful for
+    /// wallets with more transactions than we have RAM for
+    pub disk_tx_index: bool,
+    /// With `disk_tx_index`, how many index entries we keep in memory
+    pub tx_index_cache_size: NonZeroUsize,
+    /// Below this many free bytes on the disk holding our data dir, we stop growing our
+    /// watch list and pause writes we can do without
+    pub min_free_disk_space: u64,
+    /// How many bytes of verbose transactions we keep, for clients asking for the same
+    /// ones again
+    pub verbose_cache_size: usize,
+    /// Addresses with more mined transactions than this get their oldest ones archived, so
+    /// spammed addresses stay fast to serve. 0 means we never archive
+    pub max_hot_history: usize,
+    /// How many Electrum clients may be connected at once. Past this, new ones are turned
+    /// away. 0 means no limit
+    pub max_clients: usize,
+    /// If set, we warn whenever our resident memory goes past this many bytes, so you can
+    /// tell whether these limits are small enough for your machine
+    pub max_rss: Option<u64>,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism()
+            .map(|cpus| cpus.get())
+            .unwrap_or(1);
+        let mut system = System::new();
+        system.refresh_memory();
+        let memory = system.total_memory();
+
+        ResourceLimits {
+            verification_workers: cpus,
+            async_threads: cpus,
+            max_inflight_blocks: (memory / (256 * 1024 * 1024)).clamp(2, 64) as usize,
+            max_inflight_bytes: (memory / 16).clamp(64 * 1024 * 1024, 1024 * 1024 * 1024) as usize,
+            db_cache_size: (memory / 16).clamp(64 * 1024 * 1024, 1024 * 1024 * 1024),
+            disk_tx_index: false,
+            tx_index_cache_size: NonZeroUsize::new(100_000).expect("Cache size is not zero"),
+            min_free_disk_space: 1024 * 1024 * 1024,
+            verbose_cache_size: (memory / 256).clamp(4 * 1024 * 1024, 64 * 1024 * 1024) as usize,
+            max_hot_history: 5_000,
+            max_clients: 0,
+            max_rss: None,
+        }
+    }
+}
+
+/// Where we tell about things happening to our wallet
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// URLs that get a JSON POST when an address receives funds, an output is spent, or a
+    /// transaction we've broadcast gets conflicted
+    pub webhooks: Vec<String>,
+    /// Sends the same events to a Telegram chat
+    pub telegram: Option<TelegramConfig>,
+    /// Emails the same events
+    pub email: Option<EmailConfig>,
+    /// Gets a JSON POST with the report of a crash, see [crate::crash]
+    pub crash_report_url: Option<String>,
+}
+
+/// A Telegram bot that tells a chat about our wallet events
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TelegramConfig {
+    #[serde(serialize_with = "redact")]
+    pub bot_token: String,
+    pub chat_id: String,
+    /// The message we send, where `{field}` is replaced by that field of the event, e.g.
+    /// `{event}`, `{value}` or `{txid}`
+    pub template: Option<String>,
+}
+
+/// An SMTP server we send emails about our wallet events through
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailConfig {
+    pub smtp_server: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    pub username: Option<String>,
+    #[serde(serialize_with = "redact_option")]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Templates for the subject and body, like the Telegram one
+    pub subject: Option<String>,
+    pub template: Option<String>,
+}
+
+impl EmailConfig {
+    /// The username and password we log in with, if we have both
+    pub fn credentials(&self) -> Option<(String, String)> {
+        Some((self.username.clone()?, self.password.clone()?))
+    }
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+/// What transactions we are willing to relay for our clients. Defaults match Bitcoin Core's
+/// for the network we run on, and `[networks.<network>.policy]` sets them for one network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayPolicy {
+    /// Outputs worth less than this, in satoshis, are dust and won't be relayed
+    pub dust_threshold: u64,
+    /// The lowest feerate, in sat/vB, we relay transactions with
+    pub min_relay_feerate: f64,
+    /// How many unconfirmed ancestors a transaction we relay may have
+    pub max_ancestors: usize,
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        RelayPolicy {
+            dust_threshold: 546,
+            min_relay_feerate: 1.0,
+            max_ancestors: 25,
+        }
+    }
+}
+
+/// How we talk to clients
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Open the Electrum port. Without it, we only keep our wallet in sync, which is still
+    /// useful for block exports and webhooks
+    pub listen: bool,
+    /// Where we serve Electrum clients, on localhost. Defaults to the usual port for our
+    /// network
+    pub electrum_port: u16,
+    /// If set, we also serve Electrum clients over TLS on this port, on localhost unless
+    /// `map_port` is set. Needs `tls_cert` and `tls_key`
+    pub tls_port: Option<u16>,
+    /// Our TLS certificate, and the chain up to its CA if any, PEM encoded
+    pub tls_cert: Option<PathBuf>,
+    /// The private key of `tls_cert`, PEM encoded
+    pub tls_key: Option<PathBuf>,
+    /// If set, TLS clients must present a certificate issued by one of the CAs in this PEM
+    /// file, or the handshake fails
+    pub tls_client_ca: Option<PathBuf>,
+    /// If not empty, TLS clients must also present one of these certificates, given by
+    /// their SHA-256 fingerprint in hex. Needs `tls_client_ca`
+    pub tls_client_fingerprints: Vec<String>,
+    /// If set, we serve a read-only REST interface on this port
+    pub rest_port: Option<u16>,
+    /// If set, we serve the Prometheus metrics electrs serves, under the same names, on
+    /// this port. Connections are tuned like the REST ones
+    pub monitoring_port: Option<u16>,
+    /// If set, we serve our admin API over gRPC on this port. See `proto/admin.proto`. Needs
+    /// the `grpc` feature
+    pub grpc_port: Option<u16>,
+    /// Accept clients before our initial sync is done. Until it is, wallet queries get a
+    /// "server is syncing" error
+    pub serve_during_sync: bool,
+    /// Accept methods older clients use, like `blockchain.address.get_balance`, by
+    /// translating them into the ones that replaced them
+    pub legacy_methods: bool,
+    /// How we tune connections to our Electrum port
+    pub electrum_socket: SocketConfig,
+    /// How we tune connections to our REST port
+    pub rest_socket: SocketConfig,
+    /// How we tune connections to our gRPC port. gRPC only lets us set when keepalive
+    /// probes start, so the interval and retries are ignored
+    pub grpc_socket: SocketConfig,
+    /// Ask our router to forward our Electrum TLS port to us, with UPnP or NAT-PMP, and tell
+    /// clients our public address in `server.features`. Needs `tls_port`, which we then
+    /// listen on on every interface. Clients from other machines may authenticate as one of
+    /// our wallets, but never as our operator, and only get public methods until they do
+    pub map_port: bool,
+    /// The router NAT-PMP requests go to. Found in our routing table if unset, on Linux
+    pub gateway: Option<Ipv4Addr>,
+    /// How we show addresses in verbose transactions, admin output, wallet and block exports
+    /// and our commands' output
+    pub address_format: AddressFormat,
+    /// Electrum clients authenticating with `server.authenticate ["admin", <admin_token>]`
+    /// may call `admin.*` methods. Without it, they are only served over gRPC
+    #[serde(serialize_with = "redact_option")]
+    pub admin_token: Option<String>,
+}
+
+impl ServerConfig {
+    pub fn electrum_address(&self) -> String {
+        format!("127.0.0.1:{}", self.electrum_port)
+    }
+    /// Where we serve Electrum clients over TLS, if we do
+    pub fn electrum_tls_address(&self) -> Option<String> {
+        let port = self.tls_port?;
+        // Our forwarded port must reach us from our network, not just this machine
+        let host = if self.map_port {
+            "0.0.0.0"
+        } else {
+            "127.0.0.1"
+        };
+        Some(format!("{host}:{port}"))
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: true,
+            electrum_port: 50001,
+            tls_port: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_client_fingerprints: vec![],
+            rest_port: None,
+            monitoring_port: None,
+            grpc_port: None,
+            serve_during_sync: false,
+            legacy_methods: false,
+            electrum_socket: SocketConfig::default(),
+            rest_socket: SocketConfig::default(),
+            grpc_socket: SocketConfig::default(),
+            map_port: false,
+            gateway: None,
+            address_format: AddressFormat::default(),
+            admin_token: None,
+        }
+    }
+}
+
+/// TCP options for the connections one of our ports accepts. Electrum clients keep their
+/// connection open for as long as they run, and often sit behind NATs that silently forget
+/// idle connections, so keepalive probes are what lets both sides find out a connection is
+/// dead.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketConfig {
+    /// Send small writes, like notifications, right away instead of waiting to fill a packet
+    pub nodelay: bool,
+    /// Send keepalive probes on idle connections
+    pub keepalive: bool,
+    /// For how many seconds a connection may be idle before we start probing it
+    pub keepalive_time_secs: u64,
+    /// How many seconds we wait between probes
+    pub keepalive_interval_secs: u64,
+    /// How many probes may go unanswered before the connection is dropped. Ignored on
+    /// Windows, which always sends 10
+    pub keepalive_retries: u32,
+}
+
+impl SocketConfig {
+    /// When keepalive probes start, if we send them
+    pub fn keepalive_time(&self) -> Option<Duration> {
+        self.keepalive
+            .then(|| Duration::from_secs(self.keepalive_time_secs))
+    }
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            nodelay: true,
+            keepalive: true,
+            keepalive_time_secs: 60,
+            keepalive_interval_secs: 10,
+            keepalive_retries: 5,
+        }
+    }
+}
+
+/// One of the wallets sharing this server. Its clients authenticate with
+/// `server.authenticate [name, token]`, and can then only ask about its own addresses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletConfig {
+    pub name: String,
+    /// The secret its clients authenticate with
+    #[serde(serialize_with = "redact")]
+    pub token: String,
+    /// Its descriptors, or extended public keys, as `setup` takes them. Change addresses,
+    /// from `/1/*`, belong to it too
+    pub descriptors: Vec<String>,
+    /// How many addresses of each branch belong to it
+    #[serde(default = "default_wallet_addresses")]
+    pub addresses: u32,
+    /// If set, we open this Electrum port for it alone. Its clients connect there without
+    /// authenticating, and never see another wallet
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+fn default_wallet_addresses() -> u32 {
+    1_000
+}
+
+/// How we treat the unconfirmed transactions our clients broadcast
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MempoolConfig {
+    /// For how many days we keep rebroadcasting a transaction that doesn't confirm. After
+    /// that, it's reported as dropped
+    pub expiry_days: u64,
+}
+
+impl MempoolConfig {
+    pub fn expiry(&self) -> Duration {
+        Duration::from_secs(self.expiry_days * 24 * 60 * 60)
+    }
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        // Bitcoin Core's default mempool expiry
+        MempoolConfig { expiry_days: 14 }
+    }
+}
+
+/// Extra data we index, besides our wallet
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexConfig {
+    /// Hex encoded prefixes. OP_RETURN outputs whose payload starts with one of them are kept
+    pub op_return_prefixes: Vec<String>,
+}
+
+impl IndexConfig {
+    pub fn get_op_return_prefixes(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.op_return_prefixes
+            .iter()
+            .map(|prefix| match Vec::from_hex(prefix) {
+                Ok(prefix) if !prefix.is_empty() => Ok(prefix),
+                _ => Err(format!(
+                    "index.op_return_prefixes: {prefix} is not a non-empty hex string"
+                )),
+            })
+            .collect()
+    }
+}
+
+/// How often our periodic maintenance jobs run. Setting an interval to 0 disables its job
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceConfig {
+    /// Drops transaction bodies no address refers to anymore, like `compact` does
+    pub compact_interval_secs: u64,
+    /// Writes our chain state to `chainstate.json` in our data dir, ready for
+    /// `load-chainstate`
+    pub snapshot_interval_secs: u64,
+    /// Sends the transactions we've broadcast, but didn't confirm yet, to our node again.
+    /// They are also sent after each block
+    pub rebroadcast_interval_secs: u64,
+    /// Checks that our fallback nodes are reachable and keeping up with our node
+    pub peer_check_interval_secs: u64,
+    /// Drops broadcast transactions older than `mempool.expiry_days`. This is also done
+    /// after each block
+    pub mempool_expiry_interval_secs: u64,
+    /// Cross-checks our wallet and accumulator with the Bitcoin Core node in `[audit]`
+    pub audit_interval_secs: u64,
+    /// Each run is moved by up to this fraction of its interval, either way, so jobs
+    /// started together don't keep running at the same time
+    pub jitter: f64,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            compact_interval_secs: 24 * 60 * 60,
+            snapshot_interval_secs: 6 * 60 * 60,
+            rebroadcast_interval_secs: 30 * 60,
+            peer_check_interval_secs: 5 * 60,
+            mempool_expiry_interval_secs: 60 * 60,
+            audit_interval_secs: 6 * 60 * 60,
+            jitter: 0.1,
+        }
+    }
+}
+
+/// A Bitcoin Core node we cross-check our wallet and accumulator with, see [crate::audit].
+/// Auditing is off unless `core_rpc_url` is set
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub core_rpc_url: Option<String>,
+    pub core_rpc_user: String,
+    #[serde(serialize_with = "redact")]
+    pub core_rpc_password: String,
+}
+
+/// Keys for BIP352 silent payments scanning. Scanning is off unless both are set
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SilentPaymentsConfig {
+    /// Our scan private key, hex encoded. It can find our payments, but not spend them
+    #[serde(serialize_with = "redact_option")]
+    pub scan_key: Option<String>,
+    /// Our spend public key, hex encoded
+    pub spend_key: Option<String>,
+}
+
+impl SilentPaymentsConfig {
+    pub fn get_keys(&self) -> Result<Option<(SecretKey, PublicKey)>, String> {
+        match (&self.scan_key, &self.spend_key) {
+            (None, None) => Ok(None),
+            (Some(scan_key), Some(spend_key)) => {
+                let scan_key = SecretKey::from_str(scan_key)
+                    .map_err(|err| format!("silent_payments.scan_key is invalid: {err}"))?;
+                let spend_key = PublicKey::from_str(spend_key)
+                    .map_err(|err| format!("silent_payments.spend_key is invalid: {err}"))?;
+                Ok(Some((scan_key, spend_key)))
+            }
+            _ => Err("silent_payments needs both scan_key and spend_key".to_string()),
+        }
+    }
+}
+
+/// How we get our blocks
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncConfig {
+    /// Other utreexo bridge nodes, only asked for a block if the proof our node gave us
+    /// doesn't fit our accumulator
+    pub fallback_nodes: Vec<NodeConfig>,
+    /// A unix socket a bridge node on this machine serves blocks and proofs on. If set, we
+    /// get them from it instead of our node's RPC
+    pub ipc_socket: Option<PathBuf>,
+    /// Another of these servers, as `host:port`, we get block proofs from instead of our
+    /// node, so they share one bridge. Blocks still come from our node, and so do the
+    /// proofs that server doesn't keep anymore
+    pub proof_server: Option<String>,
+    /// Download new tip blocks from whichever of our node and fallback nodes has been the
+    /// fastest lately, so clients are notified sooner
+    pub prefer_fastest_node: bool,
+}
+
+/// How we reach a utreexo bridge node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeConfig {
+    /// The node's hostname:port
+    pub host: String,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default, serialize_with = "redact")]
+    pub password: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{env_overrides, merge, network_defaults, profile_defaults, Config};
+    use crate::cli::Profile;
+    use crate::electrum::{scope::ADMIN, tls::TlsListener};
+    use bitcoin::Network;
+
+    #[test]
+    fn test_network_layers() {
+        let file = toml::from_str::<toml::Value>(
+            r#"
+            [server]
+            rest_port = 3000
+
+            [networks.signet.server]
+            electrum_port = 50002
+            "#,
+        )
+        .unwrap();
+        let mut config = network_defaults(Network::Testnet);
+        merge(&mut config, file.clone());
+        let config: Config = config.try_into().unwrap();
+        assert_eq!(config.server.electrum_port, 60001);
+        assert_eq!(config.server.rest_port, Some(3000));
+
+        assert_eq!(config.policy.dust_threshold, 0);
+
+        let mut config = network_defaults(Network::Signet);
+        merge(&mut config, file["networks"]["signet"].clone());
+        let config: Config = config.try_into().unwrap();
+        assert_eq!(config.server.electrum_port, 50002);
+        assert_eq!(config.policy.dust_threshold, 546);
+    }
+
+    #[test]
+    fn test_profile_layer() {
+        let file = toml::from_str::<toml::Value>("resources = { async_threads = 2 }").unwrap();
+        let mut config = network_defaults(Network::Bitcoin);
+        merge(&mut config, profile_defaults(Profile::LowMemory));
+        merge(&mut config, file);
+        let config: Config = config.try_into().unwrap();
+        assert!(config.resources.disk_tx_index);
+        assert_eq!(config.resources.verification_workers, 1);
+        // The file still wins over the profile
+        assert_eq!(config.resources.async_threads, 2);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let vars = [
+            ("UES_SERVER_ELECTRUM_PORT", "50002"),
+            ("UES_SERVER_LISTEN", "false"),
+            ("UES_SILENT_PAYMENTS_SCAN_KEY", "abcd"),
+            ("UES_ALERTS_WEBHOOKS", r#"["http://localhost:8080"]"#),
+            ("UES_ALERTS__TELEGRAM__BOT_TOKEN", "123:abc"),
+            ("UES_ALERTS__TELEGRAM__CHAT_ID", r#""42""#),
+            ("UES_RPC_USER", "satoshi"),
+            ("UES_RPC__USER", "satoshi"),
+            ("HOME", "/root"),
+        ];
+        let mut config = network_defaults(Network::Bitcoin);
+        merge(
+            &mut config,
+            env_overrides(
+                vars.iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string())),
+            ),
+        );
+        let config: Config = config.try_into().unwrap();
+        assert_eq!(config.server.electrum_port, 50002);
+        assert!(!config.server.listen);
+        assert_eq!(config.silent_payments.scan_key.as_deref(), Some("abcd"));
+        assert_eq!(config.alerts.webhooks, vec!["http://localhost:8080"]);
+        let telegram = config.alerts.telegram.unwrap();
+        assert_eq!(telegram.bot_token, "123:abc");
+        assert_eq!(telegram.chat_id, "42");
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = std::path::Path::new("/tmp/utreexo_validate");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let mut config: Config = network_defaults(Network::Bitcoin).try_into().unwrap();
+        config.server.listen = false;
+        config.server.rest_port = None;
+        config.server.monitoring_port = None;
+        config.server.grpc_port = None;
+        assert_eq!(config.validate(dir), Vec::<String>::new());
+
+        // Every problem is reported at once
+        config.server.rest_port = Some(3000);
+        config.server.monitoring_port = Some(3000);
+        config.alerts.webhooks = vec!["localhost:8080".to_string()];
+        config.resources.async_threads = 0;
+        let problems = config.validate(&dir.join("missing"));
+        for problem in [
+            "The data directory /tmp/utreexo_validate/missing is not writable",
+            "The monitoring and REST listeners are both set to 127.0.0.1:3000",
+            "Webhook localhost:8080 must be an http:// or https:// URL",
+            "resources.async_threads must be at least 1",
+        ] {
+            assert!(
+                problems.iter().any(|found| found.starts_with(problem)),
+                "{problem} not in {problems:?}"
+            );
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_wallet_ports() {
+        let dir = std::path::Path::new("/tmp/utreexo_wallet_ports");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let file = toml::from_str::<toml::Value>(
+            r#"
+            [server]
+            listen = false
+            rest_port = 3000
+
+            [[wallets]]
+            name = "alice"
+            token = "secret"
+            descriptors = ["xpub"]
+            port = 3000
+
+            [[wallets]]
+            name = "bob"
+            token = "hunter3"
+            descriptors = ["xpub"]
+            port = 3001
+
+            [[wallets]]
+            name = "carol"
+            token = "hunter4"
+            descriptors = ["xpub"]
+            port = 3001
+            "#,
+        )
+        .unwrap();
+        let mut config = network_defaults(Network::Bitcoin);
+        merge(&mut config, file);
+        let config: Config = config.try_into().unwrap();
+        let problems = config
+            .validate(dir)
+            .into_iter()
+            .filter(|problem| problem.starts_with("Wallet"))
+            .collect::<Vec<_>>();
+        // A wallet's port can't be one of our listeners, or another wallet's
+        assert_eq!(
+            problems,
+            vec![
+                "Wallet alice can't have port 3000, it's already in use",
+                "Wallet carol can't have port 3001, it's already in use",
+            ]
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_map_port_needs_tls() {
+        let dir = std::path::Path::new("/tmp/utreexo_map_port");
+        std::fs::create_dir_all(dir).unwrap();
+        let mut config: Config = network_defaults(Network::Bitcoin).try_into().unwrap();
+        config.server.map_port = true;
+        let problem = "map_port needs tls_port, only our TLS port is forwarded".to_string();
+        assert!(config.validate(dir).contains(&problem));
+
+        // Only the TLS port is reachable from other machines
+        config.server.tls_port = Some(50002);
+        assert!(!config.validate(dir).contains(&problem));
+        assert_eq!(config.server.electrum_address(), "127.0.0.1:50001");
+        assert_eq!(
+            config.server.electrum_tls_address().as_deref(),
+            Some("0.0.0.0:50002")
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_dump_redacts_secrets() {
+        let file = toml::from_str::<toml::Value>(
+            r#"
+            [server]
+            admin_token = "hunter2"
+
+            [silent_payments]
+            scan_key = "not-a-real-scan-key"
+
+            [[sync.fallback_nodes]]
+            host = "localhost:8334"
+            password = "s3cret"
+            "#,
+        )
+        .unwrap();
+        let mut config = network_defaults(Network::Bitcoin);
+        merge(&mut config, file);
+        let config: Config = config.try_into().unwrap();
+        // We still run with the real ones
+        assert_eq!(config.server.admin_token.as_deref(), Some("hunter2"));
+
+        let dump = toml::to_string_pretty(&config).unwrap();
+        for secret in ["hunter2", "not-a-real-scan-key", "s3cret"] {
+            assert!(!dump.contains(secret), "{secret} was printed");
+        }
+        assert!(dump.contains("<redacted>"));
+        // Unset secrets stay unset
+        assert!(dump.contains("core_rpc_password = \"\""));
+    }
+}
@@ -0,0 +1,215 @@
+//! Writes a report when we panic, so a bug seen in the field can be looked into without the
+//! operator digging through logs. Reports go to `crashes/` in our data dir, and are also
+//! POSTed to [crate::config::AlertConfig::crash_report_url] if it's set. Besides the panic
+//! and its backtrace, they say where our chain state was and which subsystem was running.
+//! Anything long enough to be a hash, key or address is redacted, and so is our data dir.
+
+use std::{
+    cell::Cell,
+    fs::{File, OpenOptions},
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcoin::BlockHash;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{address_cache::webhooks::WEBHOOK_TIMEOUT, supervisor::Subsystem};
+
+/// Runs of this many letters and digits are redacted
+const REDACT_MIN_LENGTH: usize = 26;
+
+thread_local! {
+    /// The subsystem being polled on this thread, if any
+    static SUBSYSTEM: Cell<Option<Subsystem>> = Cell::new(None);
+}
+
+/// Where our chain state is, kept up to date by our wallet
+#[derive(Debug, Clone, Default)]
+pub struct StateFingerprint(Arc<Mutex<Option<(u32, BlockHash, u64)>>>);
+
+impl StateFingerprint {
+    /// Records our new tip, and how many leaves our accumulator has after it
+    pub fn update(&self, height: u32, block_hash: BlockHash, leaves: u64) {
+        *self.0.lock().expect("Poisoned lock") = Some((height, block_hash, leaves));
+    }
+}
+
+/// Runs a future as part of `subsystem`, so a panic while polling it is reported as its
+pub struct InSubsystem<F> {
+    subsystem: Subsystem,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> InSubsystem<F> {
+    pub fn new(subsystem: Subsystem, future: F) -> InSubsystem<F> {
+        InSubsystem {
+            subsystem,
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl<F: Future> Future for InSubsystem<F> {
+    type Output = F::Output;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let previous = SUBSYSTEM.with(|current| current.replace(Some(self.subsystem)));
+        let poll = self.future.as_mut().poll(cx);
+        SUBSYSTEM.with(|current| current.set(previous));
+        poll
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CrashReport {
+    pub version: &'static str,
+    /// Unix time of the crash
+    pub time: u64,
+    pub height: Option<u32>,
+    pub block_hash: Option<BlockHash>,
+    /// How many leaves our accumulator had
+    pub leaves: Option<u64>,
+    pub subsystem: Option<String>,
+    pub thread: Option<String>,
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: String,
+}
+
+/// Replaces `data_dir`, and any long run of letters and digits, in `text`
+fn redact(text: &str, data_dir: &str) -> String {
+    let text = if data_dir.is_empty() {
+        text.to_string()
+    } else {
+        text.replace(data_dir, "<data_dir>")
+    };
+    let mut redacted = String::with_capacity(text.len());
+    let mut run = String::new();
+    for char in text.chars().chain(std::iter::once(' ')) {
+        if char.is_ascii_alphanumeric() {
+            run.push(char);
+            continue;
+        }
+        if run.len() >= REDACT_MIN_LENGTH {
+            redacted.push_str("<redacted>");
+        } else {
+            redacted.push_str(&run);
+        }
+        run.clear();
+        redacted.push(char);
+    }
+    redacted.pop();
+    redacted
+}
+
+/// Creates a new file in `dir` for a report of a crash at `time`. Threads may panic within
+/// the same second, so we never overwrite a report, and number the later ones instead.
+fn create_report_file(dir: &Path, time: u64) -> std::io::Result<(PathBuf, File)> {
+    std::fs::create_dir_all(dir)?;
+    let mut number = 0;
+    loop {
+        let name = match number {
+            0 => format!("crash-{time}.json"),
+            number => format!("crash-{time}-{number}.json"),
+        };
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => number += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Starts writing a report for every panic, after the usual panic message is printed
+pub fn install(data_dir: &str, url: Option<String>, fingerprint: StateFingerprint) {
+    let data_dir = data_dir.to_string();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic| {
+        default_hook(panic);
+        let message = panic
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        // The panic may have happened while updating it, so we don't wait for it
+        let state = fingerprint.0.try_lock().ok().and_then(|state| *state);
+        let report = CrashReport {
+            version: env!("CARGO_PKG_VERSION"),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+            height: state.map(|(height, _, _)| height),
+            block_hash: state.map(|(_, block_hash, _)| block_hash),
+            leaves: state.map(|(_, _, leaves)| leaves),
+            subsystem: SUBSYSTEM
+                .with(|current| current.get().map(|subsystem| subsystem.to_string())),
+            thread: std::thread::current().name().map(str::to_string),
+            message: redact(&message, &data_dir),
+            location: panic.location().map(|location| location.to_string()),
+            backtrace: redact(
+                &std::backtrace::Backtrace::force_capture().to_string(),
+                &data_dir,
+            ),
+        };
+        let dir = PathBuf::from(&data_dir).join("crashes");
+        let written = create_report_file(&dir, report.time)
+            .map_err(|err| err.to_string())
+            .and_then(|(path, file)| {
+                serde_json::to_writer_pretty(file, &report)
+                    .map(|_| path)
+                    .map_err(|err| err.to_string())
+            });
+        match written {
+            Ok(path) => info!("Crash report written to {}", path.display()),
+            Err(err) => error!("Could not write a crash report: {err}"),
+        }
+        if let Some(url) = &url {
+            if let Err(err) = ureq::post(url).timeout(WEBHOOK_TIMEOUT).send_json(&report) {
+                error!("Could not send the crash report to {url}: {err}");
+            }
+        }
+    }));
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{create_report_file, redact};
+
+    #[test]
+    fn test_redact() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        assert_eq!(
+            redact(&format!("Could not cache {txid} at 7"), ""),
+            "Could not cache <redacted> at 7"
+        );
+        assert_eq!(
+            redact(
+                "/home/satoshi/.utreexo/wallet.db: no such file",
+                "/home/satoshi/.utreexo"
+            ),
+            "<data_dir>/wallet.db: no such file"
+        );
+        let frame = "utreexo_wallet::address_cache::AddressCache::block_process::h1a2b3c4d5e6f7a8b";
+        assert_eq!(redact(frame, ""), frame);
+    }
+    #[test]
+    fn test_reports_in_the_same_second() {
+        let dir = Path::new("/tmp/utreexo_crashes/");
+        let _ = std::fs::remove_dir_all(dir);
+        let first = create_report_file(dir, 7).unwrap().0;
+        let second = create_report_file(dir, 7).unwrap().0;
+        assert_eq!(first, dir.join("crash-7.json"));
+        assert_eq!(second, dir.join("crash-7-1.json"));
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+    }
+}
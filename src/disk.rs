@@ -0,0 +1,93 @@
+//! Watches the free space left on the disk holding our data dir. A write that fails halfway
+//! because the disk filled up may leave our database in a state we can't recover from, so
+//! once free space drops below [crate::config::ResourceLimits::min_free_disk_space] we stop
+//! taking on new work that grows the database, and skip writes we can live without.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use sysinfo::{DiskExt, System, SystemExt};
+
+/// How often we look at the free space again
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What we last saw on our disk, shared with whoever needs to know
+#[derive(Debug, Clone, Default)]
+pub struct DiskSpace {
+    low: Arc<AtomicBool>,
+    free: Arc<AtomicU64>,
+}
+
+impl DiskSpace {
+    /// Whether we are below our free space threshold
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+    /// How many bytes were free on our last check
+    pub fn free(&self) -> u64 {
+        self.free.load(Ordering::Relaxed)
+    }
+    /// A disk that stays low, or not, whatever is on it
+    #[cfg(test)]
+    pub fn fixed(low: bool) -> DiskSpace {
+        let space = DiskSpace::default();
+        space.low.store(low, Ordering::Relaxed);
+        space
+    }
+}
+
+/// Returns how many bytes are available on the disk holding `path`. That's the disk with
+/// the longest mount point `path` is under.
+fn free_space(system: &mut System, path: &Path) -> Option<u64> {
+    system.refresh_disks_list();
+    system
+        .disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().components().count())
+        .map(|disk| disk.available_space())
+}
+
+/// Checks the disk holding `data_dir` right away, then keeps checking it in the background
+pub fn monitor(data_dir: &str, min_free: u64) -> DiskSpace {
+    let path = std::fs::canonicalize(data_dir).unwrap_or_else(|_| PathBuf::from(data_dir));
+    let space = DiskSpace::default();
+    let mut system = System::new();
+    if free_space(&mut system, &path).is_none() {
+        warn!("Could not find the disk holding {data_dir}, free space won't be checked");
+        return space;
+    }
+    let shared = space.clone();
+    let check = move |system: &mut System| {
+        let free = match free_space(system, &path) {
+            Some(free) => free,
+            None => return,
+        };
+        shared.free.store(free, Ordering::Relaxed);
+        let low = free < min_free;
+        if shared.low.swap(low, Ordering::Relaxed) == low {
+            return;
+        }
+        if low {
+            warn!(
+                "Only {free} bytes left on disk, not watching anything new and pausing \
+                 block logs, exports and proofs until at least {min_free} are free"
+            );
+        } else {
+            info!("{free} bytes free on disk again, resuming everything we paused");
+        }
+    };
+    check(&mut system);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(DISK_CHECK_INTERVAL);
+        check(&mut system);
+    });
+    space
+}
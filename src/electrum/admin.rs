@@ -0,0 +1,66 @@
+//! What our admin API can be asked, whichever way it's served. Requests are answered by our
+//! main loop, see `grpc.rs` for the service itself.
+// Only the gRPC service sends requests, so they're unused without it
+#![cfg_attr(not(feature = "grpc"), allow(dead_code))]
+
+use async_std::channel::Sender;
+use bitcoin::hashes::sha256;
+
+use crate::{
+    address_cache::{AddressSummary, BalanceCheckpoint},
+    config::RelayPolicy,
+};
+
+#[derive(Debug)]
+pub enum AdminRequest {
+    SyncStatus,
+    WalletSummary,
+    Peers,
+    WalletCommitment,
+    BalanceHistory {
+        from: u32,
+        to: u32,
+    },
+    GetPolicy,
+    SetPolicy(RelayPolicy),
+    /// Starts watching the first `addresses` receive and change addresses of `descriptor`
+    ImportDescriptor {
+        descriptor: String,
+        addresses: u32,
+    },
+    /// Looks for our addresses' transactions in blocks `from` to `to` again. `to` is our
+    /// wallet's height if zero
+    Rescan {
+        from: u32,
+        to: u32,
+    },
+}
+
+#[derive(Debug)]
+pub enum AdminResponse {
+    SyncStatus {
+        height: u32,
+        tip_height: u32,
+        leaves: u64,
+    },
+    WalletSummary(Vec<AddressSummary>),
+    Peers(Vec<u32>),
+    WalletCommitment {
+        height: u32,
+        commitment: sha256::Hash,
+    },
+    BalanceHistory(Vec<BalanceCheckpoint>),
+    Policy(RelayPolicy),
+    /// How many addresses we weren't watching yet
+    Imported(u32),
+    /// Which blocks we'll rescan. It runs in the background, one block at a time
+    RescanStarted {
+        from: u32,
+        to: u32,
+    },
+    /// We couldn't do what was asked, and why
+    Failed(String),
+}
+
+/// A request and where its answer should go
+pub type AdminMessage = (AdminRequest, Sender<AdminResponse>);
@@ -0,0 +1,120 @@
+//! Methods older clients still use, that we don't serve under their own name. With
+//! `server.legacy_methods` set, requests for them are translated into the methods that
+//! replaced them before we handle them.
+
+use std::str::FromStr;
+
+use bitcoin::{Address, Network};
+use log::debug;
+use serde_json::json;
+
+use super::{electrum_protocol::get_spk_hash, error::Error, request::Request};
+
+/// How the params of an old method map to the params of its replacement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Params {
+    /// The first param is an address, the replacement wants its script hash
+    AddressToScriptHash,
+}
+
+/// A method we only serve through its replacement
+struct Alias {
+    method: &'static str,
+    replacement: &'static str,
+    params: Params,
+}
+
+/// Every old method we know how to translate
+const ALIASES: &[Alias] = &[
+    Alias {
+        method: "blockchain.address.get_balance",
+        replacement: "blockchain.scripthash.get_balance",
+        params: Params::AddressToScriptHash,
+    },
+    Alias {
+        method: "blockchain.address.get_history",
+        replacement: "blockchain.scripthash.get_history",
+        params: Params::AddressToScriptHash,
+    },
+    Alias {
+        method: "blockchain.address.get_mempool",
+        replacement: "blockchain.scripthash.get_mempool",
+        params: Params::AddressToScriptHash,
+    },
+];
+
+/// Rewrites a request for an old method into one for its replacement. Requests for
+/// anything else are returned as they are.
+pub fn translate(mut request: Request, network: Network) -> Result<Request, Error> {
+    let alias = match ALIASES.iter().find(|alias| alias.method == request.method) {
+        Some(alias) => alias,
+        None => return Ok(request),
+    };
+    debug!("Translating {} into {}", alias.method, alias.replacement);
+    match alias.params {
+        Params::AddressToScriptHash => {
+            let address = request
+                .params
+                .first()
+                .and_then(|address| address.as_str())
+                .ok_or(Error::InvalidParams)?;
+            request.params[0] = json!(address_to_script_hash(address, network)?);
+        }
+    }
+    request.method = alias.replacement.to_string();
+    Ok(request)
+}
+
+/// Decodes an address for our network, and returns the script hash Electrum uses for it
+pub fn address_to_script_hash(
+    address: &str,
+    network: Network,
+) -> Result<bitcoin::hashes::sha256::Hash, Error> {
+    let address = Address::from_str(address).map_err(|_| Error::InvalidParams)?;
+    if !address.is_valid_for_network(network) {
+        return Err(Error::InvalidParams);
+    }
+    Ok(get_spk_hash(&address.script_pubkey()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::translate;
+    use crate::electrum::request::Request;
+    use bitcoin::Network;
+    use serde_json::json;
+
+    fn request(method: &str, address: &str) -> Request {
+        Request {
+            id: 0,
+            method: method.to_string(),
+            jsonrpc: "2.0".to_string(),
+            params: vec![json!(address)],
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        // The example from Electrum's protocol docs
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let translated = translate(
+            request("blockchain.address.get_balance", address),
+            Network::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(translated.method, "blockchain.scripthash.get_balance");
+        assert_eq!(
+            translated.params[0],
+            json!("8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161")
+        );
+        // Addresses for another network are refused
+        assert!(translate(
+            request("blockchain.address.get_history", address),
+            Network::Testnet
+        )
+        .is_err());
+        // Anything else is left alone
+        let untouched = translate(request("server.ping", address), Network::Testnet).unwrap();
+        assert_eq!(untouched.method, "server.ping");
+    }
+}
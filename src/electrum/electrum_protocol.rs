@@ -1,59 +1,421 @@
-use crate::address_cache::{AddressCache, CachedTransaction};
-use crate::blockchain::chainstore::KvChainStore;
-use crate::electrum::request::Request;
-use crate::electrum::TransactionHistoryEntry;
-use crate::{address_cache::kv_database::KvDatabase, blockchain::sync::BlockchainSync};
+use crate::address_cache::{
+    find_derivations, get_derivations,
+    script_type::{get_address, AddressFormat, ScriptType},
+    AddressCache, HistoryEntry, OutpointStatus,
+};
+use crate::audit::{AuditSnapshot, CoreRpc};
+use crate::blockchain::{chain_params::ChainParams, chainstore::KvChainStore};
+use crate::cli::Branch;
+use crate::config::{MempoolConfig, RelayPolicy, ResourceLimits, SocketConfig};
+use crate::disk::DiskSpace;
+use crate::electrum::admin::{AdminMessage, AdminRequest, AdminResponse};
+use crate::electrum::compat;
+use crate::electrum::identity::ServerIdentity;
+use crate::electrum::queue::RequestQueue;
+use crate::electrum::request::{read_frame, FrameError, Request, MAX_PARAMS};
+use crate::electrum::rest::{Credentials, RestMessage, RestReply, RestRequest};
+use crate::electrum::scope::{authenticate, tokens_match, WalletScope, ADMIN};
+use crate::electrum::session::{ProtocolVersion, Session};
+use crate::electrum::tls::TlsListener;
+use crate::electrum::verbose_cache::VerboseCache;
+use crate::electrum::{electrum_height, history_entry_json, tune_socket, CoinHint, UnspentEntry};
+use crate::portmap::PortMapping;
+use crate::scheduler::Task;
+use crate::supervisor::HealthReport;
+use crate::{
+    address_cache::kv_database::KvDatabase,
+    blockchain::sync::{
+        BlockQueue, BlockSource, BlockchainSync, MAX_SYNC_BACKOFF, MIN_SYNC_BACKOFF,
+    },
+    blockchain::tip_check::{TipCheck, TipChecker, TipVerdict},
+};
 use crate::{get_arg, json_rpc_res};
 use async_std::{
-    io::BufReader,
+    channel::{unbounded, Sender as AsyncSender},
+    io::{BufReader, Read, Write},
     net::{TcpListener, TcpStream},
     prelude::*,
+    sync::Mutex,
+    task::JoinHandle,
 };
 
-use bitcoin::hashes::{sha256, Hash};
-use bitcoin::{Script, Txid};
+use bitcoin::consensus::{deserialize, encode::serialize_hex};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxOut, Txid};
 
 use btcd_rpc::client::{BTCDClient, BtcdRpc};
 use log::{log, trace, Level};
+use miniscript::{psbt::PsbtExt, Descriptor, DescriptorPublicKey};
 use serde_json::{json, Value};
 use sha2::Digest;
 use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
 use std::sync::{
+    atomic::{AtomicU32, Ordering},
     mpsc::{channel, Receiver, Sender},
-    Arc,
+    Arc, RwLock,
 };
+use std::time::Duration;
+
+/// How many blocks' worth of block log entries, or OP_RETURN matches, a client may ask for
+/// at once
+const MAX_BLOCK_LOG_ENTRIES: u32 = 1_000;
+/// How many events a client gets at once from `blockchain.events.since`
+const MAX_EVENTS_PER_REQUEST: usize = 1_000;
+/// How far past our address count `admin.getderivations` looks, for wallets with gaps
+const DERIVATION_LOOKAHEAD: u32 = 1_000;
+/// How many descriptors, besides our wallet's, a client may send us to derive addresses from
+const MAX_EXTRA_DESCRIPTORS: usize = 16;
+/// The JSON-RPC error code we tell clients we're closing their connection with, from the
+/// range the spec leaves to servers
+const DISCONNECT_ERROR_CODE: i32 = -32000;
+/// The id our next Electrum client gets
+static NEXT_PEER_ID: AtomicU32 = AtomicU32::new(0);
+/// How many blocks we apply at a time while catching up with our node. Clients are served
+/// in between.
+const SYNC_CHUNK_SIZE: u32 = 1_000;
+/// How many addresses, or transactions, we load into memory at a time while warming up
+const WARMUP_CHUNK_SIZE: usize = 100;
+/// How many addresses, on each branch, one admin request may import
+const MAX_IMPORTED_ADDRESSES: u32 = 10_000;
+/// How many addresses `admin.attestunused` vouches for at once
+const MAX_ATTESTED_ADDRESSES: u32 = 1_000;
+/// How many blocks a fallback node may be behind ours before we warn about it
+const MAX_FALLBACK_LAG: u64 = 6;
+/// How many of our latest blocks an audit checks the accumulator of
+const AUDIT_DEPTH: u32 = 6;
+/// How long we wait for a peer to take our last message before closing on it anyway
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where we write to a peer: its socket, or the TLS session over it
+pub struct PeerWriter(Box<dyn Write + Send + Unpin>);
+
+impl std::fmt::Debug for PeerWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PeerWriter")
+    }
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct Peer {
     _addresses: HashSet<Script>,
-    stream: Option<Arc<TcpStream>>,
+    /// This peer's socket, only used to close it. Everything goes through `writer`
+    stream: Option<TcpStream>,
+    /// Held while writing a frame, so responses and notifications never interleave
+    writer: Option<Arc<Mutex<PeerWriter>>>,
+    /// What this peer negotiated with us
+    session: Arc<RwLock<Session>>,
 }
 
 impl Peer {
+    /// Writes one message to this peer. The whole frame is written before any other
+    /// message to this same peer may start.
     pub async fn write(&self, data: &[u8]) -> Result<(), std::io::Error> {
-        if let Some(stream) = &self.stream {
-            let mut stream = &**stream;
-            let _ = stream.write(data).await;
-            let _ = stream.write('\n'.to_string().as_bytes()).await;
+        if let Some(writer) = &self.writer {
+            let mut writer = writer.lock().await;
+            let _ = writer.0.write_all(&frame(data)).await;
+            let _ = writer.0.flush().await;
+        }
+
+        Ok(())
+    }
+    /// Writes several messages to this peer at once, in a single burst. Fails if the peer
+    /// can't be written to anymore.
+    pub async fn write_many(&self, messages: &[Vec<u8>]) -> Result<(), std::io::Error> {
+        if let Some(writer) = &self.writer {
+            let data = messages
+                .iter()
+                .flat_map(|message| frame(message))
+                .collect::<Vec<_>>();
+            let mut writer = writer.lock().await;
+            writer.0.write_all(&data).await?;
+            writer.0.flush().await?;
         }
 
         Ok(())
     }
-    pub fn new(stream: Arc<TcpStream>) -> Self {
+    /// Tells this peer why we're closing its connection, then closes it. The protocol has
+    /// no message for this, so it's an error without a request id, which wallets can show
+    /// instead of a bare socket error.
+    pub async fn disconnect(&self, reason: &str) {
+        if let (Some(stream), Some(writer)) = (&self.stream, &self.writer) {
+            let goodbye = json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": {
+                    "code": DISCONNECT_ERROR_CODE,
+                    "message": reason
+                }
+            });
+            let mut writer = writer.lock().await;
+            let _ = async_std::io::timeout(DISCONNECT_TIMEOUT, async {
+                writer
+                    .0
+                    .write_all(&frame(goodbye.to_string().as_bytes()))
+                    .await?;
+                writer.0.close().await
+            })
+            .await;
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+    /// A peer we talk to in plain text
+    pub fn new(stream: TcpStream) -> Self {
+        Self::with_writer(stream.clone(), Box::new(stream))
+    }
+    /// A peer on `stream`, that we write to through `writer`, like a TLS session
+    pub fn with_writer(stream: TcpStream, writer: Box<dyn Write + Send + Unpin>) -> Self {
         Peer {
             _addresses: HashSet::new(),
             stream: Some(stream),
+            writer: Some(Arc::new(Mutex::new(PeerWriter(writer)))),
+            session: Arc::new(RwLock::new(Session::default())),
         }
     }
+    pub fn session(&self) -> Session {
+        self.session.read().expect("Poisoned lock").clone()
+    }
+    pub fn set_session(&self, session: Session) {
+        *self.session.write().expect("Poisoned lock") = session;
+    }
+}
+/// Notifications we've generated while applying a block, grouped by peer, so each peer
+/// gets all of theirs in a single write instead of one per script hash
+#[derive(Default)]
+struct NotificationBatch(HashMap<usize, (Arc<Peer>, Vec<Value>)>);
+
+impl NotificationBatch {
+    fn push(&mut self, peer: &Arc<Peer>, method: &str, params: Value) {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        });
+        self.0
+            .entry(Arc::as_ptr(peer) as usize)
+            .or_insert_with(|| (peer.clone(), vec![]))
+            .1
+            .push(notification);
+    }
+    async fn send(self) {
+        for (peer, notifications) in self.0.into_values() {
+            let messages = notifications
+                .iter()
+                .map(|notification| serde_json::to_vec(notification).unwrap())
+                .collect::<Vec<_>>();
+            if let Err(err) = peer.write_many(&messages).await {
+                log!(
+                    Level::Warn,
+                    "Could not notify a peer, disconnecting it: {err}"
+                );
+                // Closing its socket ends its reader, which tells our main loop it's gone
+                async_std::task::spawn(async move {
+                    peer.disconnect("Could not send notifications").await
+                });
+            }
+        }
+    }
+}
+/// Whether answering `method` needs our wallet to be in sync with our node
+fn is_wallet_query(method: &str) -> bool {
+    method.starts_with("blockchain.scripthash.")
+        || method.starts_with("blockchain.address.")
+        || method.starts_with("blockchain.outpoint.")
+        || method.starts_with("blockchain.opreturn.")
+        || method.starts_with("blockchain.transaction.get")
+}
+/// Every Electrum method we serve, anything else is refused before reaching our handlers.
+/// Each one has a schema for its result in `schema/electrum.json`
+pub const METHODS: &[&str] = &[
+    "admin.attestunused",
+    "admin.getblocklog",
+    "admin.getderivations",
+    "admin.getdiskspace",
+    "admin.gethealth",
+    "admin.getpolicy",
+    "admin.getsilentpayments",
+    "admin.getwalletcommitment",
+    "admin.setpolicy",
+    "blockchain.address.listunspent",
+    "blockchain.address.subscribe",
+    "blockchain.block.header",
+    "blockchain.block.headers",
+    "blockchain.block.stats",
+    "blockchain.estimatefee",
+    "blockchain.events.since",
+    "blockchain.headers.subscribe",
+    "blockchain.opreturn.get_matches",
+    "blockchain.outpoint.subscribe",
+    "blockchain.outpoint.unsubscribe",
+    "blockchain.psbt.update",
+    "blockchain.relayfee",
+    "blockchain.scripthash.get_balance",
+    "blockchain.scripthash.get_history",
+    "blockchain.scripthash.get_mempool",
+    "blockchain.scripthash.subscribe",
+    "blockchain.transaction.broadcast",
+    "blockchain.transaction.get",
+    "blockchain.transaction.get_merkle",
+    "blockchain.utreexo.get_block_proof",
+    "blockchain.utreexo.get_roots_at_height",
+    "blockchain.wallet.get_coin_hints",
+    "mempool.get_fee_histogram",
+    "server.authenticate",
+    "server.banner",
+    "server.donation_address",
+    "server.features",
+    "server.identity",
+    "server.peers.subscribe",
+    "server.ping",
+    "server.version",
+    "wallet.get_balance_history",
+];
+/// Whether `method` is only served to our operator. Admin methods change how we run, and
+/// wallet methods show all our wallets together
+fn is_admin_method(method: &str) -> bool {
+    method.starts_with("admin.") || method.starts_with("wallet.")
+}
+/// Whether a client that reached us through our mapped port is refused `method`. That port
+/// speaks TLS, so it may authenticate as one of our wallets, and then use the methods showing
+/// that wallet, but it never gets our operator's.
+fn refused_to_public(session: &Session, method: &str) -> bool {
+    let shows_wallet = matches!(
+        method,
+        "blockchain.wallet.get_coin_hints" | "blockchain.events.since" | "blockchain.psbt.update"
+    );
+    session.public && (is_admin_method(method) || (shows_wallet && session.wallet.is_none()))
+}
+/// What we answer request `id` with when it failed
+fn error_response(id: i32, err: super::error::Error) -> Value {
+    let error = match err {
+        super::error::Error::PolicyViolation(reason) => reason,
+        super::error::Error::Syncing { height, tip } => {
+            format!("Server is syncing, at block {height} of {tip}")
+        }
+        super::error::Error::Unauthorized => "Not allowed for this session".to_string(),
+        super::error::Error::MethodNotFound => "Method not found".to_string(),
+        super::error::Error::CacheError(crate::error::Error::LowDiskSpace) => {
+            "Server is low on disk space".to_string()
+        }
+        _ => "Unknown".to_string(),
+    };
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": error,
+        "data": null
+    })
+}
+/// Answers request `id` of `peer` with what `work` returns, on another thread, so a slow
+/// request doesn't hold up everyone else. What this peer sends meanwhile waits until the
+/// answer is written, so it still gets its answers in order.
+fn answer_later(
+    peer: Arc<Peer>,
+    id: i32,
+    work: impl FnOnce() -> Result<Value, super::error::Error> + Send + 'static,
+) -> Reply {
+    Reply::Deferred(async_std::task::spawn(async move {
+        let result: Result<Value, super::error::Error> =
+            async_std::task::spawn_blocking(work).await;
+        let res = match result {
+            Ok(result) => json!({
+                "jsonrpc": "2.0",
+                "result": result,
+                "id": id
+            }),
+            Err(err) => error_response(id, err),
+        };
+        let _ = peer
+            .write(serde_json::to_string(&res).unwrap().as_bytes())
+            .await;
+    }))
+}
+/// Electrum messages are separated by a newline
+fn frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 1);
+    frame.extend_from_slice(data);
+    frame.push(b'\n');
+    frame
 }
 pub struct ElectrumServer {
     pub rpc: Arc<BTCDClient>,
+    /// Where we get blocks and proofs from, our node unless a local bridge is configured
+    pub block_source: Arc<dyn BlockSource + Send>,
+    /// Where we get new tip blocks from, once we've caught up
+    pub tip_source: Arc<dyn BlockSource + Send>,
+    /// Nodes we ask for a block when our node's proof doesn't fit
+    pub fallbacks: Vec<Arc<BTCDClient>>,
     pub address_cache: AddressCache<KvDatabase, KvChainStore>,
     pub listener: Option<Arc<TcpListener>>,
     pub peers: HashMap<u32, Arc<Peer>>,
     pub peer_accept: Receiver<Message>,
+    /// What we got from `peer_accept`, cheap messages first
+    queue: RequestQueue,
     pub notify_tx: Sender<Message>,
     pub peer_addresses: HashMap<sha256::Hash, Arc<Peer>>,
+    /// Peers subscribed to a script hash through `blockchain.address.subscribe`, and the
+    /// address each used. They are notified with that address, instead of the script hash.
+    pub address_subscriptions: HashMap<sha256::Hash, Vec<(Arc<Peer>, String)>>,
+    /// Peers subscribed to each outpoint
+    pub outpoint_subscriptions: HashMap<OutPoint, Vec<Arc<Peer>>>,
+    pub identity: ServerIdentity,
+    pub resources: ResourceLimits,
+    /// Verbose transactions we've built lately
+    verbose_cache: VerboseCache,
+    /// The chain we are following
+    pub chain_params: Box<dyn ChainParams>,
+    /// What transactions we relay for our clients
+    pub policy: RelayPolicy,
+    /// How long we wait before retrying a failed sync
+    sync_backoff: Duration,
+    /// Tips waiting to be applied
+    block_queue: BlockQueue,
+    /// Where we send tips to be compared with our fallback nodes'. None without fallback
+    /// nodes, see [ElectrumServer::tip_checker]
+    tip_checks: Option<AsyncSender<(u32, String)>>,
+    /// Where we are with checking the tip we want to apply
+    tip_check: TipCheck,
+    /// How each part of this server is doing
+    pub health: HealthReport,
+    /// The height and header of our tip, so we don't have to ask our node every time a
+    /// client wants it
+    tip: Option<(u32, String)>,
+    /// How long a transaction we've broadcast may stay unconfirmed before we drop it
+    pub mempool_expiry: Duration,
+    /// While we are catching up with our node, how far our wallet is, and our node's tip
+    sync_progress: Option<(u32, u32)>,
+    /// Whether we translate methods older clients use, see [super::compat]
+    pub legacy_methods: bool,
+    /// How we show addresses in verbose transactions and admin output
+    pub address_format: AddressFormat,
+    /// Wallets sharing this server. If there's any, each session only sees its own
+    pub wallets: Vec<WalletScope>,
+    /// What our operator authenticates with to call admin methods. Without it, they are only
+    /// served over gRPC
+    pub admin_token: Option<String>,
+    /// How much room is left on the disk holding our data dir
+    pub disk: DiskSpace,
+    /// Our router's forwarding of our port, if we asked for one
+    pub port_mapping: Option<PortMapping>,
+    /// The Bitcoin Core node we audit ourselves against, if any
+    pub auditor: Option<Arc<CoreRpc>>,
+}
+/// How we answer a request
+pub enum Reply {
+    /// Right away, with this response
+    Now(Value),
+    /// Off our main loop, by this task, which is done once the answer is written
+    Deferred(JoinHandle<()>),
+}
+/// What's left to load into memory after starting, see [ElectrumServer::start_warmup]
+pub enum Warmup {
+    /// Addresses whose status hash and unspent outputs we still have to find
+    Addresses(Vec<sha256::Hash>),
+    /// Recent transactions whose bodies we still have to load
+    Transactions(Vec<Txid>),
 }
 #[allow(clippy::enum_variant_names)]
 pub enum Message {
@@ -61,66 +423,354 @@ pub enum Message {
     Message((u32, String)),
     Disconnect(u32),
     NewBlock,
+    /// What we still have to load into memory after starting
+    Warmup(Warmup),
+    /// A request from our REST interface
+    Rest(RestMessage),
+    /// A request from our admin API
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    Admin(AdminMessage),
+    /// Periodic maintenance, from our scheduler
+    Maintenance(Task),
+    /// A request of this client was answered off our loop
+    Done(u32),
+    /// What our nodes think of the tip with this hash, from our [TipChecker]
+    TipChecked {
+        hash: String,
+        verdict: TipVerdict,
+    },
+    Shutdown,
 }
 
 impl ElectrumServer {
     pub async fn new<'a>(
-        address: &'static str,
+        address: Option<String>,
         rpc: Arc<BTCDClient>,
         address_cache: AddressCache<KvDatabase, KvChainStore>,
+        identity: ServerIdentity,
+        resources: ResourceLimits,
+        chain_params: Box<dyn ChainParams>,
+        policy: RelayPolicy,
     ) -> Result<ElectrumServer, Box<dyn std::error::Error>> {
-        let listener = Arc::new(TcpListener::bind(address).await?);
+        let listener = match address {
+            Some(address) => Some(Arc::new(TcpListener::bind(address).await?)),
+            None => None,
+        };
         let (tx, rx) = channel();
-        Ok(ElectrumServer {
+        let tip = address_cache.get_tip_header();
+        let mut server = ElectrumServer {
+            block_source: rpc.clone(),
+            tip_source: rpc.clone(),
             rpc,
+            fallbacks: vec![],
             address_cache,
-            listener: Some(listener),
+            listener,
             peers: HashMap::new(),
             peer_accept: rx,
+            queue: RequestQueue::default(),
             notify_tx: tx,
             peer_addresses: HashMap::new(),
-        })
+            address_subscriptions: HashMap::new(),
+            outpoint_subscriptions: HashMap::new(),
+            identity,
+            verbose_cache: VerboseCache::new(resources.verbose_cache_size),
+            resources,
+            chain_params,
+            policy,
+            sync_backoff: MIN_SYNC_BACKOFF,
+            block_queue: BlockQueue::default(),
+            tip_checks: None,
+            tip_check: TipCheck::Idle,
+            health: HealthReport::default(),
+            mempool_expiry: MempoolConfig::default().expiry(),
+            sync_progress: None,
+            legacy_methods: false,
+            address_format: AddressFormat::default(),
+            wallets: vec![],
+            admin_token: None,
+            disk: DiskSpace::default(),
+            port_mapping: None,
+            auditor: None,
+            tip,
+        };
+        // Our wallet may have moved since we last saved our tip
+        let height = server.address_cache.get_cache_height()?;
+        if server.tip.as_ref().map(|(tip, _)| *tip) != Some(height) {
+            let hash = server
+                .rpc
+                .getblockhash(height as usize)
+                .map_err(crate::error::Error::from)?;
+            let header = server
+                .rpc
+                .getblockheader(hash, false)
+                .map_err(crate::error::Error::from)?
+                .get_simple();
+            server.set_tip(height, header);
+        }
+        Ok(server)
+    }
+    /// Updates our tip, both in memory and on disk
+    fn set_tip(&mut self, height: u32, header: String) {
+        self.address_cache.save_tip_header(height, header.clone());
+        self.tip = Some((height, header));
     }
     pub fn handle_blockchain_request(
         &mut self,
         peer: Arc<Peer>,
         request: Request,
-    ) -> Result<Value, super::error::Error> {
+    ) -> Result<Reply, super::error::Error> {
+        let mut session = peer.session();
+        if !session.supports(&request.method) {
+            return Err(super::error::Error::MethodNotFound);
+        }
+        if request.params.len() > MAX_PARAMS {
+            return Err(super::error::Error::InvalidParams);
+        }
+        let request = if self.legacy_methods {
+            compat::translate(request, self.chain_params.network())?
+        } else if request.method.starts_with("blockchain.address.") {
+            return Err(super::error::Error::MethodNotFound);
+        } else {
+            request
+        };
+        if !METHODS.contains(&request.method.as_str()) {
+            return Err(super::error::Error::MethodNotFound);
+        }
+        // Answering from a wallet that's still syncing would give incomplete histories
+        if let Some((height, tip)) = self.sync_progress {
+            if is_wallet_query(&request.method) {
+                return Err(super::error::Error::Syncing { height, tip });
+            }
+        }
+        if refused_to_public(&session, &request.method) {
+            return Err(super::error::Error::Unauthorized);
+        }
+        self.check_access(&session, &request)?;
         match request.method.as_str() {
             "blockchain.estimatefee" => json_rpc_res!(request, 0.0001),
             "blockchain.headers.subscribe" => {
-                let best = self.rpc.getbestblock().unwrap();
-                let header = self
-                    .rpc
-                    .getblockheader(best.hash, false)
-                    .unwrap()
-                    .get_simple();
-                let result = json!({
-                    "height": best.height,
-                    "hex": header
-                });
+                if session.version == ProtocolVersion::V1_2 {
+                    session.raw_headers = request
+                        .params
+                        .get(0)
+                        .and_then(|raw| raw.as_bool())
+                        .unwrap_or(false);
+                    peer.set_session(session.clone());
+                }
+                let (height, header) = self
+                    .tip
+                    .as_ref()
+                    .ok_or(super::error::Error::InvalidParams)?;
+                let result = session.format_header(*height, header);
                 json_rpc_res!(request, result)
             }
-            "server.version" => json_rpc_res!(request, ["ElectrumX 1.16.0", "1.4"]),
-            // TODO: Create an actual histogram
-            "mempool.get_fee_histogram" => json_rpc_res!(request, []),
+            "server.version" => {
+                session.version = ProtocolVersion::negotiate(request.params.get(1))
+                    .ok_or(super::error::Error::InvalidParams)?;
+                session.negotiated = true;
+                let version = session.version.to_string();
+                peer.set_session(session);
+                json_rpc_res!(request, ["ElectrumX 1.16.0", version])
+            }
+            "server.features" => {
+                let genesis_hash = self.chain_params.genesis_hash();
+                let mut hosts = serde_json::Map::new();
+                if let Some(address) = self
+                    .port_mapping
+                    .as_ref()
+                    .and_then(PortMapping::external_address)
+                {
+                    hosts.insert(
+                        address.ip().to_string(),
+                        json!({"tcp_port": null, "ssl_port": address.port()}),
+                    );
+                }
+                json_rpc_res!(request, {
+                    "genesis_hash": genesis_hash,
+                    "hosts": hosts,
+                    "protocol_min": ProtocolVersion::V1_2.to_string(),
+                    "protocol_max": ProtocolVersion::V1_4.to_string(),
+                    "pruning": null,
+                    "server_version": "ElectrumX 1.16.0",
+                    "hash_function": "sha256"
+                })
+            }
+            "mempool.get_fee_histogram" => {
+                let histogram = self.get_fee_histogram();
+                json_rpc_res!(request, histogram)
+            }
+            "admin.getpolicy" => {
+                let policy = &self.policy;
+                json_rpc_res!(request, policy)
+            }
+            "admin.getblocklog" => {
+                let from = get_arg!(request, u32, 0);
+                let to = get_arg!(request, u32, 1);
+                if to < from || to - from >= MAX_BLOCK_LOG_ENTRIES {
+                    return Err(super::error::Error::InvalidParams);
+                }
+                let entries = (from..=to)
+                    .filter_map(|height| self.address_cache.get_block_log(height))
+                    .collect::<Vec<_>>();
+                let entries = self.with_confirmations(&entries);
+                json_rpc_res!(request, entries)
+            }
+            // Extension: returns the OP_RETURN outputs in a range of blocks whose payload starts
+            // with `prefix`, which must be one of our watch rules
+            "blockchain.opreturn.get_matches" => {
+                let prefix = get_arg!(request, String, 0);
+                let from = get_arg!(request, u32, 1);
+                let to = get_arg!(request, u32, 2);
+                let watched = Vec::from_hex(&prefix)
+                    .map(|prefix| self.address_cache.watches_op_return_prefix(&prefix))
+                    .unwrap_or(false);
+                if !watched || to < from || to - from >= MAX_BLOCK_LOG_ENTRIES {
+                    return Err(super::error::Error::InvalidParams);
+                }
+                let prefix = prefix.to_lowercase();
+                let prefix = prefix.as_str();
+                let matches = (from..=to)
+                    .flat_map(|height| {
+                        self.address_cache
+                            .get_op_return_matches(height)
+                            .into_iter()
+                            .filter(move |entry| entry.payload.starts_with(prefix))
+                            .map(move |entry| {
+                                json!({
+                                    "tx_hash": entry.txid,
+                                    "tx_pos": entry.vout,
+                                    "height": height,
+                                    "confirmations": self.address_cache.get_confirmations(height),
+                                    "payload": entry.payload
+                                })
+                            })
+                    })
+                    .collect::<Vec<_>>();
+                json_rpc_res!(request, matches)
+            }
+            "wallet.get_balance_history" => {
+                let from = get_arg!(request, u32, 0);
+                let to = get_arg!(request, u32, 1);
+                let history = self.address_cache.get_balance_history(from, to);
+                let history = self.with_confirmations(&history);
+                json_rpc_res!(request, history)
+            }
+            "admin.getsilentpayments" => {
+                let payments = self.address_cache.get_silent_payments();
+                let payments = self.with_confirmations(&payments);
+                json_rpc_res!(request, payments)
+            }
+            "admin.gethealth" => {
+                let health = self.health.read().expect("Poisoned lock").clone();
+                json_rpc_res!(request, health)
+            }
+            "admin.getdiskspace" => {
+                json_rpc_res!(request, {
+                    "free": self.disk.free(),
+                    "min_free": self.resources.min_free_disk_space,
+                    "low": self.disk.is_low()
+                })
+            }
+            // Tells which descriptor, and index, each address we watch comes from. Besides our
+            // wallet's own descriptor, clients may send others, like the ones of wallets
+            // sharing this server. Addresses none of them derive were likely imported by
+            // mistake. Deriving that many addresses takes a while, so it's answered off our
+            // loop
+            "admin.getderivations" => {
+                let descriptors = self.get_descriptors(request.params.get(0))?;
+                let script_hashes = self.address_cache.get_watched_script_hashes();
+                Ok(answer_later(peer, request.id, move || {
+                    let derivations =
+                        get_derivations(&descriptors, script_hashes, DERIVATION_LOOKAHEAD);
+                    let underivable = derivations
+                        .iter()
+                        .filter(|derivation| derivation.descriptor.is_none())
+                        .count();
+                    Ok(json!({
+                        "addresses": derivations,
+                        "underivable": underivable
+                    }))
+                }))
+            }
+            // Signs whether the addresses a descriptor derives from `from` up to `to` were
+            // ever used up to our tip, for operators to hand to auditors
+            "admin.attestunused" => {
+                let descriptor = get_arg!(request, String, 0);
+                let from = get_arg!(request, u32, 1);
+                let to = get_arg!(request, u32, 2);
+                if to <= from || to - from > MAX_ATTESTED_ADDRESSES {
+                    return Err(super::error::Error::InvalidParams);
+                }
+                let desc = crate::parse_descriptor(&descriptor)
+                    .map_err(|_| super::error::Error::InvalidParams)?;
+                let script_hashes = (from..to)
+                    .map(|index| get_spk_hash(&desc.at_derivation_index(index).script_pubkey()))
+                    .collect::<Vec<_>>();
+                let attestation = self.address_cache.attest_unused(
+                    descriptor,
+                    from,
+                    &script_hashes,
+                    &self.identity,
+                )?;
+                json_rpc_res!(request, attestation)
+            }
+            "admin.getwalletcommitment" => {
+                let height = self.address_cache.get_cache_height()?;
+                let commitment = self.address_cache.get_wallet_commitment();
+                json_rpc_res!(request, {
+                    "height": height,
+                    "commitment": commitment
+                })
+            }
+            "admin.setpolicy" => {
+                self.policy = get_arg!(request, RelayPolicy, 0);
+                let policy = &self.policy;
+                json_rpc_res!(request, policy)
+            }
             "blockchain.scripthash.subscribe" => {
                 if let Some(hash) = request.params.get(0) {
                     let hash = serde_json::from_value::<sha256::Hash>(hash.clone())?;
+                    if self.disk.is_low() && !self.peer_addresses.contains_key(&hash) {
+                        return Err(crate::error::Error::LowDiskSpace.into());
+                    }
                     self.peer_addresses.insert(hash, peer);
 
-                    let history = self.address_cache.get_address_history(&hash);
-
-                    if history.is_empty() {
-                        return json_rpc_res!(request, null);
-                    }
-                    let status_hash = get_status(history);
+                    let status_hash = self.get_script_hash_status(&hash);
                     return json_rpc_res!(request, status_hash);
                 }
 
                 Err(super::error::Error::InvalidParams)
             }
-            "server.banner" => json_rpc_res!(request, "Welcome to Electrum"),
+            // Address methods from before 1.3, for legacy clients. The rest of them are
+            // translated into scripthash methods.
+            "blockchain.address.subscribe" => {
+                let address = get_arg!(request, String, 0);
+                let hash = compat::address_to_script_hash(&address, self.chain_params.network())?;
+                if self.disk.is_low() && !self.address_subscriptions.contains_key(&hash) {
+                    return Err(crate::error::Error::LowDiskSpace.into());
+                }
+                let subscribers = self.address_subscriptions.entry(hash).or_default();
+                subscribers.retain(|(subscriber, _)| !Arc::ptr_eq(subscriber, &peer));
+                subscribers.push((peer, address));
+                let status_hash = self.get_script_hash_status(&hash);
+                json_rpc_res!(request, status_hash)
+            }
+            "blockchain.address.listunspent" => {
+                let address = get_arg!(request, String, 0);
+                let hash = compat::address_to_script_hash(&address, self.chain_params.network())?;
+                let utxos = self.get_unspent(&hash);
+                json_rpc_res!(request, utxos)
+            }
+            "server.banner" => {
+                let banner = match self.sync_progress {
+                    Some((height, tip)) => format!(
+                        "Welcome to Electrum. This server is still syncing: block {height} of {tip} ({}%)",
+                        height as u64 * 100 / tip.max(1) as u64
+                    ),
+                    None => "Welcome to Electrum".to_string(),
+                };
+                json_rpc_res!(request, banner)
+            }
             "server.donation_address" => {
                 json_rpc_res!(request, "bcrt1q9d4zjf92nvd3zhg6cvyckzaqumk4zre2c0k8hv")
             }
@@ -131,10 +781,7 @@ impl ElectrumServer {
             "blockchain.relayfee" => json_rpc_res!(request, 0.00001),
             "blockchain.block.header" => {
                 if let Some(height) = request.params.get(0) {
-                    let hash = self
-                        .rpc
-                        .getblockhash(height.as_u64().unwrap_or(0) as usize)?;
-                    let header = self.rpc.getblockheader(hash, false)?.get_simple();
+                    let header = self.get_header(height.as_u64().unwrap_or(0) as u32)?;
                     json_rpc_res!(request, header)
                 } else {
                     Err(super::error::Error::InvalidParams)
@@ -146,9 +793,7 @@ impl ElectrumServer {
                 let mut headers = String::new();
                 let count = if count < 2016 { count } else { 2016 };
                 for height in start_height..(start_height + count) {
-                    let hash = self.rpc.getblockhash(height as usize)?;
-                    let header = self.rpc.getblockheader(hash, false)?.get_simple();
-                    headers.extend(header.chars().into_iter());
+                    headers.push_str(&self.get_header(height as u32)?);
                 }
                 json_rpc_res!(request, {
                     "count": count,
@@ -156,39 +801,319 @@ impl ElectrumServer {
                     "max": 2016
                 })
             }
+            // Extension: numbers about a block, for dashboards. Fees need the outputs the block
+            // spends, which its utreexo proof carries. We use the one we kept if we still have
+            // it, or ask our block source for it again, and only leave fees out if that fails.
+            // How many transactions touched our wallet comes from the block log, and isn't
+            // shared when wallets share this server. Blocks and proofs come from our node, so
+            // it's answered off our loop.
+            "blockchain.block.stats" => {
+                let height = get_arg!(request, u32, 0);
+                let proof = self.address_cache.get_block_proof(height);
+                let log_entry = self
+                    .address_cache
+                    .get_block_log(height)
+                    .filter(|_| self.wallets.is_empty());
+                let rpc = self.rpc.clone();
+                let block_source = self.block_source.clone();
+                Ok(answer_later(peer, request.id, move || {
+                    let block = BlockchainSync::get_block(&*rpc, height)?;
+                    let block_hash = block.block_hash();
+                    let proof = match proof.filter(|proof| proof.block_hash == block_hash) {
+                        Some(proof) => Some(proof),
+                        None => block_source
+                            .get_expected_block_and_proof(height, &block_hash)
+                            .map(|(_, proof)| proof)
+                            .map_err(|err| {
+                                log!(
+                                    Level::Warn,
+                                    "Could not get the proof of block {height}: {err}"
+                                )
+                            })
+                            .ok(),
+                    };
+                    let fees = proof
+                        .and_then(|proof| proof.decode().ok())
+                        .map(|(_, _, leaves)| {
+                            let utxos = BlockchainSync::get_utxo_map(&block, leaves);
+                            BlockchainSync::get_block_fees(&utxos, &block)
+                        });
+                    let wallet_transactions = log_entry
+                        .filter(|entry| entry.block_hash == block_hash)
+                        .map(|entry| entry.transactions);
+                    Ok(json!({
+                        "height": height,
+                        "block_hash": block_hash,
+                        "transactions": block.txdata.len(),
+                        "size": block.size(),
+                        "weight": block.weight(),
+                        "fees": fees,
+                        "wallet_transactions": wallet_transactions
+                    }))
+                }))
+            }
+            // Extension: what happened to our wallet after the event numbered `seq`, so clients
+            // can catch up without fetching every history again. If `complete` is false, or
+            // `log_id` isn't the one they had, they must fetch everything. When wallets share
+            // this server, each only gets the events about its addresses, and reorgs.
+            "blockchain.events.since" => {
+                let seq = get_arg!(request, u64, 0);
+                let scope = session
+                    .wallet
+                    .as_ref()
+                    .and_then(|name| self.wallets.iter().find(|scope| scope.name == *name));
+                if !self.wallets.is_empty() && scope.is_none() {
+                    return Err(super::error::Error::Unauthorized);
+                }
+                let log = self.address_cache.get_event_log();
+                let events = log.since(seq).map(|events| {
+                    events
+                        .filter(|logged| {
+                            let script_hashes = logged.event.script_hashes();
+                            scope.map_or(true, |scope| {
+                                script_hashes.is_empty()
+                                    || script_hashes.iter().any(|hash| scope.contains(hash))
+                            })
+                        })
+                        .take(MAX_EVENTS_PER_REQUEST)
+                        .collect::<Vec<_>>()
+                });
+                let complete = events.is_some();
+                let events = events.unwrap_or_default();
+                // Clients continue from here. If they got everything, that's past the events
+                // we've left out for them too
+                let last = match events.last() {
+                    Some(event) if events.len() == MAX_EVENTS_PER_REQUEST => event.seq,
+                    _ if complete => log.last(),
+                    _ => seq,
+                };
+                json_rpc_res!(request, {
+                    "log_id": log.id(),
+                    "complete": complete,
+                    "last": last,
+                    "events": events
+                })
+            }
+            // Extension: fills in what an external signer needs to sign the inputs spending
+            // our outputs: the transactions they spend, and where their keys come from. Takes
+            // a PSBT in base64, or an unsigned transaction in hex, and optionally more
+            // descriptors to find keys in. Returns the PSBT, and which inputs we've filled in
+            "blockchain.psbt.update" => {
+                let draft = get_arg!(request, String, 0);
+                let mut psbt = match Psbt::from_str(&draft) {
+                    Ok(psbt) => psbt,
+                    Err(_) => Vec::from_hex(&draft)
+                        .ok()
+                        .and_then(|tx| deserialize::<Transaction>(&tx).ok())
+                        .and_then(|tx| Psbt::from_unsigned_tx(tx).ok())
+                        .ok_or(super::error::Error::InvalidParams)?,
+                };
+                let scope = session
+                    .wallet
+                    .as_ref()
+                    .and_then(|name| self.wallets.iter().find(|scope| scope.name == *name));
+                if !self.wallets.is_empty() && scope.is_none() {
+                    return Err(super::error::Error::Unauthorized);
+                }
+                let descriptors = self.get_descriptors(request.params.get(1))?;
+                let mut updated = vec![];
+                for (n, input) in psbt.unsigned_tx.input.iter().enumerate() {
+                    let output = match self.address_cache.get_wallet_output(&input.previous_output)
+                    {
+                        Some(output) => output,
+                        None => continue,
+                    };
+                    let script_hash = get_spk_hash(&output.script_pubkey);
+                    if !scope.map_or(true, |scope| scope.contains(&script_hash)) {
+                        continue;
+                    }
+                    let previous = self
+                        .address_cache
+                        .get_tx_body(&input.previous_output.txid)
+                        .map(|body| body.tx.clone());
+                    // Hardware wallets want the whole transaction even for segwit inputs, so
+                    // they can check the amount they sign for
+                    psbt.inputs[n].non_witness_utxo = previous;
+                    if output.script_pubkey.is_witness_program() {
+                        psbt.inputs[n].witness_utxo = Some(output);
+                    }
+                    updated.push((n, script_hash));
+                }
+                // Finding where keys come from derives every address we watch, and more, so
+                // it's answered off our loop
+                let watched = self.address_cache.watched_count();
+                Ok(answer_later(peer, request.id, move || {
+                    let script_hashes = updated
+                        .iter()
+                        .map(|(_, script_hash)| *script_hash)
+                        .collect();
+                    let derivations = find_derivations(
+                        &descriptors,
+                        &script_hashes,
+                        watched,
+                        DERIVATION_LOOKAHEAD,
+                    );
+                    for (n, script_hash) in updated.iter() {
+                        if let Some((descriptor, index)) = derivations.get(script_hash) {
+                            let descriptor = descriptors[*descriptor].at_derivation_index(*index);
+                            if let Err(err) = psbt.update_input_with_descriptor(*n, &descriptor) {
+                                log!(Level::Warn, "Could not add derivations to input {n}: {err}");
+                            }
+                        }
+                    }
+                    let updated = updated.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+                    Ok(json!({
+                        "psbt": psbt.to_string(),
+                        "updated": updated
+                    }))
+                }))
+            }
+            // Extension: the outputs this session's wallet can spend, so thin clients can
+            // build transactions without asking for every address' unspent outputs. Outputs
+            // our broadcast transactions spend and immature coinbases are left out. Given a
+            // target amount, in satoshis, only enough of the biggest non-dust ones to reach it
+            // are returned. A feerate, in sat/vB, takes what spending each one costs off it
+            "blockchain.wallet.get_coin_hints" => {
+                let target = match request.params.get(0) {
+                    Some(Value::Null) | None => None,
+                    Some(target) => Some(serde_json::from_value::<u64>(target.clone())?),
+                };
+                let feerate = match request.params.get(1) {
+                    Some(Value::Null) | None => 0.0,
+                    Some(feerate) => serde_json::from_value::<f64>(feerate.clone())?,
+                };
+                if !feerate.is_finite() || feerate < 0.0 {
+                    return Err(super::error::Error::InvalidParams);
+                }
+                let scope = session
+                    .wallet
+                    .as_ref()
+                    .and_then(|name| self.wallets.iter().find(|scope| scope.name == *name));
+                if !self.wallets.is_empty() && scope.is_none() {
+                    return Err(super::error::Error::Unauthorized);
+                }
+                let spendable = self
+                    .address_cache
+                    .spendable(self.chain_params.coinbase_maturity());
+                let mut hints = self
+                    .address_cache
+                    .get_wallet_utxos()
+                    .into_iter()
+                    .filter(|(script_hash, outpoint, _, height)| {
+                        scope.map_or(true, |scope| scope.contains(script_hash))
+                            && spendable(outpoint, *height)
+                    })
+                    .map(|(script_hash, outpoint, output, height)| {
+                        let fee = ScriptType::classify(&output.script_pubkey)
+                            .input_vsize()
+                            .map_or(0, |vsize| (vsize as f64 * feerate).ceil() as i64);
+                        let effective_value = output.value as i64 - fee;
+                        CoinHint {
+                            tx_hash: outpoint.txid,
+                            tx_pos: outpoint.vout,
+                            height,
+                            value: output.value,
+                            script_hash,
+                            confirmations: self.address_cache.get_confirmations(height),
+                            effective_value,
+                            dust: output.value < self.policy.dust_threshold || effective_value <= 0,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                hints.sort_by_key(|hint| std::cmp::Reverse(hint.effective_value));
+                if let Some(target) = target {
+                    let mut total = 0;
+                    hints.retain(|hint| {
+                        if hint.dust || total >= target {
+                            return false;
+                        }
+                        total += hint.effective_value as u64;
+                        true
+                    });
+                }
+                let total = hints
+                    .iter()
+                    .map(|hint| hint.effective_value.max(0) as u64)
+                    .sum::<u64>();
+                let enough = target.map_or(true, |target| total >= target);
+                json_rpc_res!(request, {
+                    "utxos": hints,
+                    "total": total,
+                    "enough": enough
+                })
+            }
+            // Long histories are partly archived on disk, so they're loaded off our loop
             "blockchain.scripthash.get_history" => {
                 if let Some(script_hash) = request.params.get(0) {
                     let script_hash =
                         serde_json::from_value::<sha256::Hash>(script_hash.to_owned())?;
-                    let transactions = self.address_cache.get_address_history(&script_hash);
-                    let mut res = vec![];
-                    for transaction in transactions {
-                        let entry = TransactionHistoryEntry {
-                            tx_hash: transaction.hash,
-                            height: transaction.height,
-                        };
-                        res.push(entry);
-                    }
-
-                    return json_rpc_res!(request, res);
+                    let load = self.address_cache.history_loader(&script_hash);
+                    return Ok(answer_later(peer, request.id, move || {
+                        let history = load()?.iter().map(history_entry_json).collect::<Vec<_>>();
+                        Ok(json!(history))
+                    }));
                 }
 
                 Err(super::error::Error::InvalidParams)
             }
+            "blockchain.scripthash.get_mempool" => {
+                let script_hash = get_arg!(request, sha256::Hash, 0);
+                let res = self
+                    .address_cache
+                    .get_address_mempool(&script_hash)
+                    .iter()
+                    .map(history_entry_json)
+                    .collect::<Vec<_>>();
+                json_rpc_res!(request, res)
+            }
             "blockchain.transaction.broadcast" => {
                 let tx = get_arg!(request, String, 0);
+                let transaction = Vec::from_hex(&tx)
+                    .ok()
+                    .and_then(|tx| deserialize::<Transaction>(&tx).ok())
+                    .ok_or(super::error::Error::InvalidParams)?;
+                self.check_policy(&transaction)?;
                 let hex = self.rpc.sendrawtransaction(tx)?;
+                self.address_cache.journal_broadcast(transaction);
                 json_rpc_res!(request, hex)
             }
             "blockchain.transaction.get" => {
-                if let Some(script_hash) = request.params.get(0) {
-                    let tx_id = serde_json::from_value::<Txid>(script_hash.to_owned())?;
-                    let tx = self.address_cache.get_cached_transaction(&tx_id);
-                    if let Some(tx) = tx {
-                        return json_rpc_res!(request, tx);
-                    }
+                let tx_id = get_arg!(request, Txid, 0);
+                let verbose = request
+                    .params
+                    .get(1)
+                    .and_then(|verbose| verbose.as_bool())
+                    .unwrap_or(false);
+                // Transactions our clients broadcast are served too, even after we drop them.
+                // We only look our body up once, since it has everything a verbose reply needs.
+                let body = self.address_cache.get_tx_body(&tx_id);
+                let (tx, dropped) = match &body {
+                    Some(body) => (body.tx.clone(), None),
+                    None => self
+                        .address_cache
+                        .get_broadcast(&tx_id)
+                        .map(|(tx, dropped)| (tx, Some(dropped)))
+                        .ok_or(super::error::Error::InvalidParams)?,
+                };
+                if !verbose {
+                    let tx = serialize_hex(&tx);
+                    return json_rpc_res!(request, tx);
                 }
-                Err(super::error::Error::InvalidParams)
+                let mut result = self.get_verbose(
+                    &tx,
+                    body.as_ref()
+                        .map(|body| body.prevouts.as_slice())
+                        .unwrap_or_default(),
+                    body.as_ref()
+                        .and_then(|body| body.merkle_block.as_ref())
+                        .map(|merkle_block| merkle_block.header),
+                );
+                // Dropped transactions are likely gone from every mempool, so wallets should
+                // offer to broadcast them again
+                if let Some(dropped) = dropped {
+                    result["mempool_status"] = json!(if dropped { "dropped" } else { "pending" });
+                }
+                json_rpc_res!(request, result)
             }
             "blockchain.transaction.get_merkle" => {
                 if let Some(script_hash) = request.params.get(0) {
@@ -221,157 +1146,1300 @@ impl ElectrumServer {
                 }
                 Err(super::error::Error::InvalidParams)
             }
+            // Extension: returns the accumulator state at a given height, so other utreexo
+            // nodes and auditors can cross-check our historical roots.
+            "blockchain.utreexo.get_roots_at_height" => {
+                let height = get_arg!(request, u32, 0);
+                let acc = self
+                    .address_cache
+                    .get_acc_at(height)
+                    .ok_or(super::error::Error::InvalidParams)?;
+                let roots = acc
+                    .roots
+                    .iter()
+                    .map(|root| root.to_string())
+                    .collect::<Vec<_>>();
+                json_rpc_res!(request, {
+                    "height": height,
+                    "leaves": acc.leafs,
+                    "roots": roots
+                })
+            }
+            // Extension: returns the utreexo proof for a recent block, so other utreexo
+            // clients can share our bridge node instead of each connecting to their own.
+            "blockchain.utreexo.get_block_proof" => {
+                let height = get_arg!(request, u32, 0);
+                let proof = self
+                    .address_cache
+                    .get_block_proof(height)
+                    .ok_or(super::error::Error::InvalidParams)?;
+                json_rpc_res!(request, proof)
+            }
+            // Protocol 1.5 draft
+            "blockchain.outpoint.subscribe" => {
+                let outpoint = OutPoint {
+                    txid: get_arg!(request, Txid, 0),
+                    vout: get_arg!(request, u32, 1),
+                };
+                if !self.address_cache.watch_outpoint(outpoint) {
+                    return Err(super::error::Error::InvalidParams);
+                }
+                let subscribers = self.outpoint_subscriptions.entry(outpoint).or_default();
+                if !subscribers
+                    .iter()
+                    .any(|subscriber| Arc::ptr_eq(subscriber, &peer))
+                {
+                    subscribers.push(peer);
+                }
+                let status = self.get_outpoint_status(&outpoint);
+                json_rpc_res!(request, status)
+            }
+            "blockchain.outpoint.unsubscribe" => {
+                let outpoint = OutPoint {
+                    txid: get_arg!(request, Txid, 0),
+                    vout: get_arg!(request, u32, 1),
+                };
+                let subscribers = match self.outpoint_subscriptions.get_mut(&outpoint) {
+                    Some(subscribers) => subscribers,
+                    None => return json_rpc_res!(request, false),
+                };
+                let before = subscribers.len();
+                subscribers.retain(|subscriber| !Arc::ptr_eq(subscriber, &peer));
+                let removed = subscribers.len() != before;
+                if subscribers.is_empty() {
+                    self.outpoint_subscriptions.remove(&outpoint);
+                    self.address_cache.unwatch_outpoint(&outpoint);
+                }
+                json_rpc_res!(request, removed)
+            }
+            // Extension: when wallets share this server, says which one this client belongs to.
+            // A session can't switch wallets, as it may hold subscriptions to the first one.
+            // Our operator authenticates as `admin`, with `server.admin_token`, to call admin
+            // methods
+            "server.authenticate" => {
+                let name = get_arg!(request, String, 0);
+                let token = get_arg!(request, String, 1);
+                if session.wallet.is_some() || session.admin {
+                    return Err(super::error::Error::Unauthorized);
+                }
+                self.authenticate_session(&mut session, &name, &token)?;
+                peer.set_session(session);
+                json_rpc_res!(request, true)
+            }
+            // Extension: a signed commitment to our tip, made with our persistent identity key
+            "server.identity" => {
+                let height = self.address_cache.get_cache_height()?;
+                let block_hash = BlockHash::from_hex(&self.rpc.getblockhash(height as usize)?)
+                    .map_err(|_| super::error::Error::InvalidParams)?;
+                let acc = self.address_cache.get_acc();
+                let commitment = ServerIdentity::tip_commitment(height, &block_hash, acc);
+                let signature = self.identity.sign(&commitment);
+                json_rpc_res!(request, {
+                    "pubkey": self.identity.public_key().to_string(),
+                    "height": height,
+                    "block_hash": block_hash,
+                    "leaves": acc.leafs,
+                    "commitment": commitment,
+                    "signature": signature.to_string()
+                })
+            }
+            // In METHODS, but without a handler. Our schema tests call every method, so
+            // this shouldn't happen, but it isn't worth crashing for
             method => {
-                // TODO: Remove this when all methods are implemented
-                unimplemented!("Unsupported method: {method}");
+                log!(Level::Error, "{method} is in METHODS, but has no handler");
+                Err(super::error::Error::MethodNotFound)
             }
         }
     }
 
     pub async fn main_loop(mut self) -> Result<(), crate::error::Error> {
         loop {
-            if let Ok(message) = self.peer_accept.recv() {
+            if let Some(message) = self.queue.next(&self.peer_accept) {
                 match message {
                     Message::NewPeer((id, stream)) => {
+                        let max_clients = self.resources.max_clients;
+                        if max_clients != 0 && self.peers.len() >= max_clients {
+                            log!(
+                                Level::Warn,
+                                "Turning a client away, we already have {max_clients}"
+                            );
+                            // Saying goodbye may take a while, and everyone else is waiting
+                            async_std::task::spawn(async move {
+                                stream.disconnect("Server is full, try again later").await
+                            });
+                            continue;
+                        }
                         self.peers.insert(id, stream);
                     }
-                    Message::Message((peer, msg)) => {
+                    Message::Message((peer_id, msg)) => {
                         trace!("Message: {msg}");
                         if let Ok(req) = serde_json::from_str::<Request>(msg.as_str()) {
-                            let peer = self.peers.get(&peer);
+                            let peer = self.peers.get(&peer_id);
                             if peer.is_none() {
                                 log!(
                                     Level::Error,
                                     "Peer sent a message but is not listed as peer"
                                 );
+                                self.queue.done(peer_id);
                                 continue;
                             }
                             let peer = peer.unwrap().to_owned();
                             let id = req.id;
                             let res = self.handle_blockchain_request(peer.clone(), req);
 
-                            if let Ok(res) = res {
-                                peer.write(serde_json::to_string(&res).unwrap().as_bytes())
-                                    .await?;
-                            } else {
-                                let res = json!({
-                                    "jsonrpc": "2.0",
-                                    "id": id,
-                                    "error":"Unknown",
-                                    "data": null
-                                });
-                                peer.write(serde_json::to_string(&res).unwrap().as_bytes())
-                                    .await?;
+                            match res {
+                                Ok(Reply::Now(res)) => {
+                                    peer.write(serde_json::to_string(&res).unwrap().as_bytes())
+                                        .await?;
+                                }
+                                // This peer's next request waits for the answer to this one
+                                Ok(Reply::Deferred(answer)) => {
+                                    let notify_tx = self.notify_tx.clone();
+                                    async_std::task::spawn(async move {
+                                        answer.await;
+                                        let _ = notify_tx.send(Message::Done(peer_id));
+                                    });
+                                    continue;
+                                }
+                                Err(err) => {
+                                    let res = error_response(id, err);
+                                    peer.write(serde_json::to_string(&res).unwrap().as_bytes())
+                                        .await?;
+                                }
                             }
                         }
+                        self.queue.done(peer_id);
+                    }
+                    Message::Done(peer_id) => self.queue.done(peer_id),
+                    Message::TipChecked { hash, verdict } => {
+                        // A verdict on a tip we don't want anymore is of no use
+                        if matches!(&self.tip_check, TipCheck::Asked(asked) if *asked == hash) {
+                            self.tip_check = TipCheck::Checked(hash, verdict);
+                            let _ = self.notify_tx.send(Message::NewBlock);
+                        }
                     }
                     Message::NewBlock => {
                         log!(Level::Debug, "New Block!");
-                        let best = self.rpc.getbestblock().unwrap();
-                        let limits = self.address_cache.get_sync_limits(best.height as u32)?;
-
-                        BlockchainSync::sync_range(&*self.rpc, &mut self.address_cache, limits, false)?;
-                        let header = self
-                            .rpc
-                            .getblockheader(best.hash, false)
-                            .unwrap()
-                            .get_simple();
-                        let result = json!({
-                            "jsonrpc": "2.0",
-                            "method": "blockchain.headers.subscribe",
-                            "params": [{
-                                "height": best.height,
-                                "hex": header
-                            }]
-                        });
-                        for peer in &mut self.peers.values() {
-                            peer.write(serde_json::to_string(&result).unwrap().as_bytes())
-                                .await?;
+                        // Tips we put back while waiting on something are still queued
+                        if !self.queue_tip() && self.block_queue.is_empty() {
+                            continue;
+                        }
+                        while let Some((height, hash)) = self.block_queue.pop() {
+                            let limits = self.address_cache.get_sync_limits(height)?;
+                            // If we are far behind, we catch up a chunk at a time
+                            let chunk_end = limits.start().saturating_add(SYNC_CHUNK_SIZE - 1);
+                            if *limits.end() > chunk_end {
+                                if !self.sync_blocks(*limits.start()..=chunk_end, None, true)? {
+                                    break;
+                                }
+                                self.sync_progress = Some((chunk_end, height));
+                                self.block_queue.push(height, hash);
+                                // The chunk is applied either way, we only miss its header
+                                // as our tip until we try again
+                                match self.get_header(chunk_end) {
+                                    Ok(header) => {
+                                        self.set_tip(chunk_end, header);
+                                        let _ = self.notify_tx.send(Message::NewBlock);
+                                    }
+                                    Err(err) => {
+                                        log!(
+                                            Level::Warn,
+                                            "Could not get the header of block {chunk_end}: {err:?}"
+                                        );
+                                        self.retry_sync();
+                                    }
+                                }
+                                break;
+                            }
+                            // If our nodes disagree on the tip, we only apply the blocks they
+                            // agree on, and hold the rest until one branch wins
+                            let contested = match self.check_tip(height, &hash) {
+                                Some(Ok(contested)) => contested,
+                                // We're told when the verdict is in, and take it from there
+                                None => {
+                                    self.block_queue.push(height, hash);
+                                    break;
+                                }
+                                Some(Err(err)) => {
+                                    log!(Level::Warn, "Could not compare our tip: {err}");
+                                    self.retry_sync();
+                                    break;
+                                }
+                            };
+                            let (height, hash) = match contested {
+                                Some(contested) => {
+                                    if self.block_queue.hold(contested.clone()) {
+                                        log!(
+                                            Level::Warn,
+                                            "Our nodes disagree on block {}: {}, waiting for one to win",
+                                            contested.height,
+                                            contested.candidates.join(", ")
+                                        );
+                                    }
+                                    let settled = contested.height.saturating_sub(1);
+                                    if settled < *limits.start() {
+                                        break;
+                                    }
+                                    match self.rpc.getblockhash(settled as usize) {
+                                        Ok(hash) => (settled, hash),
+                                        Err(err) => {
+                                            log!(
+                                                Level::Warn,
+                                                "Could not get block {settled}: {err:?}"
+                                            );
+                                            self.retry_sync();
+                                            break;
+                                        }
+                                    }
+                                }
+                                None => {
+                                    if let Some(contested) = self.block_queue.resolve() {
+                                        log!(
+                                            Level::Info,
+                                            "Our nodes agree on block {} again",
+                                            contested.height
+                                        );
+                                    }
+                                    (height, hash)
+                                }
+                            };
+                            // The fastest of our nodes may be on another branch, so it must
+                            // give us the block our node announced. The last chunk of our
+                            // catching up is still old blocks
+                            let tip = BlockHash::from_hex(&hash).ok();
+                            let ibd = self.sync_progress.is_some();
+                            if !self.sync_blocks(*limits.start()..=height, tip.as_ref(), ibd)? {
+                                break;
+                            }
+                            if self.sync_progress.take().is_some() {
+                                log!(Level::Info, "Caught up with our node at block {height}");
+                            }
+                            // Without its header we can't notify anyone, so we try this tip
+                            // again later. Its blocks are already applied by then.
+                            let header = match self.rpc.getblockheader(hash.clone(), false) {
+                                Ok(header) => header.get_simple(),
+                                Err(err) => {
+                                    log!(
+                                        Level::Warn,
+                                        "Could not get the header of block {height}: {err:?}"
+                                    );
+                                    self.retry_sync();
+                                    break;
+                                }
+                            };
+                            self.block_queue.applied(hash);
+                            self.sync_backoff = MIN_SYNC_BACKOFF;
+                            self.set_tip(height, header.clone());
+                            let mut batch = NotificationBatch::default();
+                            for peer in self.peers.values() {
+                                batch.push(
+                                    peer,
+                                    "blockchain.headers.subscribe",
+                                    json!([peer.session().format_header(height, &header)]),
+                                );
+                            }
+                            self.wallet_notify(height, &mut batch);
+                            self.outpoint_notify(&mut batch);
+                            let dropped = self.address_cache.expire_broadcasts(self.mempool_expiry);
+                            self.mempool_notify(&dropped, &mut batch);
+                            batch.send().await;
+                            self.rebroadcast();
+                            // Blocks found while we were busy are applied right after this one
+                            self.queue_tip();
                         }
-                        self.wallet_notify(best.height as u32).await;
                     }
+                    Message::Warmup(warmup) => self.warm_up(warmup),
+                    Message::Rest((request, credentials, reply)) => {
+                        let _ = reply.try_send(self.handle_rest_request(request, credentials));
+                    }
+                    Message::Admin((request, reply)) => {
+                        let _ = reply.try_send(self.handle_admin_request(request));
+                    }
+                    Message::Maintenance(task) => self.maintain(task).await,
                     Message::Disconnect(id) => {
-                        self.peers.remove(&id);
+                        if let Some(peer) = self.peers.remove(&id) {
+                            self.drop_outpoint_subscriptions(&peer);
+                            self.address_subscriptions.retain(|_, subscribers| {
+                                subscribers
+                                    .retain(|(subscriber, _)| !Arc::ptr_eq(subscriber, &peer));
+                                !subscribers.is_empty()
+                            });
+                        }
+                        self.queue.done(id);
+                    }
+                    Message::Shutdown => {
+                        log!(Level::Info, "Shutting down");
+                        let goodbyes = self
+                            .peers
+                            .drain()
+                            .map(|(_, peer)| {
+                                async_std::task::spawn(async move {
+                                    peer.disconnect("Server is shutting down").await
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        for goodbye in goodbyes {
+                            goodbye.await;
+                        }
+                        self.address_cache.save_acc();
+                        return Ok(());
                     }
                 }
             }
         }
     }
-    async fn wallet_notify(&self, height: u32) {
-        let block = BlockchainSync::get_block(&*self.rpc, height);
-        if let Err(err) = block {
-            log!(Level::Error, "Got an error while loading block {}", err);
-            return;
+    /// Answers a request from our gRPC admin API
+    fn handle_admin_request(&mut self, request: AdminRequest) -> AdminResponse {
+        match request {
+            AdminRequest::SyncStatus => AdminResponse::SyncStatus {
+                height: self.address_cache.get_cache_height().unwrap_or(0),
+                tip_height: self
+                    .rpc
+                    .getbestblock()
+                    .map(|best| best.height as u32)
+                    .unwrap_or(0),
+                leaves: self.address_cache.get_acc().leafs,
+            },
+            AdminRequest::WalletSummary => AdminResponse::WalletSummary(
+                self.address_cache
+                    .get_wallet_summary(self.chain_params.network(), self.address_format),
+            ),
+            AdminRequest::Peers => AdminResponse::Peers(self.peers.keys().copied().collect()),
+            AdminRequest::WalletCommitment => AdminResponse::WalletCommitment {
+                height: self.address_cache.get_cache_height().unwrap_or(0),
+                commitment: self.address_cache.get_wallet_commitment(),
+            },
+            AdminRequest::BalanceHistory { from, to } => {
+                AdminResponse::BalanceHistory(self.address_cache.get_balance_history(from, to))
+            }
+            AdminRequest::GetPolicy => AdminResponse::Policy(self.policy.clone()),
+            AdminRequest::SetPolicy(policy) => {
+                self.policy = policy;
+                AdminResponse::Policy(self.policy.clone())
+            }
+            AdminRequest::ImportDescriptor {
+                descriptor,
+                addresses,
+            } => match self.import_descriptor(&descriptor, addresses) {
+                Ok(imported) => AdminResponse::Imported(imported),
+                Err(err) => AdminResponse::Failed(err),
+            },
+            AdminRequest::Rescan { from, to } => {
+                let height = self.address_cache.get_cache_height().unwrap_or(0);
+                let to = if to == 0 { height } else { to };
+                if from == 0 || from > to || to > height {
+                    return AdminResponse::Failed(format!(
+                        "can't rescan blocks {from} to {to}, we're at {height}"
+                    ));
+                }
+                log!(Level::Info, "Rescanning blocks {from} to {to}");
+                let _ = self
+                    .notify_tx
+                    .send(Message::Maintenance(Task::Rescan(from..=to)));
+                AdminResponse::RescanStarted { from, to }
+            }
         }
-        for transaction in block.unwrap().txdata {
-            for out in transaction.output.iter() {
-                let hash = get_spk_hash(&out.script_pubkey);
-
-                if let Some(peer) = self.peer_addresses.get(&hash) {
-                    let hash = get_spk_hash(&out.script_pubkey);
-                    let history = self.address_cache.get_address_history(&hash);
-
-                    let status_hash = get_status(history);
-                    let notify = json!({
-                        "jsonrpc": "2.0",
-                        "method": "blockchain.scripthash.subscribe",
-                        "params": [hash, status_hash]
-                    });
-                    if let Err(err) = peer
-                        .write(serde_json::to_string(&notify).unwrap().as_bytes())
-                        .await
-                    {
-                        log!(Level::Error, "{err}");
+    }
+    /// Starts watching the first `addresses` receive and change addresses of `descriptor`,
+    /// returns how many we weren't watching yet
+    fn import_descriptor(&mut self, descriptor: &str, addresses: u32) -> Result<u32, String> {
+        if addresses > MAX_IMPORTED_ADDRESSES {
+            return Err(format!(
+                "can't import more than {MAX_IMPORTED_ADDRESSES} addresses at once"
+            ));
+        }
+        let descriptor = crate::parse_descriptor(descriptor).map_err(|err| err.to_string())?;
+        let mut imported = 0;
+        for branch in [Branch::Receive, Branch::Change] {
+            let descriptor = match crate::branch_descriptor(&descriptor, branch) {
+                Ok(descriptor) => descriptor,
+                // Some descriptors don't have a change branch
+                Err(_) if matches!(branch, Branch::Change) => continue,
+                Err(err) => return Err(err.to_string()),
+            };
+            for index in 0..addresses {
+                let script = descriptor.at_derivation_index(index).script_pubkey();
+                if self.address_cache.is_watched(&script) {
+                    continue;
+                }
+                self.address_cache
+                    .cache_address(script)
+                    .map_err(|err| err.to_string())?;
+                imported += 1;
+            }
+        }
+        log!(Level::Info, "Imported {imported} addresses");
+        Ok(imported)
+    }
+    /// Answers a request from our REST interface, returns None if we don't have what was
+    /// asked for
+    /// Answers a request from our REST API, if its client may send it, the same as if it
+    /// came from an Electrum client authenticated with `credentials`
+    pub(super) fn handle_rest_request(
+        &self,
+        request: RestRequest,
+        credentials: Option<Credentials>,
+    ) -> RestReply {
+        let mut session = Session::default();
+        if let Some((name, token)) = credentials {
+            if self
+                .authenticate_session(&mut session, &name, &token)
+                .is_err()
+            {
+                return RestReply::Unauthorized;
+            }
+        }
+        if let Some(electrum) = request.as_electrum() {
+            if self.check_access(&session, &electrum).is_err() {
+                return RestReply::Unauthorized;
+            }
+        }
+        match self.answer_rest(request) {
+            Some(value) => RestReply::Found(value),
+            None => RestReply::NotFound,
+        }
+    }
+    fn answer_rest(&self, request: RestRequest) -> Option<Value> {
+        match request {
+            RestRequest::Tip => {
+                let height = self.address_cache.get_cache_height().ok()?;
+                let hash = self.rpc.getblockhash(height as usize).ok()?;
+                Some(json!({
+                    "height": height,
+                    "hash": hash
+                }))
+            }
+            RestRequest::History(script_hash) => {
+                let history = self.address_cache.get_full_history(&script_hash);
+                serde_json::to_value(history).ok()
+            }
+            RestRequest::BalanceHistory { from, to } => {
+                let history = self.address_cache.get_balance_history(from, to);
+                Some(json!(self.with_confirmations(&history)))
+            }
+            RestRequest::Utxos(script_hash) => {
+                serde_json::to_value(self.get_unspent(&script_hash)).ok()
+            }
+            RestRequest::Stats => {
+                let height = self.address_cache.get_cache_height().ok()?;
+                Some(json!({
+                    "height": height,
+                    "connections": self.peers.len(),
+                    "disk_free": self.disk.free(),
+                    "disk_low": self.disk.is_low()
+                }))
+            }
+            RestRequest::Transaction(txid) => {
+                let body = self.address_cache.get_tx_body(&txid)?;
+                Some(
+                    self.get_verbose(
+                        &body.tx,
+                        &body.prevouts,
+                        body.merkle_block
+                            .as_ref()
+                            .map(|merkle_block| merkle_block.header),
+                    ),
+                )
+            }
+        }
+    }
+    /// The header of the block at `height`, in hex. From our header store if we have it,
+    /// from our node otherwise
+    fn get_header(&self, height: u32) -> Result<String, super::error::Error> {
+        if let Some(header) = self.address_cache.get_header(height) {
+            return Ok(serialize_hex(&header));
+        }
+        let hash = self.rpc.getblockhash(height as usize)?;
+        Ok(self.rpc.getblockheader(hash, false)?.get_simple())
+    }
+    /// Our wallet's descriptors, and the ones in `extra` if given, each as its receive and
+    /// change branches
+    fn get_descriptors(
+        &self,
+        extra: Option<&Value>,
+    ) -> Result<Vec<Descriptor<DescriptorPublicKey>>, super::error::Error> {
+        let extra = match extra {
+            Some(extra) => serde_json::from_value::<Vec<String>>(extra.clone())?,
+            None => vec![],
+        };
+        if extra.len() > MAX_EXTRA_DESCRIPTORS {
+            return Err(super::error::Error::InvalidParams);
+        }
+        let mut descriptors = vec![];
+        for descriptor in self.address_cache.get_descriptor().into_iter().chain(extra) {
+            let descriptor = crate::parse_descriptor(&descriptor)
+                .map_err(|_| super::error::Error::InvalidParams)?;
+            for branch in [Branch::Receive, Branch::Change] {
+                if let Ok(descriptor) = crate::branch_descriptor(&descriptor, branch) {
+                    descriptors.push(descriptor);
+                }
+            }
+        }
+        Ok(descriptors)
+    }
+    /// The verbose version of a transaction, from our cache if it didn't move since
+    fn get_verbose(
+        &self,
+        transaction: &Transaction,
+        prevouts: &[TxOut],
+        header: Option<BlockHeader>,
+    ) -> Value {
+        let txid = transaction.txid();
+        let height = self.address_cache.get_height(&txid).unwrap_or(0);
+        let block_hash = header.map(|header| header.block_hash());
+        let mut verbose = self
+            .verbose_cache
+            .get(&txid, height, block_hash)
+            .unwrap_or_else(|| {
+                let verbose = get_verbose_transaction(
+                    transaction,
+                    prevouts,
+                    header,
+                    self.chain_params.network(),
+                    self.address_format,
+                );
+                self.verbose_cache
+                    .insert(txid, height, block_hash, verbose.clone());
+                verbose
+            });
+        verbose["confirmations"] = json!(self.address_cache.get_confirmations(height));
+        verbose
+    }
+    /// Catches up with our node from our main loop, serving clients in between. Wallet
+    /// queries are refused from now on until we're done, so none is answered from a wallet
+    /// that didn't apply its first chunk yet.
+    pub fn start_initial_sync(&mut self) {
+        let height = self.address_cache.get_cache_height().unwrap_or(0);
+        // Our main loop asks again, if our node can't tell us now
+        let tip = self
+            .rpc
+            .getbestblock()
+            .map_or(height, |best| best.height as u32);
+        self.sync_progress = Some((height, tip.max(height)));
+        let _ = self.notify_tx.send(Message::NewBlock);
+    }
+    /// Starts computing the status hash and unspent outputs of our addresses, then loading
+    /// our most recent transactions into memory, in the background. Until we are done,
+    /// they are computed or loaded from disk when needed, as usual. Our tip is loaded from
+    /// disk when we start, so it's already there.
+    pub fn start_warmup(&self) {
+        let script_hashes = self.address_cache.get_script_hashes();
+        let _ = self
+            .notify_tx
+            .send(Message::Warmup(Warmup::Addresses(script_hashes)));
+    }
+    /// Does the next bit of `warmup` and queues the rest, so clients are served in between
+    fn warm_up(&self, warmup: Warmup) {
+        let next = match warmup {
+            Warmup::Addresses(mut script_hashes) => {
+                let rest = script_hashes.split_off(script_hashes.len().min(WARMUP_CHUNK_SIZE));
+                self.address_cache.warm_up_addresses(&script_hashes);
+                if rest.is_empty() {
+                    // Last, so they are the ones our cache keeps
+                    let txids = self
+                        .address_cache
+                        .get_recent_transactions(self.address_cache.get_tx_cache_size());
+                    Warmup::Transactions(txids)
+                } else {
+                    Warmup::Addresses(rest)
+                }
+            }
+            Warmup::Transactions(mut txids) => {
+                let rest = txids.split_off(txids.len().min(WARMUP_CHUNK_SIZE));
+                self.address_cache.warm_up(&txids);
+                if rest.is_empty() {
+                    log!(Level::Info, "Cache warmed up");
+                    return;
+                }
+                Warmup::Transactions(rest)
+            }
+        };
+        let _ = self.notify_tx.send(Message::Warmup(next));
+    }
+    /// Checks whether a client's transaction follows our relay policy
+    fn check_policy(&self, transaction: &Transaction) -> Result<(), super::error::Error> {
+        let dust = transaction.output.iter().any(|output| {
+            !output.script_pubkey.is_provably_unspendable()
+                && output.value < self.policy.dust_threshold
+        });
+        if dust {
+            return Err(super::error::Error::PolicyViolation("dust".into()));
+        }
+        // We only know the outputs of our own wallet, so with a minimum feerate, we can't
+        // relay what spends anything else
+        if self.policy.min_relay_feerate > 0.0 {
+            let fee = self
+                .address_cache
+                .get_mempool_fee(transaction)
+                .ok_or_else(|| {
+                    super::error::Error::PolicyViolation(
+                        "unknown fee, it spends outputs we don't know".into(),
+                    )
+                })?;
+            if (fee as f64 / transaction.vsize() as f64) < self.policy.min_relay_feerate {
+                return Err(super::error::Error::PolicyViolation(
+                    "min relay fee not met".into(),
+                ));
+            }
+        }
+        if self.address_cache.count_unconfirmed_ancestors(transaction) > self.policy.max_ancestors {
+            return Err(super::error::Error::PolicyViolation(
+                "too many unconfirmed ancestors".into(),
+            ));
+        }
+        Ok(())
+    }
+    /// Serializes `items`, adding to each one how many confirmations the block at its
+    /// `height` has. They change with every block, so they aren't stored with the items.
+    fn with_confirmations<T: serde::Serialize>(&self, items: &[T]) -> Vec<Value> {
+        items
+            .iter()
+            .map(|item| {
+                let mut item = serde_json::to_value(item).expect("Items are always serializable");
+                let height = item["height"].as_u64().unwrap_or(0) as u32;
+                item["confirmations"] = json!(self.address_cache.get_confirmations(height));
+                item
+            })
+            .collect()
+    }
+    /// Builds a fee histogram out of the unconfirmed transactions we know about, as pairs of
+    /// feerate and size, highest feerate first
+    fn get_fee_histogram(&self) -> Vec<(f64, usize)> {
+        let mut histogram = self
+            .address_cache
+            .get_unconfirmed_broadcasts()
+            .filter_map(|transaction| {
+                let fee = self.address_cache.get_mempool_fee(transaction)?;
+                let feerate = fee as f64 / transaction.vsize() as f64;
+                Some((feerate, transaction.vsize()))
+            })
+            .filter(|(feerate, _)| *feerate >= self.policy.min_relay_feerate)
+            .collect::<Vec<_>>();
+        histogram.sort_by(|a, b| b.0.total_cmp(&a.0));
+        histogram
+    }
+    /// Applies the blocks in `range` to our wallet, the last one being `tip` if given, and the
+    /// others the ones our node has. If it fails for a reason that may go away, we try again
+    /// later and return false.
+    fn sync_blocks(
+        &mut self,
+        range: RangeInclusive<u32>,
+        tip: Option<&BlockHash>,
+        ibd: bool,
+    ) -> Result<bool, crate::error::Error> {
+        let source = if ibd {
+            &self.block_source
+        } else {
+            &self.tip_source
+        };
+        if let Err(err) = BlockchainSync::sync_range(
+            &*self.rpc,
+            &**source,
+            &self.fallbacks,
+            &mut self.address_cache,
+            range,
+            tip,
+            ibd,
+            &self.resources,
+            &*self.chain_params,
+        ) {
+            if !err.is_transient() {
+                return Err(err);
+            }
+            log!(Level::Warn, "Could not sync: {err}");
+            self.retry_sync();
+            return Ok(false);
+        }
+        Ok(true)
+    }
+    /// Asks our node for its tip and queues it, returns whether there's something new to apply
+    fn queue_tip(&mut self) -> bool {
+        match self.rpc.getbestblock() {
+            // We're pinned below our node's tip, so the block we stop at is our tip
+            Ok(best) => match self.address_cache.get_stop_height() {
+                Some(stop) if best.height as u32 > stop => {
+                    match self.rpc.getblockhash(stop as usize) {
+                        Ok(hash) => self.block_queue.push(stop, hash),
+                        Err(err) => {
+                            log!(Level::Warn, "Could not get block {stop}: {err:?}");
+                            self.retry_sync();
+                            false
+                        }
                     }
                 }
+                _ => self.block_queue.push(best.height as u32, best.hash),
+            },
+            Err(err) => {
+                log!(Level::Warn, "Could not get the best block: {err:?}");
+                self.retry_sync();
+                false
+            }
+        }
+    }
+    /// Schedules another attempt at syncing, backing off exponentially while it keeps
+    /// failing. We don't block here, so clients are still served in the meantime.
+    fn retry_sync(&mut self) {
+        let notify_tx = self.notify_tx.clone();
+        let backoff = self.sync_backoff;
+        std::thread::spawn(move || {
+            std::thread::sleep(backoff);
+            let _ = notify_tx.send(Message::NewBlock);
+        });
+        self.sync_backoff = (backoff * 2).min(MAX_SYNC_BACKOFF);
+    }
+    /// Authenticates `session` as the wallet called `name`, or as our operator if `name` is
+    /// [ADMIN]
+    fn authenticate_session(
+        &self,
+        session: &mut Session,
+        name: &str,
+        token: &str,
+    ) -> Result<(), super::error::Error> {
+        if name == ADMIN {
+            // Our operator is only ever on this machine
+            if session.public {
+                return Err(super::error::Error::Unauthorized);
+            }
+            let admin_token = self
+                .admin_token
+                .as_deref()
+                .ok_or(super::error::Error::Unauthorized)?;
+            if !tokens_match(admin_token, token) {
+                return Err(super::error::Error::Unauthorized);
+            }
+            session.admin = true;
+        } else {
+            let wallet = authenticate(&self.wallets, name, token)
+                .ok_or(super::error::Error::Unauthorized)?;
+            session.wallet = Some(wallet.name.clone());
+        }
+        Ok(())
+    }
+    /// Refuses requests this session may not make. Admin methods are for our operator, and
+    /// when wallets share this server, wallet queries are for the wallet they're about.
+    fn check_access(
+        &self,
+        session: &Session,
+        request: &Request,
+    ) -> Result<(), super::error::Error> {
+        if is_admin_method(&request.method) {
+            if !session.admin {
+                return Err(super::error::Error::Unauthorized);
+            }
+        } else if !self.wallets.is_empty() && !self.is_allowed(session, request) {
+            return Err(super::error::Error::Unauthorized);
+        }
+        Ok(())
+    }
+    /// Whether a session may make this request, when wallets share this server. Wallet
+    /// queries must be about the wallet the session authenticated as.
+    fn is_allowed(&self, session: &Session, request: &Request) -> bool {
+        let method = request.method.as_str();
+        // The OP_RETURN index isn't tied to any wallet
+        if !is_wallet_query(method) || method.starts_with("blockchain.opreturn.") {
+            return true;
+        }
+        let scope = match session
+            .wallet
+            .as_ref()
+            .and_then(|name| self.wallets.iter().find(|scope| scope.name == *name))
+        {
+            Some(scope) => scope,
+            None => return false,
+        };
+        scope.allows(request, self.chain_params.network(), |txid| {
+            let transaction = match self.address_cache.get_tx_body(txid) {
+                Some(body) => body.tx.clone(),
+                None => self.address_cache.get_broadcast(txid)?.0,
+            };
+            let script_hashes = self.address_cache.get_script_hashes(&transaction);
+            Some((transaction, script_hashes))
+        })
+    }
+    /// The checker comparing our tips with our fallback nodes', for our supervisor to run.
+    /// None without fallback nodes, as there's nothing to compare then.
+    pub fn tip_checker(&mut self) -> Option<TipChecker> {
+        if self.fallbacks.is_empty() {
+            return None;
+        }
+        let (sender, receiver) = unbounded();
+        self.tip_checks = Some(sender);
+        Some(TipChecker::new(
+            self.rpc.clone(),
+            self.fallbacks.clone(),
+            receiver,
+            self.notify_tx.clone(),
+        ))
+    }
+    /// What our nodes think of the tip `hash`, at `height`. None until our [TipChecker] is
+    /// done with it, which it tells us with a [Message::TipChecked].
+    fn check_tip(&mut self, height: u32, hash: &str) -> Option<TipVerdict> {
+        let checks = match &self.tip_checks {
+            Some(checks) => checks,
+            None => return Some(Ok(None)),
+        };
+        match std::mem::replace(&mut self.tip_check, TipCheck::Idle) {
+            TipCheck::Checked(checked, verdict) if checked == hash => return Some(verdict),
+            TipCheck::Asked(asked) if asked == hash => {
+                self.tip_check = TipCheck::Asked(asked);
+                return None;
             }
+            _ => {}
+        }
+        if checks.try_send((height, hash.to_string())).is_err() {
+            return Some(Err("our tip checker stopped".into()));
         }
+        self.tip_check = TipCheck::Asked(hash.to_string());
+        None
+    }
+    /// Runs a maintenance task for our scheduler
+    async fn maintain(&mut self, task: Task) {
+        match task {
+            Task::Rescan(mut blocks) => {
+                let height = match blocks.next() {
+                    Some(height) => height,
+                    None => return,
+                };
+                match BlockchainSync::get_block(&*self.rpc, height) {
+                    Ok(block) => {
+                        let found = self.address_cache.rescan_block(&block, height);
+                        if found > 0 {
+                            log!(
+                                Level::Info,
+                                "Rescan found {found} transactions in block {height}"
+                            );
+                        }
+                    }
+                    Err(err) => {
+                        log!(Level::Error, "Rescan stopped at block {height}: {err}");
+                        return;
+                    }
+                }
+                // One block at a time, so clients are served in between
+                if blocks.is_empty() {
+                    log!(Level::Info, "Rescan done");
+                } else {
+                    let _ = self
+                        .notify_tx
+                        .send(Message::Maintenance(Task::Rescan(blocks)));
+                }
+            }
+            Task::Compact => {
+                let notify_tx = self.notify_tx.clone();
+                let started = self.address_cache.start_compaction(move |orphaned| {
+                    let orphaned = orphaned
+                        .map_err(|err| log!(Level::Error, "Could not compact our database: {err}"))
+                        .ok();
+                    let _ = notify_tx.send(Message::Maintenance(Task::DropOrphaned(orphaned)));
+                });
+                if !started {
+                    log!(Level::Debug, "Our last compaction is still running");
+                }
+            }
+            Task::DropOrphaned(orphaned) => {
+                match self
+                    .address_cache
+                    .drop_orphaned_bodies(orphaned.unwrap_or_default())
+                {
+                    Ok(dropped) => log!(
+                        Level::Info,
+                        "Compacted our database, dropped {dropped} transactions"
+                    ),
+                    Err(err) => log!(Level::Error, "Could not compact our database: {err}"),
+                }
+            }
+            Task::Snapshot(path) => match self.address_cache.snapshot_chainstate(&path) {
+                Ok(height) => log!(
+                    Level::Info,
+                    "Saved our chain state at height {height} to {}",
+                    path.display()
+                ),
+                Err(err) => log!(Level::Error, "Could not save our chain state: {err}"),
+            },
+            Task::Rebroadcast => self.rebroadcast(),
+            Task::CheckPeers => self.check_fallbacks(),
+            Task::Audit => self.audit(),
+            Task::ExpireMempool => {
+                let dropped = self.address_cache.expire_broadcasts(self.mempool_expiry);
+                let mut batch = NotificationBatch::default();
+                self.mempool_notify(&dropped, &mut batch);
+                batch.send().await;
+            }
+        }
+    }
+    /// Cross-checks our wallet's unspent outputs, and how our accumulator grew over the last
+    /// [AUDIT_DEPTH] blocks, with a Bitcoin Core node. We only take a snapshot here, Core is
+    /// asked on another thread, so clients aren't kept waiting. Anything we disagree on is
+    /// logged
+    fn audit(&self) {
+        let core = match &self.auditor {
+            Some(core) => core.clone(),
+            None => return,
+        };
+        let height = match self.address_cache.get_cache_height() {
+            Ok(height) => height,
+            Err(err) => {
+                log!(Level::Error, "Audit: could not load our height: {err}");
+                return;
+            }
+        };
+        let utxos = self
+            .address_cache
+            .get_wallet_utxos()
+            .into_iter()
+            .filter(|(_, _, _, confirmed)| *confirmed != 0)
+            .map(|(_, outpoint, _, confirmed)| (outpoint, confirmed))
+            .collect();
+        let leaves = (height.saturating_sub(AUDIT_DEPTH).max(1)..=height)
+            .filter_map(|height| {
+                let before = self.address_cache.get_acc_at(height - 1)?;
+                let after = self.address_cache.get_acc_at(height)?;
+                Some((height, before.leafs, after.leafs))
+            })
+            .collect();
+        let snapshot = AuditSnapshot {
+            height,
+            utxos,
+            leaves,
+        };
+        std::thread::spawn(move || core.audit(snapshot));
+    }
+    /// Warns about fallback nodes we can't reach, or that fell behind our node
+    fn check_fallbacks(&self) {
+        let ours = match self.rpc.getbestblock() {
+            Ok(best) => best.height,
+            Err(err) => {
+                log!(Level::Warn, "Could not reach our node: {err:?}");
+                return;
+            }
+        };
+        for (n, fallback) in self.fallbacks.iter().enumerate() {
+            match fallback.getbestblock() {
+                Ok(best) if best.height + MAX_FALLBACK_LAG < ours => log!(
+                    Level::Warn,
+                    "Fallback node {n} is {} blocks behind our node",
+                    ours - best.height
+                ),
+                Ok(_) => {}
+                Err(err) => log!(Level::Warn, "Could not reach fallback node {n}: {err:?}"),
+            }
+        }
+    }
+    /// Sends all transactions we've broadcast, but didn't confirm yet, to our node again
+    fn rebroadcast(&self) {
+        for transaction in self.address_cache.get_unconfirmed_broadcasts() {
+            if let Err(err) = self.rpc.sendrawtransaction(serialize_hex(transaction)) {
+                log!(
+                    Level::Debug,
+                    "Could not rebroadcast {}: {err:?}",
+                    transaction.txid()
+                );
+            }
+        }
+    }
+    /// The status of an outpoint, as in the protocol 1.5 draft
+    fn get_outpoint_status(&self, outpoint: &OutPoint) -> Value {
+        let OutpointStatus { height, spender } = self
+            .address_cache
+            .get_outpoint_status(outpoint)
+            .unwrap_or_default();
+        let mut status = json!({});
+        if let Some(height) = height {
+            status["height"] = json!(height);
+        }
+        if let Some((txid, height)) = spender {
+            status["spender_txhash"] = json!(txid);
+            status["spender_height"] = json!(height);
+        }
+        status
+    }
+    /// Unsubscribes a peer that went away from every outpoint, and stops watching the
+    /// outpoints nobody else cares about
+    fn drop_outpoint_subscriptions(&mut self, peer: &Arc<Peer>) {
+        let address_cache = &mut self.address_cache;
+        self.outpoint_subscriptions.retain(|outpoint, subscribers| {
+            subscribers.retain(|subscriber| !Arc::ptr_eq(subscriber, peer));
+            if subscribers.is_empty() {
+                address_cache.unwatch_outpoint(outpoint);
+                return false;
+            }
+            true
+        });
+    }
+    /// Tells subscribers about outpoints that got created or spent
+    fn outpoint_notify(&mut self, batch: &mut NotificationBatch) {
+        for outpoint in self.address_cache.take_changed_outpoints() {
+            let subscribers = match self.outpoint_subscriptions.get(&outpoint) {
+                Some(subscribers) => subscribers,
+                None => continue,
+            };
+            let params = json!([
+                [outpoint.txid, outpoint.vout],
+                self.get_outpoint_status(&outpoint)
+            ]);
+            for peer in subscribers {
+                batch.push(peer, "blockchain.outpoint.subscribe", params.clone());
+            }
+        }
+    }
+    /// Tells subscribers about addresses whose unconfirmed transactions changed. Those don't
+    /// count towards statuses, so the status they get may be the one they have, but it's
+    /// their cue to ask for the history again.
+    fn mempool_notify(&self, transactions: &[Transaction], batch: &mut NotificationBatch) {
+        let script_hashes = transactions
+            .iter()
+            .flat_map(|transaction| self.address_cache.get_script_hashes(transaction))
+            .collect::<HashSet<_>>();
+        self.script_hash_notify(script_hashes, batch);
+    }
+    /// Returns the outputs this script hash can spend: the ones it didn't spend yet, but
+    /// for those spent by transactions we broadcast and immature coinbase outputs
+    fn get_unspent(&self, script_hash: &sha256::Hash) -> Vec<UnspentEntry> {
+        let spendable = self
+            .address_cache
+            .spendable(self.chain_params.coinbase_maturity());
+        self.address_cache
+            .get_address_utxos(script_hash)
+            .into_iter()
+            .filter(|(outpoint, _, height)| spendable(outpoint, *height))
+            .map(|(outpoint, output, height)| UnspentEntry {
+                tx_hash: outpoint.txid,
+                tx_pos: outpoint.vout,
+                height,
+                value: output.value,
+            })
+            .collect()
+    }
+    /// Tells subscribers the new status of these script hashes
+    fn script_hash_notify(
+        &self,
+        script_hashes: HashSet<sha256::Hash>,
+        batch: &mut NotificationBatch,
+    ) {
+        for hash in script_hashes {
+            if let Some(peer) = self.peer_addresses.get(&hash) {
+                let params = json!([hash, self.get_script_hash_status(&hash)]);
+                batch.push(peer, "blockchain.scripthash.subscribe", params);
+            }
+            if let Some(subscribers) = self.address_subscriptions.get(&hash) {
+                let status = self.get_script_hash_status(&hash);
+                for (peer, address) in subscribers {
+                    let params = json!([address, status]);
+                    batch.push(peer, "blockchain.address.subscribe", params);
+                }
+            }
+        }
+    }
+    /// The status of a script hash, from its mined transactions. None if it has none.
+    fn get_script_hash_status(&self, script_hash: &sha256::Hash) -> Option<sha256::Hash> {
+        self.address_cache.get_status(script_hash)
+    }
+    /// Tells subscribers about addresses paid in the block at `height`. Each script hash
+    /// is only notified once, no matter how many outputs it got.
+    fn wallet_notify(&self, height: u32, batch: &mut NotificationBatch) {
+        let block = BlockchainSync::get_block(&*self.rpc, height);
+        if let Err(err) = block {
+            log!(Level::Error, "Got an error while loading block {}", err);
+            return;
+        }
+        let script_hashes = block
+            .unwrap()
+            .txdata
+            .iter()
+            .flat_map(|transaction| transaction.output.iter())
+            .map(|out| get_spk_hash(&out.script_pubkey))
+            .collect::<HashSet<_>>();
+        self.script_hash_notify(script_hashes, batch);
     }
 }
 /// Each peer get one reading loop
 async fn peer_loop(
-    stream: Arc<TcpStream>,
+    reader: impl Read + Unpin,
+    peer: Arc<Peer>,
     id: u32,
     notify_channel: Sender<Message>,
 ) -> Result<(), std::io::Error> {
-    let mut _stream = &*stream;
-    let mut lines = BufReader::new(_stream).lines();
-    while let Some(Ok(line)) = lines.next().await {
-        notify_channel
-            .send(Message::Message((id, line)))
-            .expect("Main loop is broken");
+    let mut reader = BufReader::new(reader);
+    loop {
+        match read_frame(&mut reader).await {
+            Ok(Some(line)) => notify_channel
+                .send(Message::Message((id, line)))
+                .expect("Main loop is broken"),
+            Ok(None) => break,
+            Err(FrameError::InvalidUtf8) | Err(FrameError::TooDeep) => {
+                log!(
+                    Level::Warn,
+                    "Peer {id} sent a malformed request, ignoring it"
+                );
+            }
+            Err(FrameError::TooLong) => {
+                log!(
+                    Level::Warn,
+                    "Peer {id} sent an oversized request, disconnecting"
+                );
+                peer.disconnect("Request too large").await;
+                break;
+            }
+            Err(FrameError::Io(err)) => {
+                log!(Level::Debug, "Peer {id}: {err}");
+                break;
+            }
+        }
     }
     log!(Level::Info, "Lost a peer");
     let _ = notify_channel.send(Message::Disconnect(id));
     Ok(())
 }
 
-pub async fn accept_loop(listener: Arc<TcpListener>, notify_channel: Sender<Message>) {
-    let mut id_count = 0;
+/// Accepts Electrum clients on `listener`. If `wallet` is set, they are that wallet's from
+/// the start, as if they had authenticated as it. If `mapped`, our router forwards this port
+/// to us, and clients from other machines are public, see [refused_to_public]. With `tls`,
+/// clients speak TLS, and only the ones it allows get to send us requests.
+pub async fn accept_loop(
+    listener: Arc<TcpListener>,
+    socket: SocketConfig,
+    wallet: Option<String>,
+    mapped: bool,
+    tls: Option<Arc<TlsListener>>,
+    notify_channel: Sender<Message>,
+) -> Result<(), std::io::Error> {
     loop {
-        if let Ok((stream, _addr)) = listener.accept().await {
-            log!(Level::Info, "New peer");
-            let stream = Arc::new(stream);
-            async_std::task::spawn(peer_loop(stream.clone(), id_count, notify_channel.clone()));
-            let peer = Arc::new(Peer::new(stream));
+        let (stream, addr) = super::accept(&listener).await;
+        log!(Level::Info, "New peer");
+        if let Err(err) = tune_socket(&stream, &socket) {
+            log!(Level::Warn, "Could not set our socket options: {err}");
+        }
+        let wallet = wallet.clone();
+        let tls = tls.clone();
+        let notify_channel = notify_channel.clone();
+        // The TLS handshake waits on the client, so it can't hold up the next one
+        async_std::task::spawn(async move {
+            let (peer, reader): (Peer, Box<dyn Read + Send + Unpin>) = match tls {
+                Some(tls) => match tls.accept(stream.clone()).await {
+                    Ok(session) => {
+                        let (reader, writer) = futures_util::io::AsyncReadExt::split(session);
+                        (
+                            Peer::with_writer(stream, Box::new(writer)),
+                            Box::new(reader),
+                        )
+                    }
+                    Err(err) => {
+                        log!(Level::Info, "Turned a TLS client away: {err}");
+                        let _ = stream.shutdown(std::net::Shutdown::Both);
+                        return;
+                    }
+                },
+                None => (Peer::new(stream.clone()), Box::new(stream)),
+            };
+            // Ids must stay unique if we get restarted
+            let id = NEXT_PEER_ID.fetch_add(1, Ordering::SeqCst);
+            let peer = Arc::new(peer);
+            // Set before we read anything, so no request gets past it
+            let public = mapped && !addr.ip().is_loopback();
+            if wallet.is_some() || public {
+                peer.set_session(Session {
+                    wallet,
+                    public,
+                    ..Session::default()
+                });
+            }
             notify_channel
-                .send(Message::NewPeer((id_count, peer)))
+                .send(Message::NewPeer((id, peer.clone())))
                 .expect("Main loop is broken");
-            id_count += 1;
-        }
+            let _ = peer_loop(reader, peer, id, notify_channel).await;
+        });
     }
 }
 
-fn get_hash_from_u8(data: &[u8]) -> sha256::Hash {
-    let hash = sha2::Sha256::new().chain_update(data).finalize();
-    sha256::Hash::from_slice(hash.as_slice()).expect("Engines shouldn't be Err")
-}
 pub fn get_spk_hash(spk: &Script) -> sha256::Hash {
     let script_hash = spk.as_bytes();
     let mut hash = sha2::Sha256::new().chain_update(script_hash).finalize();
     hash.reverse();
     sha256::Hash::from_slice(hash.as_slice()).expect("Engines shouldn't be Err")
 }
+/// Builds the verbose version of a transaction, in the same format as Bitcoin Core's
+/// `getrawtransaction`. `confirmations` is left for the caller, since it changes with every
+/// block.
+fn get_verbose_transaction(
+    transaction: &Transaction,
+    prevouts: &[TxOut],
+    header: Option<BlockHeader>,
+    network: Network,
+    format: AddressFormat,
+) -> Value {
+    let vin = transaction
+        .input
+        .iter()
+        .enumerate()
+        .map(|(n, input)| {
+            if transaction.is_coin_base() {
+                return json!({
+                    "coinbase": input.script_sig.to_hex(),
+                    "sequence": input.sequence.0
+                });
+            }
+            let mut vin = json!({
+                "txid": input.previous_output.txid,
+                "vout": input.previous_output.vout,
+                "scriptSig": {
+                    "asm": input.script_sig.asm(),
+                    "hex": input.script_sig.to_hex()
+                },
+                "txinwitness": input.witness.iter().map(|item| item.to_hex()).collect::<Vec<_>>(),
+                "sequence": input.sequence.0
+            });
+            if let Some(prevout) = prevouts.get(n) {
+                vin["prevout"] = json!({
+                    "value": prevout.value as f64 / 100_000_000.0,
+                    "scriptPubKey": get_verbose_script_pubkey(&prevout.script_pubkey, network, format)
+                });
+            }
+            vin
+        })
+        .collect::<Vec<_>>();
+    let vout = transaction
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            json!({
+                "value": output.value as f64 / 100_000_000.0,
+                "n": n,
+                "scriptPubKey": get_verbose_script_pubkey(&output.script_pubkey, network, format)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut verbose = json!({
+        "txid": transaction.txid(),
+        "hash": transaction.wtxid(),
+        "version": transaction.version,
+        "size": transaction.size(),
+        "vsize": transaction.vsize(),
+        "weight": transaction.weight(),
+        "locktime": transaction.lock_time.0,
+        "vin": vin,
+        "vout": vout,
+        "hex": serialize_hex(transaction)
+    });
+    if let Some(header) = header {
+        verbose["blockhash"] = json!(header.block_hash());
+        verbose["time"] = json!(header.time);
+        verbose["blocktime"] = json!(header.time);
+    }
+    verbose
+}
+/// Describes a script the way Bitcoin Core does in verbose transactions
+fn get_verbose_script_pubkey(script: &Script, network: Network, format: AddressFormat) -> Value {
+    let mut script_pubkey = json!({
+        "asm": script.asm(),
+        "hex": script.to_hex(),
+        "type": ScriptType::classify(script).core_name()
+    });
+    if let Some(address) = get_address(script, network, format) {
+        script_pubkey["address"] = json!(address);
+    }
+    script_pubkey
+}
 /// As per electrum documentation:
 /// ### To calculate the status of a script hash (or address):
 ///
@@ -390,39 +2458,38 @@ pub fn get_spk_hash(spk: &Script) -> sha256::Hash {
 /// 4. The status of the script hash is the sha256() hash of the full string expressed
 /// as a hexadecimal string, or null if the string is empty because there are no
 /// transactions.
-fn get_status(transactions: Vec<CachedTransaction>) -> sha256::Hash {
-    let mut status_preimage = String::new();
-    for transaction in transactions {
-        status_preimage.extend(
-            format!("{}:{}:", transaction.hash, transaction.height)
-                .chars()
-                .into_iter(),
-        );
-    }
-    get_hash_from_u8(status_preimage.as_bytes())
+pub fn get_status(history: &[HistoryEntry]) -> sha256::Hash {
+    extend_status(sha256::HashEngine::default(), history)
+}
+/// Finishes a status hash, whose `engine` already hashed the entries before `history`
+pub fn extend_status(mut engine: sha256::HashEngine, history: &[HistoryEntry]) -> sha256::Hash {
+    for entry in history {
+        engine.input(format!("{}:{}:", entry.hash(), electrum_height(entry)).as_bytes());
+    }
+    sha256::Hash::from_engine(engine)
 }
 #[macro_export]
 macro_rules! json_rpc_res {
     ($request: ident, $result: ident) => (
-        Ok(json!({
+        Ok($crate::electrum::electrum_protocol::Reply::Now(json!({
             "jsonrpc": "2.0",
             "result": $result,
             "id": $request.id
-        }))
+        })))
     );
     ($request: ident, $result: literal) => (
-        Ok(json!({
+        Ok($crate::electrum::electrum_protocol::Reply::Now(json!({
             "jsonrpc": "2.0",
             "result": $result,
             "id": $request.id
-        }))
+        })))
     );
     ($request: ident, $result: tt) => {
-        Ok(json!({
+        Ok($crate::electrum::electrum_protocol::Reply::Now(json!({
             "jsonrpc": "2.0",
             "result": $result,
             "id": $request.id
-        }))
+        })))
     }
 }
 #[macro_export]
@@ -435,3 +2502,160 @@ macro_rules! get_arg {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::{accept_loop, frame, refused_to_public, Message, Peer, DISCONNECT_ERROR_CODE};
+    use crate::{config::SocketConfig, electrum::session::Session};
+    use async_std::{
+        io::BufReader,
+        net::{TcpListener, TcpStream},
+        prelude::*,
+        task,
+    };
+    use serde_json::{json, Value};
+    use std::{
+        sync::{mpsc::channel, Arc},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame(b"{}"), b"{}\n".to_vec());
+    }
+    #[test]
+    fn test_refused_to_public() {
+        let local = Session::default();
+        let mut public = Session {
+            public: true,
+            ..Session::default()
+        };
+        for method in [
+            "admin.gethealth",
+            "blockchain.events.since",
+            "server.authenticate",
+        ] {
+            assert!(!refused_to_public(&local, method));
+        }
+        // It may authenticate, over TLS, but sees no wallet until it does
+        assert!(!refused_to_public(&public, "server.authenticate"));
+        assert!(!refused_to_public(
+            &public,
+            "blockchain.scripthash.get_history"
+        ));
+        assert!(refused_to_public(&public, "blockchain.events.since"));
+        assert!(refused_to_public(&public, "admin.gethealth"));
+        public.wallet = Some("alice".into());
+        assert!(!refused_to_public(&public, "blockchain.events.since"));
+        assert!(refused_to_public(&public, "admin.gethealth"));
+        assert!(refused_to_public(&public, "wallet.get_balance_history"));
+    }
+    #[test]
+    fn test_concurrent_writes_dont_interleave() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let peer = Arc::new(Peer::new(server));
+
+            // Big messages, so each write needs more than one syscall
+            let writers = (0..8)
+                .map(|id| {
+                    let peer = peer.clone();
+                    task::spawn(async move {
+                        let message = json!({"id": id, "result": "a".repeat(1 << 16)});
+                        peer.write(message.to_string().as_bytes()).await.unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            // We must read while they write, or they'll block on a full socket buffer
+            let mut lines = BufReader::new(client).lines();
+            let mut ids = vec![];
+            for _ in 0..8 {
+                let line = lines.next().await.unwrap().unwrap();
+                let message = serde_json::from_str::<Value>(&line).unwrap();
+                ids.push(message["id"].as_u64().unwrap());
+            }
+            for writer in writers {
+                writer.await;
+            }
+            ids.sort_unstable();
+            assert_eq!(ids, (0..8).collect::<Vec<_>>());
+        });
+    }
+    #[test]
+    fn test_write_many_fails_on_closed_peer() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let peer = Peer::new(server.clone());
+            let messages = vec![b"{}".to_vec(), b"[]".to_vec()];
+            peer.write_many(&messages).await.unwrap();
+            let mut lines = BufReader::new(client).lines();
+            assert_eq!(lines.next().await.unwrap().unwrap(), "{}");
+            assert_eq!(lines.next().await.unwrap().unwrap(), "[]");
+            // Nothing can be written after this
+            server.shutdown(std::net::Shutdown::Write).unwrap();
+            assert!(peer.write_many(&messages).await.is_err());
+        });
+    }
+    #[test]
+    fn test_disconnect() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let peer = Peer::new(server);
+            peer.disconnect("Server is full, try again later").await;
+            // Wallets get told why, in an error that answers no request, then the socket closes
+            let mut lines = BufReader::new(client).lines();
+            let goodbye = lines.next().await.unwrap().unwrap();
+            assert_eq!(
+                serde_json::from_str::<Value>(&goodbye).unwrap(),
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {
+                        "code": DISCONNECT_ERROR_CODE,
+                        "message": "Server is full, try again later"
+                    }
+                })
+            );
+            assert!(lines.next().await.is_none());
+        });
+    }
+    #[test]
+    fn test_wallet_port() {
+        let session = |wallet: Option<&str>| {
+            task::block_on(async {
+                let listener = Arc::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+                let address = listener.local_addr().unwrap();
+                let (notify_tx, notify_rx) = channel();
+                let wallet = wallet.map(str::to_string);
+                task::spawn(accept_loop(
+                    listener,
+                    SocketConfig::default(),
+                    wallet,
+                    false,
+                    None,
+                    notify_tx,
+                ));
+                let _client = TcpStream::connect(address).await.unwrap();
+                match notify_rx.recv_timeout(Duration::from_secs(30)) {
+                    Ok(Message::NewPeer((_, peer))) => peer.session(),
+                    _ => panic!("We should have been told about our new peer"),
+                }
+            })
+        };
+        // Clients on a wallet's own port are its clients from the start
+        assert_eq!(session(Some("alice")).wallet.as_deref(), Some("alice"));
+        assert_eq!(session(None).wallet, None);
+    }
+}
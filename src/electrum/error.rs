@@ -5,7 +5,19 @@ use crate::impl_from_error;
 pub enum Error {
     BackendError(UtreexodError),
     InvalidParams,
+    /// A method we don't serve, or not to this session's protocol version
+    MethodNotFound,
     ParsingError(serde_json::Error),
+    CacheError(crate::error::Error),
+    /// A transaction we won't relay, and why
+    PolicyViolation(String),
+    /// We can't answer wallet queries before our wallet caught up with our node
+    Syncing {
+        height: u32,
+        tip: u32,
+    },
+    /// Wallets share this server, and this session didn't authenticate as the one it asked about
+    Unauthorized,
 }
 impl From<UtreexodError> for Error {
     fn from(err: UtreexodError) -> Self {
@@ -13,3 +25,4 @@ impl From<UtreexodError> for Error {
     }
 }
 impl_from_error!(ParsingError, serde_json::Error);
+impl_from_error!(CacheError, crate::error::Error);
@@ -0,0 +1,246 @@
+//! Our admin API over gRPC, for services that want typed control over a running server. The
+//! service is defined in `proto/admin.proto`, and requests are answered by our main loop,
+//! just like the `admin.*` Electrum methods. This is only built with the `grpc` feature, as
+//! it needs `protoc` and a tokio runtime.
+
+use std::{
+    net::SocketAddr,
+    sync::{mpsc::Sender, Mutex},
+};
+
+use async_std::channel::bounded;
+use tonic::{transport::Server, Request, Response, Status};
+
+use super::{
+    admin::{AdminRequest, AdminResponse},
+    electrum_protocol::Message,
+};
+use crate::{
+    address_cache::AddressSummary,
+    config::{RelayPolicy, SocketConfig},
+};
+
+#[allow(unused, clippy::all)]
+pub mod proto {
+    tonic::include_proto!("admin");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+
+/// Serves the admin API on `port`, until it fails or this future is dropped. gRPC needs a
+/// tokio runtime, so it gets a thread of its own.
+pub async fn serve(
+    port: u16,
+    socket: SocketConfig,
+    notify_channel: Sender<Message>,
+) -> Result<(), String> {
+    let (sender, receiver) = bounded(1);
+    // Never sent on. When our supervisor stops us, it's dropped, which tells the gRPC thread
+    // to shut down too
+    let (_stop, stopped) = bounded::<()>(1);
+    std::thread::spawn(move || {
+        let result = tokio::runtime::Runtime::new()
+            .map_err(|err| err.to_string())
+            .and_then(|runtime| {
+                let service = AdminServer::new(AdminService(Mutex::new(notify_channel)));
+                let address = SocketAddr::from(([127, 0, 0, 1], port));
+                runtime
+                    .block_on(
+                        Server::builder()
+                            .tcp_nodelay(socket.nodelay)
+                            .tcp_keepalive(socket.keepalive_time())
+                            .add_service(service)
+                            .serve_with_shutdown(address, async move {
+                                let _ = stopped.recv().await;
+                            }),
+                    )
+                    .map_err(|err| err.to_string())
+            });
+        let _ = sender.try_send(result);
+    });
+    receiver
+        .recv()
+        .await
+        .unwrap_or_else(|_| Err("the gRPC thread panicked".into()))
+}
+
+struct AdminService(Mutex<Sender<Message>>);
+
+impl AdminService {
+    async fn ask(&self, request: AdminRequest) -> Result<AdminResponse, Status> {
+        let (sender, receiver) = bounded(1);
+        self.0
+            .lock()
+            .expect("Poisoned lock")
+            .send(Message::Admin((request, sender)))
+            .map_err(|_| Status::unavailable("shutting down"))?;
+        receiver
+            .recv()
+            .await
+            .map_err(|_| Status::unavailable("shutting down"))
+    }
+}
+
+fn unexpected(response: AdminResponse) -> Status {
+    match response {
+        AdminResponse::Failed(reason) => Status::invalid_argument(reason),
+        response => Status::internal(format!("unexpected response {response:?}")),
+    }
+}
+
+fn to_proto_policy(policy: RelayPolicy) -> proto::Policy {
+    proto::Policy {
+        dust_threshold: policy.dust_threshold,
+        min_relay_feerate: policy.min_relay_feerate,
+        max_ancestors: policy.max_ancestors as u64,
+    }
+}
+
+fn to_proto_address(address: AddressSummary) -> proto::AddressSummary {
+    proto::AddressSummary {
+        script_hash: address.script_hash.to_string(),
+        script: address.script,
+        script_type: address.script_type.name().to_string(),
+        address: address.address.unwrap_or_default(),
+        balance: address.balance,
+        transactions: address.transactions as u64,
+        first_seen_height: address.first_seen_height.unwrap_or(0),
+        last_active_height: address.last_active_height.unwrap_or(0),
+        last_active_confirmations: address.last_active_confirmations,
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn get_sync_status(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::SyncStatus>, Status> {
+        match self.ask(AdminRequest::SyncStatus).await? {
+            AdminResponse::SyncStatus {
+                height,
+                tip_height,
+                leaves,
+            } => Ok(Response::new(proto::SyncStatus {
+                height,
+                tip_height,
+                leaves,
+            })),
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn get_wallet_summary(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::WalletSummary>, Status> {
+        match self.ask(AdminRequest::WalletSummary).await? {
+            AdminResponse::WalletSummary(addresses) => {
+                let balance = addresses.iter().map(|address| address.balance).sum();
+                let addresses = addresses.into_iter().map(to_proto_address).collect();
+                Ok(Response::new(proto::WalletSummary { addresses, balance }))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn get_peers(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Peers>, Status> {
+        match self.ask(AdminRequest::Peers).await? {
+            AdminResponse::Peers(ids) => Ok(Response::new(proto::Peers { ids })),
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn get_wallet_commitment(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::WalletCommitment>, Status> {
+        match self.ask(AdminRequest::WalletCommitment).await? {
+            AdminResponse::WalletCommitment { height, commitment } => {
+                Ok(Response::new(proto::WalletCommitment {
+                    height,
+                    commitment: commitment.to_string(),
+                }))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn get_balance_history(
+        &self,
+        request: Request<proto::BalanceHistoryRequest>,
+    ) -> Result<Response<proto::BalanceHistory>, Status> {
+        let request = request.into_inner();
+        let request = AdminRequest::BalanceHistory {
+            from: request.from_height,
+            to: request.to_height,
+        };
+        match self.ask(request).await? {
+            AdminResponse::BalanceHistory(checkpoints) => {
+                let checkpoints = checkpoints
+                    .into_iter()
+                    .map(|checkpoint| proto::BalanceCheckpoint {
+                        height: checkpoint.height,
+                        balance: checkpoint.balance,
+                    })
+                    .collect();
+                Ok(Response::new(proto::BalanceHistory { checkpoints }))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn get_policy(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Policy>, Status> {
+        match self.ask(AdminRequest::GetPolicy).await? {
+            AdminResponse::Policy(policy) => Ok(Response::new(to_proto_policy(policy))),
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn set_policy(
+        &self,
+        request: Request<proto::Policy>,
+    ) -> Result<Response<proto::Policy>, Status> {
+        let policy = request.into_inner();
+        let policy = RelayPolicy {
+            dust_threshold: policy.dust_threshold,
+            min_relay_feerate: policy.min_relay_feerate,
+            max_ancestors: policy.max_ancestors as usize,
+        };
+        match self.ask(AdminRequest::SetPolicy(policy)).await? {
+            AdminResponse::Policy(policy) => Ok(Response::new(to_proto_policy(policy))),
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn import_descriptor(
+        &self,
+        request: Request<proto::ImportDescriptorRequest>,
+    ) -> Result<Response<proto::Imported>, Status> {
+        let request = request.into_inner();
+        let request = AdminRequest::ImportDescriptor {
+            descriptor: request.descriptor,
+            addresses: request.addresses,
+        };
+        match self.ask(request).await? {
+            AdminResponse::Imported(addresses) => Ok(Response::new(proto::Imported { addresses })),
+            response => Err(unexpected(response)),
+        }
+    }
+    async fn rescan(
+        &self,
+        request: Request<proto::RescanRequest>,
+    ) -> Result<Response<proto::RescanStarted>, Status> {
+        let request = request.into_inner();
+        let request = AdminRequest::Rescan {
+            from: request.from_height,
+            to: request.to_height,
+        };
+        match self.ask(request).await? {
+            AdminResponse::RescanStarted { from, to } => Ok(Response::new(proto::RescanStarted {
+                from_height: from,
+                to_height: to,
+            })),
+            response => Err(unexpected(response)),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! A persistent identity for this server. Clients may pin our public key and check that
+//! whoever claims to be us can sign a commitment to our current tip with it, even after
+//! our IP address or certificate changes.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use bitcoin::{
+    hashes::{sha256, Hash, HashEngine},
+    secp256k1::{
+        ecdsa::Signature, rand::thread_rng, All, Message, PublicKey, Secp256k1, SecretKey,
+    },
+    BlockHash,
+};
+use rustreexo::accumulator::stump::Stump;
+
+/// Domain separation for tip commitments, so we never sign something that could be
+/// interpreted as a transaction or message from another protocol.
+const TIP_COMMITMENT_TAG: &[u8] = b"utreexo-electrum-server/tip-commitment";
+
+pub struct ServerIdentity {
+    secp: Secp256k1<All>,
+    secret_key: SecretKey,
+}
+
+impl ServerIdentity {
+    /// Loads our identity key from `data_dir`, or creates a new one if this is our first run
+    pub fn load_or_create(data_dir: &str) -> Result<ServerIdentity, crate::error::Error> {
+        let path = Path::new(data_dir).join("identity.key");
+        let secp = Secp256k1::new();
+        let secret_key = match fs::read_to_string(&path) {
+            Ok(key) => {
+                SecretKey::from_str(key.trim()).map_err(|_| crate::error::Error::DbParseError)?
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let (secret_key, _) = secp.generate_keypair(&mut thread_rng());
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                // Only we may read our key, whatever the umask is
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options
+                    .open(&path)?
+                    .write_all(secret_key.display_secret().to_string().as_bytes())?;
+                secret_key
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(ServerIdentity { secp, secret_key })
+    }
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_secret_key(&self.secp, &self.secret_key)
+    }
+    /// Commits to our view of the chain: the last block we've processed and the
+    /// accumulator we've got after it.
+    pub fn tip_commitment(height: u32, block_hash: &BlockHash, acc: &Stump) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        engine.input(TIP_COMMITMENT_TAG);
+        engine.input(&height.to_le_bytes());
+        engine.input(&block_hash[..]);
+        engine.input(&acc.leafs.to_le_bytes());
+        for root in acc.roots.iter() {
+            engine.input(&root[..]);
+        }
+        sha256::Hash::from_engine(engine)
+    }
+    pub fn sign(&self, commitment: &sha256::Hash) -> Signature {
+        let message =
+            Message::from_slice(&commitment[..]).expect("sha256 hashes are always 32 bytes");
+        self.secp.sign_ecdsa(&message, &self.secret_key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        hashes::{sha256, Hash},
+        secp256k1::{Message, Secp256k1},
+        BlockHash,
+    };
+    use rustreexo::accumulator::stump::Stump;
+
+    use super::ServerIdentity;
+
+    #[test]
+    fn test_identity() {
+        let dir = "/tmp/utreexo_identity/";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let identity = ServerIdentity::load_or_create(dir).unwrap();
+        // We keep the same key across restarts
+        let reloaded = ServerIdentity::load_or_create(dir).unwrap();
+        assert_eq!(identity.public_key(), reloaded.public_key());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(format!("{dir}identity.key")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+
+        let acc = Stump {
+            leafs: 1,
+            roots: vec![sha256::Hash::hash(b"root")],
+        };
+        let block_hash = BlockHash::hash(b"tip");
+        let commitment = ServerIdentity::tip_commitment(1, &block_hash, &acc);
+        assert_ne!(
+            commitment,
+            ServerIdentity::tip_commitment(2, &block_hash, &acc)
+        );
+        let signature = identity.sign(&commitment);
+        let message = Message::from_slice(&commitment[..]).unwrap();
+        Secp256k1::verification_only()
+            .verify_ecdsa(&message, &signature, &reloaded.public_key())
+            .unwrap();
+    }
+}
@@ -1,10 +1,179 @@
+use std::{io::ErrorKind, net::SocketAddr, time::Duration};
+
+use crate::address_cache::HistoryEntry;
+use crate::config::SocketConfig;
+use async_std::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use bitcoin::{hashes::sha256, Txid};
+use log::{log, Level};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use socket2::{SockRef, TcpKeepalive};
 
+pub mod admin;
+pub mod compat;
 pub mod electrum_protocol;
 pub mod error;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod identity;
+pub mod monitoring;
+mod queue;
 pub mod request;
+pub mod rest;
+#[cfg(test)]
+mod schema;
+pub mod scope;
+pub mod session;
+pub mod tls;
+pub mod verbose_cache;
+
+/// How long we wait to accept clients again, after running out of something we need for them
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Deserialize, Serialize)]
 struct TransactionHistoryEntry {
     height: u32,
-    tx_hash: String,
+    tx_hash: Txid,
+}
+/// An unconfirmed entry in an address history. Height is -1 if this transaction has
+/// unconfirmed parents, 0 otherwise. The fee is left out if we don't know every output it
+/// spends, rather than shown as zero.
+#[derive(Debug, Deserialize, Serialize)]
+struct MempoolHistoryEntry {
+    height: i32,
+    tx_hash: Txid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fee: Option<u64>,
+}
+/// Accepts the next client on `listener`. Failing to accept one client shouldn't stop our
+/// listener: a client that gave up is skipped, and if we're out of file descriptors or
+/// memory, we wait a bit for some to be freed, then try again
+async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(err) => match err.kind() {
+                ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::Interrupted => {}
+                _ => {
+                    log!(Level::Warn, "Could not accept a client: {err}");
+                    task::sleep(ACCEPT_BACKOFF).await;
+                }
+            },
+        }
+    }
+}
+/// Applies our TCP options to a connection we've just accepted
+fn tune_socket(stream: &TcpStream, config: &SocketConfig) -> std::io::Result<()> {
+    stream.set_nodelay(config.nodelay)?;
+    let socket = SockRef::from(stream);
+    match config.keepalive_time() {
+        Some(time) => {
+            let keepalive = TcpKeepalive::new()
+                .with_time(time)
+                .with_interval(Duration::from_secs(config.keepalive_interval_secs));
+            #[cfg(not(windows))]
+            let keepalive = keepalive.with_retries(config.keepalive_retries);
+            socket.set_tcp_keepalive(&keepalive)
+        }
+        None => socket.set_keepalive(false),
+    }
+}
+/// The height Electrum shows for a history entry. Unconfirmed transactions are at -1 if
+/// they have unconfirmed parents, 0 otherwise.
+fn electrum_height(entry: &HistoryEntry) -> i64 {
+    match entry {
+        HistoryEntry::Confirmed { height, .. } => *height as i64,
+        HistoryEntry::Mempool {
+            has_unconfirmed_parents: true,
+            ..
+        } => -1,
+        HistoryEntry::Mempool { .. } => 0,
+    }
+}
+/// Formats a history entry as in `blockchain.scripthash.get_history`
+fn history_entry_json(entry: &HistoryEntry) -> Value {
+    match entry {
+        HistoryEntry::Confirmed { hash, height, .. } => json!(TransactionHistoryEntry {
+            height: *height,
+            tx_hash: *hash,
+        }),
+        HistoryEntry::Mempool { hash, fee, .. } => json!(MempoolHistoryEntry {
+            height: electrum_height(entry) as i32,
+            tx_hash: *hash,
+            fee: *fee,
+        }),
+    }
+}
+/// An output an address didn't spend yet
+#[derive(Debug, Deserialize, Serialize)]
+struct UnspentEntry {
+    tx_hash: Txid,
+    tx_pos: u32,
+    height: u32,
+    value: u64,
+}
+/// An output a wallet can spend, with what a client needs to pick it for a transaction
+#[derive(Debug, Serialize)]
+struct CoinHint {
+    tx_hash: Txid,
+    tx_pos: u32,
+    height: u32,
+    value: u64,
+    script_hash: sha256::Hash,
+    confirmations: u32,
+    /// What it adds to a transaction after paying for its own input, at the feerate asked
+    /// for. Its value if there's none, or we can't tell how big its input is
+    effective_value: i64,
+    /// Whether it's below our dust threshold, or costs more to spend than it's worth
+    dust: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{hashes::Hash, Txid};
+    use serde_json::json;
+
+    use super::history_entry_json;
+    use crate::address_cache::HistoryEntry;
+
+    #[test]
+    fn test_history_entry_json() {
+        let hash = Txid::all_zeros();
+        let confirmed = HistoryEntry::Confirmed {
+            hash,
+            height: 100,
+            position: 1,
+        };
+        assert_eq!(
+            history_entry_json(&confirmed),
+            json!({ "height": 100, "tx_hash": hash })
+        );
+        let known = HistoryEntry::Mempool {
+            hash,
+            first_seen: 0,
+            fee: Some(1000),
+            has_unconfirmed_parents: false,
+        };
+        assert_eq!(
+            history_entry_json(&known),
+            json!({ "height": 0, "tx_hash": hash, "fee": 1000 })
+        );
+        // We don't know what it spends, so we can't tell its fee
+        let unknown = HistoryEntry::Mempool {
+            hash,
+            first_seen: 0,
+            fee: None,
+            has_unconfirmed_parents: true,
+        };
+        assert_eq!(
+            history_entry_json(&unknown),
+            json!({ "height": -1, "tx_hash": hash })
+        );
+    }
 }
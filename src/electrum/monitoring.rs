@@ -0,0 +1,132 @@
+//! Serves the metrics electrs serves, under the same names, so dashboards and node-in-a-box
+//! distros made for electrs keep working when they're pointed at us. Like electrs, we answer
+//! `GET /metrics` in the Prometheus text format, with:
+//!  - `electrs_index_height{type="tip"}`: the last block our wallet has
+//!  - `electrs_index_db_size{db="wallet"}`: how many bytes our data dir takes
+//!  - `electrs_electrum_active_connections`: how many Electrum clients are connected
+//!
+//! And, under our own names:
+//!  - `utreexo_disk_free_bytes`: how many bytes were free on our disk on our last check
+//!  - `utreexo_disk_low`: 1 while our disk is almost full, and we pause what we can
+
+use std::{
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc},
+};
+
+use async_std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+use log::{log, Level};
+
+use super::{electrum_protocol::Message, rest::RestRequest, tune_socket};
+use crate::config::SocketConfig;
+
+pub async fn monitoring_accept_loop(
+    listener: Arc<TcpListener>,
+    socket: SocketConfig,
+    data_dir: PathBuf,
+    notify_channel: Sender<Message>,
+) -> Result<(), std::io::Error> {
+    loop {
+        let (stream, _addr) = super::accept(&listener).await;
+        if let Err(err) = tune_socket(&stream, &socket) {
+            log!(Level::Warn, "Could not set our socket options: {err}");
+        }
+        async_std::task::spawn(serve(stream, data_dir.clone(), notify_channel.clone()));
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    data_dir: PathBuf,
+    notify_channel: Sender<Message>,
+) -> Result<(), std::io::Error> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut request_line = request_line.split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let (status, stats) =
+                super::rest::answer(RestRequest::Stats, None, &notify_channel).await;
+            match stats.get("height") {
+                Some(height) => {
+                    let db_size = crate::get_dir_size(&data_dir);
+                    let metrics = format_metrics(height, db_size, &stats["connections"]);
+                    let disk = format_disk_metrics(&stats["disk_free"], &stats["disk_low"]);
+                    (status, metrics + &disk)
+                }
+                None => (status, String::new()),
+            }
+        }
+        (Some("GET"), _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let mut stream = &stream;
+    stream.write_all(response.as_bytes()).await
+}
+
+fn format_metrics(
+    height: &serde_json::Value,
+    db_size: u64,
+    connections: &serde_json::Value,
+) -> String {
+    format!(
+        "# HELP electrs_index_height Indexed block height\n\
+         # TYPE electrs_index_height gauge\n\
+         electrs_index_height{{type=\"tip\"}} {height}\n\
+         # HELP electrs_index_db_size Index DB size (bytes)\n\
+         # TYPE electrs_index_db_size gauge\n\
+         electrs_index_db_size{{db=\"wallet\"}} {db_size}\n\
+         # HELP electrs_electrum_active_connections # of active Electrum connections\n\
+         # TYPE electrs_electrum_active_connections gauge\n\
+         electrs_electrum_active_connections {connections}\n"
+    )
+}
+
+fn format_disk_metrics(free: &serde_json::Value, low: &serde_json::Value) -> String {
+    let low = u8::from(low.as_bool().unwrap_or(false));
+    format!(
+        "# HELP utreexo_disk_free_bytes Free space on the disk holding our data (bytes)\n\
+         # TYPE utreexo_disk_free_bytes gauge\n\
+         utreexo_disk_free_bytes {free}\n\
+         # HELP utreexo_disk_low Whether our disk is almost full, and optional writes paused\n\
+         # TYPE utreexo_disk_low gauge\n\
+         utreexo_disk_low {low}\n"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{format_disk_metrics, format_metrics};
+
+    #[test]
+    fn test_format_metrics() {
+        let metrics = format_metrics(&json!(800_000), 1024, &json!(3));
+        assert!(metrics.contains("electrs_index_height{type=\"tip\"} 800000\n"));
+        assert!(metrics.contains("electrs_index_db_size{db=\"wallet\"} 1024\n"));
+        assert!(metrics.contains("electrs_electrum_active_connections 3\n"));
+    }
+    #[test]
+    fn test_format_disk_metrics() {
+        let metrics = format_disk_metrics(&json!(4096), &json!(true));
+        assert!(metrics.contains("utreexo_disk_free_bytes 4096\n"));
+        assert!(metrics.contains("utreexo_disk_low 1\n"));
+        assert!(format_disk_metrics(&json!(4096), &json!(false)).contains("utreexo_disk_low 0\n"));
+    }
+}
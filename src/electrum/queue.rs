@@ -0,0 +1,232 @@
+//! Orders the messages our main loop handles. Every request is answered by that one loop, so
+//! a client asking for the history of a huge address holds up everyone queued behind it,
+//! including new blocks and the notifications they bring. We keep requests we know to be
+//! expensive in a queue of their own, and only take from it when nothing else is waiting, or
+//! after a burst of cheap messages, so they aren't starved either. The slowest of them are
+//! answered off our loop, see `electrum_protocol::answer_later`.
+//!
+//! Each client still gets its requests handled in the order it sent them: we take one of
+//! them at a time, and what it sends meanwhile waits until we're [done](RequestQueue::done)
+//! with it, even if it's being answered off our loop.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::mpsc::Receiver,
+};
+
+use serde::Deserialize;
+
+use super::electrum_protocol::Message;
+
+/// How many cheap messages we handle in a row while an expensive one is waiting
+const URGENT_BURST: usize = 16;
+/// Methods that may walk a lot of history or load blocks
+const HEAVY_METHODS: &[&str] = &[
+    "blockchain.scripthash.get_history",
+    "blockchain.address.get_history",
+    "blockchain.scripthash.listunspent",
+    "blockchain.address.listunspent",
+    "blockchain.opreturn.get_matches",
+    "blockchain.block.stats",
+    "blockchain.events.since",
+    "blockchain.psbt.update",
+    "blockchain.utreexo.get_block_proof",
+];
+
+#[derive(Deserialize)]
+struct Method<'a> {
+    #[serde(borrow)]
+    method: &'a str,
+}
+
+fn is_heavy(message: &Message) -> bool {
+    let request = match message {
+        Message::Message((_, request)) => request,
+        _ => return false,
+    };
+    match serde_json::from_str::<Method>(request) {
+        Ok(Method { method }) => {
+            HEAVY_METHODS.contains(&method) || method.starts_with("blockchain.wallet.")
+        }
+        Err(_) => false,
+    }
+}
+
+/// The client a message comes from, if any
+fn sender(message: &Message) -> Option<u32> {
+    match message {
+        Message::Message((peer, _)) | Message::Disconnect(peer) => Some(*peer),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+pub struct RequestQueue {
+    urgent: VecDeque<Message>,
+    heavy: VecDeque<Message>,
+    /// Clients with a message queued or being handled, and what they sent since, in order
+    busy: HashMap<u32, VecDeque<Message>>,
+    /// Cheap messages handled since we last took an expensive one
+    burst: usize,
+}
+
+impl RequestQueue {
+    fn push(&mut self, message: Message) {
+        if let Some(peer) = sender(&message) {
+            if let Some(waiting) = self.busy.get_mut(&peer) {
+                waiting.push_back(message);
+                return;
+            }
+            self.busy.insert(peer, VecDeque::new());
+        }
+        self.enqueue(message);
+    }
+    fn enqueue(&mut self, message: Message) {
+        if is_heavy(&message) {
+            self.heavy.push_back(message);
+        } else {
+            self.urgent.push_back(message);
+        }
+    }
+    /// We're done with the last message `peer` sent, so the next one it sent can be handled
+    pub fn done(&mut self, peer: u32) {
+        let next = match self.busy.get_mut(&peer) {
+            Some(waiting) => waiting.pop_front(),
+            None => return,
+        };
+        match next {
+            Some(message) => self.enqueue(message),
+            None => {
+                self.busy.remove(&peer);
+            }
+        }
+    }
+    /// The next message to handle, waiting for one if we have none. Returns None once every
+    /// sender is gone and we handled everything they sent.
+    pub fn next(&mut self, receiver: &Receiver<Message>) -> Option<Message> {
+        while let Ok(message) = receiver.try_recv() {
+            self.push(message);
+        }
+        // What busy clients send doesn't count, it waits for them
+        while self.urgent.is_empty() && self.heavy.is_empty() {
+            let message = receiver.recv().ok()?;
+            self.push(message);
+        }
+        if self.heavy.is_empty() {
+            self.burst = 0;
+            return self.urgent.pop_front();
+        }
+        if !self.urgent.is_empty() && self.burst < URGENT_BURST {
+            self.burst += 1;
+            return self.urgent.pop_front();
+        }
+        self.burst = 0;
+        self.heavy.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+
+    use super::{RequestQueue, URGENT_BURST};
+    use crate::electrum::electrum_protocol::Message;
+
+    fn request(method: &str) -> Message {
+        from(0, method)
+    }
+    fn from(peer: u32, method: &str) -> Message {
+        Message::Message((
+            peer,
+            format!(r#"{{"id":0,"method":"{method}","params":[]}}"#),
+        ))
+    }
+    /// The method of a request, and who sent it
+    fn method(message: Option<Message>) -> (u32, String) {
+        match message {
+            Some(Message::Message((peer, request))) => {
+                let request = serde_json::from_str::<serde_json::Value>(&request).unwrap();
+                (peer, request["method"].as_str().unwrap().to_string())
+            }
+            _ => panic!("not a request"),
+        }
+    }
+
+    #[test]
+    fn test_priority() {
+        let (sender, receiver) = channel();
+        let mut queue = RequestQueue::default();
+        sender
+            .send(request("blockchain.scripthash.get_history"))
+            .unwrap();
+        sender.send(from(1, "server.ping")).unwrap();
+        sender.send(Message::NewBlock).unwrap();
+        assert!(matches!(queue.next(&receiver), Some(Message::Message(_))));
+        assert!(matches!(queue.next(&receiver), Some(Message::NewBlock)));
+        assert!(matches!(queue.next(&receiver), Some(Message::Message(_))));
+
+        // Expensive requests still get their turn while cheap ones keep coming
+        queue.done(0);
+        sender
+            .send(request("blockchain.wallet.get_coin_hints"))
+            .unwrap();
+        for _ in 0..URGENT_BURST * 2 {
+            sender.send(Message::NewBlock).unwrap();
+        }
+        let waited = (0..URGENT_BURST * 2)
+            .take_while(|_| matches!(queue.next(&receiver), Some(Message::NewBlock)))
+            .count();
+        assert!(waited <= URGENT_BURST);
+
+        drop(sender);
+        while queue.next(&receiver).is_some() {
+            queue.done(0);
+        }
+    }
+
+    #[test]
+    fn test_client_order() {
+        let (sender, receiver) = channel();
+        let mut queue = RequestQueue::default();
+        sender.send(from(1, "server.ping")).unwrap();
+        sender
+            .send(from(1, "blockchain.scripthash.get_history"))
+            .unwrap();
+        sender.send(from(1, "server.version")).unwrap();
+        sender.send(from(2, "server.ping")).unwrap();
+        // Other clients don't wait for client 1, but it gets its answers in order
+        assert_eq!(method(queue.next(&receiver)), (1, "server.ping".into()));
+        assert_eq!(method(queue.next(&receiver)), (2, "server.ping".into()));
+        queue.done(1);
+        queue.done(2);
+        assert_eq!(
+            method(queue.next(&receiver)),
+            (1, "blockchain.scripthash.get_history".into())
+        );
+        sender.send(Message::NewBlock).unwrap();
+        assert!(matches!(queue.next(&receiver), Some(Message::NewBlock)));
+        queue.done(1);
+        assert_eq!(method(queue.next(&receiver)), (1, "server.version".into()));
+        queue.done(1);
+
+        // Requests answered off our loop hold the ones after them too, even its goodbye
+        sender.send(from(5, "blockchain.psbt.update")).unwrap();
+        sender.send(from(5, "server.ping")).unwrap();
+        sender.send(Message::Disconnect(5)).unwrap();
+        sender.send(Message::NewBlock).unwrap();
+        assert!(matches!(queue.next(&receiver), Some(Message::NewBlock)));
+        assert_eq!(
+            method(queue.next(&receiver)),
+            (5, "blockchain.psbt.update".into())
+        );
+        sender.send(Message::NewBlock).unwrap();
+        assert!(matches!(queue.next(&receiver), Some(Message::NewBlock)));
+        queue.done(5);
+        assert_eq!(method(queue.next(&receiver)), (5, "server.ping".into()));
+        queue.done(5);
+        assert!(matches!(
+            queue.next(&receiver),
+            Some(Message::Disconnect(5))
+        ));
+    }
+}
@@ -1,8 +1,87 @@
 #![allow(unused)]
 
+use async_std::io::{prelude::BufReadExt, BufRead, ReadExt};
 use bitcoin::psbt::serialize::Serialize;
 use serde::Deserialize;
 use serde_json::Value;
+
+/// The longest line we accept from a client. A raw transaction being broadcast is the
+/// biggest thing clients send us, and a standard one is far smaller than this.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// How deep arrays and objects may be nested in a request. Nothing we serve needs more
+/// than a couple of levels.
+pub const MAX_JSON_DEPTH: usize = 16;
+/// How many params a request may have
+pub const MAX_PARAMS: usize = 16;
+
+/// Why we refused a frame
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    /// Longer than [MAX_FRAME_SIZE]. We can't tell where the next frame starts, so the
+    /// peer has to go.
+    TooLong,
+    InvalidUtf8,
+    /// Nested deeper than [MAX_JSON_DEPTH]
+    TooDeep,
+}
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+/// Reads the next newline-terminated frame, without ever buffering more than
+/// [MAX_FRAME_SIZE] bytes. Returns None once the peer is gone.
+pub async fn read_frame<R: BufRead + Unpin>(reader: &mut R) -> Result<Option<String>, FrameError> {
+    let mut frame = vec![];
+    let read = (&mut *reader)
+        .take(MAX_FRAME_SIZE as u64 + 1)
+        .read_until(b'\n', &mut frame)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if frame.last() == Some(&b'\n') {
+        frame.pop();
+    } else if frame.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLong);
+    }
+    let frame = String::from_utf8(frame).map_err(|_| FrameError::InvalidUtf8)?;
+    check_depth(&frame)?;
+    Ok(Some(frame))
+}
+
+/// Makes sure arrays and objects aren't nested too deep, before a JSON parser allocates
+/// anything for them
+fn check_depth(frame: &str) -> Result<(), FrameError> {
+    let mut depth = 0_usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for byte in frame.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > MAX_JSON_DEPTH {
+                    return Err(FrameError::TooDeep);
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
 pub enum BlockchainRequest {
     BlockHeaders,
     EstimateFee,
@@ -43,3 +122,45 @@ pub enum Server {
     AddPeer,
     Features,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{check_depth, read_frame, FrameError, MAX_FRAME_SIZE, MAX_JSON_DEPTH};
+    use async_std::{io::BufReader, task};
+
+    #[test]
+    fn test_check_depth() {
+        assert!(check_depth(r#"{"params": [["a", 1]]}"#).is_ok());
+        // Brackets inside strings don't count
+        let quoted = format!(
+            r#"{{"params": ["{}\"{}"]}}"#,
+            "[".repeat(100),
+            "{".repeat(100)
+        );
+        assert!(check_depth(&quoted).is_ok());
+        let deep = "[".repeat(MAX_JSON_DEPTH + 1);
+        assert!(matches!(check_depth(&deep), Err(FrameError::TooDeep)));
+    }
+    #[test]
+    fn test_read_frame() {
+        task::block_on(async {
+            let mut reader = BufReader::new(&b"{}\n[1]"[..]);
+            assert_eq!(read_frame(&mut reader).await.unwrap().unwrap(), "{}");
+            assert_eq!(read_frame(&mut reader).await.unwrap().unwrap(), "[1]");
+            assert!(read_frame(&mut reader).await.unwrap().is_none());
+
+            let long = vec![b'a'; MAX_FRAME_SIZE + 10];
+            let mut reader = BufReader::new(&long[..]);
+            assert!(matches!(
+                read_frame(&mut reader).await,
+                Err(FrameError::TooLong)
+            ));
+
+            let mut reader = BufReader::new(&b"\xff\n"[..]);
+            assert!(matches!(
+                read_frame(&mut reader).await,
+                Err(FrameError::InvalidUtf8)
+            ));
+        });
+    }
+}
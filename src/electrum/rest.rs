@@ -0,0 +1,266 @@
+//! A small, read-only HTTP interface for scripts and dashboards that don't speak Electrum.
+//! Requests are answered by our main loop, from the same cache Electrum clients use.
+//!
+//! Routes:
+//!  - `GET /tip`
+//!  - `GET /address/<script hash>/history`
+//!  - `GET /utxo/<script hash>`
+//!  - `GET /tx/<txid>`
+//!  - `GET /wallet/balance_history/<from height>/<to height>`
+//!
+//! Each route is allowed to the same clients as the Electrum method asking for the same. So
+//! when wallets share this server, clients authenticate as one with HTTP basic auth, its name
+//! and token, and only get answers about that wallet. The balance history is for our
+//! operator, authenticating as `admin` with our admin token.
+
+use std::{
+    str::FromStr,
+    sync::{mpsc::Sender, Arc},
+};
+
+use async_std::{
+    channel::{bounded, Sender as ReplySender},
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+use bitcoin::{base64, hashes::sha256, Txid};
+use log::{log, Level};
+use serde_json::{json, Value};
+
+use super::{electrum_protocol::Message, request::Request, tune_socket};
+use crate::config::SocketConfig;
+
+#[derive(Debug)]
+pub enum RestRequest {
+    Tip,
+    History(sha256::Hash),
+    Utxos(sha256::Hash),
+    Transaction(Txid),
+    BalanceHistory {
+        from: u32,
+        to: u32,
+    },
+    /// Our height and how many clients we have, for [super::monitoring]
+    Stats,
+}
+
+impl RestRequest {
+    /// Parses a request path, returns None for anything we don't serve
+    fn parse(path: &str) -> Option<RestRequest> {
+        let parts = path
+            .trim_matches('/')
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["tip"] => Some(RestRequest::Tip),
+            ["address", script_hash, "history"] => Some(RestRequest::History(
+                sha256::Hash::from_str(script_hash).ok()?,
+            )),
+            ["utxo", script_hash] => Some(RestRequest::Utxos(
+                sha256::Hash::from_str(script_hash).ok()?,
+            )),
+            ["tx", txid] => Some(RestRequest::Transaction(Txid::from_str(txid).ok()?)),
+            ["wallet", "balance_history", from, to] => Some(RestRequest::BalanceHistory {
+                from: from.parse().ok()?,
+                to: to.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+    /// The Electrum request asking for the same, which decides who may send this one. None
+    /// for what isn't about any wallet.
+    pub fn as_electrum(&self) -> Option<Request> {
+        let (method, params) = match self {
+            RestRequest::Tip | RestRequest::Stats => return None,
+            RestRequest::History(script_hash) => {
+                ("blockchain.scripthash.get_history", json!([script_hash]))
+            }
+            RestRequest::Utxos(script_hash) => {
+                ("blockchain.scripthash.listunspent", json!([script_hash]))
+            }
+            RestRequest::Transaction(txid) => ("blockchain.transaction.get", json!([txid])),
+            RestRequest::BalanceHistory { from, to } => {
+                ("wallet.get_balance_history", json!([from, to]))
+            }
+        };
+        serde_json::from_value(json!({
+            "id": 0,
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params
+        }))
+        .ok()
+    }
+}
+
+/// Who a client says it is: a name and a token, as for `server.authenticate`
+pub type Credentials = (String, String);
+
+/// How our main loop answered a request
+#[derive(Debug)]
+pub enum RestReply {
+    Found(Value),
+    /// We don't have what was asked for
+    NotFound,
+    /// The client didn't authenticate as someone who may ask this
+    Unauthorized,
+}
+
+/// A request, who sent it, and where its answer should go
+pub type RestMessage = (RestRequest, Option<Credentials>, ReplySender<RestReply>);
+
+/// The credentials in an `Authorization` header, if that's what `header` is
+fn parse_credentials(header: &str) -> Option<Credentials> {
+    let (name, value) = header.split_once(':')?;
+    if !name.trim().eq_ignore_ascii_case("authorization") {
+        return None;
+    }
+    let encoded = value.trim().strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (name, token) = decoded.split_once(':')?;
+    Some((name.to_string(), token.to_string()))
+}
+
+pub async fn rest_accept_loop(
+    listener: Arc<TcpListener>,
+    socket: SocketConfig,
+    notify_channel: Sender<Message>,
+) -> Result<(), std::io::Error> {
+    loop {
+        let (stream, _addr) = super::accept(&listener).await;
+        if let Err(err) = tune_socket(&stream, &socket) {
+            log!(Level::Warn, "Could not set our socket options: {err}");
+        }
+        async_std::task::spawn(serve(stream, notify_channel.clone()));
+    }
+}
+
+async fn serve(stream: TcpStream, notify_channel: Sender<Message>) -> Result<(), std::io::Error> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // Who the client is is all we care about in its headers
+    let mut credentials = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+        credentials = credentials.or_else(|| parse_credentials(&header));
+    }
+    let mut request_line = request_line.split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => match RestRequest::parse(path) {
+            Some(request) => answer(request, credentials, &notify_channel).await,
+            None => ("404 Not Found", json!({"error": "not found"})),
+        },
+        _ => (
+            "405 Method Not Allowed",
+            json!({"error": "only GET is supported"}),
+        ),
+    };
+    let body = body.to_string();
+    // Tells clients to send their credentials
+    let challenge = if status.starts_with("401") {
+        "WWW-Authenticate: Basic realm=\"utreexo\"\r\n"
+    } else {
+        ""
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\n{challenge}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let mut stream = &stream;
+    stream.write_all(response.as_bytes()).await
+}
+
+pub(super) async fn answer(
+    request: RestRequest,
+    credentials: Option<Credentials>,
+    notify_channel: &Sender<Message>,
+) -> (&'static str, Value) {
+    log!(Level::Debug, "REST request: {request:?}");
+    let (sender, receiver) = bounded(1);
+    if notify_channel
+        .send(Message::Rest((request, credentials, sender)))
+        .is_err()
+    {
+        return ("503 Service Unavailable", json!({"error": "shutting down"}));
+    }
+    match receiver.recv().await {
+        Ok(RestReply::Found(value)) => ("200 OK", value),
+        Ok(RestReply::NotFound) => ("404 Not Found", json!({"error": "not found"})),
+        Ok(RestReply::Unauthorized) => ("401 Unauthorized", json!({"error": "unauthorized"})),
+        Err(_) => ("503 Service Unavailable", json!({"error": "shutting down"})),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::base64;
+
+    use super::{parse_credentials, RestRequest};
+
+    #[test]
+    fn test_parse() {
+        let script_hash = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        assert!(matches!(RestRequest::parse("/tip"), Some(RestRequest::Tip)));
+        assert!(matches!(
+            RestRequest::parse(&format!("/address/{script_hash}/history")),
+            Some(RestRequest::History(_))
+        ));
+        assert!(matches!(
+            RestRequest::parse(&format!("/utxo/{script_hash}")),
+            Some(RestRequest::Utxos(_))
+        ));
+        assert!(matches!(
+            RestRequest::parse("/wallet/balance_history/100/200"),
+            Some(RestRequest::BalanceHistory { from: 100, to: 200 })
+        ));
+        assert!(RestRequest::parse("/tx/not-a-txid").is_none());
+        assert!(RestRequest::parse("/address").is_none());
+    }
+    #[test]
+    fn test_as_electrum() {
+        assert!(RestRequest::Tip.as_electrum().is_none());
+        assert!(RestRequest::Stats.as_electrum().is_none());
+        let script_hash = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        let history = RestRequest::parse(&format!("/address/{script_hash}/history"))
+            .unwrap()
+            .as_electrum()
+            .unwrap();
+        assert_eq!(history.method, "blockchain.scripthash.get_history");
+        assert_eq!(history.params, vec![serde_json::json!(script_hash)]);
+        let balances = RestRequest::BalanceHistory { from: 1, to: 2 }
+            .as_electrum()
+            .unwrap();
+        assert_eq!(balances.method, "wallet.get_balance_history");
+        assert_eq!(
+            balances.params,
+            vec![serde_json::json!(1), serde_json::json!(2)]
+        );
+    }
+    #[test]
+    fn test_parse_credentials() {
+        let header = format!(
+            "Authorization: Basic {}\r\n",
+            base64::encode("alice:se:cret")
+        );
+        assert_eq!(
+            parse_credentials(&header),
+            Some(("alice".to_string(), "se:cret".to_string()))
+        );
+        let header = format!("authorization:Basic {}", base64::encode("admin:hunter2"));
+        assert_eq!(
+            parse_credentials(&header),
+            Some(("admin".to_string(), "hunter2".to_string()))
+        );
+        assert!(parse_credentials("Accept: Basic YWxpY2U6c2VjcmV0").is_none());
+        assert!(parse_credentials("Authorization: Bearer YWxpY2U6c2VjcmV0").is_none());
+        // Without a colon, there's no token
+        let header = format!("Authorization: Basic {}", base64::encode("alice"));
+        assert!(parse_credentials(&header).is_none());
+    }
+}
@@ -0,0 +1,651 @@
+//! Golden tests for the shape of our Electrum responses. Every method we implement has a
+//! schema for its result in `schema/electrum.json`, and here we call every one of them on a
+//! small wallet, backed by a fake node, and check what they return against it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_std::task::block_on;
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    consensus::encode::{deserialize, serialize, serialize_hex},
+    hashes::hex::{FromHex, ToHex},
+    util::psbt::PartiallySignedTransaction as Psbt,
+    Address, Block, MerkleBlock, Network, OutPoint, PackedLockTime, Script, Transaction, TxIn,
+    TxOut, Txid,
+};
+use btcd_rpc::client::{BTCDClient, BTCDConfigs};
+use jsonschema::JSONSchema;
+use serde_json::{json, Value};
+
+use super::{
+    electrum_protocol::{get_spk_hash, ElectrumServer, Peer, Reply, METHODS},
+    error::Error,
+    request::Request,
+    rest::{RestReply, RestRequest},
+    scope::WalletScope,
+};
+use crate::{
+    address_cache::{kv_database::KvDatabase, test::mined_block, AddressCache},
+    blockchain::{
+        chain_params::BitcoinParams,
+        chainstore::{ChainStore, KvChainStore},
+        headers::HeaderStore,
+        sync::{BlockSource, BlockchainSync},
+        udata::BlockProof,
+    },
+    config::{RelayPolicy, ResourceLimits},
+    electrum::identity::ServerIdentity,
+    supervisor::{Health, Subsystem},
+};
+
+const SCHEMA: &str = include_str!("../../schema/electrum.json");
+const TEST_DB_CACHE: u64 = 64 * 1024 * 1024;
+const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+fn schema() -> Value {
+    serde_json::from_str(SCHEMA).expect("Our schema is valid JSON")
+}
+/// Checks `value` against the schema for `method`'s result, returning every mismatch
+fn validate(schema: &Value, method: &str, value: &Value) -> Result<(), String> {
+    // Each method's schema refers to our shared definitions
+    let result = json!({
+        "definitions": schema["definitions"],
+        "allOf": [schema["methods"][method]],
+    });
+    let result = JSONSchema::compile(&result)
+        .unwrap_or_else(|err| panic!("The schema for {method} is invalid: {err}"));
+    result.validate(value).map_err(|errors| {
+        errors
+            .map(|err| format!("{}: {err}", err.instance_path))
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+/// A request, like a client would send it
+fn request(id: u32, method: &str, params: Value) -> Request {
+    serde_json::from_value(json!({
+        "id": id,
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params
+    }))
+    .unwrap()
+}
+/// A node answering the few RPCs our handlers make, as if it only had `block`. Returns the
+/// port it listens on.
+fn fake_node(block: Block) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = answer_rpc(stream, &block);
+        }
+    });
+    port
+}
+/// Answers one JSON-RPC request over HTTP, then closes the connection
+fn answer_rpc(mut stream: TcpStream, block: &Block) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let request = serde_json::from_slice::<Value>(&body).unwrap_or_default();
+    let result = match request["method"].as_str() {
+        Some("getblockhash") => json!(block.block_hash().to_string()),
+        Some("getblock") => json!(serialize_hex(block)),
+        Some("sendrawtransaction") => request["params"][0]
+            .as_str()
+            .and_then(|tx| Vec::from_hex(tx).ok())
+            .and_then(|tx| deserialize::<Transaction>(&tx).ok())
+            .map_or(Value::Null, |tx| json!(tx.txid().to_string())),
+        _ => Value::Null,
+    };
+    let error = match result {
+        Value::Null => json!({"code": -32601, "message": "Method not found"}),
+        _ => Value::Null,
+    };
+    let response = json!({"result": result, "error": error, "id": request["id"]}).to_string();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{response}",
+        response.len()
+    )
+}
+/// A client on a real socket, so we also get the answers sent later, off our main loop
+struct Client {
+    peer: Arc<Peer>,
+    answers: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn new() -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let answers = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        answers
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        Client {
+            peer: Arc::new(Peer::new(stream.into())),
+            answers: BufReader::new(answers),
+        }
+    }
+    /// Calls `method`, returning our response
+    fn call(&mut self, server: &mut ElectrumServer, method: &str, params: Value) -> Value {
+        let response = server
+            .handle_blockchain_request(self.peer.clone(), request(7, method, params))
+            .unwrap_or_else(|err| panic!("{method} failed: {err:?}"));
+        match response {
+            Reply::Now(response) => response,
+            Reply::Deferred(_) => self.answer(),
+        }
+    }
+    /// The next answer we've sent to this client
+    fn answer(&mut self) -> Value {
+        let mut line = String::new();
+        self.answers.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+}
+/// A transaction spending the first output of `transaction` back to the same address, in hex
+fn spending(transaction: &Transaction) -> String {
+    serialize_hex(&Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(transaction.txid(), 0),
+            ..TxIn::default()
+        }],
+        output: vec![TxOut {
+            value: transaction.output[0].value - 1_000,
+            script_pubkey: transaction.output[0].script_pubkey.clone(),
+        }],
+    })
+}
+/// A server in `dir`, holding one transaction at height 1, paying the first address of our
+/// wallet, with a node that only has its block. Returns it with that address, its script
+/// hash, and the transaction.
+fn test_server(dir: &str) -> (ElectrumServer, Address, String, Transaction) {
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let database = KvDatabase::new(dir.into(), TEST_DB_CACHE).unwrap();
+    let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+    // What our payment below left us with
+    chain_store.save_balance(1, 10_000).unwrap();
+    let mut cache = AddressCache::new(database, chain_store);
+    cache.setup(XPUB.to_string()).unwrap();
+
+    let script = crate::parse_descriptor(XPUB)
+        .unwrap()
+        .at_derivation_index(0)
+        .script_pubkey();
+    cache.cache_address(script.clone()).unwrap();
+    // Not a coinbase, so it's spendable right away
+    let transaction = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::from_hex(&"11".repeat(32)).unwrap(), 0),
+            ..TxIn::default()
+        }],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: script.clone(),
+        }],
+    };
+    let (mut block, _) = mined_block(&transaction);
+    while block.header.validate_pow(&block.header.target()).is_err() {
+        block.header.nonce += 1;
+    }
+    let txid = transaction.txid();
+    let merkle_block = MerkleBlock::from_block_with_predicate(&block, |id| *id == txid);
+    cache
+        .cache_transaction(&transaction, 1, merkle_block, 1, vec![])
+        .unwrap();
+    cache.set_op_return_prefixes(vec![b"test".to_vec()]);
+    let headers = HeaderStore::open(
+        &Path::new(dir).join("headers"),
+        Box::new(BitcoinParams::new(Network::Regtest)),
+    )
+    .unwrap();
+    headers
+        .save(0, &genesis_block(Network::Regtest).header)
+        .unwrap();
+    headers.save(1, &block.header).unwrap();
+    cache.set_header_store(headers);
+    cache.bump_height(1);
+    cache.save_acc_at(1);
+    cache.save_block_proof(
+        1,
+        &BlockProof {
+            block_hash: block.block_hash(),
+            targets: vec![],
+            proof_hashes: vec![],
+            target_hashes: vec![],
+            target_preimages: vec![],
+        },
+    );
+    // With our tip saved, the server doesn't ask our node for it
+    cache.save_tip_header(1, serialize_hex(&block.header));
+
+    let rpc = BTCDClient::new(BTCDConfigs::new(
+        false,
+        None,
+        None,
+        Some("127.0.0.1".into()),
+        Some(fake_node(block).into()),
+    ))
+    .unwrap();
+    let server = block_on(ElectrumServer::new(
+        None,
+        Arc::new(rpc),
+        cache,
+        ServerIdentity::load_or_create(dir).unwrap(),
+        ResourceLimits::default(),
+        Box::new(BitcoinParams::new(Network::Regtest)),
+        RelayPolicy::default(),
+    ))
+    .unwrap();
+    server
+        .health
+        .write()
+        .unwrap()
+        .insert(Subsystem::Sync, Health::Running);
+    let address = Address::from_script(&script, Network::Regtest).unwrap();
+    (
+        server,
+        address,
+        get_spk_hash(&script).to_string(),
+        transaction,
+    )
+}
+
+#[test]
+fn test_schemas_cover_every_method() {
+    let schema = schema();
+    let documented = schema["methods"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<HashSet<_>>();
+    let implemented = METHODS
+        .iter()
+        .map(|method| method.to_string())
+        .collect::<HashSet<_>>();
+    assert_eq!(
+        implemented.difference(&documented).collect::<Vec<_>>(),
+        Vec::<&String>::new(),
+        "methods without a schema"
+    );
+    assert_eq!(
+        documented.difference(&implemented).collect::<Vec<_>>(),
+        Vec::<&String>::new(),
+        "schemas for methods we don't implement"
+    );
+}
+#[test]
+fn test_validate() {
+    let schema = schema();
+    let balance = "blockchain.scripthash.get_balance";
+    let valid = json!({"confirmed": 1, "unconfirmed": 0});
+    assert!(validate(&schema, balance, &valid).is_ok());
+    let renamed = json!({"confirmed": 1, "pending": 0});
+    assert!(validate(&schema, balance, &renamed).is_err());
+    let as_string = json!({"confirmed": "1", "unconfirmed": 0});
+    assert!(validate(&schema, balance, &as_string).is_err());
+
+    let status = "blockchain.scripthash.subscribe";
+    assert!(validate(&schema, status, &Value::Null).is_ok());
+    assert!(validate(&schema, status, &json!("00")).is_err());
+}
+#[test]
+fn test_responses_match_schemas() {
+    let schema = schema();
+    let (mut server, address, script_hash, transaction) = test_server("/tmp/utreexo_schema/");
+    let address = address.to_string();
+    let txid = transaction.txid().to_string();
+    let policy = serde_json::to_value(RelayPolicy::default()).unwrap();
+    let spend = spending(&transaction);
+    let calls = [
+        // Clients that didn't negotiate a version may use the methods from before 1.3
+        ("blockchain.address.subscribe", json!([address])),
+        ("blockchain.address.listunspent", json!([address])),
+        ("blockchain.estimatefee", json!([2])),
+        ("blockchain.relayfee", json!([])),
+        ("blockchain.headers.subscribe", json!([])),
+        ("blockchain.block.header", json!([1])),
+        ("blockchain.block.headers", json!([0, 2])),
+        ("blockchain.block.stats", json!([1])),
+        ("server.version", json!(["test", "1.4"])),
+        ("server.features", json!([])),
+        ("server.banner", json!([])),
+        ("server.donation_address", json!([])),
+        ("server.ping", json!([])),
+        ("server.peers.subscribe", json!([])),
+        ("server.identity", json!([])),
+        ("mempool.get_fee_histogram", json!([])),
+        ("blockchain.scripthash.subscribe", json!([script_hash])),
+        ("blockchain.scripthash.get_history", json!([script_hash])),
+        ("blockchain.scripthash.get_mempool", json!([script_hash])),
+        ("blockchain.scripthash.get_balance", json!([script_hash])),
+        ("blockchain.transaction.get", json!([txid])),
+        ("blockchain.transaction.get", json!([txid, true])),
+        ("blockchain.transaction.get_merkle", json!([txid, 1])),
+        ("blockchain.outpoint.subscribe", json!([txid, 0])),
+        ("blockchain.outpoint.unsubscribe", json!([txid, 0])),
+        ("blockchain.opreturn.get_matches", json!(["74657374", 0, 1])),
+        ("blockchain.utreexo.get_roots_at_height", json!([1])),
+        ("blockchain.utreexo.get_block_proof", json!([1])),
+        ("blockchain.events.since", json!([0])),
+        ("blockchain.wallet.get_coin_hints", json!([])),
+        ("blockchain.psbt.update", json!([spend])),
+        ("server.authenticate", json!(["admin", "hunter2"])),
+        ("admin.getpolicy", json!([])),
+        ("admin.setpolicy", json!([policy])),
+        ("admin.getblocklog", json!([0, 1])),
+        ("wallet.get_balance_history", json!([0, 1])),
+        ("admin.getsilentpayments", json!([])),
+        ("admin.gethealth", json!([])),
+        ("admin.getdiskspace", json!([])),
+        ("admin.getwalletcommitment", json!([])),
+        ("admin.getderivations", json!([])),
+        ("admin.attestunused", json!([XPUB, 0, 2])),
+        // Last, since the output it spends isn't spendable anymore once it's sent
+        ("blockchain.transaction.broadcast", json!([spend])),
+    ];
+    server.admin_token = Some("hunter2".into());
+    server.legacy_methods = true;
+    let check = |server: &mut ElectrumServer, client: &mut Client, method: &str, params: Value| {
+        let response = client.call(server, method, params);
+        assert_eq!(response["jsonrpc"], "2.0", "{method}");
+        assert_eq!(response["id"], 7, "{method}");
+        if let Err(err) = validate(&schema, method, &response["result"]) {
+            panic!("{method} doesn't match its schema: {err}");
+        }
+        response["result"].clone()
+    };
+    let mut client = Client::new();
+    let mut results = HashMap::new();
+    for (method, params) in calls {
+        results.insert(method, check(&mut server, &mut client, method, params));
+    }
+    let called = results.keys().copied().collect::<HashSet<_>>();
+    let missing = METHODS
+        .iter()
+        .filter(|method| !called.contains(*method))
+        .collect::<Vec<_>>();
+    assert!(missing.is_empty(), "methods we didn't check: {missing:?}");
+    // Our payment isn't a coinbase, so it's spendable, and signers get told about it
+    assert_eq!(
+        results["blockchain.address.listunspent"][0]["tx_hash"],
+        json!(txid)
+    );
+    assert_eq!(
+        results["blockchain.wallet.get_coin_hints"]["utxos"][0]["tx_hash"],
+        json!(txid)
+    );
+    assert_eq!(results["blockchain.psbt.update"]["updated"], json!([0]));
+    assert_eq!(results["blockchain.block.headers"]["count"], json!(2));
+    assert_eq!(
+        results["wallet.get_balance_history"],
+        json!([{"height": 1, "balance": 10_000, "confirmations": 1}])
+    );
+
+    // Methods we don't serve are refused, not passed to our handlers
+    let unknown = request(8, "blockchain.nonexistent", json!([]));
+    assert!(matches!(
+        server.handle_blockchain_request(client.peer.clone(), unknown),
+        Err(Error::MethodNotFound)
+    ));
+    // Address methods were removed in 1.3, which this client negotiated
+    let removed = request(8, "blockchain.address.listunspent", json!([address]));
+    assert!(matches!(
+        server.handle_blockchain_request(client.peer.clone(), removed),
+        Err(Error::MethodNotFound)
+    ));
+    // A 1.2 client still gets them, but not blockchain.block.header, which came in 1.3
+    let mut old = Client::new();
+    check(
+        &mut server,
+        &mut old,
+        "server.version",
+        json!(["test", "1.2"]),
+    );
+    check(
+        &mut server,
+        &mut old,
+        "blockchain.address.listunspent",
+        json!([address]),
+    );
+    let header = request(8, "blockchain.block.header", json!([1]));
+    assert!(matches!(
+        server.handle_blockchain_request(old.peer.clone(), header),
+        Err(Error::MethodNotFound)
+    ));
+    // Admin methods need authenticating as our operator, even with a single wallet
+    let setpolicy = request(8, "admin.setpolicy", json!([policy]));
+    assert!(server
+        .handle_blockchain_request(Arc::new(Peer::default()), setpolicy)
+        .is_err());
+    // Clients coming through our mapped port only get public methods
+    let mut public = Client::new();
+    public.peer.set_session(super::session::Session {
+        public: true,
+        ..Default::default()
+    });
+    check(&mut server, &mut public, "server.ping", json!([]));
+    // They can't be our operator, whatever token they send
+    for method in ["server.authenticate", "blockchain.events.since"] {
+        let private = request(9, method, json!(["admin", "hunter2"]));
+        assert!(server
+            .handle_blockchain_request(public.peer.clone(), private)
+            .is_err());
+    }
+    // Wallets can only be authenticated as when they share this server
+    server.wallets = vec![WalletScope::new(
+        "alice".into(),
+        "secret".into(),
+        HashSet::new(),
+    )];
+    check(
+        &mut server,
+        &mut Client::new(),
+        "server.authenticate",
+        json!(["alice", "secret"]),
+    );
+    // Clients through our mapped port use TLS, so they may authenticate as a wallet
+    check(
+        &mut server,
+        &mut public,
+        "server.authenticate",
+        json!(["alice", "secret"]),
+    );
+}
+#[test]
+fn test_psbt_update_answers_later() {
+    let (mut server, _, _, transaction) = test_server("/tmp/utreexo_schema_psbt/");
+    let mut client = Client::new();
+    let update = request(7, "blockchain.psbt.update", json!([spending(&transaction)]));
+    // Finding our keys derives addresses, so our main loop only starts it
+    let pending = server
+        .handle_blockchain_request(client.peer.clone(), update)
+        .unwrap();
+    assert!(matches!(pending, Reply::Deferred(_)));
+    let answer = client.answer();
+    assert_eq!(answer["id"], 7);
+    assert_eq!(answer["result"]["updated"], json!([0]));
+    let psbt = Psbt::from_str(answer["result"]["psbt"].as_str().unwrap()).unwrap();
+    assert_eq!(psbt.inputs[0].non_witness_utxo, Some(transaction.clone()));
+    assert_eq!(
+        psbt.inputs[0].witness_utxo,
+        Some(transaction.output[0].clone())
+    );
+    // Our payment went to the first address of our wallet, so signers can find its key
+    assert_eq!(psbt.inputs[0].bip32_derivation.len(), 1);
+}
+#[test]
+fn test_initial_sync_gates_wallet_queries() {
+    let (mut server, _, script_hash, _) = test_server("/tmp/utreexo_schema_syncing/");
+    let client = Client::new();
+    // Before a single block is applied, wallet queries are already refused
+    server.start_initial_sync();
+    let balance = request(7, "blockchain.scripthash.get_balance", json!([script_hash]));
+    assert!(matches!(
+        server.handle_blockchain_request(client.peer.clone(), balance),
+        Err(Error::Syncing { height: 1, .. })
+    ));
+    let banner = request(7, "server.banner", json!([]));
+    let banner = match server.handle_blockchain_request(client.peer.clone(), banner) {
+        Ok(Reply::Now(banner)) => banner,
+        _ => panic!("server.banner is answered right away"),
+    };
+    assert!(banner["result"].as_str().unwrap().contains("still syncing"));
+}
+#[test]
+fn test_rest_scopes() {
+    let (mut server, _, script_hash, _) = test_server("/tmp/utreexo_schema_rest/");
+    let hash = bitcoin::hashes::sha256::Hash::from_str(&script_hash).unwrap();
+    let history = || RestRequest::History(hash);
+    let balances = || RestRequest::BalanceHistory { from: 0, to: 1 };
+    let credentials = |name: &str, token: &str| Some((name.to_string(), token.to_string()));
+    // With a single wallet, only our operator needs to authenticate
+    server.admin_token = Some("hunter2".into());
+    assert!(matches!(
+        server.handle_rest_request(history(), None),
+        RestReply::Found(_)
+    ));
+    assert!(matches!(
+        server.handle_rest_request(balances(), None),
+        RestReply::Unauthorized
+    ));
+    assert!(matches!(
+        server.handle_rest_request(balances(), credentials("admin", "hunter2")),
+        RestReply::Found(_)
+    ));
+    // With wallets sharing this server, each only sees its own addresses
+    server.wallets = vec![
+        WalletScope::new("alice".into(), "secret".into(), HashSet::from([hash])),
+        WalletScope::new("bob".into(), "hunter3".into(), HashSet::new()),
+    ];
+    for (credentials, allowed) in [
+        (None, false),
+        (credentials("alice", "wrong"), false),
+        (credentials("bob", "hunter3"), false),
+        (credentials("alice", "secret"), true),
+    ] {
+        let reply = server.handle_rest_request(history(), credentials);
+        assert_eq!(matches!(reply, RestReply::Found(_)), allowed, "{reply:?}");
+    }
+    // Our tip isn't about any wallet
+    assert!(matches!(
+        server.handle_rest_request(RestRequest::Tip, None),
+        RestReply::Found(_)
+    ));
+}
+#[test]
+fn test_broadcast_needs_a_known_fee() {
+    let (mut server, _, _, transaction) = test_server("/tmp/utreexo_schema_fee/");
+    let client = Client::new();
+    // Our payment spends an output we know nothing about
+    let broadcast = || {
+        request(
+            7,
+            "blockchain.transaction.broadcast",
+            json!([serialize_hex(&transaction)]),
+        )
+    };
+    assert!(matches!(
+        server.handle_blockchain_request(client.peer.clone(), broadcast()),
+        Err(Error::PolicyViolation(_))
+    ));
+    // Without a minimum feerate, the fee doesn't matter
+    server.policy.min_relay_feerate = 0.0;
+    assert!(server
+        .handle_blockchain_request(client.peer.clone(), broadcast())
+        .is_ok());
+}
+/// A block source that has `block`, with a proof carrying the leaves in `leaves`, if any
+struct ProvingSource(Block, Option<Vec<String>>);
+
+impl BlockSource for ProvingSource {
+    fn get_block_and_proof(
+        &self,
+        _height: u32,
+    ) -> Result<(Block, BlockProof), crate::error::Error> {
+        let leaves = self.1.clone().ok_or(crate::error::Error::BlockNotFound)?;
+        let proof = BlockProof {
+            block_hash: self.0.block_hash(),
+            targets: vec![],
+            proof_hashes: vec![],
+            target_hashes: vec![],
+            target_preimages: leaves,
+        };
+        Ok((self.0.clone(), proof))
+    }
+}
+#[test]
+fn test_block_stats_fees() {
+    let (mut server, _, _, transaction) = test_server("/tmp/utreexo_schema_stats/");
+    let mut client = Client::new();
+    let block = BlockchainSync::get_block(&*server.rpc, 1).unwrap();
+    // Our payment spends 12 000 sats to pay 10 000
+    let spent = TxOut {
+        value: 12_000,
+        script_pubkey: Script::new(),
+    };
+    let leaf = [
+        serialize(&block.block_hash()),
+        serialize(&transaction.input[0].previous_output),
+        serialize(&2_u32),
+        serialize(&spent),
+    ]
+    .concat()
+    .to_hex();
+    let proof = |block_hash, leaves: Vec<String>| BlockProof {
+        block_hash,
+        targets: vec![],
+        proof_hashes: vec![],
+        target_hashes: vec![],
+        target_preimages: leaves,
+    };
+    let mut fees = |server: &mut ElectrumServer| {
+        client.call(server, "blockchain.block.stats", json!([1]))["result"]["fees"].clone()
+    };
+    // A proof we kept is enough
+    server
+        .address_cache
+        .save_block_proof(1, &proof(block.block_hash(), vec![leaf.clone()]));
+    server.block_source = Arc::new(ProvingSource(block.clone(), None));
+    assert_eq!(fees(&mut server), json!(2_000));
+    // A block we didn't keep the proof of has it fetched again
+    let stale = genesis_block(Network::Regtest).block_hash();
+    server
+        .address_cache
+        .save_block_proof(1, &proof(stale, vec![]));
+    server.block_source = Arc::new(ProvingSource(block.clone(), Some(vec![leaf])));
+    assert_eq!(fees(&mut server), json!(2_000));
+    // Without any proof, we don't know them, but still answer
+    server.block_source = Arc::new(ProvingSource(block, None));
+    assert_eq!(fees(&mut server), Value::Null);
+}
@@ -0,0 +1,243 @@
+//! Keeps wallets sharing one server apart. Each wallet's clients authenticate with
+//! `server.authenticate`, and from then on only get answers about that wallet's addresses.
+//! Which addresses those are is derived from its descriptors when we start. Operators
+//! authenticate the same way, as [ADMIN], to call `admin.*` methods.
+
+use std::collections::HashSet;
+
+use bitcoin::{hashes::sha256, Network, Transaction, Txid};
+
+use super::{compat::address_to_script_hash, electrum_protocol::get_spk_hash, request::Request};
+
+/// The name operators authenticate as, with `server.admin_token`
+pub const ADMIN: &str = "admin";
+
+/// The addresses one wallet may ask about
+#[derive(Debug)]
+pub struct WalletScope {
+    pub name: String,
+    token: String,
+    script_hashes: HashSet<sha256::Hash>,
+}
+
+impl WalletScope {
+    pub fn new(name: String, token: String, script_hashes: HashSet<sha256::Hash>) -> WalletScope {
+        WalletScope {
+            name,
+            token,
+            script_hashes,
+        }
+    }
+    pub fn contains(&self, script_hash: &sha256::Hash) -> bool {
+        self.script_hashes.contains(script_hash)
+    }
+    /// Whether a wallet query is about this wallet. Addresses must be for `network`, and
+    /// `transaction` finds a transaction we know of, with the script hashes it pays to or
+    /// spends from. Anything we can't tell is refused.
+    pub fn allows(
+        &self,
+        request: &Request,
+        network: Network,
+        transaction: impl Fn(&Txid) -> Option<(Transaction, HashSet<sha256::Hash>)>,
+    ) -> bool {
+        let method = request.method.as_str();
+        let param = |n: usize| request.params.get(n).cloned().unwrap_or_default();
+        if method.starts_with("blockchain.scripthash.") {
+            return serde_json::from_value::<sha256::Hash>(param(0))
+                .map_or(false, |script_hash| self.contains(&script_hash));
+        }
+        if method.starts_with("blockchain.address.") {
+            return param(0)
+                .as_str()
+                .and_then(|address| address_to_script_hash(address, network).ok())
+                .map_or(false, |script_hash| self.contains(&script_hash));
+        }
+        let (transaction, script_hashes) = match serde_json::from_value::<Txid>(param(0))
+            .ok()
+            .and_then(|txid| transaction(&txid))
+        {
+            Some(found) => found,
+            None => return false,
+        };
+        if method.starts_with("blockchain.outpoint.") {
+            // Only the owner of an output may follow it
+            return serde_json::from_value::<u32>(param(1))
+                .ok()
+                .and_then(|vout| transaction.output.get(vout as usize))
+                .map_or(false, |output| {
+                    self.contains(&get_spk_hash(&output.script_pubkey))
+                });
+        }
+        script_hashes
+            .iter()
+            .any(|script_hash| self.contains(script_hash))
+    }
+    /// Whether `token` is this wallet's
+    fn check_token(&self, token: &str) -> bool {
+        tokens_match(&self.token, token)
+    }
+}
+
+/// Whether `theirs` is our token. Every byte is looked at, so how long this takes doesn't
+/// tell how much of a guess was right.
+pub fn tokens_match(ours: &str, theirs: &str) -> bool {
+    let (ours, theirs) = (ours.as_bytes(), theirs.as_bytes());
+    ours.len() == theirs.len()
+        && ours
+            .iter()
+            .zip(theirs)
+            .fold(0, |diff, (ours, theirs)| diff | (ours ^ theirs))
+            == 0
+}
+
+/// Finds the wallet a client authenticates as, if its token is right
+pub fn authenticate<'a>(
+    scopes: &'a [WalletScope],
+    name: &str,
+    token: &str,
+) -> Option<&'a WalletScope> {
+    scopes
+        .iter()
+        .find(|scope| scope.name == name)
+        .filter(|scope| scope.check_token(token))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, str::FromStr};
+
+    use bitcoin::{hashes::Hash, Address, Network, PackedLockTime, Transaction, TxOut, Txid};
+    use serde_json::{json, Value};
+
+    use super::{authenticate, WalletScope};
+    use crate::electrum::{electrum_protocol::get_spk_hash, request::Request};
+
+    fn request(method: &str, params: Vec<Value>) -> Request {
+        Request {
+            id: 0,
+            method: method.to_string(),
+            jsonrpc: "2.0".to_string(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let scopes = [
+            WalletScope::new("alice".into(), "secret".into(), HashSet::new()),
+            WalletScope::new("bob".into(), "hunter2".into(), HashSet::new()),
+        ];
+        assert_eq!(
+            authenticate(&scopes, "bob", "hunter2").map(|scope| scope.name.as_str()),
+            Some("bob")
+        );
+        assert!(authenticate(&scopes, "bob", "secret").is_none());
+        assert!(authenticate(&scopes, "bob", "hunter").is_none());
+        assert!(authenticate(&scopes, "carol", "secret").is_none());
+    }
+    #[test]
+    fn test_allows() {
+        let ours = Address::from_str("bcrt1q9d4zjf92nvd3zhg6cvyckzaqumk4zre2c0k8hv").unwrap();
+        let theirs = Address::from_str("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn").unwrap();
+        let ours_hash = get_spk_hash(&ours.script_pubkey());
+        let theirs_hash = get_spk_hash(&theirs.script_pubkey());
+        let scope = WalletScope::new("alice".into(), "secret".into(), HashSet::from([ours_hash]));
+        // Pays them first, then us
+        let transaction = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![],
+            output: [&theirs, &ours]
+                .iter()
+                .map(|address| TxOut {
+                    value: 1000,
+                    script_pubkey: address.script_pubkey(),
+                })
+                .collect(),
+        };
+        // And one only paying them
+        let other = Transaction {
+            output: vec![transaction.output[0].clone()],
+            ..transaction.clone()
+        };
+        let (txid, other_txid) = (transaction.txid(), other.txid());
+        let lookup = |txid: &Txid| {
+            let found = [&transaction, &other]
+                .into_iter()
+                .find(|known| known.txid() == *txid)?
+                .clone();
+            let script_hashes = found
+                .output
+                .iter()
+                .map(|output| get_spk_hash(&output.script_pubkey))
+                .collect();
+            Some((found, script_hashes))
+        };
+        let allows = |method: &str, params: Vec<Value>| {
+            scope.allows(&request(method, params), Network::Regtest, &lookup)
+        };
+
+        assert!(allows(
+            "blockchain.scripthash.get_history",
+            vec![json!(ours_hash)]
+        ));
+        assert!(!allows(
+            "blockchain.scripthash.get_history",
+            vec![json!(theirs_hash)]
+        ));
+        assert!(!allows(
+            "blockchain.scripthash.get_history",
+            vec![json!("nonsense")]
+        ));
+
+        assert!(allows(
+            "blockchain.address.subscribe",
+            vec![json!(ours.to_string())]
+        ));
+        assert!(!allows(
+            "blockchain.address.listunspent",
+            vec![json!(theirs.to_string())]
+        ));
+        // An address for another network
+        let mainnet = Address::p2wpkh(
+            &bitcoin::PublicKey::from_str(
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+            Network::Bitcoin,
+        )
+        .unwrap();
+        assert!(!allows(
+            "blockchain.address.subscribe",
+            vec![json!(mainnet.to_string())]
+        ));
+
+        assert!(allows("blockchain.transaction.get", vec![json!(txid)]));
+        assert!(!allows(
+            "blockchain.transaction.get",
+            vec![json!(other_txid)]
+        ));
+
+        assert!(allows(
+            "blockchain.outpoint.subscribe",
+            vec![json!(txid), json!(1)]
+        ));
+        assert!(!allows(
+            "blockchain.outpoint.subscribe",
+            vec![json!(txid), json!(0)]
+        ));
+        assert!(!allows(
+            "blockchain.outpoint.subscribe",
+            vec![json!(txid), json!(2)]
+        ));
+        assert!(!allows(
+            "blockchain.outpoint.subscribe",
+            vec![json!(other_txid), json!(0)]
+        ));
+        // Transactions we don't know of
+        assert!(!allows(
+            "blockchain.transaction.get",
+            vec![json!(Txid::all_zeros())]
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+//! Per-connection state. Each client negotiates a protocol version with `server.version`,
+//! and we answer in the way that version expects, so older clients keep working as new
+//! versions land.
+
+use bitcoin::{consensus::deserialize, hashes::hex::FromHex, BlockHeader};
+use serde_json::{json, Value};
+
+/// The protocol versions we speak, oldest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    V1_2,
+    V1_3,
+    V1_4,
+}
+
+impl ProtocolVersion {
+    const ALL: [ProtocolVersion; 3] = [
+        ProtocolVersion::V1_2,
+        ProtocolVersion::V1_3,
+        ProtocolVersion::V1_4,
+    ];
+    fn number(&self) -> (u32, u32) {
+        match self {
+            ProtocolVersion::V1_2 => (1, 2),
+            ProtocolVersion::V1_3 => (1, 3),
+            ProtocolVersion::V1_4 => (1, 4),
+        }
+    }
+    /// Parses a version like "1.4" or "1.4.2". We only care about major and minor numbers.
+    fn parse(version: &str) -> Option<(u32, u32)> {
+        let mut numbers = version.split('.');
+        let major = numbers.next()?.parse().ok()?;
+        let minor = numbers.next().unwrap_or("0").parse().ok()?;
+        Some((major, minor))
+    }
+    /// Picks the newest version we both speak. Clients send either a single version, or a
+    /// `[min, max]` range, and older clients may send nothing at all.
+    pub fn negotiate(client: Option<&Value>) -> Option<ProtocolVersion> {
+        let (min, max) = match client {
+            None => ((1, 4), (1, 4)),
+            Some(Value::String(version)) => {
+                let version = Self::parse(version)?;
+                (version, version)
+            }
+            Some(Value::Array(range)) if range.len() == 2 => (
+                Self::parse(range[0].as_str()?)?,
+                Self::parse(range[1].as_str()?)?,
+            ),
+            _ => return None,
+        };
+        Self::ALL
+            .iter()
+            .rev()
+            .find(|version| (min..=max).contains(&version.number()))
+            .copied()
+    }
+    /// The first version having this standard method, and the first one that doesn't have
+    /// it anymore. Methods in every version we speak, like our extensions, have neither.
+    fn lifetime(method: &str) -> (Option<ProtocolVersion>, Option<ProtocolVersion>) {
+        use ProtocolVersion::*;
+        match method {
+            // Replaced by their blockchain.scripthash.* versions
+            "blockchain.address.get_balance"
+            | "blockchain.address.get_history"
+            | "blockchain.address.get_mempool"
+            | "blockchain.address.listunspent"
+            | "blockchain.address.subscribe" => (None, Some(V1_3)),
+            // Replaced by blockchain.block.header and blockchain.block.headers
+            "blockchain.block.get_chunk" | "blockchain.block.get_header" => (None, Some(V1_3)),
+            "blockchain.block.header" => (Some(V1_3), None),
+            "blockchain.scripthash.unsubscribe" | "blockchain.transaction.id_from_pos" => {
+                (Some(V1_4), None)
+            }
+            _ => (None, None),
+        }
+    }
+    /// Whether a method exists in this version
+    pub fn supports(&self, method: &str) -> bool {
+        let (added, removed) = Self::lifetime(method);
+        added.iter().all(|added| self >= added) && removed.iter().all(|removed| self < removed)
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (major, minor) = self.number();
+        write!(f, "{major}.{minor}")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub version: ProtocolVersion,
+    /// Whether this client sent `server.version`. Clients that don't may be older than it
+    pub negotiated: bool,
+    /// Version 1.2 clients may ask for headers as a dictionary instead of hex
+    pub raw_headers: bool,
+    /// The wallet this client authenticated as, when wallets share this server
+    pub wallet: Option<String>,
+    /// Whether this client authenticated as our operator, and may call `admin.*` methods
+    pub admin: bool,
+    /// Whether this client reached us from another machine, through our mapped TLS port. It
+    /// may authenticate as a wallet, but never as our operator
+    pub public: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            version: ProtocolVersion::V1_4,
+            negotiated: false,
+            raw_headers: true,
+            wallet: None,
+            admin: false,
+            public: false,
+        }
+    }
+}
+
+impl Session {
+    /// Whether this client may call `method`. Until it negotiates a version, it may also
+    /// call the methods removed since 1.2, as old clients do without asking
+    pub fn supports(&self, method: &str) -> bool {
+        self.version.supports(method)
+            || (!self.negotiated && ProtocolVersion::V1_2.supports(method))
+    }
+    /// Formats a header for `blockchain.headers.subscribe`, as this session expects
+    pub fn format_header(&self, height: u32, header: &str) -> Value {
+        if self.version >= ProtocolVersion::V1_3 || self.raw_headers {
+            return json!({
+                "height": height,
+                "hex": header
+            });
+        }
+        let header = match Vec::from_hex(header)
+            .ok()
+            .and_then(|header| deserialize::<BlockHeader>(&header).ok())
+        {
+            Some(header) => header,
+            None => return json!(null),
+        };
+        json!({
+            "block_height": height,
+            "version": header.version,
+            "prev_block_hash": header.prev_blockhash,
+            "merkle_root": header.merkle_root,
+            "timestamp": header.time,
+            "bits": header.bits,
+            "nonce": header.nonce
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProtocolVersion, Session};
+    use serde_json::json;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            ProtocolVersion::negotiate(Some(&json!("1.2"))),
+            Some(ProtocolVersion::V1_2)
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(Some(&json!(["1.1", "1.4.2"]))),
+            Some(ProtocolVersion::V1_4)
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(Some(&json!(["1.2", "1.3"]))),
+            Some(ProtocolVersion::V1_3)
+        );
+        assert_eq!(
+            ProtocolVersion::negotiate(None),
+            Some(ProtocolVersion::V1_4)
+        );
+        assert_eq!(ProtocolVersion::negotiate(Some(&json!("1.0"))), None);
+    }
+    #[test]
+    fn test_supports() {
+        assert!(ProtocolVersion::V1_2.supports("blockchain.address.get_balance"));
+        assert!(!ProtocolVersion::V1_3.supports("blockchain.address.get_balance"));
+        assert!(!ProtocolVersion::V1_3.supports("blockchain.scripthash.unsubscribe"));
+        assert!(ProtocolVersion::V1_4.supports("blockchain.scripthash.get_balance"));
+        // Each version has one way of getting a single header
+        for version in ProtocolVersion::ALL {
+            assert_ne!(
+                version.supports("blockchain.block.get_header"),
+                version.supports("blockchain.block.header"),
+                "{version}"
+            );
+        }
+        assert!(!ProtocolVersion::V1_3.supports("blockchain.transaction.id_from_pos"));
+        assert!(ProtocolVersion::V1_4.supports("blockchain.transaction.id_from_pos"));
+        // Our extensions are there in every version
+        assert!(ProtocolVersion::V1_2.supports("blockchain.utreexo.get_block_proof"));
+
+        let mut session = Session::default();
+        assert!(session.supports("blockchain.address.get_balance"));
+        assert!(session.supports("blockchain.scripthash.unsubscribe"));
+        session.negotiated = true;
+        assert!(!session.supports("blockchain.address.get_balance"));
+    }
+}
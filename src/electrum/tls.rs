@@ -0,0 +1,199 @@
+//! Electrum over TLS, for wallets reaching us from other machines. With `tls_client_ca`,
+//! clients must present a certificate that CA issued, and with `tls_client_fingerprints`,
+//! one of the certificates listed there. Both are checked when the connection is set up,
+//! so a client that isn't allowed is dropped before we read a single request from it.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, ErrorKind},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_std::net::TcpStream;
+use bitcoin::hashes::{hex::ToHex, sha256, Hash};
+use futures_rustls::{server::TlsStream, TlsAcceptor};
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore,
+    ServerConfig as TlsConfig,
+};
+use rustls_pemfile::Item;
+
+use crate::config::ServerConfig;
+
+/// How long a client has to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TlsListener {
+    acceptor: TlsAcceptor,
+    allowlist: Allowlist,
+}
+
+/// SHA-256 fingerprints of the client certificates we accept, lowercase hex. Empty if any
+/// certificate our client CA issued will do
+struct Allowlist(HashSet<String>);
+
+impl Allowlist {
+    /// Fingerprints may be written with colons and in uppercase, like openssl shows them
+    fn new(fingerprints: &[String]) -> Allowlist {
+        Allowlist(
+            fingerprints
+                .iter()
+                .map(|fingerprint| {
+                    fingerprint
+                        .chars()
+                        .filter(|c| *c != ':')
+                        .collect::<String>()
+                        .to_lowercase()
+                })
+                .collect(),
+        )
+    }
+    /// Whether a client presenting `certificates` may connect. Our CA already checked them
+    /// during the handshake, so only the fingerprint is left.
+    fn allows(&self, certificates: Option<&[Certificate]>) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+        certificates
+            .and_then(|certificates| certificates.first())
+            .map_or(false, |certificate| {
+                self.0.contains(&fingerprint(certificate))
+            })
+    }
+}
+
+impl TlsListener {
+    /// Loads our certificate and key, and who may connect. `None` if TLS isn't configured.
+    pub fn load(config: &ServerConfig) -> Result<Option<TlsListener>, String> {
+        let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => return Err("TLS needs both tls_cert and tls_key".to_string()),
+        };
+        let certs = read_pem(cert_path)?
+            .into_iter()
+            .filter_map(|item| match item {
+                Item::X509Certificate(der) => Some(Certificate(der)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            return Err(format!("{} has no certificate", cert_path.display()));
+        }
+        let key = read_pem(key_path)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+                _ => None,
+            })
+            .ok_or_else(|| format!("{} has no private key", key_path.display()))?;
+        if !config.tls_client_fingerprints.is_empty() && config.tls_client_ca.is_none() {
+            return Err("tls_client_fingerprints needs tls_client_ca".to_string());
+        }
+        let builder = TlsConfig::builder().with_safe_defaults();
+        let builder = match &config.tls_client_ca {
+            Some(ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for item in read_pem(ca_path)? {
+                    if let Item::X509Certificate(der) = item {
+                        roots.add(&Certificate(der)).map_err(|err| {
+                            format!("{} has an invalid certificate: {err}", ca_path.display())
+                        })?;
+                    }
+                }
+                if roots.is_empty() {
+                    return Err(format!("{} has no certificate", ca_path.display()));
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let tls = builder
+            .with_single_cert(certs, key)
+            .map_err(|err| format!("Our TLS certificate or key is invalid: {err}"))?;
+        Ok(Some(TlsListener {
+            acceptor: TlsAcceptor::from(Arc::new(tls)),
+            allowlist: Allowlist::new(&config.tls_client_fingerprints),
+        }))
+    }
+    /// Sets up TLS with a client we've just accepted, making sure it may connect
+    pub async fn accept(&self, stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        let stream =
+            async_std::io::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream)).await?;
+        let (_, connection) = stream.get_ref();
+        if !self.allowlist.allows(connection.peer_certificates()) {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "client certificate isn't in our allowlist",
+            ));
+        }
+        Ok(stream)
+    }
+}
+
+/// The SHA-256 of a certificate, in lowercase hex, as `openssl x509 -fingerprint -sha256`
+/// shows it, without the colons
+pub fn fingerprint(certificate: &Certificate) -> String {
+    sha256::Hash::hash(&certificate.0).to_hex()
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, String> {
+    let file = File::open(path).map_err(|err| format!("Can't open {}: {err}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| format!("Can't read {}: {err}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use rustls::Certificate;
+
+    use super::{fingerprint, Allowlist, TlsListener};
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn test_load() {
+        assert!(TlsListener::load(&ServerConfig::default())
+            .unwrap()
+            .is_none());
+        let dir = "/tmp/utreexo_tls_load";
+        std::fs::create_dir_all(dir).unwrap();
+        let empty = format!("{dir}/empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let config = ServerConfig {
+            tls_cert: Some(empty.clone().into()),
+            ..ServerConfig::default()
+        };
+        assert!(TlsListener::load(&config).is_err());
+        let config = ServerConfig {
+            tls_cert: Some(empty.clone().into()),
+            tls_key: Some(empty.into()),
+            ..ServerConfig::default()
+        };
+        assert!(TlsListener::load(&config)
+            .unwrap_err()
+            .contains("has no certificate"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let allowed = Certificate(b"allowed".to_vec());
+        let other = Certificate(b"other".to_vec());
+        let written = fingerprint(&allowed)
+            .to_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8(pair.to_vec()).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        let allowlist = Allowlist::new(&[written]);
+        assert!(allowlist.allows(Some(&[allowed])));
+        assert!(!allowlist.allows(Some(&[other.clone()])));
+        assert!(!allowlist.allows(None));
+        // Without fingerprints, our CA alone decides
+        assert!(Allowlist::new(&[]).allows(Some(&[other])));
+    }
+}
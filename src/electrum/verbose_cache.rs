@@ -0,0 +1,100 @@
+//! Wallets ask for the same verbose transactions over and over, every time they refresh
+//! their UI, and building one means describing every script in it. So we keep the ones
+//! asked for lately, up to [crate::config::ResourceLimits::verbose_cache_size] bytes of
+//! JSON. Confirmations change with every block, so they aren't cached, but added to each
+//! reply. An entry is only used while its transaction is still in the same block.
+
+use std::sync::Mutex;
+
+use bitcoin::{BlockHash, Txid};
+use lru::LruCache;
+use serde_json::Value;
+
+struct Entry {
+    height: u32,
+    block_hash: Option<BlockHash>,
+    size: usize,
+    verbose: Value,
+}
+
+pub struct VerboseCache {
+    entries: Mutex<(LruCache<Txid, Entry>, usize)>,
+    max_size: usize,
+}
+
+impl VerboseCache {
+    pub fn new(max_size: usize) -> VerboseCache {
+        VerboseCache {
+            entries: Mutex::new((LruCache::unbounded(), 0)),
+            max_size,
+        }
+    }
+    /// The verbose transaction we've built for `txid`, if it was in the same block then
+    pub fn get(&self, txid: &Txid, height: u32, block_hash: Option<BlockHash>) -> Option<Value> {
+        let mut entries = self.entries.lock().expect("Poisoned lock");
+        let (cache, size) = &mut *entries;
+        match cache.get(txid) {
+            Some(entry) if entry.height == height && entry.block_hash == block_hash => {
+                Some(entry.verbose.clone())
+            }
+            Some(_) => {
+                let stale = cache.pop(txid).expect("We've just seen it");
+                *size -= stale.size;
+                None
+            }
+            None => None,
+        }
+    }
+    pub fn insert(&self, txid: Txid, height: u32, block_hash: Option<BlockHash>, verbose: Value) {
+        let entry_size = verbose.to_string().len();
+        if entry_size > self.max_size {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("Poisoned lock");
+        let (cache, size) = &mut *entries;
+        let entry = Entry {
+            height,
+            block_hash,
+            size: entry_size,
+            verbose,
+        };
+        if let Some((_, replaced)) = cache.push(txid, entry) {
+            *size -= replaced.size;
+        }
+        *size += entry_size;
+        while *size > self.max_size {
+            match cache.pop_lru() {
+                Some((_, evicted)) => *size -= evicted.size,
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{hashes::Hash, BlockHash, Txid};
+    use serde_json::json;
+
+    use super::VerboseCache;
+
+    #[test]
+    fn test_verbose_cache() {
+        let verbose = json!({"hex": "00".repeat(40)});
+        let size = verbose.to_string().len();
+        let cache = VerboseCache::new(size * 2);
+        let txids = (0..3u8)
+            .map(|n| Txid::from_slice(&[n; 32]).unwrap())
+            .collect::<Vec<_>>();
+        for txid in txids.iter() {
+            cache.insert(*txid, 0, None, verbose.clone());
+        }
+        // Only the two most recent fit
+        assert!(cache.get(&txids[0], 0, None).is_none());
+        assert_eq!(cache.get(&txids[2], 0, None), Some(verbose.clone()));
+        // Once confirmed, it's built again
+        let block_hash = Some(BlockHash::from_slice(&[1; 32]).unwrap());
+        assert!(cache.get(&txids[1], 10, block_hash).is_none());
+        assert!(cache.get(&txids[1], 0, None).is_none());
+    }
+}
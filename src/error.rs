@@ -9,6 +9,7 @@ pub enum Error {
     EncodeError(encode::Error),
     BlockNotFound,
     WalletNotInitialized,
+    WalletAlreadySynced,
     DbError(kv::Error),
     DbParseError,
     ParseNumError(std::num::ParseIntError),
@@ -16,6 +17,30 @@ pub enum Error {
     InvalidProof,
     IoError(std::io::Error),
     ValidationError(bitcoin::blockdata::script::Error),
+    JsonError(serde_json::Error),
+    ConfigError(String),
+    ConsensusError(String),
+    DescriptorError(miniscript::Error),
+    ScriptHashCollision(bitcoin::hashes::sha256::Hash),
+    /// Other nodes agree with the proof for this block, but it doesn't fit our accumulator
+    AccumulatorCorrupted(u32),
+    /// We don't watch the address with this script hash
+    AddressNotFound(bitcoin::hashes::sha256::Hash),
+    /// Something made for another network was given to us
+    WrongNetwork(bitcoin::Network),
+    /// A wallet export only has history up to this height, which is behind our wallet
+    StaleExport(u32),
+    /// The block at this height in a wallet export isn't the one our node has
+    NotInOurChain(u32),
+    /// The headers we keep for the retarget window starting at this height break the
+    /// difficulty rules, and why
+    InvalidHeaders(u32, String),
+    /// A source gave us this block at this height, which isn't the one our node announced
+    UnexpectedBlock(u32, bitcoin::BlockHash),
+    /// Our disk is almost full, so we don't take on anything that grows our database
+    LowDiskSpace,
+    /// A peer answered something our protocol with it doesn't allow, and what
+    ProtocolError(String),
 }
 
 impl std::fmt::Display for Error {
@@ -27,6 +52,7 @@ impl std::fmt::Display for Error {
             Error::TxNotFound => write!(f, "TxNotFound"),
             Error::UtreexodError(_) => write!(f, "UtreexodError"),
             Error::WalletNotInitialized => write!(f, "WalletNotInitialized"),
+            Error::WalletAlreadySynced => write!(f, "This wallet already processed some blocks"),
             Error::DbError(err) => write!(f, "Database error {err}"),
             Error::DbParseError => write!(f, "Database parse error"),
             Error::ParseNumError(err) => write!(f, "int parse error: {err}"),
@@ -34,10 +60,58 @@ impl std::fmt::Display for Error {
             Error::InvalidProof => write!(f, "Invalid proof passed in"),
             Error::IoError(err) => write!(f, "Io error {err}"),
             Error::ValidationError(err) => write!(f, "Error during script evaluation: {err}"),
+            Error::JsonError(err) => write!(f, "Json error: {err}"),
+            Error::ConfigError(err) => write!(f, "Invalid config file: {err}"),
+            Error::ConsensusError(err) => write!(f, "Block breaks consensus rules: {err}"),
+            Error::DescriptorError(err) => write!(f, "Invalid descriptor: {err}"),
+            Error::ScriptHashCollision(hash) => {
+                write!(f, "Another script already has the hash {hash}")
+            }
+            Error::AccumulatorCorrupted(height) => write!(
+                f,
+                "Our accumulator doesn't fit the proof for block {height}, which other nodes agree on. It may be corrupted"
+            ),
+            Error::AddressNotFound(hash) => write!(f, "We don't watch the script hash {hash}"),
+            Error::WrongNetwork(network) => write!(f, "This was made for {network}"),
+            Error::StaleExport(height) => write!(
+                f,
+                "This export only goes up to block {height}, which is behind our wallet"
+            ),
+            Error::NotInOurChain(height) => write!(
+                f,
+                "Block {height} in this export is not the one our node has at that height"
+            ),
+            Error::InvalidHeaders(start, reason) => write!(
+                f,
+                "Our headers for the window starting at {start} are invalid: {reason}"
+            ),
+            Error::UnexpectedBlock(height, hash) => write!(
+                f,
+                "Got block {hash} at height {height}, not the one our node announced"
+            ),
+            Error::LowDiskSpace => write!(f, "Our disk is almost full"),
+            Error::ProtocolError(err) => write!(f, "Protocol error: {err}"),
         }
     }
 }
 
+impl Error {
+    /// Whether retrying what caused this error may work. Problems talking to our node
+    /// usually go away on their own, as do sources lagging behind it, and we start a new
+    /// connection after a protocol error, but database or validation errors won't go away.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::UtreexodError(_)
+                | Error::IoError(_)
+                | Error::BlockNotFound
+                | Error::TxNotFound
+                | Error::UnexpectedBlock(..)
+                | Error::ProtocolError(_)
+        )
+    }
+}
+
 impl_from_error!(ParsingError, bitcoin::hashes::hex::Error);
 impl_from_error!(UtreexodError, UtreexodError);
 impl_from_error!(EncodeError, encode::Error);
@@ -46,6 +120,8 @@ impl_from_error!(ParseNumError, std::num::ParseIntError);
 impl_from_error!(RustreexoError, String);
 impl_from_error!(IoError, std::io::Error);
 impl_from_error!(ValidationError, bitcoin::blockdata::script::Error);
+impl_from_error!(JsonError, serde_json::Error);
+impl_from_error!(DescriptorError, miniscript::Error);
 
 impl std::error::Error for Error {}
 #[macro_export]
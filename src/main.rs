@@ -38,96 +38,893 @@
 #![allow(clippy::enum_variant_names)]
 
 mod address_cache;
+mod audit;
 mod blockchain;
 mod cli;
+mod config;
+mod crash;
+mod disk;
 mod electrum;
 mod error;
+mod memory;
+mod portmap;
+mod scheduler;
+mod selftest;
+mod sharded;
+mod supervisor;
 
-use std::{process::exit, sync::Arc};
+use std::{
+    io::Write,
+    process::exit,
+    sync::{mpsc::Sender, Arc},
+    time::Duration,
+};
 
-use crate::electrum::electrum_protocol::Message;
-use address_cache::{kv_database::KvDatabase, AddressCache, AddressCacheDatabase};
-use async_std::task::{self, block_on};
-use bitcoin::Network;
+use crate::electrum::{
+    electrum_protocol::{get_spk_hash, Message},
+    identity::ServerIdentity,
+    scope::WalletScope,
+    tls::TlsListener,
+};
+use address_cache::{
+    block_export::BlockExporter,
+    chainstate_dump::ChainStateDump,
+    kv_database::KvDatabase,
+    script_type::AddressFormat,
+    silent_payments::SilentPaymentsFilter,
+    transports::{Email, Telegram},
+    wallet_export::WalletExport,
+    webhooks::{AlertTransport, Alerts, Webhook},
+    AddressCache, AddressCacheDatabase,
+};
+use async_std::{net::TcpListener, task::block_on};
+use audit::CoreRpc;
+use bitcoin::{
+    hashes::hex::FromHex,
+    util::bip32::{ChildNumber, DerivationPath},
+    BlockHash, BlockHeader, Network,
+};
 use blockchain::{
+    chain_params::{BitcoinParams, ChainParams},
     chainstore::{ChainStore, KvChainStore},
-    sync::BlockchainSync,
+    headers::HeaderStore,
+    latency::LatencyRankedSource,
+    proof_peer::PeerProofSource,
+    sync::{BlockSource, BlockchainSync},
     ChainWatch,
 };
 use btcd_rpc::client::{BTCDClient, BTCDConfigs, BtcdRpc};
 use clap::Parser;
-use cli::{Cli, Commands};
-use log::{error, info};
-use miniscript::{Descriptor, DescriptorPublicKey};
-use pretty_env_logger::env_logger::TimestampPrecision;
-use std::str::FromStr;
+use cli::{Branch, Cli, Commands};
+use config::{AlertConfig, Config, MaintenanceConfig, ResourceLimits, SyncConfig, WalletConfig};
+use directories::ProjectDirs;
+use log::{error, info, warn};
+use miniscript::{
+    descriptor::Wildcard, translate_hash_clone, Descriptor, DescriptorPublicKey, Translator,
+};
+use portmap::PortMapping;
+use pretty_env_logger::env_logger::{Target, TimestampPrecision};
+use rustreexo::accumulator::stump::Stump;
+use scheduler::{Scheduler, Task};
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use supervisor::{Subsystem, Supervisor};
 
-fn main() {
-    // Setup global logger
-    pretty_env_logger::formatted_timed_builder()
+/// Whether we run in a container, where whatever runs us collects our logs from stdout
+fn in_container() -> bool {
+    Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || std::env::var_os("KUBERNETES_SERVICE_HOST").is_some()
+        || std::env::var_os("container").is_some()
+}
+/// Logs for humans, or one JSON object per line on stdout if we run in a container.
+/// `UES_LOG_FORMAT` can be set to `json` or `text` to choose either
+fn init_logger() {
+    let mut builder = pretty_env_logger::formatted_timed_builder();
+    builder
         .filter_level(log::LevelFilter::Info)
         .format_timestamp(Some(TimestampPrecision::Seconds))
-        .format_module_path(false)
-        .init();
+        .format_module_path(false);
+    let json = match std::env::var("UES_LOG_FORMAT").as_deref() {
+        Ok("json") => true,
+        Ok("text") => false,
+        _ => in_container(),
+    };
+    if json {
+        builder.target(Target::Stdout).format(|buf, record| {
+            let line = json!({
+                "time": buf.timestamp_seconds().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string()
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    builder.init();
+}
+
+fn main() {
+    init_logger();
 
     let params = Cli::parse();
+    let config = Config::load(params.config, get_net(&params.network), params.profile)
+        .expect("Could not load the config file");
+    // async-std reads this when its runtime starts, so it must be set before we spawn anything
+    std::env::set_var(
+        "ASYNC_STD_THREAD_COUNT",
+        config.resources.async_threads.to_string(),
+    );
+    let chain_params = BitcoinParams::new(get_net(&params.network));
     match params.command {
         Commands::Run {
             data_dir,
             rpc_user,
             rpc_password,
             rpc_host,
+            export_blocks,
+            tx_cache_size,
+            warmup,
+            stop_at_height,
         } => {
+            let data_dir = get_data_dir(data_dir);
+            let problems = config.validate(Path::new(&data_dir));
+            if !problems.is_empty() {
+                for problem in problems.iter() {
+                    error!("{problem}");
+                }
+                exit(1);
+            }
             let rpc = create_rpc_connection(rpc_host, Some(rpc_user), Some(rpc_password));
             if !test_rpc(&rpc) {
                 info!("Unable to connect with rpc");
                 return;
             }
+            if !test_genesis(&rpc, &chain_params) {
+                error!("Our node is following a different chain, check your network");
+                exit(1);
+            }
+            let identity = ServerIdentity::load_or_create(&data_dir)
+                .expect("Could not load the server identity");
+            info!("Server identity: {}", identity.public_key());
             info!("Starting sync worker, this might take a while!");
-            let cache = load_wallet(data_dir);
-            let cache = start_sync(&rpc, cache).expect("Could not sync");
+            let disk = disk::monitor(&data_dir, config.resources.min_free_disk_space);
+            if let Some(budget) = config.resources.max_rss {
+                memory::monitor(budget);
+            }
+            let fingerprint = crash::StateFingerprint::default();
+            crash::install(
+                &data_dir,
+                config.alerts.crash_report_url.clone(),
+                fingerprint.clone(),
+            );
+            let mut cache = load_wallet(data_dir.clone(), &config.resources);
+            cache.set_header_store(open_header_store(&data_dir, chain_params.network()));
+            cache.set_disk_space(disk.clone());
+            cache.set_fingerprint(fingerprint);
+            cache.set_tx_cache_size(tx_cache_size);
+            if let Some(height) = stop_at_height {
+                // We can't undo blocks we've already applied
+                let synced = cache.get_cache_height().unwrap_or(0);
+                if synced > height {
+                    error!("Our wallet is already synced to block {synced}, past {height}");
+                    exit(1);
+                }
+                info!("Stopping at block {height}, newer blocks won't be applied");
+                cache.set_stop_height(height);
+            }
+            cache.set_check_balances(params.debug > 0);
+            cache.set_op_return_prefixes(
+                config
+                    .index
+                    .get_op_return_prefixes()
+                    .expect("Prefixes were validated"),
+            );
+            if let Some((scan_key, spend_key)) = config
+                .silent_payments
+                .get_keys()
+                .expect("Keys were validated")
+            {
+                cache.add_filter(Box::new(SilentPaymentsFilter::new(scan_key, spend_key)));
+            }
+            let transports = create_alert_transports(&config.alerts);
+            if !transports.is_empty() {
+                cache.set_alerts(Alerts::new(transports));
+            }
+            if let Some(export_blocks) = export_blocks {
+                let exporter = BlockExporter::new(
+                    export_blocks,
+                    chain_params.network(),
+                    config.server.address_format,
+                )
+                .expect("Could not open the export file");
+                cache.set_block_exporter(exporter);
+            }
+            let fallbacks = create_fallback_connections(&config.sync);
+            let block_source = create_block_source(&config.sync, &rpc);
+            let cache = if config.server.serve_during_sync {
+                // Our main loop catches up, in between serving clients
+                check_wallet(&cache);
+                cache
+            } else {
+                match start_sync(
+                    &rpc,
+                    &*block_source,
+                    &fallbacks,
+                    cache,
+                    &config.resources,
+                    &chain_params,
+                ) {
+                    Ok(cache) => cache,
+                    Err(err) => {
+                        error!("Could not sync: {err}");
+                        exit(1);
+                    }
+                }
+            };
             info!("Starting server...");
-            let electrum_server = block_on(electrum::electrum_protocol::ElectrumServer::new(
-                "127.0.0.1:50001",
+            let address = config
+                .server
+                .listen
+                .then(|| config.server.electrum_address());
+            if address.is_none() {
+                info!("Not listening for Electrum clients, as configured");
+            }
+            let wallets = create_wallet_scopes(&config.wallets);
+            let mut electrum_server = block_on(electrum::electrum_protocol::ElectrumServer::new(
+                address,
                 rpc.clone(),
                 cache,
+                identity,
+                config.resources,
+                Box::new(chain_params),
+                config.policy,
             ))
             .unwrap();
+            electrum_server.tip_source = create_tip_source(&config.sync, &block_source, &fallbacks);
+            electrum_server.block_source = block_source;
+            electrum_server.fallbacks = fallbacks;
+            electrum_server.mempool_expiry = config.mempool.expiry();
+            electrum_server.legacy_methods = config.server.legacy_methods;
+            electrum_server.address_format = config.server.address_format;
+            if !wallets.is_empty() {
+                info!(
+                    "Serving {} wallets, each session only sees its own",
+                    wallets.len()
+                );
+            }
+            electrum_server.wallets = wallets;
+            electrum_server.admin_token = config.server.admin_token.clone();
+            electrum_server.disk = disk;
+            electrum_server.auditor = config.audit.core_rpc_url.as_ref().map(|url| {
+                Arc::new(CoreRpc::new(
+                    url,
+                    &config.audit.core_rpc_user,
+                    &config.audit.core_rpc_password,
+                ))
+            });
+            let port_mapping = config
+                .server
+                .tls_port
+                .filter(|_| config.server.listen && config.server.map_port)
+                .map(|port| PortMapping::new(port, config.server.gateway));
+            electrum_server.port_mapping = port_mapping.clone();
 
-            let notify_sender = electrum_server.notify_tx.clone();
-            let timer = timer::Timer::new();
-            let mut current_block = ChainWatch::get_block(&rpc);
-            let rpc = electrum_server.rpc.clone();
-            timer
-                .schedule_repeating(chrono::Duration::seconds(5), move || {
-                    let new_block = ChainWatch::get_block(&rpc);
-                    if new_block > current_block {
-                        let _ = notify_sender.send(Message::NewBlock);
-                        current_block = new_block;
-                    }
-                })
-                .ignore();
-            task::spawn(electrum::electrum_protocol::accept_loop(
-                electrum_server.listener.clone().unwrap(),
+            if warmup {
+                electrum_server.start_warmup();
+            }
+            if config.server.serve_during_sync {
+                electrum_server.start_initial_sync();
+            }
+            let scheduler = create_scheduler(
+                &config.maintenance,
+                electrum_server.auditor.is_some(),
+                &data_dir,
+                electrum_server.rpc.clone(),
                 electrum_server.notify_tx.clone(),
-            ));
-            task::block_on(electrum_server.main_loop()).expect("Main loop failed");
+            );
+            // Service managers (systemd, launchd, Windows' SCM) stop us with a termination
+            // signal, so we handle it like a regular shutdown. We only do this after the initial
+            // sync, before that, the default handler is fine.
+            let shutdown_sender = electrum_server.notify_tx.clone();
+            ctrlc::set_handler(move || {
+                let _ = shutdown_sender.send(Message::Shutdown);
+            })
+            .expect("Could not set a termination handler");
+            let health = electrum_server.health.clone();
+            let mut supervisor = Supervisor::new(health.clone(), electrum_server.notify_tx.clone());
+            if let Some(listener) = electrum_server.listener.clone() {
+                let notify_tx = electrum_server.notify_tx.clone();
+                let socket = config.server.electrum_socket;
+                supervisor.add_service(Subsystem::Electrum, move || {
+                    let listener = listener.clone();
+                    let notify_tx = notify_tx.clone();
+                    async move {
+                        electrum::electrum_protocol::accept_loop(
+                            listener, socket, None, false, None, notify_tx,
+                        )
+                        .await
+                        .map_err(|err| err.to_string())
+                    }
+                });
+            }
+            let tls_address = config
+                .server
+                .electrum_tls_address()
+                .filter(|_| config.server.listen);
+            if let Some(address) = tls_address {
+                let tls = TlsListener::load(&config.server)
+                    .ok()
+                    .flatten()
+                    .expect("Checked when validating our config");
+                let tls = Arc::new(tls);
+                let listener = block_on(TcpListener::bind(&address))
+                    .expect("Could not open our Electrum TLS port");
+                let listener = Arc::new(listener);
+                info!("Serving Electrum clients over TLS on {address}");
+                let notify_tx = electrum_server.notify_tx.clone();
+                let socket = config.server.electrum_socket;
+                let map_port = config.server.map_port;
+                supervisor.add_service(Subsystem::ElectrumTls, move || {
+                    let listener = listener.clone();
+                    let notify_tx = notify_tx.clone();
+                    let tls = Some(tls.clone());
+                    async move {
+                        electrum::electrum_protocol::accept_loop(
+                            listener, socket, None, map_port, tls, notify_tx,
+                        )
+                        .await
+                        .map_err(|err| err.to_string())
+                    }
+                });
+            }
+            for wallet in config.wallets.iter() {
+                let port = match wallet.port {
+                    Some(port) => port,
+                    None => continue,
+                };
+                let listener = block_on(TcpListener::bind(("127.0.0.1", port)))
+                    .expect("Could not open a wallet's Electrum port");
+                let listener = Arc::new(listener);
+                info!("Serving wallet {} alone on port {port}", wallet.name);
+                let notify_tx = electrum_server.notify_tx.clone();
+                let socket = config.server.electrum_socket;
+                let name = wallet.name.clone();
+                supervisor.add_service(Subsystem::WalletListener(port), move || {
+                    let listener = listener.clone();
+                    let notify_tx = notify_tx.clone();
+                    let name = Some(name.clone());
+                    async move {
+                        electrum::electrum_protocol::accept_loop(
+                            listener, socket, name, false, None, notify_tx,
+                        )
+                        .await
+                        .map_err(|err| err.to_string())
+                    }
+                });
+            }
+            if let Some(port) = config.server.rest_port {
+                let listener = block_on(TcpListener::bind(("127.0.0.1", port)))
+                    .expect("Could not open the REST port");
+                let listener = Arc::new(listener);
+                info!("Serving REST requests on port {port}");
+                let notify_tx = electrum_server.notify_tx.clone();
+                let socket = config.server.rest_socket;
+                supervisor.add_service(Subsystem::Rest, move || {
+                    let listener = listener.clone();
+                    let notify_tx = notify_tx.clone();
+                    async move {
+                        electrum::rest::rest_accept_loop(listener, socket, notify_tx)
+                            .await
+                            .map_err(|err| err.to_string())
+                    }
+                });
+            }
+            if let Some(port) = config.server.monitoring_port {
+                let listener = block_on(TcpListener::bind(("127.0.0.1", port)))
+                    .expect("Could not open the monitoring port");
+                let listener = Arc::new(listener);
+                info!("Serving electrs-compatible metrics on port {port}");
+                let notify_tx = electrum_server.notify_tx.clone();
+                let socket = config.server.rest_socket;
+                let data_dir = PathBuf::from(&data_dir);
+                supervisor.add_service(Subsystem::Monitoring, move || {
+                    let listener = listener.clone();
+                    let notify_tx = notify_tx.clone();
+                    let data_dir = data_dir.clone();
+                    async move {
+                        electrum::monitoring::monitoring_accept_loop(
+                            listener, socket, data_dir, notify_tx,
+                        )
+                        .await
+                        .map_err(|err| err.to_string())
+                    }
+                });
+            }
+            #[cfg(feature = "grpc")]
+            if let Some(port) = config.server.grpc_port {
+                info!("Serving the admin API over gRPC on port {port}");
+                let notify_tx = electrum_server.notify_tx.clone();
+                let socket = config.server.grpc_socket;
+                supervisor.add_service(Subsystem::Grpc, move || {
+                    electrum::grpc::serve(port, socket, notify_tx.clone())
+                });
+            }
+            if let Some(mapping) = port_mapping.clone() {
+                supervisor.add_service(Subsystem::PortMapping, move || {
+                    mapping.clone().keep_mapped()
+                });
+            }
+            supervisor.add_service(Subsystem::Scheduler, move || scheduler.clone().run());
+            if let Some(checker) = electrum_server.tip_checker() {
+                supervisor.add_service(Subsystem::TipCheck, move || checker.clone().run());
+            }
+            let result = supervisor.run(electrum_server.main_loop());
+            if let Some(mapping) = port_mapping {
+                mapping.remove();
+            }
+            if let Err(err) = result {
+                error!("Main loop failed: {err}");
+                exit(1);
+            }
+            if Supervisor::any_failed(&health) {
+                exit(1);
+            }
         }
         Commands::Setup {
             data_dir,
             wallet_descriptor,
+            receive,
+            change,
+        } => {
+            let mut wallet = load_wallet(get_data_dir(data_dir), &config.resources);
+            setup_wallet(
+                wallet_descriptor,
+                receive,
+                change,
+                &mut wallet,
+                &chain_params,
+            );
+            info!("Wallet setup completed! You can now execute run");
+        }
+        Commands::Compact { data_dir } => {
+            let data_dir = get_data_dir(data_dir);
+            let size_before = get_dir_size(Path::new(&data_dir));
+            let database = KvDatabase::new(data_dir.clone(), config.resources.db_cache_size)
+                .expect("Could not open the database");
+            let dropped = database.compact().expect("Could not compact the database");
+            drop(database);
+            let size_after = get_dir_size(Path::new(&data_dir));
+            info!(
+                "Dropped {dropped} orphaned transactions, reclaimed {} bytes",
+                size_before.saturating_sub(size_after)
+            );
+        }
+        Commands::Scan {
+            descriptor,
+            from,
+            to,
+            data_dir,
+            rpc_user,
+            rpc_password,
+            rpc_host,
         } => {
-            let wallet = load_wallet(data_dir);
-            setup_wallet(wallet_descriptor, wallet, params.network);
+            let rpc = create_rpc_connection(rpc_host, Some(rpc_user), Some(rpc_password));
+            if !test_rpc(&rpc) || !test_genesis(&rpc, &chain_params) {
+                error!("Unable to use our node, is it up and on the right network?");
+                exit(1);
+            }
+            let to = match to {
+                Some(to) => to,
+                None => rpc.getbestblock().expect("Could not get our tip").height as u32,
+            };
+            // We can only validate blocks starting from `from` if we know the accumulator
+            // right before it
+            let acc = if from <= 1 {
+                Stump::new()
+            } else {
+                let data_dir = get_data_dir(data_dir);
+                match load_snapshot(data_dir.clone(), from - 1) {
+                    Ok(Some(acc)) => acc,
+                    Ok(None) => {
+                        error!("No accumulator snapshot for height {}", from - 1);
+                        exit(1);
+                    }
+                    Err(err) => {
+                        error!(
+                            "Could not read the snapshots in {data_dir}: {err}. A running server \
+                             keeps them locked, stop it or scan from the start"
+                        );
+                        exit(1);
+                    }
+                }
+            };
+
+            let scan_dir =
+                std::env::temp_dir().join(format!("utreexo-scan-{}", std::process::id()));
+            let mut wallet = load_wallet(
+                get_data_dir(Some(scan_dir.to_string_lossy().to_string())),
+                &config.resources,
+            );
+            setup_wallet(descriptor, 0..100, None, &mut wallet, &chain_params);
+            wallet.reset_to(from.saturating_sub(1), acc);
+            let result = BlockchainSync::sync_range(
+                &*rpc,
+                &*create_block_source(&config.sync, &rpc),
+                &create_fallback_connections(&config.sync),
+                &mut wallet,
+                from..=to,
+                None,
+                true,
+                &config.resources,
+                &chain_params,
+            );
+            if let Err(err) = result {
+                error!("Could not scan: {err}");
+                let _ = std::fs::remove_dir_all(&scan_dir);
+                exit(1);
+            }
+            println!(
+                "{:#}",
+                get_scan_result(
+                    &wallet,
+                    from,
+                    to,
+                    chain_params.network(),
+                    config.server.address_format,
+                )
+            );
+            drop(wallet);
+            let _ = std::fs::remove_dir_all(&scan_dir);
+        }
+        Commands::Rescan {
+            branch,
+            range,
+            from,
+            data_dir,
+            rpc_user,
+            rpc_password,
+            rpc_host,
+        } => {
+            let rpc = create_rpc_connection(rpc_host, Some(rpc_user), Some(rpc_password));
+            if !test_rpc(&rpc) || !test_genesis(&rpc, &chain_params) {
+                error!("Unable to use our node, is it up and on the right network?");
+                exit(1);
+            }
+            let mut wallet = load_wallet(get_data_dir(data_dir), &config.resources);
+            let (descriptor, to) = match (wallet.get_descriptor(), wallet.get_cache_height()) {
+                (Ok(descriptor), Ok(height)) => (descriptor, height),
+                _ => {
+                    error!("Wallet not set up!");
+                    exit(1);
+                }
+            };
+            let desc = match parse_descriptor(&descriptor)
+                .and_then(|desc| branch_descriptor(&desc, branch))
+            {
+                Ok(desc) => desc,
+                Err(e) => {
+                    error!("{e}");
+                    exit(1);
+                }
+            };
+            let acc = if from <= 1 {
+                Stump::new()
+            } else {
+                match wallet.get_acc_at(from - 1) {
+                    Some(acc) => acc,
+                    None => {
+                        error!("No accumulator snapshot for height {}", from - 1);
+                        exit(1);
+                    }
+                }
+            };
+            // We look for the new addresses alone in a scratch wallet, then bring what we
+            // found over, so our wallet never goes back in time. What it already had is
+            // skipped when we do
+            let scan_dir =
+                std::env::temp_dir().join(format!("utreexo-rescan-{}", std::process::id()));
+            let mut scratch = load_wallet(
+                get_data_dir(Some(scan_dir.to_string_lossy().to_string())),
+                &config.resources,
+            );
+            let result = scratch
+                .setup(desc.to_string())
+                .and_then(|_| derive_addresses(&desc, range.clone(), &mut scratch, &chain_params))
+                .and_then(|_| {
+                    scratch.reset_to(from.saturating_sub(1), acc);
+                    BlockchainSync::sync_range(
+                        &*rpc,
+                        &*create_block_source(&config.sync, &rpc),
+                        &create_fallback_connections(&config.sync),
+                        &mut scratch,
+                        from..=to,
+                        None,
+                        true,
+                        &config.resources,
+                        &chain_params,
+                    )
+                })
+                .and_then(|_| derive_addresses(&desc, range.clone(), &mut wallet, &chain_params));
+            if let Err(err) = result {
+                error!("Could not rescan: {err}");
+                let _ = std::fs::remove_dir_all(&scan_dir);
+                exit(1);
+            }
+            let found = wallet.import_history(&scratch);
+            info!(
+                "Rescanned {branch:?} addresses {}..{} from block {from} to {to}, found {found} transactions",
+                range.start, range.end
+            );
+            drop(scratch);
+            let _ = std::fs::remove_dir_all(&scan_dir);
+        }
+        Commands::Selftest => {
+            if !selftest::run() {
+                exit(1);
+            }
+        }
+        Commands::DumpConfig => match toml::to_string_pretty(&config) {
+            Ok(config) => print!("{config}"),
+            Err(err) => {
+                error!("Could not print our config: {err}");
+                exit(1);
+            }
+        },
+        Commands::DumpChainstate { file, data_dir } => {
+            let data_dir = get_data_dir(data_dir);
+            let mut wallet = load_wallet(data_dir.clone(), &config.resources);
+            wallet.set_header_store(open_header_store(&data_dir, chain_params.network()));
+            let dump = wallet.dump_chainstate();
+            let file = std::fs::File::create(file).expect("Could not create the dump file");
+            serde_json::to_writer(file, &dump).expect("Could not write the dump file");
+            info!("Dumped our chain state at height {}", dump.tip.height);
+        }
+        Commands::LoadChainstate { file, data_dir } => {
+            let data_dir = get_data_dir(data_dir);
+            let mut wallet = load_wallet(data_dir.clone(), &config.resources);
+            wallet.set_header_store(open_header_store(&data_dir, chain_params.network()));
+            let file = std::fs::File::open(file).expect("Could not open the dump file");
+            let dump = serde_json::from_reader::<_, ChainStateDump>(file)
+                .expect("Could not parse the dump file");
+            let height = dump.tip.height;
+            if let Err(err) = wallet.load_chainstate(dump) {
+                error!("Could not load the chain state: {err}");
+                exit(1);
+            }
+            info!("Loaded our chain state, we'll continue syncing from height {height}");
+        }
+        Commands::ExportWallet {
+            script_hashes,
+            file,
+            with_roots,
+            data_dir,
+        } => {
+            let wallet = load_wallet(get_data_dir(data_dir), &config.resources);
+            let export = match wallet.export_wallet(
+                &script_hashes,
+                chain_params.network(),
+                config.server.address_format,
+                with_roots,
+            ) {
+                Ok(export) => export,
+                Err(err) => {
+                    error!("Could not export our wallet: {err}");
+                    exit(1);
+                }
+            };
+            let file = std::fs::File::create(file).expect("Could not create the export file");
+            serde_json::to_writer(file, &export).expect("Could not write the export file");
+            info!(
+                "Exported {} addresses and {} transactions, up to height {}",
+                export.addresses.len(),
+                export.transactions.len(),
+                export.height
+            );
+        }
+        Commands::ImportWallet {
+            file,
+            data_dir,
+            rpc_user,
+            rpc_password,
+            rpc_host,
+        } => {
+            let rpc = create_rpc_connection(rpc_host, Some(rpc_user), Some(rpc_password));
+            if !test_rpc(&rpc) || !test_genesis(&rpc, &chain_params) {
+                error!("Unable to use our node, is it up and on the right network?");
+                exit(1);
+            }
+            let mut wallet = load_wallet(get_data_dir(data_dir), &config.resources);
+            let file = std::fs::File::open(file).expect("Could not open the export file");
+            let export = serde_json::from_reader::<_, WalletExport>(file)
+                .expect("Could not parse the export file");
+            let addresses = export.addresses.len();
+            let block_hash = |height: u32| -> Result<BlockHash, error::Error> {
+                Ok(BlockHash::from_hex(&rpc.getblockhash(height as usize)?)?)
+            };
+            match wallet.import_wallet(export, chain_params.network(), block_hash) {
+                Ok(transactions) => {
+                    info!("Imported {addresses} addresses and {transactions} transactions")
+                }
+                Err(err) => {
+                    error!("Could not import the wallet: {err}");
+                    exit(1);
+                }
+            }
+        }
+        Commands::VerifyExport {
+            file,
+            rpc_user,
+            rpc_password,
+            rpc_host,
+        } => {
+            let rpc = create_rpc_connection(rpc_host, Some(rpc_user), Some(rpc_password));
+            if !test_rpc(&rpc) || !test_genesis(&rpc, &chain_params) {
+                error!("Unable to use our node, is it up and on the right network?");
+                exit(1);
+            }
+            let file = std::fs::File::open(file).expect("Could not open the export file");
+            let export = serde_json::from_reader::<_, WalletExport>(file)
+                .expect("Could not parse the export file");
+            let header = |height: u32| -> Result<BlockHeader, error::Error> {
+                let hash = rpc.getblockhash(height as usize)?;
+                Ok(rpc.getblockheader(hash, false)?.get_simple())
+            };
+            match export.verify(&chain_params, header) {
+                Ok(blocks) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&blocks).expect("Blocks are always serializable")
+                ),
+                Err(err) => {
+                    error!("This export is not valid: {err}");
+                    exit(1);
+                }
+            }
+        }
+        Commands::RecomputeBalances { data_dir, dry_run } => {
+            let mut wallet = load_wallet(get_data_dir(data_dir), &config.resources);
+            let discrepancies = wallet.recompute_balances(dry_run);
+            for discrepancy in discrepancies.iter() {
+                info!(
+                    "{}: balance was {}, its history says {}. Had {} unspent outputs, its \
+                     history has {}, {} transactions were missing from our index",
+                    discrepancy.script_hash,
+                    discrepancy.stored,
+                    discrepancy.computed,
+                    discrepancy.stored_utxos,
+                    discrepancy.computed_utxos,
+                    discrepancy.unindexed.len()
+                );
+                if discrepancy.history < 0 {
+                    warn!(
+                        "{}: its history spends {} more than it received, transactions are \
+                         missing from it. Rescanning its blocks finds them",
+                        discrepancy.script_hash, -discrepancy.history
+                    );
+                }
+            }
+            info!("Found {} wrong addresses", discrepancies.len());
+        }
+        Commands::Summary { data_dir } => {
+            let wallet = load_wallet(get_data_dir(data_dir), &config.resources);
+            for address in
+                wallet.get_wallet_summary(chain_params.network(), config.server.address_format)
+            {
+                println!(
+                    "{}",
+                    serde_json::to_string(&address).expect("Summaries are always serializable")
+                );
+            }
         }
     }
 }
+/// Everything a scan found, in JSON
+fn get_scan_result<D: AddressCacheDatabase, S: ChainStore>(
+    wallet: &AddressCache<D, S>,
+    from: u32,
+    to: u32,
+    network: Network,
+    format: AddressFormat,
+) -> serde_json::Value {
+    let addresses = wallet.get_wallet_summary(network, format);
+    let balance = addresses.iter().map(|address| address.balance).sum::<u64>();
+    let addresses = addresses
+        .iter()
+        .filter(|address| address.transactions > 0)
+        .map(|address| {
+            let history = wallet
+                .get_address_history(&address.script_hash)
+                .iter()
+                .map(|tx| {
+                    json!({
+                        "tx_hash": tx.hash,
+                        "height": tx.height,
+                        "confirmations": wallet.get_confirmations(tx.height)
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "script_hash": address.script_hash,
+                "script": address.script,
+                "script_type": address.script_type,
+                "address": address.address,
+                "balance": address.balance,
+                "history": history,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "from": from,
+        "to": to,
+        "balance": balance,
+        "addresses": addresses,
+    })
+}
+/// Returns how many bytes are used by the files inside `path`
+fn get_dir_size(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => get_dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
 
-fn load_wallet(data_dir: String) -> AddressCache<KvDatabase, KvChainStore> {
-    let database = KvDatabase::new(data_dir.clone()).expect("Could not create a database");
+/// Returns where we should store our data. If the user doesn't tell us, we follow the
+/// platform's convention, e.g. `~/.local/share` on Linux and `%APPDATA%` on Windows.
+fn get_data_dir(data_dir: Option<String>) -> String {
+    let data_dir = data_dir.map(PathBuf::from).unwrap_or_else(|| {
+        ProjectDirs::from("", "", "utreexo-wallet")
+            .expect("Could not find a home directory, please provide a data directory")
+            .data_dir()
+            .to_path_buf()
+    });
+    std::fs::create_dir_all(&data_dir).expect("Could not create the data directory");
+    data_dir.to_string_lossy().to_string()
+}
+fn load_wallet(
+    data_dir: String,
+    resources: &ResourceLimits,
+) -> AddressCache<KvDatabase, KvChainStore> {
+    let mut database = KvDatabase::new(data_dir.clone(), resources.db_cache_size)
+        .expect("Could not create a database");
+    database.set_load_workers(resources.verification_workers);
     let chain_store = KvChainStore::new(data_dir).unwrap();
 
-    AddressCache::new(database, chain_store)
+    let mut cache = AddressCache::new(database, chain_store);
+    cache.set_max_hot_history(resources.max_hot_history);
+    if resources.disk_tx_index {
+        cache
+            .use_disk_tx_index(resources.tx_index_cache_size)
+            .expect("Could not move the transaction index to disk");
+    }
+    cache
+}
+/// Our accumulator after block `height`, from the snapshots in `data_dir`. Only its chain
+/// store is opened, and closed again before we return, so we don't load a whole wallet or
+/// keep its database locked. A server using `data_dir` locks it, so this fails while it runs.
+fn load_snapshot(data_dir: String, height: u32) -> Result<Option<Stump>, kv::Error> {
+    let chain_store = KvChainStore::new(data_dir)?;
+    AddressCache::<KvDatabase, KvChainStore>::load_acc_at(&chain_store, height)
+}
+/// The headers of the blocks we've processed, in `data_dir`
+fn open_header_store(data_dir: &str, network: Network) -> HeaderStore {
+    HeaderStore::open(
+        &Path::new(data_dir).join("headers"),
+        Box::new(BitcoinParams::new(network)),
+    )
+    .expect("Could not open our header store")
 }
 fn create_rpc_connection(
     hostname: String,
@@ -150,6 +947,68 @@ fn create_rpc_connection(
 
     Arc::new(BTCDClient::new(config).unwrap())
 }
+/// Connects to the nodes we ask for blocks our own node gave us bad proofs for
+fn create_fallback_connections(config: &SyncConfig) -> Vec<Arc<BTCDClient>> {
+    config
+        .fallback_nodes
+        .iter()
+        .map(|node| {
+            create_rpc_connection(
+                node.host.clone(),
+                Some(node.user.clone()),
+                Some(node.password.clone()),
+            )
+        })
+        .collect()
+}
+/// Where we get blocks and proofs from. That's our node, unless a local bridge, or a server
+/// sharing its bridge's proofs, is configured
+fn create_block_source(config: &SyncConfig, rpc: &Arc<BTCDClient>) -> Arc<dyn BlockSource + Send> {
+    let path = match (&config.ipc_socket, &config.proof_server) {
+        (Some(path), _) => path,
+        (None, Some(server)) => {
+            return Arc::new(PeerProofSource::new(rpc.clone(), server.clone()));
+        }
+        (None, None) => return rpc.clone(),
+    };
+    #[cfg(unix)]
+    return match blockchain::ipc::IpcBlockSource::connect(rpc.clone(), path.clone()) {
+        Ok(source) => Arc::new(source),
+        Err(err) => {
+            error!(
+                "Could not connect to the bridge at {}: {err}",
+                path.display()
+            );
+            exit(1);
+        }
+    };
+    #[cfg(not(unix))]
+    {
+        warn!(
+            "Ignoring ipc_socket {}, unix sockets aren't supported here",
+            path.display()
+        );
+        rpc.clone()
+    }
+}
+/// Where we get new tip blocks from: the fastest of our nodes if asked for, otherwise the
+/// same place as every other block
+fn create_tip_source(
+    config: &SyncConfig,
+    block_source: &Arc<dyn BlockSource + Send>,
+    fallbacks: &[Arc<BTCDClient>],
+) -> Arc<dyn BlockSource + Send> {
+    if !config.prefer_fastest_node || fallbacks.is_empty() {
+        return block_source.clone();
+    }
+    let mut sources = vec![block_source.clone()];
+    sources.extend(
+        fallbacks
+            .iter()
+            .map(|fallback| fallback.clone() as Arc<dyn BlockSource + Send>),
+    );
+    Arc::new(LatencyRankedSource::new(sources))
+}
 fn get_net(net: &cli::Network) -> Network {
     match net {
         cli::Network::Bitcoin => Network::Bitcoin,
@@ -160,39 +1019,262 @@ fn get_net(net: &cli::Network) -> Network {
 }
 fn setup_wallet<D: AddressCacheDatabase, S: ChainStore>(
     descriptor: String,
-    mut wallet: AddressCache<D, S>,
-    network: cli::Network,
+    receive: Range<u32>,
+    change: Option<Range<u32>>,
+    wallet: &mut AddressCache<D, S>,
+    chain_params: &dyn ChainParams,
 ) {
-    if let Err(e) = wallet.setup(descriptor.clone()) {
+    let desc = match parse_descriptor(&descriptor) {
+        Ok(desc) => desc,
+        Err(e) => {
+            error!("{e}");
+            exit(1);
+        }
+    };
+    // We store the canonical form, with its checksum, so what we save is exactly what we
+    // derive our addresses from
+    if let Err(e) = wallet.setup(desc.to_string()) {
         error!("Could not setup wallet: {e}");
         exit(1);
     }
+    let mut branches = vec![(Branch::Receive, receive)];
+    branches.extend(change.map(|change| (Branch::Change, change)));
+    for (branch, range) in branches {
+        let result = branch_descriptor(&desc, branch)
+            .and_then(|desc| derive_addresses(&desc, range, wallet, chain_params));
+        if let Err(e) = result {
+            error!("Could not add {branch:?} addresses: {e}");
+            exit(1);
+        }
+    }
+}
+/// Returns the descriptor for one branch of our wallet. Our descriptor is the receiving one,
+/// change addresses come from the same keys, ending in `/1/*` instead of `/0/*`.
+fn branch_descriptor(
+    desc: &Descriptor<DescriptorPublicKey>,
+    branch: Branch,
+) -> Result<Descriptor<DescriptorPublicKey>, error::Error> {
+    match branch {
+        Branch::Receive => Ok(desc.clone()),
+        Branch::Change => {
+            let mut change = ChangeBranch(false);
+            let change_desc = desc.translate_pk(&mut change)?;
+            if !change.0 {
+                return Err(error::Error::ConfigError(format!(
+                    "can't tell the change branch of {desc}, it has no key deriving from /0/*"
+                )));
+            }
+            Ok(change_desc)
+        }
+    }
+}
+/// Moves every key deriving from `/0/*` to `/1/*`. Keys that derive nothing, like single
+/// keys, are the same in both branches. Whether any key moved is kept, as a descriptor
+/// without one has no change branch
+struct ChangeBranch(bool);
 
-    let desc =
-        Descriptor::<DescriptorPublicKey>::from_str(format!("wpkh({}/0/*)", descriptor).as_str())
-            .expect("Error while parsing descriptor");
-    for index in 0..100 {
+impl Translator<DescriptorPublicKey, DescriptorPublicKey, error::Error> for ChangeBranch {
+    fn pk(&mut self, pk: &DescriptorPublicKey) -> Result<DescriptorPublicKey, error::Error> {
+        let mut xkey = match pk {
+            DescriptorPublicKey::XPub(xkey) if xkey.wildcard != Wildcard::None => xkey.clone(),
+            _ => return Ok(pk.clone()),
+        };
+        let mut path = xkey.derivation_path.as_ref().to_vec();
+        match path.last_mut() {
+            Some(last) if *last == ChildNumber::Normal { index: 0 } => {
+                *last = ChildNumber::Normal { index: 1 }
+            }
+            _ => {
+                return Err(error::Error::ConfigError(format!(
+                    "can't tell the change branch of {pk}, it doesn't derive from /0/*"
+                )))
+            }
+        }
+        xkey.derivation_path = DerivationPath::from(path);
+        self.0 = true;
+        Ok(DescriptorPublicKey::XPub(xkey))
+    }
+    translate_hash_clone!(DescriptorPublicKey, DescriptorPublicKey, error::Error);
+}
+/// Starts watching the addresses `desc` has in `range`
+fn derive_addresses<D: AddressCacheDatabase, S: ChainStore>(
+    desc: &Descriptor<DescriptorPublicKey>,
+    range: Range<u32>,
+    wallet: &mut AddressCache<D, S>,
+    chain_params: &dyn ChainParams,
+) -> Result<(), error::Error> {
+    for index in range {
         let address = desc
             .at_derivation_index(index)
-            .address(get_net(&network))
+            .address(chain_params.network())
             .expect("Error while deriving address. Is this an active descriptor?");
-        wallet.cache_address(address.script_pubkey());
+        wallet.cache_address(address.script_pubkey())?;
     }
-    info!("Wallet setup completed! You can now execute run");
+    Ok(())
 }
-fn start_sync<D: AddressCacheDatabase, Rpc: BtcdRpc, S: ChainStore>(
+/// Sets up our periodic jobs: looking for new blocks, and the maintenance in `config`
+fn create_scheduler(
+    config: &MaintenanceConfig,
+    audit: bool,
+    data_dir: &str,
+    rpc: Arc<BTCDClient>,
+    notify_tx: Sender<Message>,
+) -> Scheduler {
+    let scheduler = Scheduler::new(config.jitter);
+    let mut current_block = ChainWatch::get_block(&rpc);
+    let notify = notify_tx.clone();
+    scheduler.every("tip polling", Duration::from_secs(5), move || {
+        let new_block = ChainWatch::get_block(&rpc);
+        if new_block > current_block {
+            let _ = notify.send(Message::NewBlock);
+            current_block = new_block;
+        }
+    });
+    let snapshot = Path::new(data_dir).join("chainstate.json");
+    let tasks = [
+        ("compaction", config.compact_interval_secs, Task::Compact),
+        (
+            "snapshot",
+            config.snapshot_interval_secs,
+            Task::Snapshot(snapshot),
+        ),
+        (
+            "rebroadcast",
+            config.rebroadcast_interval_secs,
+            Task::Rebroadcast,
+        ),
+        (
+            "peer check",
+            config.peer_check_interval_secs,
+            Task::CheckPeers,
+        ),
+        (
+            "mempool expiry",
+            config.mempool_expiry_interval_secs,
+            Task::ExpireMempool,
+        ),
+        (
+            "audit",
+            if audit { config.audit_interval_secs } else { 0 },
+            Task::Audit,
+        ),
+    ];
+    for (name, interval, task) in tasks {
+        let notify = notify_tx.clone();
+        scheduler.every(name, Duration::from_secs(interval), move || {
+            let _ = notify.send(Message::Maintenance(task.clone()));
+        });
+    }
+    scheduler
+}
+/// Works out which addresses belong to each wallet sharing this server
+fn create_wallet_scopes(wallets: &[WalletConfig]) -> Vec<WalletScope> {
+    wallets
+        .iter()
+        .map(|wallet| {
+            let mut script_hashes = HashSet::new();
+            for descriptor in wallet.descriptors.iter() {
+                let desc = match parse_descriptor(descriptor) {
+                    Ok(desc) => desc,
+                    Err(err) => {
+                        error!("Wallet {} has an invalid descriptor: {err}", wallet.name);
+                        exit(1);
+                    }
+                };
+                for branch in [Branch::Receive, Branch::Change] {
+                    let desc = match branch_descriptor(&desc, branch) {
+                        Ok(desc) => desc,
+                        Err(err) => {
+                            warn!("Wallet {} has no {branch:?} addresses: {err}", wallet.name);
+                            continue;
+                        }
+                    };
+                    for index in 0..wallet.addresses {
+                        let script = desc.at_derivation_index(index).script_pubkey();
+                        script_hashes.insert(get_spk_hash(&script));
+                    }
+                }
+            }
+            WalletScope::new(wallet.name.clone(), wallet.token.clone(), script_hashes)
+        })
+        .collect()
+}
+/// Parses a wallet descriptor. We take either a full descriptor, whose checksum is verified if
+/// present, or just an extended public key, which is used as `wpkh(xpub/0/*)`.
+fn parse_descriptor(descriptor: &str) -> Result<Descriptor<DescriptorPublicKey>, error::Error> {
+    let descriptor = descriptor.trim();
+    if descriptor.contains('(') {
+        return Ok(Descriptor::from_str(descriptor)?);
+    }
+    Ok(Descriptor::from_str(&format!("wpkh({descriptor}/0/*)"))?)
+}
+fn start_sync<D: AddressCacheDatabase, Rpc: BtcdRpc + Sync, S: ChainStore>(
     rpc: &Arc<Rpc>,
+    source: &dyn BlockSource,
+    fallbacks: &[Arc<Rpc>],
     mut address_cache: AddressCache<D, S>,
+    resources: &ResourceLimits,
+    chain_params: &dyn ChainParams,
 ) -> Result<AddressCache<D, S>, error::Error> {
-    let current_hight = rpc.getbestblock()?.height as u32;
-    let sync_range = address_cache.get_sync_limits(current_hight);
-    if let Err(crate::error::Error::WalletNotInitialized) = sync_range {
+    check_wallet(&address_cache);
+    BlockchainSync::sync_with_retry(
+        &**rpc,
+        source,
+        fallbacks,
+        &mut address_cache,
+        true,
+        resources,
+        chain_params,
+    )?;
+    Ok(address_cache)
+}
+/// Builds every alert transport we've configured
+fn create_alert_transports(alerts: &AlertConfig) -> Vec<Box<dyn AlertTransport>> {
+    let mut transports = alerts
+        .webhooks
+        .iter()
+        .map(|url| Box::new(Webhook(url.clone())) as Box<dyn AlertTransport>)
+        .collect::<Vec<_>>();
+    if let Some(telegram) = &alerts.telegram {
+        transports.push(Box::new(Telegram::new(
+            telegram.bot_token.clone(),
+            telegram.chat_id.clone(),
+            telegram.template.clone(),
+        )));
+    }
+    if let Some(email) = &alerts.email {
+        let transport = Email::new(
+            &email.smtp_server,
+            email.smtp_port,
+            email.credentials(),
+            &email.from,
+            &email.to,
+            email.subject.clone(),
+            email.template.clone(),
+        );
+        match transport {
+            Ok(transport) => transports.push(Box::new(transport)),
+            Err(err) => {
+                error!("Invalid email alerts: {err}");
+                exit(1);
+            }
+        }
+    }
+    transports
+}
+/// Exits if this wallet wasn't set up yet
+fn check_wallet<D: AddressCacheDatabase, S: ChainStore>(address_cache: &AddressCache<D, S>) {
+    if let Err(crate::error::Error::WalletNotInitialized) = address_cache.get_cache_height() {
         error!("Wallet not set up!");
         exit(1);
     }
-
-    BlockchainSync::sync_range(&**rpc, &mut address_cache, sync_range?, true)?;
-    Ok(address_cache)
+}
+/// Checks whether our node and us agree on which chain we are following
+fn test_genesis(rpc: &BTCDClient, chain_params: &dyn ChainParams) -> bool {
+    match rpc.getblockhash(0) {
+        Ok(hash) => hash == chain_params.genesis_hash().to_string(),
+        Err(_) => false,
+    }
 }
 /// Finds out whether our RPC works or not
 fn test_rpc(rpc: &BTCDClient) -> bool {
@@ -201,3 +1283,85 @@ fn test_rpc(rpc: &BTCDClient) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod test {
+    use super::{branch_descriptor, load_snapshot, parse_descriptor};
+    use crate::blockchain::chainstore::{ChainStore, KvChainStore};
+    use crate::cli::Branch;
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn test_parse_descriptor() {
+        // A bare xpub is a wpkh receive branch, saved with its checksum
+        let canonical = parse_descriptor(&format!(" {XPUB}\n")).unwrap().to_string();
+        let (descriptor, checksum) = canonical.split_once('#').unwrap();
+        assert_eq!(descriptor, format!("wpkh({XPUB}/0/*)"));
+        assert_eq!(checksum.len(), 8);
+        assert_eq!(parse_descriptor(&canonical).unwrap().to_string(), canonical);
+        // Checksums are checked when given
+        let wrong = if checksum == "qqqqqqqq" {
+            "pppppppp"
+        } else {
+            "qqqqqqqq"
+        };
+        assert!(parse_descriptor(&format!("{descriptor}#{wrong}")).is_err());
+        assert!(parse_descriptor("wpkh(not a key)").is_err());
+    }
+    #[test]
+    fn test_branch_descriptor() {
+        let change = |descriptor: &str| {
+            branch_descriptor(&parse_descriptor(descriptor).unwrap(), Branch::Change)
+                .map(|change| change.to_string())
+        };
+        let receive = parse_descriptor(XPUB).unwrap();
+        assert_eq!(
+            branch_descriptor(&receive, Branch::Receive).unwrap(),
+            receive
+        );
+        assert_eq!(
+            change(XPUB).unwrap(),
+            parse_descriptor(&format!("wpkh({XPUB}/1/*)"))
+                .unwrap()
+                .to_string()
+        );
+        // Only the last step moves, even if an earlier one is /0 too
+        assert_eq!(
+            change(&format!("wpkh([d34db33f/84'/0'/0']{XPUB}/0/0/*)")).unwrap(),
+            parse_descriptor(&format!("wpkh([d34db33f/84'/0'/0']{XPUB}/0/1/*)"))
+                .unwrap()
+                .to_string()
+        );
+        // Single keys are the same in both branches
+        let key = "02e6642fd69bd211f93f7f1f36ca51a26a5290eb2dd1b0d8279a87bb0d480c8443";
+        assert_eq!(
+            change(&format!("wsh(multi(1,{XPUB}/0/*,{key}))")).unwrap(),
+            parse_descriptor(&format!("wsh(multi(1,{XPUB}/1/*,{key}))"))
+                .unwrap()
+                .to_string()
+        );
+        // Nothing derives from /0/*, so there's no change branch to tell
+        assert!(change(&format!("wpkh({XPUB}/7/*)")).is_err());
+        assert!(change(&format!("wpkh({key})")).is_err());
+    }
+    #[test]
+    fn test_load_snapshot() {
+        let dir = "/tmp/utreexo_load_snapshot/";
+        let _ = std::fs::remove_dir_all(dir);
+        let chain_store = KvChainStore::new(dir.to_owned()).unwrap();
+        chain_store
+            .save_roots_at(5, format!("3 {}", "11".repeat(32)))
+            .unwrap();
+        // A server still using our data dir keeps it locked
+        assert!(load_snapshot(dir.to_owned(), 5).is_err());
+        drop(chain_store);
+
+        let acc = load_snapshot(dir.to_owned(), 5).unwrap().unwrap();
+        assert_eq!(acc.leafs, 3);
+        assert_eq!(acc.roots.len(), 1);
+        assert!(load_snapshot(dir.to_owned(), 4).unwrap().is_none());
+        // We closed it again, so a server can start on it
+        assert!(KvChainStore::new(dir.to_owned()).is_ok());
+    }
+}
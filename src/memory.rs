@@ -0,0 +1,40 @@
+//! Watches how much memory we actually use, against [crate::config::ResourceLimits::max_rss].
+//! Our limits only bound the caches and queues we know of, so this is how to tell whether
+//! they are small enough for a given machine, like a Raspberry Pi running `--profile
+//! low-memory`.
+
+use std::time::Duration;
+
+use log::{info, warn};
+use sysinfo::{get_current_pid, ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+/// How often we look at our memory again
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Checks our resident memory in the background, warning when it goes past `budget` bytes
+pub fn monitor(budget: u64) {
+    let pid = match get_current_pid() {
+        Ok(pid) => pid,
+        Err(err) => {
+            warn!("Could not find our own process, memory use won't be checked: {err}");
+            return;
+        }
+    };
+    let mut system = System::new();
+    let mut over = false;
+    std::thread::spawn(move || loop {
+        system.refresh_process_specifics(pid, ProcessRefreshKind::new());
+        if let Some(rss) = system.process(pid).map(|process| process.memory()) {
+            if rss > budget && !over {
+                warn!(
+                    "Using {rss} bytes of memory, past our budget of {budget}. Lower our \
+                     resource limits, or raise resources.max_rss"
+                );
+            } else if rss <= budget && over {
+                info!("Using {rss} bytes of memory, within our budget of {budget} again");
+            }
+            over = rss > budget;
+        }
+        std::thread::sleep(MEMORY_CHECK_INTERVAL);
+    });
+}
@@ -0,0 +1,276 @@
+//! Asks our router to forward our Electrum TLS port to us, so wallets outside our network,
+//! like our own phone, can reach a server at home without setting up the router by hand. We
+//! try UPnP first, then NAT-PMP. Mappings are leased, so they're renewed by a service of
+//! their own, and removed when we shut down. The address clients can reach us at is reported
+//! in `server.features`. Only the TLS port is forwarded, so wallet tokens never cross the
+//! internet in the clear, and clients coming through it never get operator methods, see
+//! `refused_to_public`.
+
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_std::task;
+use igd::{Gateway, PortMappingProtocol, SearchOptions};
+use log::{info, warn};
+
+/// How long our router keeps a mapping. We renew it at half that
+pub const MAPPING_LEASE: Duration = Duration::from_secs(60 * 60);
+/// How long we look for a UPnP router
+const UPNP_TIMEOUT: Duration = Duration::from_secs(3);
+/// The port NAT-PMP routers listen on
+const NAT_PMP_PORT: u16 = 5351;
+/// How many times we send a NAT-PMP request, waiting twice as long each time
+const NAT_PMP_TRIES: u32 = 5;
+const MAPPING_DESCRIPTION: &str = "utreexo electrum server";
+
+/// How a mapping was made, which is also how it's removed
+#[derive(Debug, Clone)]
+enum Method {
+    Upnp(Gateway),
+    NatPmp(Ipv4Addr),
+}
+
+impl Method {
+    fn name(&self) -> &'static str {
+        match self {
+            Method::Upnp(_) => "UPnP",
+            Method::NatPmp(_) => "NAT-PMP",
+        }
+    }
+}
+
+/// Our port mapping, shared with whoever needs to know our public address
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    port: u16,
+    /// The router to ask with NAT-PMP, if we know better than our routing table
+    gateway: Option<Ipv4Addr>,
+    state: Arc<RwLock<Option<(Method, SocketAddrV4)>>>,
+}
+
+impl PortMapping {
+    pub fn new(port: u16, gateway: Option<Ipv4Addr>) -> PortMapping {
+        PortMapping {
+            port,
+            gateway,
+            state: Arc::default(),
+        }
+    }
+    /// The address our router forwards to us, if it does
+    pub fn external_address(&self) -> Option<SocketAddrV4> {
+        self.state
+            .read()
+            .expect("Poisoned lock")
+            .as_ref()
+            .map(|(_, address)| *address)
+    }
+    /// Maps our port, or renews our mapping
+    pub fn refresh(&self) {
+        let mapped = map_upnp(self.port)
+            .map(|(gateway, address)| (Method::Upnp(gateway), address))
+            .or_else(|upnp_err| {
+                let gateway = self
+                    .gateway
+                    .or_else(default_gateway)
+                    .ok_or_else(|| format!("UPnP: {upnp_err}, NAT-PMP: no gateway known"))?;
+                map_nat_pmp(gateway, self.port, MAPPING_LEASE.as_secs() as u32)
+                    .map(|address| (Method::NatPmp(gateway), address))
+                    .map_err(|nat_pmp_err| format!("UPnP: {upnp_err}, NAT-PMP: {nat_pmp_err}"))
+            });
+        let mut state = self.state.write().expect("Poisoned lock");
+        match mapped {
+            Ok((method, address)) => {
+                if state.as_ref().map(|(_, old)| *old) != Some(address) {
+                    info!(
+                        "Our router forwards {address} to our Electrum TLS port, via {}",
+                        method.name()
+                    );
+                }
+                *state = Some((method, address));
+            }
+            Err(err) => {
+                warn!("Could not map our Electrum TLS port on our router. {err}");
+                *state = None;
+            }
+        }
+    }
+    /// Keeps our port mapped until we're stopped. Talking to our router can take seconds, so
+    /// it's done off our async threads
+    pub async fn keep_mapped(self) -> Result<(), String> {
+        loop {
+            let mapping = self.clone();
+            task::spawn_blocking(move || mapping.refresh()).await;
+            task::sleep(MAPPING_LEASE / 2).await;
+        }
+    }
+    /// Asks our router to stop forwarding our port
+    pub fn remove(&self) {
+        let state = self.state.write().expect("Poisoned lock").take();
+        let result = match state {
+            Some((Method::Upnp(gateway), address)) => gateway
+                .remove_port(PortMappingProtocol::TCP, address.port())
+                .map_err(|err| err.to_string()),
+            // A lifetime of 0 removes the mapping
+            Some((Method::NatPmp(gateway), _)) => map_nat_pmp(gateway, self.port, 0).map(|_| ()),
+            None => return,
+        };
+        if let Err(err) = result {
+            warn!("Could not remove our port mapping: {err}");
+        }
+    }
+}
+
+/// Our address on the network `gateway` is in
+fn local_address(gateway: Ipv4Addr) -> Result<Ipv4Addr, String> {
+    // Nothing is sent, this only makes our OS pick the interface it would route through
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|err| err.to_string())?;
+    socket
+        .connect((gateway, NAT_PMP_PORT))
+        .map_err(|err| err.to_string())?;
+    match socket.local_addr().map_err(|err| err.to_string())?.ip() {
+        std::net::IpAddr::V4(address) => Ok(address),
+        std::net::IpAddr::V6(address) => Err(format!("{address} is not an IPv4 address")),
+    }
+}
+
+/// Maps `port` with UPnP, returning the router and our external address
+fn map_upnp(port: u16) -> Result<(Gateway, SocketAddrV4), String> {
+    let options = SearchOptions {
+        timeout: Some(UPNP_TIMEOUT),
+        ..Default::default()
+    };
+    let gateway = igd::search_gateway(options).map_err(|err| err.to_string())?;
+    let local = SocketAddrV4::new(local_address(*gateway.addr.ip())?, port);
+    gateway
+        .add_port(
+            PortMappingProtocol::TCP,
+            port,
+            local,
+            MAPPING_LEASE.as_secs() as u32,
+            MAPPING_DESCRIPTION,
+        )
+        .map_err(|err| err.to_string())?;
+    let ip = gateway.get_external_ip().map_err(|err| err.to_string())?;
+    Ok((gateway, SocketAddrV4::new(ip, port)))
+}
+
+/// Sends a NAT-PMP request until `gateway` answers it, and checks the answer is a success
+fn nat_pmp_request(gateway: Ipv4Addr, request: &[u8], response: &mut [u8]) -> Result<(), String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|err| err.to_string())?;
+    socket
+        .connect((gateway, NAT_PMP_PORT))
+        .map_err(|err| err.to_string())?;
+    let mut timeout = Duration::from_millis(250);
+    for _ in 0..NAT_PMP_TRIES {
+        socket
+            .set_read_timeout(Some(timeout))
+            .map_err(|err| err.to_string())?;
+        socket.send(request).map_err(|err| err.to_string())?;
+        match socket.recv(response) {
+            // Answers have the request's opcode plus 128
+            Ok(read) if read == response.len() && response[1] == request[1] + 128 => {
+                return match u16::from_be_bytes([response[2], response[3]]) {
+                    0 => Ok(()),
+                    code => Err(format!("{gateway} refused, with result code {code}")),
+                }
+            }
+            Ok(_) => continue,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                timeout *= 2
+            }
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Err(format!("{gateway} didn't answer"))
+}
+
+/// Maps `port` with NAT-PMP for `lifetime` seconds, returning our external address
+fn map_nat_pmp(gateway: Ipv4Addr, port: u16, lifetime: u32) -> Result<SocketAddrV4, String> {
+    let mut request = vec![0, 2, 0, 0];
+    request.extend(port.to_be_bytes());
+    request.extend(port.to_be_bytes());
+    request.extend(lifetime.to_be_bytes());
+    let mut response = [0; 16];
+    nat_pmp_request(gateway, &request, &mut response)?;
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+
+    let mut response = [0; 12];
+    nat_pmp_request(gateway, &[0, 0], &mut response)?;
+    let ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+    Ok(SocketAddrV4::new(ip, external_port))
+}
+
+/// Finds our default gateway in a Linux routing table, as in `/proc/net/route`
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|route| {
+        let fields = route.split_whitespace().collect::<Vec<_>>();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // Addresses are written in our own byte order, which we take as little endian
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+fn default_gateway() -> Option<Ipv4Addr> {
+    parse_default_gateway(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+
+    use super::{map_nat_pmp, parse_default_gateway, NAT_PMP_PORT};
+
+    #[test]
+    fn test_nat_pmp_mapping() {
+        // A router on this machine, forwarding its port 60002 to whatever we ask for
+        let router = UdpSocket::bind((Ipv4Addr::LOCALHOST, NAT_PMP_PORT)).unwrap();
+        let requests = std::thread::spawn(move || {
+            let mut requests = vec![];
+            let mut buffer = [0; 16];
+            for _ in 0..2 {
+                let (read, client) = router.recv_from(&mut buffer).unwrap();
+                let request = buffer[..read].to_vec();
+                let mut response = vec![0, request[1] + 128, 0, 0, 0, 0, 0, 1];
+                match request[1] {
+                    2 => {
+                        response.extend(&request[4..6]);
+                        response.extend(60002u16.to_be_bytes());
+                        response.extend(&request[8..12]);
+                    }
+                    _ => response.extend([203, 0, 113, 7]),
+                }
+                router.send_to(&response, client).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        let address = map_nat_pmp(Ipv4Addr::LOCALHOST, 50002, 3600).unwrap();
+        assert_eq!(
+            address,
+            SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 60002)
+        );
+        // We asked for our port, for as long as we wanted it
+        let requests = requests.join().unwrap();
+        assert_eq!(requests[0][4..6], 50002u16.to_be_bytes());
+        assert_eq!(requests[0][8..12], 3600u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                      eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            parse_default_gateway(routes),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+}
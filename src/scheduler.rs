@@ -0,0 +1,134 @@
+//! Runs our periodic jobs, like polling our node for new blocks or compacting the database,
+//! from one place. Each job runs again after its interval, moved by a random jitter so jobs
+//! started together don't keep running at the same time. Jobs needing our wallet send a
+//! [Task] to our main loop, which owns it.
+
+use std::{
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use bitcoin::{
+    secp256k1::rand::{thread_rng, Rng},
+    Txid,
+};
+use log::debug;
+
+/// Maintenance our main loop runs when the scheduler tells it to
+#[derive(Debug, Clone)]
+pub enum Task {
+    /// Drops transaction bodies no address refers to anymore
+    Compact,
+    /// Drops the transaction bodies a compaction found, once it's done looking. None if it
+    /// failed
+    DropOrphaned(Option<Vec<Txid>>),
+    /// Writes a dump of our chain state to this file, like `dump-chainstate` does
+    Snapshot(PathBuf),
+    /// Sends the transactions we've broadcast, but didn't confirm yet, to our node again
+    Rebroadcast,
+    /// Checks that our fallback nodes are reachable, and following the same chain
+    CheckPeers,
+    /// Stops rebroadcasting transactions that didn't confirm for too long
+    ExpireMempool,
+    /// Cross-checks our wallet and accumulator with a Bitcoin Core node
+    Audit,
+    /// Looks for our addresses' transactions in these blocks again, one block each time
+    Rescan(RangeInclusive<u32>),
+}
+
+struct Job {
+    name: &'static str,
+    interval: Duration,
+    run: Box<dyn FnMut() + Send>,
+}
+
+/// Our periodic jobs. Clones share them, so the scheduler can be restarted by our
+/// supervisor without losing any
+#[derive(Clone)]
+pub struct Scheduler {
+    jobs: Arc<Mutex<Vec<Job>>>,
+    /// Up to which fraction of its interval each run is moved by
+    jitter: f64,
+}
+
+impl Scheduler {
+    pub fn new(jitter: f64) -> Scheduler {
+        Scheduler {
+            jobs: Arc::default(),
+            jitter: jitter.clamp(0.0, 1.0),
+        }
+    }
+    /// Runs `run` every `interval`. A zero interval means the job is disabled
+    pub fn every(
+        &self,
+        name: &'static str,
+        interval: Duration,
+        run: impl FnMut() + Send + 'static,
+    ) {
+        if interval.is_zero() {
+            debug!("Not scheduling {name}, it's disabled");
+            return;
+        }
+        self.jobs.lock().expect("Poisoned lock").push(Job {
+            name,
+            interval,
+            run: Box::new(run),
+        });
+    }
+    /// When a job should run next, if it last ran now
+    fn next_run(&self, interval: Duration) -> Instant {
+        let jitter = thread_rng().gen_range(-self.jitter..=self.jitter);
+        Instant::now() + interval.mul_f64(1.0 + jitter)
+    }
+    /// Runs our jobs, for as long as we're up
+    pub async fn run(self) -> Result<(), String> {
+        let mut next_runs = self
+            .jobs
+            .lock()
+            .expect("Poisoned lock")
+            .iter()
+            .map(|job| self.next_run(job.interval))
+            .collect::<Vec<_>>();
+        loop {
+            let (index, at) = match next_runs.iter().enumerate().min_by_key(|(_, at)| **at) {
+                Some((index, at)) => (index, *at),
+                None => return Ok(()),
+            };
+            task::sleep(at.saturating_duration_since(Instant::now())).await;
+            // Jobs may block, like polling our node does, so they don't run on our async
+            // threads. Our lock is only held in there, never across an await
+            let jobs = self.jobs.clone();
+            let interval = task::spawn_blocking(move || {
+                let mut jobs = jobs.lock().expect("Poisoned lock");
+                let job = &mut jobs[index];
+                debug!("Running {}", job.name);
+                (job.run)();
+                job.interval
+            })
+            .await;
+            next_runs[index] = self.next_run(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Scheduler;
+
+    #[test]
+    fn test_jitter() {
+        let scheduler = Scheduler::new(0.1);
+        let interval = Duration::from_secs(100);
+        for _ in 0..100 {
+            let wait = scheduler
+                .next_run(interval)
+                .saturating_duration_since(std::time::Instant::now());
+            assert!(wait <= Duration::from_secs(110) && wait >= Duration::from_secs(89));
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Quick checks an operator can run before pointing a wallet at us, to make sure this build
+//! works on their platform. Every check uses vectors built into the binary, so we don't need
+//! a node, network access or an existing wallet. The database check only touches a scratch
+//! directory, which is removed afterwards.
+
+use bitcoin::{
+    consensus::deserialize,
+    hashes::{hex::FromHex, sha256},
+    BlockHash, Network, Transaction, Txid,
+};
+use rustreexo::accumulator::{proof::Proof, stump::Stump};
+
+use crate::{
+    address_cache::{
+        kv_database::KvDatabase, script_type::AddressFormat, stored::StoredBody, AddressCache,
+        HistoryEntry, TransactionBody,
+    },
+    blockchain::{chainstore::KvChainStore, sync::BlockchainSync},
+    electrum::electrum_protocol::{get_spk_hash, get_status},
+};
+
+/// A testnet transaction, confirmed in block [LEAF_BLOCK_HASH] at height [LEAF_HEIGHT]
+const LEAF_TX: &str = "02000000000101d997ca3adb105089361299c6bcb79b678b79bd949f94c70d396c8813877f7ccf0000000000fdffffff01da160f0000000000160014275f567685bfe080e4789eaca36d9af30327abac0247304402201528001078868c9195ff9358a6d0b529f263e280ae86fc55617283b42ca66f8f02203deb150aeb930a1e21b792ebe50bfab60c51145447ac24a56363791b1517b730012102c97b9dd85d82fbb127bef4d6640ea6cdba460c5d092e7bfc3a7ea3777bc7523a32cc0100";
+const LEAF_BLOCK_HASH: &str = "0000001b958a51888359f5c064593255f02a217d5ada160611f87313a27e3ec4";
+const LEAF_HEIGHT: u32 = 117811;
+/// The utreexo leaf hash of [LEAF_TX]'s only output, as utreexod computes it
+const LEAF_HASH: &str = "507d175748a1fccc83f3f79ec57414fd6601bc32b0d1ab77882a86af2cc2addb";
+/// Four leaves, `sha256([i])` for `i` in `0..4`
+const LEAVES: [&str; 4] = [
+    "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+    "4bf5122f344554c53bde2ebb8cd2b7e3d1600ad631c385a5d7cce23c7785459a",
+    "dbc1b4c900ffe48d575b5da5c638040125f65db0fe3e24494b76ea986457d986",
+    "084fed08b978af4d7d196a7446a86b58009e636b611db16211b65a9aadff29c5",
+];
+/// The parent of our last two leaves
+const PARENT_2_3: &str = "9576f4ade6e9bc3a6458b506ce3e4e890df29cb14cb5d3d887672aef55647a2b";
+/// The only root of an accumulator with our four leaves
+const ROOT: &str = "df46b17be5f66f0750a4b3efa26d4679db170a72d41eb56c3e4ff75a58c65386";
+/// The genesis coinbase, which we pretend was confirmed at height 1
+const STATUS_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+/// `sha256("<STATUS_TXID>:1:")`, the status Electrum clients expect for that history
+const STATUS_HASH: &str = "6d0bc35788e773088be6b7de8ecaec311bcec7d6d460c404d4f4972b056bf8a4";
+/// How much memory the database in our scratch directory may use for its cache
+const SCRATCH_DB_CACHE: u64 = 16 * 1024 * 1024;
+
+type Check = fn() -> Result<(), String>;
+
+const CHECKS: [(&str, Check); 5] = [
+    ("utreexo leaf hash", check_leaf_hash),
+    ("utreexo proof", check_proof),
+    ("electrum status hash", check_status_hash),
+    ("serialization round trip", check_round_trip),
+    ("database", check_database),
+];
+
+/// Runs every check, printing how each one went. Returns whether they all passed
+pub fn run() -> bool {
+    let mut passed = true;
+    for (name, check) in CHECKS {
+        match check() {
+            Ok(()) => println!("ok      {name}"),
+            Err(err) => {
+                println!("FAILED  {name}: {err}");
+                passed = false;
+            }
+        }
+    }
+    passed
+}
+fn hash(hex: &str) -> sha256::Hash {
+    sha256::Hash::from_hex(hex).expect("Our vectors are valid hashes")
+}
+fn leaf_transaction() -> Transaction {
+    let tx = Vec::from_hex(LEAF_TX).expect("Our vectors are valid hex");
+    deserialize(&tx).expect("Our vectors are valid transactions")
+}
+fn check_leaf_hash() -> Result<(), String> {
+    let block_hash = BlockHash::from_hex(LEAF_BLOCK_HASH).expect("Our vectors are valid hashes");
+    let leaf_hash =
+        BlockchainSync::get_leaf_hashes(&leaf_transaction(), 0, LEAF_HEIGHT, block_hash);
+    if leaf_hash != hash(LEAF_HASH) {
+        return Err(format!("expected {LEAF_HASH}, got {leaf_hash}"));
+    }
+    Ok(())
+}
+fn check_proof() -> Result<(), String> {
+    let leaves = LEAVES.iter().map(|leaf| hash(leaf)).collect::<Vec<_>>();
+    let acc = Stump::new()
+        .modify(&leaves, &[], &Proof::new(vec![], vec![]))?
+        .0;
+    if acc.roots != [hash(ROOT)] {
+        return Err(format!("expected the root {ROOT}, got {:?}", acc.roots));
+    }
+    let proof = Proof::new(vec![0], vec![leaves[1], hash(PARENT_2_3)]);
+    if !proof.verify(&leaves[0..1], &acc)? {
+        return Err("a valid proof was rejected".into());
+    }
+    let forged = Proof::new(vec![0], vec![hash(PARENT_2_3), leaves[1]]);
+    if forged.verify(&leaves[0..1], &acc).unwrap_or(false) {
+        return Err("a forged proof was accepted".into());
+    }
+    Ok(())
+}
+fn check_status_hash() -> Result<(), String> {
+    let history = [HistoryEntry::Confirmed {
+        hash: Txid::from_hex(STATUS_TXID).expect("Our vectors are valid hashes"),
+        height: 1,
+        position: 0,
+    }];
+    let status = get_status(&history);
+    if status != hash(STATUS_HASH) {
+        return Err(format!("expected {STATUS_HASH}, got {status}"));
+    }
+    Ok(())
+}
+fn check_round_trip() -> Result<(), String> {
+    let tx = leaf_transaction();
+    let body = TransactionBody {
+        prevouts: tx.output.clone(),
+        merkle_block: None,
+        tx,
+    };
+    let record = serde_json::to_string(&StoredBody::from(&body)).map_err(|err| err.to_string())?;
+    let parsed = TransactionBody::try_from(record).map_err(|err| err.to_string())?;
+    if parsed != body {
+        return Err("a transaction changed after being written and read back".into());
+    }
+    Ok(())
+}
+fn check_database() -> Result<(), String> {
+    let dir = std::env::temp_dir().join(format!("utreexo-wallet-selftest-{}", std::process::id()));
+    let result = check_database_in(dir.to_string_lossy().to_string());
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+/// Saves an address and our height, then checks they are still there after reopening
+fn check_database_in(dir: String) -> Result<(), String> {
+    let script = leaf_transaction().output[0].script_pubkey.clone();
+    let open = || -> Result<AddressCache<KvDatabase, KvChainStore>, String> {
+        let database =
+            KvDatabase::new(dir.clone(), SCRATCH_DB_CACHE).map_err(|err| err.to_string())?;
+        let chain_store = KvChainStore::new(dir.clone()).map_err(|err| err.to_string())?;
+        Ok(AddressCache::new(database, chain_store))
+    };
+
+    let mut wallet = open()?;
+    wallet
+        .cache_address(script.clone())
+        .map_err(|err| err.to_string())?;
+    wallet.bump_height(LEAF_HEIGHT);
+    drop(wallet);
+
+    let wallet = open()?;
+    let height = wallet.get_cache_height().map_err(|err| err.to_string())?;
+    if height != LEAF_HEIGHT {
+        return Err(format!("saved height {LEAF_HEIGHT}, read back {height}"));
+    }
+    let summary = wallet.get_wallet_summary(Network::Testnet, AddressFormat::default());
+    if summary.len() != 1 || summary[0].script_hash != get_spk_hash(&script) {
+        return Err("the address we saved wasn't read back".into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run, CHECKS};
+
+    #[test]
+    fn test_run() {
+        // Checked one by one first, so a failure says which one
+        for (name, check) in CHECKS {
+            assert_eq!(check(), Ok(()), "{name}");
+        }
+        assert!(run());
+    }
+}
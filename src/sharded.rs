@@ -0,0 +1,113 @@
+//! An LRU cache split into shards, each behind its own lock, so requests looking up
+//! different keys from different threads rarely wait on each other. A key always goes to the
+//! same shard, picked by its hash.
+//!
+//! Each shard evicts on its own, so the entry dropped when one is full is the least recently
+//! used of that shard, not always of the whole cache. Anything touching every shard, like
+//! resizing, takes their locks in order, so two of them can't deadlock.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::{Mutex, MutexGuard},
+};
+
+use lru::LruCache;
+
+/// How many shards a cache is split into
+const SHARDS: usize = 16;
+
+pub struct ShardedLru<K: Hash + Eq, V> {
+    shards: Vec<Mutex<LruCache<K, V>>>,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedLru<K, V> {
+    /// A cache holding about `capacity` entries. Each shard holds at least one, so a cache
+    /// smaller than our shard count holds a few more than asked.
+    pub fn new(capacity: NonZeroUsize) -> ShardedLru<K, V> {
+        let shards = (0..SHARDS)
+            .map(|shard| Mutex::new(LruCache::new(shard_capacity(capacity, shard))))
+            .collect();
+        ShardedLru { shards }
+    }
+    fn shard(&self, key: &K) -> MutexGuard<LruCache<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .expect("Poisoned lock")
+    }
+    /// Locks every shard, in order
+    fn lock_all(&self) -> Vec<MutexGuard<LruCache<K, V>>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().expect("Poisoned lock"))
+            .collect()
+    }
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).get(key).cloned()
+    }
+    pub fn put(&self, key: K, value: V) {
+        self.shard(&key).put(key, value);
+    }
+    pub fn pop(&self, key: &K) -> Option<V> {
+        self.shard(key).pop(key)
+    }
+    /// How many entries we may hold, across every shard
+    pub fn cap(&self) -> usize {
+        self.lock_all().iter().map(|shard| shard.cap().get()).sum()
+    }
+    pub fn resize(&self, capacity: NonZeroUsize) {
+        for (n, mut shard) in self.lock_all().into_iter().enumerate() {
+            shard.resize(shard_capacity(capacity, n));
+        }
+    }
+}
+
+/// How much of `capacity` shard number `shard` gets. The remainder goes to the first ones.
+fn shard_capacity(capacity: NonZeroUsize, shard: usize) -> NonZeroUsize {
+    let share = capacity.get() / SHARDS + usize::from(shard < capacity.get() % SHARDS);
+    NonZeroUsize::new(share.max(1)).expect("At least one")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{num::NonZeroUsize, sync::Arc};
+
+    use super::{ShardedLru, SHARDS};
+
+    #[test]
+    fn test_sharded_lru() {
+        let cache = ShardedLru::<u32, u32>::new(NonZeroUsize::new(100).unwrap());
+        assert_eq!(cache.cap(), 100);
+        for key in 0..100 {
+            cache.put(key, key * 2);
+        }
+        assert_eq!(cache.get(&7), Some(14));
+        assert_eq!(cache.pop(&7), Some(14));
+        assert_eq!(cache.get(&7), None);
+        // Small caches still have room in every shard
+        cache.resize(NonZeroUsize::new(1).unwrap());
+        assert_eq!(cache.cap(), SHARDS);
+
+        // Threads working on different keys don't lose each other's entries
+        let cache = Arc::new(ShardedLru::<u32, u32>::new(
+            NonZeroUsize::new(1000).unwrap(),
+        ));
+        let threads = (0..4)
+            .map(|thread| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for key in (thread * 100)..(thread * 100 + 100) {
+                        cache.put(key, key + 1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!((0..400).all(|key| cache.get(&key) == Some(key + 1)));
+    }
+}
@@ -0,0 +1,353 @@
+//! Keeps track of the pieces of a running server. Everything depends on our main loop, which
+//! keeps our wallet in sync and answers every request, so services are started after it
+//! and stopped before it. Among themselves, each service starts after the ones it depends on,
+//! and they are stopped in reverse start order, so nothing outlives what it needs. A service that fails is restarted after a
+//! backoff, and if it keeps failing we shut the whole server down, instead of running
+//! half-alive. The health of each piece can be checked with `admin.gethealth`.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{mpsc::Sender, Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use log::{error, info, warn};
+use serde::{Serialize, Serializer};
+
+use crate::{crash::InSubsystem, electrum::electrum_protocol::Message};
+
+/// How many times in a row a service may fail before we give up on it
+const MAX_RESTARTS: u32 = 5;
+/// A service that ran for this long before failing has its failure count reset
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subsystem {
+    /// Our main loop, which syncs the wallet and answers requests
+    Sync,
+    Electrum,
+    /// Our Electrum port for TLS clients
+    ElectrumTls,
+    Rest,
+    /// Our electrs-compatible metrics
+    Monitoring,
+    Grpc,
+    /// Runs our periodic jobs
+    Scheduler,
+    /// The Electrum port on which clients are one wallet's, without authenticating
+    WalletListener(u16),
+    /// Keeps our router forwarding our Electrum TLS port
+    PortMapping,
+    /// Compares our node's tips with our fallback nodes'
+    TipCheck,
+}
+
+impl std::fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Subsystem::Sync => write!(f, "sync"),
+            Subsystem::Electrum => write!(f, "electrum"),
+            Subsystem::ElectrumTls => write!(f, "electrum_tls"),
+            Subsystem::Rest => write!(f, "rest"),
+            Subsystem::Monitoring => write!(f, "monitoring"),
+            Subsystem::Grpc => write!(f, "grpc"),
+            Subsystem::Scheduler => write!(f, "scheduler"),
+            Subsystem::WalletListener(port) => write!(f, "wallet_listener_{port}"),
+            Subsystem::PortMapping => write!(f, "port_mapping"),
+            Subsystem::TipCheck => write!(f, "tip_check"),
+        }
+    }
+}
+
+/// Subsystems are keys in our health report, so they must serialize as strings
+impl Serialize for Subsystem {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Subsystem {
+    /// What must be running for this subsystem to work
+    pub fn depends_on(&self) -> &'static [Subsystem] {
+        match self {
+            Subsystem::Sync => &[],
+            Subsystem::Electrum
+            | Subsystem::ElectrumTls
+            | Subsystem::Rest
+            | Subsystem::Monitoring
+            | Subsystem::Grpc
+            | Subsystem::Scheduler
+            | Subsystem::TipCheck
+            | Subsystem::WalletListener(_) => &[Subsystem::Sync],
+            // It forwards our router's port to our Electrum TLS listener
+            Subsystem::PortMapping => &[Subsystem::Sync, Subsystem::ElectrumTls],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Health {
+    Running,
+    /// Failed, and will be started again
+    Restarting {
+        failures: u32,
+        error: String,
+    },
+    /// Failed too many times, we gave up on it
+    Failed {
+        error: String,
+    },
+    Stopped,
+}
+
+/// The order to start `subsystems` in, as indexes into it: each one after the ones it depends
+/// on, and otherwise in the order they were added. Dependencies we don't run as services, like
+/// our main loop, hold nothing back.
+fn start_order(subsystems: &[Subsystem]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(subsystems.len());
+    let mut started = vec![false; subsystems.len()];
+    while order.len() < subsystems.len() {
+        let next = (0..subsystems.len())
+            .find(|&n| {
+                !started[n]
+                    && subsystems[n].depends_on().iter().all(|dependency| {
+                        subsystems
+                            .iter()
+                            .zip(started.iter())
+                            .all(|(other, started)| other != dependency || *started)
+                    })
+            })
+            .expect("Our subsystems depend on each other in a cycle");
+        started[next] = true;
+        order.push(next);
+    }
+    order
+}
+
+/// The health of each subsystem, shared with whoever wants to report it
+pub type HealthReport = Arc<RwLock<BTreeMap<Subsystem, Health>>>;
+
+type ServiceFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+/// Starts a service. It's called again for each restart.
+type ServiceFactory = Box<dyn Fn() -> ServiceFuture + Send>;
+
+pub struct Supervisor {
+    services: Vec<(Subsystem, ServiceFactory)>,
+    health: HealthReport,
+    /// Used to stop our main loop, if a service can't be kept up
+    notify_tx: Sender<Message>,
+}
+
+impl Supervisor {
+    pub fn new(health: HealthReport, notify_tx: Sender<Message>) -> Supervisor {
+        Supervisor {
+            services: vec![],
+            health,
+            notify_tx,
+        }
+    }
+    /// Adds a service, to be started once we run
+    pub fn add_service<F, Fut>(&mut self, subsystem: Subsystem, start: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.services
+            .push((subsystem, Box::new(move || Box::pin(start()))));
+    }
+    fn set_health(health: &HealthReport, subsystem: Subsystem, state: Health) {
+        health
+            .write()
+            .expect("Poisoned lock")
+            .insert(subsystem, state);
+    }
+    /// Runs `main_loop` on this thread, with every service around it. Returns when the main
+    /// loop does, after all services are stopped.
+    pub fn run(
+        self,
+        main_loop: impl Future<Output = Result<(), crate::error::Error>>,
+    ) -> Result<(), crate::error::Error> {
+        let order = start_order(
+            &self
+                .services
+                .iter()
+                .map(|(subsystem, _)| *subsystem)
+                .collect::<Vec<_>>(),
+        );
+        let mut services = self.services.into_iter().map(Some).collect::<Vec<_>>();
+        Self::set_health(&self.health, Subsystem::Sync, Health::Running);
+        let running = order
+            .into_iter()
+            .map(|n| {
+                let (subsystem, start) = services[n].take().expect("Each service starts once");
+                let handle = task::spawn(Self::watch(
+                    subsystem,
+                    start,
+                    self.health.clone(),
+                    self.notify_tx.clone(),
+                ));
+                (subsystem, handle)
+            })
+            .collect::<Vec<_>>();
+
+        let result = task::block_on(InSubsystem::new(Subsystem::Sync, main_loop));
+        match &result {
+            Ok(_) => Self::set_health(&self.health, Subsystem::Sync, Health::Stopped),
+            Err(err) => Self::set_health(
+                &self.health,
+                Subsystem::Sync,
+                Health::Failed {
+                    error: err.to_string(),
+                },
+            ),
+        }
+        task::block_on(async {
+            for (subsystem, handle) in running.into_iter().rev() {
+                info!("Stopping {subsystem:?}");
+                handle.cancel().await;
+                Self::set_health(&self.health, subsystem, Health::Stopped);
+            }
+        });
+        result
+    }
+    /// Whether any subsystem failed for good
+    pub fn any_failed(health: &HealthReport) -> bool {
+        health
+            .read()
+            .expect("Poisoned lock")
+            .values()
+            .any(|health| matches!(health, Health::Failed { .. }))
+    }
+    /// Keeps a service running, restarting it when it fails
+    async fn watch(
+        subsystem: Subsystem,
+        start: ServiceFactory,
+        health: HealthReport,
+        notify_tx: Sender<Message>,
+    ) {
+        let mut failures = 0;
+        let mut backoff = MIN_RESTART_BACKOFF;
+        loop {
+            Self::set_health(&health, subsystem, Health::Running);
+            let started = Instant::now();
+            let service = InSubsystem::new(subsystem, start());
+            let error = match service.await {
+                Ok(_) => {
+                    Self::set_health(&health, subsystem, Health::Stopped);
+                    return;
+                }
+                Err(error) => error,
+            };
+            if started.elapsed() > HEALTHY_AFTER {
+                failures = 0;
+                backoff = MIN_RESTART_BACKOFF;
+            }
+            failures += 1;
+            if failures > MAX_RESTARTS {
+                error!("{subsystem:?} keeps failing, shutting down: {error}");
+                Self::set_health(&health, subsystem, Health::Failed { error });
+                let _ = notify_tx.send(Message::Shutdown);
+                return;
+            }
+            warn!(
+                "{subsystem:?} failed: {error}, restarting in {}s",
+                backoff.as_secs()
+            );
+            Self::set_health(&health, subsystem, Health::Restarting { failures, error });
+            task::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{mpsc::channel, Arc, Mutex, RwLock},
+        time::Duration,
+    };
+
+    use async_std::task;
+
+    use super::{start_order, Health, Subsystem, Supervisor};
+
+    /// Records its subsystem when the service holding it is stopped
+    struct StopGuard(Subsystem, Arc<Mutex<Vec<Subsystem>>>);
+    impl Drop for StopGuard {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+
+    #[test]
+    fn test_start_order() {
+        let (mapping, electrum, rest) = (
+            Subsystem::PortMapping,
+            Subsystem::ElectrumTls,
+            Subsystem::Rest,
+        );
+        // Port mapping needs the TLS listener, wherever it was added
+        assert_eq!(start_order(&[mapping, electrum, rest]), vec![1, 0, 2]);
+        assert_eq!(start_order(&[electrum, rest, mapping]), vec![0, 1, 2]);
+        // Without the listener, nothing holds it back
+        assert_eq!(start_order(&[mapping, rest]), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_stop_order() {
+        let health = Arc::new(RwLock::new(BTreeMap::new()));
+        let (notify_tx, _notify_rx) = channel();
+        let mut supervisor = Supervisor::new(health.clone(), notify_tx);
+        let started = Arc::new(Mutex::new(vec![]));
+        let stopped = Arc::new(Mutex::new(vec![]));
+        for subsystem in [
+            Subsystem::PortMapping,
+            Subsystem::ElectrumTls,
+            Subsystem::Rest,
+        ] {
+            let started = started.clone();
+            let stopped = stopped.clone();
+            supervisor.add_service(subsystem, move || {
+                let started = started.clone();
+                let stopped = stopped.clone();
+                async move {
+                    started.lock().unwrap().push(subsystem);
+                    let _guard = StopGuard(subsystem, stopped);
+                    async_std::future::pending::<()>().await;
+                    Ok::<(), String>(())
+                }
+            });
+        }
+        let main_loop = {
+            let started = started.clone();
+            async move {
+                while started.lock().unwrap().len() < 3 {
+                    task::sleep(Duration::from_millis(10)).await;
+                }
+                Ok::<(), crate::error::Error>(())
+            }
+        };
+        supervisor.run(main_loop).unwrap();
+        // Whatever depends on a service is stopped before it
+        assert_eq!(
+            *stopped.lock().unwrap(),
+            vec![
+                Subsystem::Rest,
+                Subsystem::PortMapping,
+                Subsystem::ElectrumTls
+            ]
+        );
+        assert!(health
+            .read()
+            .unwrap()
+            .values()
+            .all(|health| matches!(health, Health::Stopped)));
+    }
+}